@@ -4,6 +4,7 @@ use anyhow::bail;
 use std::fmt::Debug;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
+use std::path::Path;
 
 //TODO: Fix config again...
 //Config structs and setup
@@ -239,7 +240,7 @@ impl RobInfo {
 }
 
 ///Helper function for both cam and rob config to extract xyz coords/rotations from a given string surrounded by "[]" and delimited by ","
-fn pos_ori_parser(line: String) -> Result<[f64; 3], anyhow::Error> {
+pub(crate) fn pos_ori_parser(line: String) -> Result<[f64; 3], anyhow::Error> {
     //Access the string array
     let line_split: Vec<&str> = line.split("[").collect();
 
@@ -259,3 +260,56 @@ fn pos_ori_parser(line: String) -> Result<[f64; 3], anyhow::Error> {
     }
     Ok(out)
 }
+
+///Read a settings file of KEY = "value" lines (or KEY = [x,y,z] for pos_ori_parser) into (key, value) pairs
+///Blank lines are skipped, the quotes are removed and the brackets are kept
+pub(crate) fn read_config_values(filepath: &str) -> Result<Vec<(String, String)>, anyhow::Error> {
+    let file = File::open(filepath.trim())?;
+    let mut values = vec![];
+
+    for line in BufReader::new(file).lines() {
+        let curr_line = line?;
+
+        if curr_line.trim().is_empty() {
+            continue;
+        }
+
+        let Some((key, val)) = curr_line.split_once("=") else {
+            bail!("Invalid config line - {}", curr_line);
+        };
+        let val = val.trim();
+
+        let val = if val.starts_with("[") {
+            val
+        } else if let Some(quoted) = val.strip_prefix("\"").and_then(|val| val.strip_suffix("\"")) {
+            quoted.trim()
+        } else {
+            bail!("Config values must be quoted - {}", curr_line);
+        };
+
+        values.push((key.trim().to_string(), val.to_string()));
+    }
+
+    Ok(values)
+}
+
+///Load a settings file with its loader - the defaults are used if the file is missing or fails to load
+///name - what the file holds (shown if it fails to load)
+pub(crate) fn load_config_or_default<T>(
+    filepath: &str,
+    name: &str,
+    load: impl FnOnce(&str) -> Result<T, anyhow::Error>,
+    default: impl FnOnce() -> T,
+) -> T {
+    if !Path::new(filepath).exists() {
+        return default();
+    }
+
+    match load(filepath) {
+        Ok(val) => val,
+        Err(e) => {
+            println!("Failed to load {} ({e}) - using defaults", name);
+            default()
+        }
+    }
+}
@@ -2,5 +2,6 @@
 pub mod abb_rob;
 pub mod egm_control;
 pub mod force_control;
+pub mod frames;
 pub mod misc_tools;
 pub mod trajectory_planner;
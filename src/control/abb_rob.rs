@@ -3,16 +3,33 @@ use crate::config::Config;
 use crate::control::egm_control::abb_egm::{EgmRobot, EgmSensor};
 use crate::control::egm_control::egm_udp::EgmServer;
 use crate::control::force_control::controllers::PIDController;
-use crate::control::force_control::controllers::PIDWithNNTuner;
+use crate::control::force_control::controllers::{ForceController, pick_force_controller};
 use crate::control::force_control::force_function_generator::ForceFunctionGenerator;
+use crate::control::force_control::load_cell_comp::{LoadCellComp, LoadCellSample};
+use crate::control::force_control::safety_envelope::{SafetyBreach, SafetyEnvelope, SafetyLimits};
+use crate::control::force_control::stability_detector::{StabilityCriteria, StabilityDetector, StabilityStatus};
+use crate::control::force_control::stiffness_estimator::StiffnessEstimator;
+use crate::control::frames::Frames;
 use crate::control::misc_tools::angle_tools::Quaternion;
-use crate::control::misc_tools::misc::wait_for_enter;
+use crate::control::misc_tools::misc::{read_with_default, wait_for_enter};
+use crate::control::misc_tools::preview;
 use crate::control::misc_tools::string_tools;
 use crate::control::trajectory_planner;
-use crate::control::trajectory_planner::{calc_lateral_timing, calc_xyz_timing};
+use crate::control::trajectory_planner::height_grid::{GridPlacement, HeightGrid};
+use crate::control::trajectory_planner::interpolation;
+use crate::control::trajectory_planner::pose::{self, PosePlan};
+use crate::control::trajectory_planner::terrain::{TerrainFollow, TerrainProfile};
+use crate::control::trajectory_planner::tracking::{ReferenceTrack, TrackingController, TrackingError};
+use crate::control::trajectory_planner::traj_file;
+use crate::control::trajectory_planner::transforms;
+use crate::control::trajectory_planner::validation::{self, ValidationLimits};
+use crate::control::trajectory_planner::velocity_profile::{self, MotionLimits, ProfileShape};
+use crate::control::trajectory_planner::{Waypoint, calc_lateral_timing, calc_waypoint_timing};
 use crate::networking::tcp_sock;
 use crate::CamSysCntrl;
+use rustgeomapping::data_types::heightmap::Heightmap;
 use anyhow::bail;
+use std::fmt::Display;
 use std::fs;
 use std::fs::OpenOptions;
 use std::io::{prelude::*, stdin};
@@ -48,6 +65,16 @@ pub struct AbbRob<'a> {
     force_target: f64,
     ///The current force error (current force - target force)
     force_err: f64,
+    ///The gravity and bias compensation model of the currently attached tool
+    load_comp: Option<LoadCellComp>,
+    ///The force/torque limits checked in the EGM loops
+    safety_env: SafetyEnvelope,
+    ///The load cycle currently running (logged with each data row during cyclic tests)
+    cycle: Option<usize>,
+    ///The orientation the trajectory is currently streaming (logged with each data row when following a pose trajectory)
+    planned_ori: Option<Quaternion>,
+    ///The base, bed, tool and sensor frames
+    frames: Frames,
     ///Programme setup config
     config: &'a mut Config,
 }
@@ -56,6 +83,8 @@ pub struct AbbRob<'a> {
 struct TestData {
     ///The trajectory of the test
     traj: Vec<(f64, f64, f64)>,
+    ///The waypoints of the trajectory (with any per-waypoint speed, orientation, force and dwell)
+    waypoints: Vec<Waypoint>,
     ///The name of the test
     test_name: String,
     ///The filepath to store all test information
@@ -67,8 +96,9 @@ struct TestData {
 }
 impl TestData {
     ///Create a test data structure
-    fn create_test_data(config_fp: String, forcemode: bool) -> TestData {
-        let traj = Self::pick_trajectory(forcemode).unwrap();
+    fn create_test_data(config_fp: String, forcemode: bool, frames: &Frames) -> TestData {
+        let (waypoints, traj_desc) = Self::pick_trajectory(forcemode, frames).unwrap();
+        let traj = trajectory_planner::positions(&waypoints);
 
         let test_name = Self::get_test_name();
 
@@ -79,6 +109,7 @@ impl TestData {
         //Create the test data structure
         let t_data = TestData {
             traj,
+            waypoints,
             test_name: test_name.clone(),
             filepath,
             data_filename,
@@ -88,11 +119,15 @@ impl TestData {
         //Create all the directories that the test needs
         fs::create_dir(t_data.filepath.clone()).expect("FAILED TO CREATE NEW DIRECTORY");
 
+        //Record how the trajectory was generated
+        t_data.log_trajectory(&traj_desc);
+
         t_data
     }
 
     ///Lets the user pick a desired trajectory from a set of predetermined trajectories (or an earlier custom made one)
-    fn pick_trajectory(_forcemode: bool) -> Result<Vec<(f64, f64, f64)>, anyhow::Error> {
+    ///The trajectory is made in the bed frame and returned in the base frame
+    fn pick_trajectory(_forcemode: bool, frames: &Frames) -> Result<(Vec<Waypoint>, String), anyhow::Error> {
         let mut traj;
         //Loop until command given
         loop {
@@ -108,13 +143,20 @@ impl TestData {
             let user_inp = user_inp.to_lowercase();
             let user_inp = user_inp.trim();
 
-            traj = trajectory_planner::traj_gen(user_inp);
+            traj = trajectory_planner::traj_gen(user_inp, frames);
 
-            if traj.is_ok() {
-                return traj;
-            } else {
-                println!("Invalid trajectory!");
-                continue;
+            //Optionally transform the trajectory then smooth the path between the waypoints - then move it onto the bed
+            let traj = traj
+                .and_then(|(waypoints, desc)| transforms::prompt_transforms(waypoints, desc))
+                .and_then(|(waypoints, desc)| interpolation::prompt_interpolation(waypoints, desc))
+                .map(|(waypoints, desc)| (frames.bed_to_base(&waypoints), desc));
+
+            match traj {
+                Ok(traj) => return Ok(traj),
+                Err(e) => {
+                    println!("Invalid trajectory! - {}", e);
+                    continue;
+                }
             }
         }
     }
@@ -143,31 +185,54 @@ impl TestData {
         user_inp.trim().to_string()
     }
 
-    ///Create a text file that contains the desired trajectory for the test
-    ///Useful for comparing with performed trajectories generated via speed control
-    fn store_desired_trajectory(&mut self) {
-        //Store the desired trajectory in the filepath
-        let traj_fp = format!("{}/des_traj_{}.txt", self.filepath, self.test_name);
-        //Store a copy of the desired trajectory
-        //Create the config file and save the config info
+    ///Write the trajectory shape and parameters to the test config
+    fn log_trajectory(&self, traj_desc: &str) {
         let mut file = OpenOptions::new()
             .append(true)
             .create(true)
-            .open(traj_fp)
+            .open(self.config_filename.trim())
             .unwrap();
 
-    
-        for pnt in self.traj.iter() {
-            let line = format!("{:?}", pnt);
-            writeln!(file, "{}", line).expect("FAILED TO WRITE TRAJ - CLOSING");
-        }
-        
+        writeln!(file, "TRAJECTORY: {}", traj_desc).expect("FAILED TO WRITE TRAJECTORY TO CONFIG - CLOSING");
+    }
+
+    ///Add the motion limits used to time the trajectory to the config file
+    fn log_motion(&self, limits: &MotionLimits) {
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(self.config_filename.trim())
+            .unwrap();
+
+        writeln!(file, "MOTION: {}", limits).expect("FAILED TO WRITE MOTION TO CONFIG - CLOSING");
+    }
+
+    ///Add the terrain following settings to the config file
+    fn log_terrain(&self, follow: &TerrainFollow) {
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(self.config_filename.trim())
+            .unwrap();
+
+        writeln!(file, "TERRAIN: {}", follow).expect("FAILED TO WRITE TERRAIN TO CONFIG - CLOSING");
+    }
+
+    ///Create a text file that contains the desired trajectory for the test
+    ///Useful for comparing with performed trajectories generated via speed control
+    ///Written in the trajectory file format so it can be reloaded as a custom trajectory
+    fn store_desired_trajectory(&mut self, frames: &Frames) {
+        //Store the desired trajectory in the filepath (in the log frame, like the measured data)
+        let traj_fp = format!("{}/des_traj_{}.txt", self.filepath, self.test_name);
+
+        traj_file::save_traj_file(&frames.log_waypoints(&self.waypoints), &traj_fp, Some(frames.log_frame()))
+            .expect("FAILED TO WRITE TRAJ - CLOSING");
     }
 
 }
 
 ///A list of implemented user commands
-pub const IMPL_COMMDS: [&str; 10] = [
+pub const IMPL_COMMDS: [&str; 12] = [
     "info",
     "cmds",
     "disconnect",
@@ -178,10 +243,29 @@ pub const IMPL_COMMDS: [&str; 10] = [
     "home",
     "req xyz",
     "req ori",
+    "tool",
+    "lc calib",
 ];
 
-///Determines whether to pretransform data before being saved
-const TRANSFORM_TO_WORK_SPACE: bool = false;
+///The min xyz of the allowed TCP workspace (checked by limit_check)
+pub const WORKSPACE_MIN: [f64; 3] = [22.0, 1350.0, -50.0];
+///The max xyz of the allowed TCP workspace (checked by limit_check)
+pub const WORKSPACE_MAX: [f64; 3] = [790.0, 2650.0, 2000.0];
+
+///The fastest a trajectory may be run in speed control (mm/s)
+pub const MAX_TRAJ_SPEED: f64 = 10.0;
+
+///The fastest the TCP orientation may change along a trajectory (deg/s)
+pub const MAX_ANG_SPEED: f64 = 10.0;
+
+///Gain of the height correction when following the terrain (mm/s per mm of height error)
+pub const TERRAIN_GAIN: f64 = 0.5;
+
+///Shortest time (s) between terrain re-plans - heightmaps arriving in between are skipped
+pub const TERRAIN_REPLAN_PERIOD: f64 = 2.0;
+
+///The geo-test phase 3 PID gains
+pub const PHASE3_GAINS: [f64; 3] = [0.02, 0.003, 0.001];
 
 impl AbbRob<'_> {
     ///Connect to the ABB robot controller
@@ -203,6 +287,8 @@ impl AbbRob<'_> {
             //Failed to connect
             bail!("Robot not connected")
         } else {
+            let frames = Frames::load_or_default();
+
             let new_rob = AbbRob {
                 socket: rob_sock,
                 local,
@@ -215,6 +301,11 @@ impl AbbRob<'_> {
                 force_axis: 3,
                 force_target: 0.0,
                 force_err: 0.0,
+                load_comp: None,
+                safety_env: SafetyEnvelope::create(SafetyLimits::load_or_default()),
+                cycle: None,
+                planned_ori: None,
+                frames,
                 config,
             };
 
@@ -328,6 +419,16 @@ impl AbbRob<'_> {
                     println!("{:?}", self.force);
                 }
 
+                //Select the gravity compensation model of the attached tool
+                "tool" => {
+                    self.select_tool();
+                }
+
+                //Calibrate the load cell for the attached tool
+                "lc calib" => {
+                    self.calibrate_load_cell();
+                }
+
                 _ => println!("Unknown command - see CMDs for list of commands"),
             }
         }
@@ -450,7 +551,7 @@ impl AbbRob<'_> {
     ///A trajectory run that stores no information other than the desired trajectory
     fn dumb_trajectory(&mut self) {
         //Create the test data and the filepaths
-        let traj = TestData::pick_trajectory(false).unwrap();  
+        let traj = trajectory_planner::positions(&TestData::pick_trajectory(false, &self.frames).unwrap().0);
 
         //Star tin the home position
         self.go_home_pos();
@@ -471,15 +572,54 @@ impl AbbRob<'_> {
     ///Run a trajectory at a desired speed that also stores the data using EGM
     fn speed_trajectory(&mut self) {
         //Create the test data and the filepaths
-        let mut test_data = TestData::create_test_data(self.config.test_fp(), self.force_mode_flag);
-        //Store the desired trajectory
-        test_data.store_desired_trajectory();
+        let mut test_data = TestData::create_test_data(self.config.test_fp(), self.force_mode_flag, &self.frames);
 
-        //Calculate the speed instructions
+        //Calculate the speed instructions (waypoints may set their own speed and dwell)
         let desired_speed = 0.1;
-        let speed_instructions = calc_xyz_timing(&mut test_data.traj, desired_speed);
+
+        //Slow down where the orientation changes too quickly
+        test_data.waypoints = pose::limit_angular_speed(&test_data.waypoints, desired_speed, MAX_ANG_SPEED, false);
+        let pose_plan = PosePlan::create(&test_data.waypoints, false);
+
+        //Store the desired trajectory
+        test_data.store_desired_trajectory(&self.frames);
+
+        if !self.confirm_trajectory(&test_data, desired_speed, None) {
+            println!("Trajectory cancelled");
+            return;
+        }
+
+        //Acceleration limited speed changes unless the profile is set to step
+        let motion_limits = MotionLimits::load_or_default();
+        test_data.log_motion(&motion_limits);
+
+        let speed_instructions = match motion_limits.shape {
+            ProfileShape::Step => calc_waypoint_timing(&test_data.waypoints, desired_speed),
+            _ => match velocity_profile::plan_velocity_profile(&test_data.waypoints, desired_speed, &motion_limits, false) {
+                Ok(profile) => profile.instructions(motion_limits.sample_period),
+                Err(e) => {
+                    println!("Failed to plan the velocity profile - {}", e);
+                    return;
+                }
+            },
+        };
 
         println!("Speed: {:?}", speed_instructions);
+
+        //Follow the mapped surface instead of the trajectory heights
+        let terrain = match TerrainFollow::prompt() {
+            Ok(terrain) => terrain,
+            Err(e) => {
+                println!("{}", e);
+                return;
+            }
+        };
+        if let Some(follow) = &terrain {
+            test_data.log_terrain(follow);
+        }
+        let hmap_placement = GridPlacement::load_or_default();
+        let mut terrain_profile: Option<TerrainProfile> = None;
+        let terrain_fp = format!("{}/terrain_traj_{}.txt", test_data.filepath, test_data.test_name);
         
 
         //Move to starting position
@@ -491,6 +631,37 @@ impl AbbRob<'_> {
         println!("START height: {}", test_data.traj[0].2);
         self.write_marker(&test_data.data_filename, "TEST STARTED");
         self.set_pos(start_pos);
+        if let Some(plan) = &pose_plan {
+            self.set_ori(plan.start_ori());
+        }
+
+        //The depth cam subsystem is only needed to follow the terrain
+        let mut last_replan = SystemTime::now();
+        let cam_sys = match &terrain {
+            Some(follow) => {
+                self.update_rob_info();
+                let (pos_tx, hmap_rx, cntrl_tx) = self.spawn_cam_sys(test_data.filepath.clone());
+
+                //Plan the height from the latest heightmap before the move starts
+                println!("Waiting for the first heightmap....");
+                let Ok(first) = hmap_rx.recv() else {
+                    println!("No heightmap from the camera system - trajectory cancelled");
+                    self.go_home_pos();
+                    return;
+                };
+                let hmap = hmap_rx.try_iter().last().unwrap_or(first);
+
+                terrain_profile = self.plan_terrain(follow, &test_data.waypoints, &hmap, hmap_placement, &terrain_fp, &test_data.data_filename);
+                last_replan = SystemTime::now();
+
+                if let Some(profile) = &terrain_profile {
+                    self.set_pos((start_pos.0, start_pos.1, profile.z_at(0.0)));
+                }
+
+                Some((pos_tx, hmap_rx, cntrl_tx))
+            }
+            None => None,
+        };
 
         //Setup and connect EGM
         let egm_client = self.connect_egm_pose().expect("Failed to connect to EGM");
@@ -508,53 +679,94 @@ impl AbbRob<'_> {
         let mut cnt = 0;
         let mut desired_speed: [f64; 3];
 
+        //Follow the time-parameterised reference from the start point - the height is left to the terrain when following it
+        let mut tracker = TrackingController::create(ReferenceTrack::create(start_pos, &speed_instructions), terrain.is_some());
+        let track_filename = format!("{}/track_{}.txt", test_data.filepath, test_data.test_name);
+        let duration = tracker.reference().duration();
+
+        //One clock drives the reference - the instruction in use follows from the time along it
+        let run_start = SystemTime::now();
+
         //Run the trajectory 
-        for instruction in speed_instructions.iter() {
-            //Get the time limit
-            let time_lim = instruction.0;
+        while run_start.elapsed().unwrap().as_secs_f64() < duration {
+            //Get the egm message
+            let msg = egm_client.recv_egm().expect("Failed to get egm message");
 
-            //Start the timer
-            let local_time = SystemTime::now();
+            let time = msg.get_time().expect("Failed to get egm time");
+            let t = run_start.elapsed().unwrap().as_secs_f64().min(duration);
 
-            //While the timer is running
-            while local_time.elapsed().unwrap().as_secs_f64() < time_lim {
-                //Get the egm message
-                let msg = egm_client.recv_egm().expect("Failed to get egm message");
+            //Orientation at the commanded point along the path
+            self.planned_ori = pose_plan
+                .as_ref()
+                .map(|plan| plan.ori_at_dist(tracker.reference().dist_at(t)));
 
-                let time = msg.get_time().expect("Failed to get egm time");
+            //Log the robot information gathered by the EGM using
+            let _ = self.egm_update_state(msg);
+            self.store_state(&test_data.data_filename, cnt);
 
-                //Log the robot information gathered by the EGM using
-                let _ = self.egm_update_state(msg);
-                self.store_state(&test_data.data_filename, cnt);
+            //Update the mapping tool
+            if let Some((pos_tx, _, _)) = &cam_sys {
+                pos_tx.send_replace([self.pos.0 as f32, self.pos.1 as f32, self.pos.2 as f32, self.ori.0 as f32, self.ori.1 as f32, self.ori.2 as f32, self.ori.3 as f32]);
+            }
 
-                if self.limit_check() {
-                    println!("Out of bounds");
-                    egm_client.egm_end();
-                    self.go_home_pos();
-                    self.write_marker(&test_data.data_filename, "TEST OUT OF  SAFETY BOUNDS");
-                    return;
+            if let Some(breach) = self.safety_check() {
+                self.safe_abort(egm_client, seqno, cnt, &test_data.data_filename, breach);
+                if let Some((_, _, cntrl_tx)) = &cam_sys {
+                    cntrl_tx.send_replace(1);
                 }
+                return;
+            }
 
-                //Load the lateral instructions - then set the force_controlled axis to the desired speed
-                desired_speed = [instruction.1.0, instruction.1.1, instruction.1.2];
+            //Re-plan the height from the newest heightmap (at most once per re-plan period)
+            if let Some(follow) = &terrain
+                && let Some((_, hmap_rx, _)) = &cam_sys
+                && last_replan.elapsed().unwrap().as_secs_f64() >= TERRAIN_REPLAN_PERIOD
+                && let Some(hmap) = hmap_rx.try_iter().last()
+            {
+                last_replan = SystemTime::now();
 
+                if let Some(profile) = self.plan_terrain(follow, &test_data.waypoints, &hmap, hmap_placement, &terrain_fp, &test_data.data_filename) {
+                    terrain_profile = Some(profile);
+                }
+            }
 
-                let sensor: EgmSensor = EgmSensor::set_pose_set_speed(
-                    seqno,
-                    time,
-                    [0.0, 0.0, 0.0],
-                    self.ori.into(),
-                    desired_speed,
-                );
-                egm_client
-                    .send_egm(sensor)
-                    .expect("Failed to send sensor info");
-                seqno += 1;
-                cnt += 1;
+            //Correct the reference speed towards the reference position
+            let track_err = tracker.update(t, self.pos);
+            self.store_tracking(&track_filename, cnt, &track_err);
+            desired_speed = track_err.speed;
+
+            //Follow the planned height above/below the surface
+            if let Some(profile) = &terrain_profile {
+                let lateral_dist = tracker.reference().lateral_dist_at(t);
+                desired_speed[2] = (TERRAIN_GAIN * (profile.z_at(lateral_dist) - self.pos.2)).clamp(-MAX_TRAJ_SPEED, MAX_TRAJ_SPEED);
             }
+
+
+            let sensor: EgmSensor = EgmSensor::set_pose_set_speed(
+                seqno,
+                time,
+                [0.0, 0.0, 0.0],
+                self.planned_ori.map_or(self.ori.into(), |q| [q.w, q.x, q.y, q.z]),
+                desired_speed,
+            );
+            egm_client
+                .send_egm(sensor)
+                .expect("Failed to send sensor info");
+            seqno += 1;
+            cnt += 1;
         }
+        self.planned_ori = None;
+        self.write_marker(
+            &test_data.data_filename,
+            &format!("TRACKING END ERROR: {:.2}mm", tracker.end_error(self.pos)),
+        );
         self.write_marker(&test_data.data_filename, "TEST ENDED");
 
+        //Send the off signal to the mapping thread
+        if let Some((_, _, cntrl_tx)) = &cam_sys {
+            cntrl_tx.send_replace(1);
+        }
+
         //End the EGM client
         egm_client.egm_end();
 
@@ -585,34 +797,127 @@ impl AbbRob<'_> {
         }
 
         //Create the test data and the filepaths
-        let mut test_data = TestData::create_test_data(self.config.test_fp(), self.force_mode_flag);
+        let mut test_data = TestData::create_test_data(self.config.test_fp(), self.force_mode_flag, &self.frames);
 
         //Create copys of the config for the threads
         let fp_copy = test_data.filepath.clone();
         let test_name_copy = test_data.test_name.clone();
 
         //Store the desired trajectory
-        test_data.store_desired_trajectory();
+        test_data.store_desired_trajectory(&self.frames);
 
         //Store the desired force profile
         let ffunc_fp = format!("{}/forcefunc_{}.txt", fp_copy, test_name_copy);
-        let _ = ffunc.save_to_file(&ffunc_fp);
+        if let Err(e) = ffunc.save_to_file(&ffunc_fp) {
+            println!("Failed to save the force profile - {}", e);
+        }
 
         //Calcualte the speed intructions
-        let desired_lat_speed = 0.1;
+        let mut desired_lat_speed = 0.1;
 
-        let speed_instructions = calc_lateral_timing(&mut test_data.traj, desired_lat_speed);
+        let mut speed_instructions = calc_lateral_timing(&mut test_data.traj, desired_lat_speed);
 
         let mut total_time = 0.0;
         for instruction in speed_instructions.iter() {
             total_time += instruction.0;
         }
 
-        //Calculate the associated force instructions
-        let force_vals = ffunc.sig_vals();
-        self.force_target = force_vals[0];
+        //Per-waypoint speeds and dwells set the trajectory timing themselves
+        let timed_waypoints = test_data.waypoints.iter().any(|w| w.speed.is_some() || w.dwell.is_some());
+
+        //Profiles defined in seconds set the test duration
+        if let Some(duration) = ffunc.duration()
+            && !timed_waypoints
+        {
+            if total_time > 0.0 {
+                //Scale the lateral speed so the trajectory takes as long as the profile
+                desired_lat_speed *= total_time / duration;
 
-        let force_times = ffunc.as_time_f64(total_time);
+                if desired_lat_speed > MAX_SPEED {
+                    println!("Profile too short for the trajectory - lateral speed would be {desired_lat_speed}mm/s");
+                    return;
+                }
+
+                speed_instructions = calc_lateral_timing(&mut test_data.traj, desired_lat_speed);
+            } else {
+                //No lateral movement - hold in place for the profile
+                speed_instructions = vec![(duration, (0.0, 0.0))];
+            }
+
+            total_time = duration;
+            println!("Test duration set by the force profile: {duration}s (lateral speed {desired_lat_speed}mm/s)");
+        }
+
+        //Acceleration limited lateral speed changes unless the profile is set to step
+        let motion_limits = MotionLimits::load_or_default();
+        test_data.log_motion(&motion_limits);
+
+        let lateral_pnts: Vec<Waypoint> = if timed_waypoints {
+            test_data.waypoints.clone()
+        } else {
+            test_data.traj.iter().map(|pnt| Waypoint::from(*pnt)).collect()
+        };
+        if (motion_limits.shape != ProfileShape::Step || timed_waypoints)
+            && let Ok(profile) = velocity_profile::plan_velocity_profile(&lateral_pnts, desired_lat_speed, &motion_limits, true)
+            && profile.duration() > 0.0
+        {
+            speed_instructions = profile.lateral_instructions(motion_limits.sample_period);
+
+            match ffunc.duration() {
+                //Profiles in seconds hold their final value if the trajectory runs longer
+                Some(duration) => {
+                    if profile.duration() > duration {
+                        println!(
+                            "The trajectory takes {:.1}s - the force profile holds its final value after {duration}s",
+                            profile.duration()
+                        );
+                    } else if timed_waypoints && profile.duration() < duration {
+                        println!(
+                            "Waypoint timing ends the trajectory at {:.1}s - the force profile is cut short ({duration}s)",
+                            profile.duration()
+                        );
+                    }
+
+                    if timed_waypoints {
+                        total_time = profile.duration();
+                    }
+                }
+                None => total_time = profile.duration(),
+            }
+        }
+
+        //Waypoints with a force target override the force profile
+        if test_data.waypoints.iter().any(|w| w.force.is_some()) {
+            println!("Waypoint force targets override the force profile in phase 3");
+        }
+
+        //Labelled profile segments - marked in the data as they start
+        let segment_labels = ffunc.labels_as_time(total_time);
+
+        //Stretch percentage profiles over the test so the profile can be evaluated in seconds
+        let ffunc = match ffunc.duration() {
+            Some(_) => ffunc,
+            None => match ffunc.with_duration(total_time) {
+                Ok(ffunc) => ffunc,
+                Err(e) => {
+                    println!("Failed to fit the force profile to the trajectory - {}", e);
+                    return;
+                }
+            },
+        };
+        self.force_target = ffunc.value_at(0.0);
+
+        //Planned TCP orientation along the path (force control moves the tool vertically so only xy distance counts)
+        let pose_plan = PosePlan::create(&test_data.waypoints, true);
+
+        //Check what is about to be run
+        Self::preview_test(&test_data, &ffunc);
+
+        if !self.confirm_trajectory(&test_data, desired_lat_speed, Some(self.force_axis)) {
+            println!("Test cancelled");
+            return;
+        }
+        let mut next_segment = 0;
 
         //Setup the seperate PID controllers
         let mut force_controller = PIDController::create_PID(0.001, 0.0005, 0.001);
@@ -620,16 +925,39 @@ impl AbbRob<'_> {
         
 
         //Original
-        let phase3_gains = [0.02, 0.003, 0.001];
+        let phase3_gains = PHASE3_GAINS;
 
         //Softer gains for lower force threshold   
         let mut max_targ = 0.0;
         let softer_gains = [0.01, 0.003, 0.0005];
         
 
+        //Pick the phase 3 controller (None - continue with the phase 2 PID)
+        let mut phase3_override = self.pick_phase3_controller(phase3_gains);
+
+        //Follow the mapped surface - horizontal loading sets the height from it, vertical loading follows its slope
+        let terrain = match TerrainFollow::prompt() {
+            Ok(terrain) => terrain,
+            Err(e) => {
+                println!("{}", e);
+                return;
+            }
+        };
+        if let Some(follow) = &terrain {
+            test_data.log_terrain(follow);
+        }
+        let hmap_placement = GridPlacement::load_or_default();
+        let mut terrain_profile: Option<TerrainProfile> = None;
+
+        //Phase 2 stability criteria
+        let stability_criteria = StabilityCriteria::load_or_default();
+
         //Setup the config information
-        self.config.set_phase2_cntrl(force_controller.to_string());
-        self.config.set_phase3_cntrl(format!("PID - P:{},I:{},D:{}",phase3_gains[0], phase3_gains[1], phase3_gains[2]));
+        self.config.set_phase2_cntrl(format!("{} - STABILITY: {}", force_controller, stability_criteria));
+        match &phase3_override {
+            Some(cntrl) => self.config.set_phase3_cntrl(cntrl.to_string()),
+            None => self.config.set_phase3_cntrl(format!("PID - P:{},I:{},D:{}",phase3_gains[0], phase3_gains[1], phase3_gains[2])),
+        }
 
         //Log the config
         self.log_config(&test_data.config_filename);
@@ -649,37 +977,45 @@ impl AbbRob<'_> {
 
         //Move to the starting point
         self.set_pos(start_pos);
-
-        //Embed self if doing horizontal loading - simplistic  approach
-        if self.force_axis != 2 {
-            self.set_pos((start_pos.0, start_pos.1, start_pos.2 - 50.0))
+        if let Some(plan) = &pose_plan {
+            self.set_ori(plan.start_ori());
         }
 
         //Read the values once
         self.update_rob_info();
 
-         //Spin up the depth cam subsystem thread
-        let rust_filepath = test_data.filepath;
-
-        //Create the thread piping system
-        let(pos_tx, pos_rx)  = watch::channel([self.pos.0 as f32, self.pos.1 as f32, self.pos.2 as f32, self.ori.0 as f32, self.ori.1 as f32, self.ori.2 as f32, self.ori.3 as f32]);
-
-        let (hmap_tx, hmap_rx) = mpsc::channel();
-
-        let (cntrl_tx, cntrl_rx) = watch::channel(0);
-
-        //Spawn the cam system thread
-        println!("Spinning up camera control thread....");
-        let cam_sys_thread = thread::spawn(|| {
-            if let Ok(mut cam_sys) = CamSysCntrl::default_connect(pos_rx, hmap_tx, cntrl_rx, rust_filepath){
-
-                cam_sys.start_system().unwrap();
+        //Track the soil stiffness throughout the test
+        let mut stiff_est = StiffnessEstimator::create();
+        let stiff_filename = format!("{}/stiff_{}.txt", test_data.filepath, test_data.test_name);
+
+        //The terrain adjusted trajectory (rewritten on each re-plan)
+        let terrain_fp = format!("{}/terrain_traj_{}.txt", test_data.filepath, test_data.test_name);
+        let track_filename = format!("{}/track_{}.txt", test_data.filepath, test_data.test_name);
+
+        //Spin up the depth cam subsystem thread
+        let (pos_tx, hmap_rx, cntrl_tx) = self.spawn_cam_sys(test_data.filepath.clone());
+
+        //Plan the height from the latest heightmap before the tool goes in
+        let mut last_replan = SystemTime::now();
+        if let Some(follow) = &terrain {
+            println!("Waiting for the first heightmap....");
+            let Ok(first) = hmap_rx.recv() else {
+                println!("No heightmap from the camera system - test cancelled");
+                self.go_home_pos();
+                return;
+            };
+            let hmap = hmap_rx.try_iter().last().unwrap_or(first);
 
-                println!("Thread closed...");
+            terrain_profile = self.plan_terrain(follow, &test_data.waypoints, &hmap, hmap_placement, &terrain_fp, &test_data.data_filename);
+            last_replan = SystemTime::now();
+        }
 
-        }else{
-            println!("failed");
-        }});
+        //Embed self if doing horizontal loading - to the planned height when following the terrain, otherwise a simplistic fixed depth
+        if self.force_axis != 2 {
+            let embed_z = terrain_profile.as_ref().map_or(start_pos.2 - 50.0, |profile| profile.z_at(0.0));
+            self.set_pos((start_pos.0, start_pos.1, embed_z));
+            self.update_rob_info();
+        }
 
 
         let mut cnt = 0;
@@ -722,6 +1058,7 @@ impl AbbRob<'_> {
                 //Log the robot information gathered by the EGM using
                 let _ = self.egm_update_state(recv_msg);
                 self.store_state(&test_data.data_filename, cnt);
+                self.update_stiffness_est(&mut stiff_est, &stiff_filename, cnt);
 
                 // println!("Z force diff:{}", self.force_target - self.force.2);
 
@@ -729,11 +1066,9 @@ impl AbbRob<'_> {
                 pos_tx.send_replace([self.pos.0 as f32, self.pos.1 as f32, self.pos.2 as f32, self.ori.0 as f32, self.ori.1 as f32, self.ori.2 as f32, self.ori.3 as f32]);
 
 
-                if self.limit_check() {
-                    println!("Out of bounds");
-                    egm_client.egm_end();
-                    self.go_home_pos();
-                    self.write_marker(&test_data.data_filename, "TEST OUT OF  SAFETY BOUNDS");
+                if let Some(breach) = self.safety_check() {
+                    self.safe_abort(egm_client, seqno, cnt, &test_data.data_filename, breach);
+                    cntrl_tx.send_replace(1);
                     return;
                 }
 
@@ -759,43 +1094,28 @@ impl AbbRob<'_> {
         //Phase 2 - force control until target force is stabilised (PID 1)
         if phase_2 {
             println!("PHASE 2: Stabilising vertical load");
-            let mut force_stable = false;
-            let mut force_errs: Vec<f64> = vec![];
-            //Minimum of 500 measurements taken - just to prove its stable
-            const FORCE_ERR_ROLL_AVG: usize = 2500;
-            let force_avg_threshold: f64 = match self.force_target {
-                10.0 => 0.2,
-                25.0 => 0.2,
-                _ => 0.05,
-            };
-
-            //Actual values have to be within 30% of the desired force
-            const FORCE_THRESH_CNT: usize = 500;
-            //The force threshold is based on the inverse of the magnitude
-            let force_threshold: f64 = match self.force_target {
-                10.0 => 0.2,
-                25.0 => 0.2,
-                _ => 0.05,
-            };
+            println!("Stability criteria: {}", stability_criteria);
+            let mut stability = StabilityDetector::create(stability_criteria);
+            let mut stability_status = StabilityStatus::Filling { samples: 0, needed: stability_criteria.window_len };
+            let phase2_start = SystemTime::now();
 
             self.write_marker(&test_data.data_filename, "PHASE 2 STARTED");
-            while !force_stable {
+            while !stability_status.is_finished() {
                 let recv_msg = egm_client.recv_egm().unwrap();
                 let time = recv_msg.get_time().unwrap();
 
                 //Log the robot information gathered by the EGM using
                 let _ = self.egm_update_state(recv_msg);
                 self.store_state(&test_data.data_filename, cnt);
+                self.update_stiffness_est(&mut stiff_est, &stiff_filename, cnt);
 
                  //Update the mapping tool
                 pos_tx.send_replace([self.pos.0 as f32, self.pos.1 as f32, self.pos.2 as f32, self.ori.0 as f32, self.ori.1 as f32, self.ori.2 as f32, self.ori.3 as f32]);
 
 
-                if self.limit_check() {
-                    println!("Out of bounds");
-                    egm_client.egm_end();
-                    self.go_home_pos();
-                    self.write_marker(&test_data.data_filename, "TEST OUT OF  SAFETY BOUNDS");
+                if let Some(breach) = self.safety_check() {
+                    self.safe_abort(egm_client, seqno, cnt, &test_data.data_filename, breach);
+                    cntrl_tx.send_replace(1);
                     return;
                 }
 
@@ -821,52 +1141,33 @@ impl AbbRob<'_> {
                 seqno += 1;
                 cnt += 1;
 
-                //Calc the force error and add to rolling average list
-                if force_errs.len() < FORCE_ERR_ROLL_AVG {
-                    force_errs.push(self.force_err);
-                } else {
-                    force_errs.remove(0);
-                    force_errs.push(self.force_err);
-                }
+                //Check whether the force error has settled
+                stability_status = stability.update(
+                    self.force_err,
+                    self.force_target,
+                    phase2_start.elapsed().unwrap().as_secs_f64(),
+                );
+            }
 
-                //Check if the rolling average is within an acceptable range
-                //Check if every value in the rolling queue is within the required threshold
-                if force_errs.len() == FORCE_ERR_ROLL_AVG {
-                    let mut force_sum = 0.0;
-                    let force_avg: f64;
-
-                    let mut all_within = true;
-                    //Check that the rolling average is within 10% (globally correct)
-                    for (i, force) in force_errs.iter().enumerate() {
-                        force_sum += force;
-
-                        //Check that the actual previous values are within 30% (locally correct)
-                        //Higher threshold to account for noise
-                        if i >= FORCE_THRESH_CNT {
-                            let curr_val = force / self.force_target;
-                            if curr_val.abs() > force_threshold {
-                                //println!("Failed at: {curr_val}");
-                                all_within = false;
-                                break;
-                            }
-                        }
-                    }
+            //Abort if the force never settled
+            if let StabilityStatus::TimedOut { .. } = stability_status {
+                println!("GEOTECH - PHASE 2 FAILED: {}", stability_status);
+                self.write_marker(&test_data.data_filename, &format!("PHASE 2 ABORTED - {}", stability_status));
 
-                    if all_within {
-                        //Check that the global average is okay
-                        force_avg = force_sum / FORCE_ERR_ROLL_AVG as f64;
-                        if (force_avg / self.force_target).abs() < force_avg_threshold {
-                            //Count the force as stable
-                            force_stable = true;
-                        } else {
-                            println!("GLOBAL AVG INCORRECT: {force_avg}")
-                        }
-                    }
-                }
+                //The tool is still loaded - unload and retract before going home
+                self.safe_abort(
+                    egm_client,
+                    seqno,
+                    cnt,
+                    &test_data.data_filename,
+                    format!("PHASE 2 NOT SETTLED - {}", stability_status),
+                );
+                cntrl_tx.send_replace(1);
+                return;
             }
 
-            println!("GEOTECH - PHASE 2 COMPLETE!");
-            self.write_marker(&test_data.data_filename, "PHASE 2 ENDED");
+            println!("GEOTECH - PHASE 2 COMPLETE! {}", stability_status);
+            self.write_marker(&test_data.data_filename, &format!("PHASE 2 ENDED - {}", stability_status));
         }
 
         //Phase 3 - Complete trajectory whilst (PID)
@@ -874,132 +1175,177 @@ impl AbbRob<'_> {
         //Update controller gains
         force_controller.update_gains(phase3_gains[0], phase3_gains[1], phase3_gains[2]);
 
+        let mut phase3_controller: Box<dyn ForceController> = match phase3_override.take() {
+            Some(cntrl) => cntrl,
+            None => Box::new(force_controller),
+        };
+
         //Start the trajectory
 
         println!("GEOTECH - PHASE 3");
         println!("Running time: {}", total_time);
-        //println!("PID Settings: {}", force_controller.controller());
+        println!("Controller: {}", phase3_controller);
 
         self.write_marker(&test_data.data_filename, "PHASE 3 STARTED");
 
 
-        let mut curr_force_val: usize = 0;
-
         let global_start = SystemTime::now();
 
-        //const OPT_STEP_CNT : usize = 250;
-        //let mut optim_tick = 0;
-        //let mut err_hist : [f64; OPT_STEP_CNT] = [0.0;OPT_STEP_CNT];
-
-
         let mut desired_speed : [f64; 3] = [0.0, 0.0, 0.0];
 
-        for instruction in speed_instructions.iter() {
-            //Get the time limit
-            let time_lim = instruction.0;
+        //Follow the lateral reference from the start point - the force controlled axis is left to the force controller
+        let mut tracker = TrackingController::create(
+            ReferenceTrack::create_lateral((start_pos.0, start_pos.1), &speed_instructions),
+            true,
+        )
+        .free_axis(self.force_axis);
+        let duration = tracker.reference().duration();
+
+        //The same clock drives the reference, the force profile and the instruction in use
+        while global_start.elapsed().unwrap().as_secs_f64() < duration {
+            let t = global_start.elapsed().unwrap().as_secs_f64().min(duration);
+
+            //Mark the start of any new profile segments
+            while next_segment < segment_labels.len()
+                && t >= segment_labels[next_segment].0
+            {
+                self.write_marker(&test_data.data_filename, &format!("SEGMENT: {}", segment_labels[next_segment].1));
+                next_segment += 1;
+            }
 
-            //Start the timer
-            let local_time = SystemTime::now();
+            //Commanded distance along the path
+            let path_dist = tracker.reference().dist_at(t);
 
-            //While the timer is running
+            //Check if the desired force value needs to be updated
+            let new_target = trajectory_planner::force_at_dist(&test_data.waypoints, path_dist)
+                .unwrap_or_else(|| ffunc.value_at(t));
 
-            while local_time.elapsed().unwrap().as_secs_f64() < time_lim {
-                //Check if the desired force value needs to be updated
-                if global_start.elapsed().unwrap().as_secs_f64() >= force_times[curr_force_val]
-                    && curr_force_val <= ffunc.force_changes()
-                {
-                    curr_force_val += 1;
-                    self.force_target = force_vals[curr_force_val];
+            //Track the load cycle of cyclic profiles
+            let cycle = ffunc.cycle_at(t);
+            if let Some(new_cycle) = cycle
+                && cycle != self.cycle
+            {
+                self.write_marker(&test_data.data_filename, &format!("CYCLE {} STARTED", new_cycle));
+            }
+            self.cycle = cycle;
 
-                    //Stiffness aware force control 
-                    if self.force_axis == 2{
+            //Orientation at the commanded point along the path
+            self.planned_ori = pose_plan.as_ref().map(|plan| plan.ori_at_dist(path_dist));
 
-                        if self.force_target > max_targ{
-                            max_targ = self.force_target;   
-                            force_controller.update_gains(phase3_gains[0], phase3_gains[1], phase3_gains[2]);                     
-                        }else if self.force_target < max_targ{ //If the target is lower than the maximum seen target
-                            force_controller.update_gains(softer_gains[0], softer_gains[1], softer_gains[2]);
-                        }
-                     }
-                    //println!("New force target: {}", self.force_target);
+            //Re-plan the height from the newest heightmap (at most once per re-plan period)
+            if let Some(follow) = &terrain
+                && last_replan.elapsed().unwrap().as_secs_f64() >= TERRAIN_REPLAN_PERIOD
+                && let Some(hmap) = hmap_rx.try_iter().last()
+            {
+                last_replan = SystemTime::now();
+
+                if let Some(profile) = self.plan_terrain(follow, &test_data.waypoints, &hmap, hmap_placement, &terrain_fp, &test_data.data_filename) {
+                    terrain_profile = Some(profile);
                 }
+            }
+            if new_target != self.force_target {
+                self.force_target = new_target;
 
-           
+                //Stiffness aware force control 
+                if self.force_axis == 2{
 
-                //Get the egm message
-                let msg = egm_client.recv_egm().expect("Failed to get egm message");
+                    if self.force_target > max_targ{
+                        max_targ = self.force_target;   
+                        phase3_controller.update_gains(phase3_gains[0], phase3_gains[1], phase3_gains[2]);                     
+                    }else if self.force_target < max_targ{ //If the target is lower than the maximum seen target
+                        phase3_controller.update_gains(softer_gains[0], softer_gains[1], softer_gains[2]);
+                    }
+                 }
+                //println!("New force target: {}", self.force_target);
+            }
 
-                let time = msg.get_time().expect("Failed to get egm time");
+       
 
-                //Log the robot information gathered by the EGM using
-                let _ = self.egm_update_state(msg);
-                self.store_state(&test_data.data_filename, cnt);
+            //Get the egm message
+            let msg = egm_client.recv_egm().expect("Failed to get egm message");
 
+            let time = msg.get_time().expect("Failed to get egm time");
 
-                 //Update the mapping tool
-                pos_tx.send_replace([self.pos.0 as f32, self.pos.1 as f32, self.pos.2 as f32, self.ori.0 as f32, self.ori.1 as f32, self.ori.2 as f32, self.ori.3 as f32]);
+            //Log the robot information gathered by the EGM using
+            let _ = self.egm_update_state(msg);
+            self.store_state(&test_data.data_filename, cnt);
 
+            //Let the controller adapt to the soil
+            if let Some(stiffness) = self.update_stiffness_est(&mut stiff_est, &stiff_filename, cnt) {
+                phase3_controller.update_stiffness(stiffness);
+            }
 
-                if self.limit_check() {
-                    println!("Out of bounds");
-                    egm_client.egm_end();
-                    self.go_home_pos();
-                    self.write_marker(&test_data.data_filename, "TEST OUT OF  SAFETY BOUNDS");
-                    return;
-                }
-             
+             //Update the mapping tool
+            pos_tx.send_replace([self.pos.0 as f32, self.pos.1 as f32, self.pos.2 as f32, self.ori.0 as f32, self.ori.1 as f32, self.ori.2 as f32, self.ori.3 as f32]);
 
-                //Apply the controller
-                let force_speed : f64 = /*if (!stable  || optim_tick != OPT_STEP_CNT || true)*/{
-                    //Save the error history
-                    //err_hist[optim_tick] = self.force_err;
-                    //optim_tick += 1;
 
-                    force_controller
-                    .calc_op(self.force_err)
-                    .expect("Failed to calculate desired axis speed")
-                    .clamp(-MAX_SPEED, MAX_SPEED)                    
-                };/*else{ //If the robot is in a stable position tune it also
-                    optim_tick = 0;
+            if let Some(breach) = self.safety_check() {
+                self.safe_abort(egm_client, seqno, cnt, &test_data.data_filename, breach);
+                cntrl_tx.send_replace(1);
+                return;
+            }
+         
 
-                    force_controller.calc_op_and_tune(self.force_err, &err_hist)
-                    .expect("Failed to calculate desired axis speed")
-                    .clamp(-MAX_SPEED, MAX_SPEED) 
-                }*/
+            //Apply the controller
+            let force_speed : f64 = phase3_controller
+                .calc_op(self.force_err)
+                .expect("Failed to calculate desired axis speed")
+                .clamp(-MAX_SPEED, MAX_SPEED);
 
-                if force_speed.is_nan(){
-                    panic!("Invalid speed!")
-                }
+            if force_speed.is_nan(){
+                panic!("Invalid speed!")
+            }
 
 
-                //Load the lateral instructions - then set the force_controlled axis to the desired speed
-                desired_speed = [instruction.1.0, instruction.1.1, 0.0];
+            //Correct the lateral instruction towards the reference - then set the force_controlled axis to the desired speed
+            let track_err = tracker.update(t, self.pos);
+            self.store_tracking(&track_filename, cnt, &track_err);
+            desired_speed = [track_err.speed[0], track_err.speed[1], 0.0];
+
+            //UNKNOWN BUG IN Y FORCE CONTROL ----- NEEDS INVERTING?
+            if self.force_axis == 1{
+                desired_speed[self.force_axis] = -force_speed;
+            }else{
+                desired_speed[self.force_axis] = force_speed;
+            }
 
-                //UNKNOWN BUG IN Y FORCE CONTROL ----- NEEDS INVERTING?
-                if self.force_axis == 1{
-                    desired_speed[self.force_axis] = -force_speed;
-                }else{
-                    desired_speed[self.force_axis] = force_speed;
+            //Follow the planned height above/below the surface - vertical loading feeds the surface slope forward to the force controller
+            if let Some(profile) = &terrain_profile {
+                if self.force_axis != 2 {
+                    desired_speed[2] = (TERRAIN_GAIN * (profile.z_at(path_dist) - self.pos.2)).clamp(-MAX_SPEED, MAX_SPEED);
+                } else {
+                    desired_speed[2] += profile.slope_at(path_dist) * desired_speed[0].hypot(desired_speed[1]);
                 }
+            }
 
-            
+        
 
-                let sensor: EgmSensor = EgmSensor::set_pose_set_speed(
-                    seqno,
-                    time,
-                    [0.0, 0.0, 0.0],
-                    self.ori.into(),
-                    desired_speed,
-                );
-                egm_client
-                    .send_egm(sensor)
-                    .expect("Failed to send sensor info");
-                seqno += 1;
-                cnt += 1;
-            }
+            let sensor: EgmSensor = EgmSensor::set_pose_set_speed(
+                seqno,
+                time,
+                [0.0, 0.0, 0.0],
+                self.planned_ori.map_or(self.ori.into(), |q| [q.w, q.x, q.y, q.z]),
+                desired_speed,
+            );
+            egm_client
+                .send_egm(sensor)
+                .expect("Failed to send sensor info");
+            seqno += 1;
+            cnt += 1;
         }
+        self.cycle = None;
+        self.planned_ori = None;
+        self.write_marker(
+            &test_data.data_filename,
+            &format!("TRACKING END ERROR: {:.2}mm", tracker.end_error(self.pos)),
+        );
         self.write_marker(&test_data.data_filename, "PHASE 3 ENDED");
 
+        //Keep anything the controller learnt
+        if let Err(e) = phase3_controller.save_state() {
+            println!("Failed to save controller state - {}", e);
+        }
+
         //Send the off signal to the mapping thread
         cntrl_tx.send_replace(1);
 
@@ -1014,6 +1360,63 @@ impl AbbRob<'_> {
         self.write_marker(&test_data.data_filename, "TEST END");
     }
 
+    ///Save previews of the force profile and trajectory to the test directory
+    fn preview_test(test_data: &TestData, ffunc: &ForceFunctionGenerator) {
+        let profile_fp = format!("{}/preview_force_{}.png", test_data.filepath, test_data.test_name);
+        if let Err(e) = preview::save_profile_preview(ffunc, &profile_fp) {
+            println!("Failed to save the force profile preview - {}", e);
+        }
+
+        let traj_fp = format!("{}/preview_traj_{}.png", test_data.filepath, test_data.test_name);
+        if let Err(e) = preview::save_traj_preview(&test_data.traj, (WORKSPACE_MIN, WORKSPACE_MAX), &traj_fp) {
+            println!("Failed to save the trajectory preview - {}", e);
+        }
+
+        println!("Previews saved to {}", test_data.filepath);
+
+        if read_with_default("Show the force profile in the terminal? (y/n)", "y".to_string()).to_lowercase() == "y" {
+            println!("{}", preview::profile_sparkline(ffunc));
+        }
+    }
+
+    ///The limits trajectories are checked against before they are run
+    ///force_axis - the axis moved by force control (not checked)
+    fn traj_limits(&self, force_axis: Option<usize>) -> ValidationLimits {
+        ValidationLimits {
+            workspace_min: WORKSPACE_MIN,
+            workspace_max: WORKSPACE_MAX,
+            min_z: self.safety_env.limits().min_tcp_z,
+            force_axis,
+            max_speed: MAX_TRAJ_SPEED,
+            max_ang_speed: MAX_ANG_SPEED,
+        }
+    }
+
+    ///Check the whole trajectory before it's run and get the user to confirm it
+    ///Errors (e.g. leaving the workspace) need an explicit override
+    fn confirm_trajectory(&self, test_data: &TestData, speed: f64, force_axis: Option<usize>) -> bool {
+        let report = validation::validate_trajectory(&test_data.waypoints, speed, &self.traj_limits(force_axis));
+        println!("{}", report);
+
+        if report.has_errors() {
+            println!("The trajectory breaks the safety limits - type override to run it anyway");
+            read_with_default("Run the test?", "n".to_string()).to_lowercase() == "override"
+        } else {
+            read_with_default("Run the test? (y/n)", "y".to_string()).to_lowercase() == "y"
+        }
+    }
+
+    ///Lets the user pick the phase 3 force controller
+    ///Returns None if the phase 2 PID should be continued
+    fn pick_phase3_controller(&self, gains: [f64; 3]) -> Option<Box<dyn ForceController>> {
+        let default_tool = match &self.load_comp {
+            Some(comp) => comp.tool_name(),
+            None => String::from("default"),
+        };
+
+        pick_force_controller(gains, &default_tool)
+    }
+
     ///Requests the xyz position of the TCP from the robot and stores it in the robot info
     fn req_xyz(&mut self) {
         //Request the info
@@ -1109,9 +1512,9 @@ impl AbbRob<'_> {
                 self.force = [f64::NAN, f64::NAN, f64::NAN, f64::NAN, f64::NAN, f64::NAN];
             } else {
                 //Store the pos in the robot info
-                self.force = [
+                self.force = self.compensate_force([
                     fc_vec[0], fc_vec[1], fc_vec[2], fc_vec[3], fc_vec[4], fc_vec[5],
-                ];
+                ]);
             }
         } else {
             //If the socket request returns nothing
@@ -1150,6 +1553,107 @@ impl AbbRob<'_> {
         if self.disconnected {}
     }
 
+    ///Update the soil stiffness estimate with the current state along the force axis and log it
+    ///Returns the estimate once it is valid
+    fn update_stiffness_est(&mut self, est: &mut StiffnessEstimator, filename: &str, i: i32) -> Option<f64> {
+        let pos: [f64; 3] = self.pos.into();
+
+        let stiffness = est.update(pos[self.force_axis], self.force[self.force_axis])?;
+
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(filename.trim())
+            .unwrap();
+
+        if let Err(e) = writeln!(
+            file,
+            "{},{:?},{}",
+            i,
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs_f64(),
+            stiffness
+        ) {
+            eprint!("Couldn't write to file: {}", e);
+        }
+
+        Some(stiffness)
+    }
+
+    ///Add a row to the tracking log (log frame) - (cnt, time, reference position, along-track error, cross-track error)
+    fn store_tracking(&self, filename: &str, i: i32, err: &TrackingError) {
+        let err = self.frames.log_tracking(*err);
+
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(filename.trim())
+            .unwrap();
+
+        if let Err(e) = writeln!(
+            file,
+            "{},{:?},{}",
+            i,
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs_f64(),
+            err
+        ) {
+            eprint!("Couldn't write to file: {}", e);
+        }
+    }
+
+    ///Spin up the depth cam subsystem on its own thread (seeded with the current pose)
+    ///Returns the pose sender, the heightmap receiver and the control sender (1 stops the subsystem)
+    fn spawn_cam_sys(&self, hmap_fp: String) -> (watch::Sender<[f32; 7]>, mpsc::Receiver<Heightmap>, watch::Sender<u32>) {
+        //Create the thread piping system
+        let (pos_tx, pos_rx) = watch::channel([self.pos.0 as f32, self.pos.1 as f32, self.pos.2 as f32, self.ori.0 as f32, self.ori.1 as f32, self.ori.2 as f32, self.ori.3 as f32]);
+
+        let (hmap_tx, hmap_rx) = mpsc::channel();
+
+        let (cntrl_tx, cntrl_rx) = watch::channel(0);
+
+        //Spawn the cam system thread
+        println!("Spinning up camera control thread....");
+        thread::spawn(|| {
+            if let Ok(mut cam_sys) = CamSysCntrl::default_connect(pos_rx, hmap_tx, cntrl_rx, hmap_fp) {
+                cam_sys.start_system().unwrap();
+
+                println!("Thread closed...");
+            } else {
+                println!("failed");
+            }
+        });
+
+        (pos_tx, hmap_rx, cntrl_tx)
+    }
+
+    ///Plan the TCP height along the waypoints from a heightmap
+    ///The adjusted trajectory is saved (log frame) and the update is marked in the data file
+    fn plan_terrain(
+        &mut self,
+        follow: &TerrainFollow,
+        waypoints: &[Waypoint],
+        hmap: &Heightmap,
+        placement: GridPlacement,
+        terrain_fp: &str,
+        data_filename: &str,
+    ) -> Option<TerrainProfile> {
+        let profile = follow.plan(waypoints, &HeightGrid::from_heightmap(hmap, placement))?;
+
+        let _ = traj_file::save_traj_file(
+            &self.frames.log_waypoints(&profile.apply(waypoints)),
+            terrain_fp,
+            Some(self.frames.log_frame()),
+        );
+        self.write_marker(data_filename, &format!("TERRAIN UPDATED - {} UNMAPPED SAMPLES", profile.unmapped));
+
+        Some(profile)
+    }
+
     ///Saves the robot state (i.e. the test data) in a given file
     fn store_state(&mut self, filename: &str, i: i32) {
         //Open the file (or create if it doesn't exist)
@@ -1159,8 +1663,14 @@ impl AbbRob<'_> {
             .open(filename.trim())
             .unwrap();
 
-        //See whether to transofmr the data by the
-        let line: String =
+        //Transform the data into the bed frame if set
+        let (pos, ori, force) = self.frames.log_state(
+            [self.pos.0, self.pos.1, self.pos.2],
+            [self.ori.0, self.ori.1, self.ori.2, self.ori.3],
+            self.force,
+        );
+
+        let mut line: String =
             //Format the line to write
             format!(
                 "{},{:?},[{},{},{}],[{},{},{},{}],[{},{},{},{},{},{}],{}",
@@ -1170,22 +1680,32 @@ impl AbbRob<'_> {
                     .unwrap()
                     .as_secs_f64(),
                 //Make sure that you don't print a lack of information in the data
-                self.pos.0,
-                self.pos.1,
-                self.pos.2,
-                self.ori.0,
-                self.ori.1,
-                self.ori.2,
-                self.ori.3,
-                self.force[0],
-                self.force[1],
-                self.force[2],
-                self.force[3],
-                self.force[4],
-                self.force[5],
+                pos[0],
+                pos[1],
+                pos[2],
+                ori[0],
+                ori[1],
+                ori[2],
+                ori[3],
+                force[0],
+                force[1],
+                force[2],
+                force[3],
+                force[4],
+                force[5],
                 self.force_err
             );
 
+        //Cyclic tests log the cycle at the end of the row
+        if let Some(cycle) = self.cycle {
+            line = format!("{},{}", line, cycle);
+        }
+
+        //Pose trajectories log the planned orientation after that
+        if let Some(q) = self.planned_ori.map(|q| self.frames.log_ori(q)) {
+            line = format!("{},[{},{},{},{}]", line, q.w, q.x, q.y, q.z);
+        }
+
         //Write to the file - indicating if writing failed (but don't worry about it!)
         if let Err(e) = writeln!(file, "{}", line) {
             eprint!("Couldn't write to file: {}", e);
@@ -1242,10 +1762,19 @@ impl AbbRob<'_> {
 
         writeln!(file, "{}", line).expect("FAILED TO WRITE ROB TO CONFIG - CLOSING");
 
-        //Save a line indicating if the data is pre-transformed
-        let line = format!("COORDS PRE-TRANSFORMED?:{}", TRANSFORM_TO_WORK_SPACE);
+        //Save the frame definitions
+        let line = format!("FRAMES: {}", self.frames);
+        writeln!(file, "{}", line).expect("FAILED TO WRITE FRAMES TO CONFIG - CLOSING");
 
-        writeln!(file, "{}", line).expect("FAILED TO WRITE TRANSFORM FLAG TO CONFIG - CLOSING");
+        //Save the tool compensation model in use (if any)
+        let line = match &self.load_comp {
+            Some(comp) => format!("LOAD CELL COMP: {}", comp),
+            None => "LOAD CELL COMP: NONE".to_string(),
+        };
+        writeln!(file, "{}", line).expect("FAILED TO WRITE TOOL COMP TO CONFIG - CLOSING");
+
+        let line = format!("SAFETY LIMITS: {}", self.safety_env.limits());
+        writeln!(file, "{}", line).expect("FAILED TO WRITE SAFETY LIMITS TO CONFIG - CLOSING");
 
         let line = format!(
             "FC_MODE:{} FC_AXIS:{}, FC_TARGET:{}",
@@ -1338,7 +1867,7 @@ impl AbbRob<'_> {
 
         //Update current measured force
         if let Some(force) = msg.get_measured_force() {
-            self.force = force;
+            self.force = self.compensate_force(force);
         } else {
             bail!("Failed to update state - force");
         };
@@ -1351,14 +1880,111 @@ impl AbbRob<'_> {
         Ok(())
     }
 
+    ///Check the TCP position and the measured load against the safety limits
+    fn safety_check(&mut self) -> Option<SafetyBreach> {
+        if self.limit_check() {
+            return Some(SafetyBreach::OutOfBounds {
+                pos: [self.pos.0, self.pos.1, self.pos.2],
+            });
+        }
+
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64();
+
+        self.safety_env.check(self.force, time)
+    }
+
+    ///Stop a test safely after a safety breach (or any other failure with the tool loaded)
+    ///Unloads the tool under force control, retracts vertically, ends EGM and then goes home
+    fn safe_abort(&mut self, egm_client: EgmServer, seqno: u32, cnt: i32, filename: &str, breach: impl Display) {
+        //Maximum speed whilst unloading/retracting (mm/s)
+        const UNLOAD_MAX_SPEED: f64 = 5.0;
+        const RETRACT_SPEED: f64 = 10.0;
+        //Give up on a stage if it takes too long (s)
+        const STAGE_TIMEOUT: f64 = 20.0;
+
+        println!("SAFETY STOP: {}", breach);
+        self.write_marker(filename, &format!("SAFETY STOP - {}", breach));
+
+        let limits = self.safety_env.limits();
+        let mut seqno = seqno;
+        let mut cnt = cnt;
+
+        //Unload along the force controlled axis (vertical if not force controlling)
+        let axis = if self.force_axis < 3 { self.force_axis } else { 2 };
+        let mut unload_controller = PIDController::create_PID(PHASE3_GAINS[0], PHASE3_GAINS[1], PHASE3_GAINS[2]);
+
+        self.write_marker(filename, "UNLOADING");
+        let stage_start = SystemTime::now();
+        while self.force[axis].abs() > limits.unload_force {
+            if stage_start.elapsed().unwrap().as_secs_f64() > STAGE_TIMEOUT {
+                println!("Failed to unload - retracting anyway");
+                break;
+            }
+
+            let Ok(msg) = egm_client.recv_egm() else { break };
+            let Some(time) = msg.get_time() else { break };
+            let _ = self.egm_update_state(msg);
+            self.store_state(filename, cnt);
+
+            //Drive the force towards zero - same sign conventions as the geo test
+            let force_speed = unload_controller
+                .calc_op(self.force[axis])
+                .unwrap_or(0.0)
+                .clamp(-UNLOAD_MAX_SPEED, UNLOAD_MAX_SPEED);
+
+            let mut desired_speed = [0.0, 0.0, 0.0];
+            if axis == 1 {
+                desired_speed[axis] = -force_speed;
+            } else {
+                desired_speed[axis] = force_speed;
+            }
+
+            if egm_client
+                .send_egm(EgmSensor::set_pose_set_speed(seqno, time, [0.0, 0.0, 0.0], self.ori.into(), desired_speed))
+                .is_err()
+            {
+                break;
+            }
+
+            seqno += 1;
+            cnt += 1;
+        }
+
+        //Retract vertically clear of the soil
+        self.write_marker(filename, "RETRACTING");
+        let retract_height = self.pos.2 + limits.retract_dist;
+        let stage_start = SystemTime::now();
+        while self.pos.2 < retract_height && stage_start.elapsed().unwrap().as_secs_f64() < STAGE_TIMEOUT {
+            let Ok(msg) = egm_client.recv_egm() else { break };
+            let Some(time) = msg.get_time() else { break };
+            let _ = self.egm_update_state(msg);
+            self.store_state(filename, cnt);
+
+            if egm_client
+                .send_egm(EgmSensor::set_pose_set_speed(seqno, time, [0.0, 0.0, 0.0], self.ori.into(), [0.0, 0.0, RETRACT_SPEED]))
+                .is_err()
+            {
+                break;
+            }
+
+            seqno += 1;
+            cnt += 1;
+        }
+
+        egm_client.egm_end();
+        self.go_home_pos();
+        self.cycle = None;
+        self.planned_ori = None;
+        self.write_marker(filename, "SAFETY STOP COMPLETE");
+    }
+
     ///Checks whether the robot is within the specified allowed cartesian limits
     fn limit_check(&mut self) -> bool {
-        let min_x = 22.0;
-        let min_y = 1350.0;
-        let min_z = -50.0;
-        let max_x = 790.0;
-        let max_y = 2650.0;
-        let max_z = 2000.0;
+        let [min_x, min_y, min_z] = WORKSPACE_MIN;
+        let [max_x, max_y, max_z] = WORKSPACE_MAX;
 
         if self.pos.0 > max_x || self.pos.0 < min_x {
             return true;
@@ -1375,7 +2001,8 @@ impl AbbRob<'_> {
 
     ///The load cell verification script
     /// Robot moves to three seperate poses and the force is measured for 1000 ticks and stored
-    fn verify_load_cell(&mut self) {
+    /// Returns the measured samples (so they can be used to fit the tool model) and the calibration record filepath
+    fn verify_load_cell(&mut self) -> (Vec<LoadCellSample>, String) {
         //The two positions
         let rotate_joints = (81.0, 12.2, 34.0, 0.0, 54.80, -118.38);
         let tool_change_joints = (21.15, 42.0, 59.0, 0.0, -57.07, -114.51);
@@ -1389,7 +2016,7 @@ impl AbbRob<'_> {
         let oris = [ori_zero, ori_one, ori_two, ori_three];
 
         //Create the test file and the config
-        let test_data = TestData::create_test_data(self.config.test_fp(), self.force_mode_flag);
+        let test_data = TestData::create_test_data(self.config.test_fp(), self.force_mode_flag, &self.frames);
         self.log_config(&test_data.config_filename);
 
         //Robot move to a position where its easy to attach tools etc
@@ -1412,6 +2039,7 @@ impl AbbRob<'_> {
         self.write_marker(&test_data.data_filename, "Test start");
         //For every rotation
         let mut cnt = 0;
+        let mut samples: Vec<LoadCellSample> = vec![];
         for (i, ori) in oris.iter().enumerate() {
             //Move the robot to the requested orientation
             self.set_ori(*ori);
@@ -1426,6 +2054,11 @@ impl AbbRob<'_> {
                 //Store the measurement
                 self.store_state(&test_data.data_filename, cnt);
                 cnt += 1;
+
+                samples.push(LoadCellSample {
+                    ori: self.ori.into(),
+                    force: self.force,
+                });
             }
         }
 
@@ -1436,16 +2069,116 @@ impl AbbRob<'_> {
         self.go_home_pos();
 
         self.write_marker(&test_data.data_filename, "Test end");
+
+        let cal_fp = format!("{}/lccal_{}.txt", test_data.filepath, test_data.test_name);
+
+        (samples, cal_fp)
+    }
+
+    ///Runs the load cell verification routine and fits the gravity/bias model of the attached tool
+    ///The model is stored for the tool and used to compensate all subsequent force measurements
+    fn calibrate_load_cell(&mut self) {
+        println!("Please provide the name of the attached tool");
+
+        let mut tool_name = String::new();
+        stdin()
+            .read_line(&mut tool_name)
+            .expect("Failed to read line");
+        let tool_name = tool_name.trim().to_string();
+
+        if tool_name.is_empty() {
+            println!("Invalid tool name - returning to cmd line");
+            return;
+        }
+
+        //Turn off the compensation so the raw readings are measured
+        let prev_comp = self.load_comp.take();
+
+        let (samples, cal_fp) = self.verify_load_cell();
+
+        match LoadCellComp::fit(&tool_name, &samples) {
+            Ok(comp) => {
+                println!("Fitted tool model - {}", comp);
+
+                if let Err(e) = comp.save_for_tool() {
+                    println!("Failed to save the tool model - {}", e);
+                }
+
+                //Keep a copy with the calibration test data
+                if let Err(e) = comp.save_to_file(&cal_fp) {
+                    println!("Failed to save the calibration record - {}", e);
+                }
+
+                self.load_comp = Some(comp);
+            }
+            Err(e) => {
+                println!("Failed to fit the tool model - {}", e);
+                self.load_comp = prev_comp;
+            }
+        }
+    }
+
+    ///Lets the user pick the stored gravity compensation model of the attached tool
+    fn select_tool(&mut self) {
+        println!("Please provide the name of the attached tool (or \"none\" to disable compensation)");
+
+        let mut tool_name = String::new();
+        stdin()
+            .read_line(&mut tool_name)
+            .expect("Failed to read line");
+        let tool_name = tool_name.trim();
+
+        if tool_name.to_lowercase() == "none" {
+            self.load_comp = None;
+            println!("Load cell compensation disabled");
+            return;
+        }
+
+        match LoadCellComp::load_for_tool(tool_name) {
+            Ok(comp) => {
+                println!("Loaded tool model - {}", comp);
+                self.load_comp = Some(comp);
+            }
+            Err(e) => {
+                println!("Failed to load the tool model - {} - run \"lc calib\" first", e);
+            }
+        }
+    }
+
+    ///Apply the tool gravity/bias compensation (if a tool model is selected) to a force measurement
+    fn compensate_force(&self, force: [f64; 6]) -> [f64; 6] {
+        match &self.load_comp {
+            Some(comp) => comp.compensate(force, self.ori.into()),
+            None => force,
+        }
     }
 
 
     ///Runs a load unload cycle with increasing force targets
+    ///Or a cyclic loading profile (peak schedule, loading rates and holds) followed in time
     fn stiffness_test(&mut self){
 
         const MAX_SPEED : f64 = 10.0;
 
         //Create the test data and the filepaths
-        let mut test_data = TestData::create_test_data(self.config.test_fp(), self.force_mode_flag);
+        let mut test_data = TestData::create_test_data(self.config.test_fp(), self.force_mode_flag, &self.frames);
+
+        //Stepped targets or a cyclic profile
+        let cyclic_ffunc = match read_with_default("Loading mode (stepped/cyclic)", "stepped".to_string()).to_lowercase().as_str() {
+            "cyclic" => match ForceFunctionGenerator::cyclic(&ForceFunctionGenerator::cyclic_interface()) {
+                Ok(ffunc) => {
+                    if let Err(e) = ffunc.save_to_file(&format!("{}/forcefunc_{}.txt", test_data.filepath, test_data.test_name)) {
+                        println!("Failed to save the force profile - {}", e);
+                    }
+                    Some(ffunc)
+                }
+                Err(e) => {
+                    println!("Invalid cyclic profile - {}", e);
+                    return;
+                }
+            },
+            _ => None,
+        };
 
 
         //Determine the target load forces
@@ -1475,6 +2208,70 @@ impl AbbRob<'_> {
         let mut seqno = 0;
         let mut cnt = 0;
 
+        //Follow the cyclic profile in time
+        if let Some(ffunc) = cyclic_ffunc {
+            let duration = ffunc.duration().unwrap_or(0.0);
+            println!("CYCLIC LOADING - {}s", duration);
+            self.write_marker(&test_data.data_filename, "CYCLIC LOADING STARTED");
+
+            let start = SystemTime::now();
+
+            while start.elapsed().unwrap().as_secs_f64() < duration {
+                let elapsed = start.elapsed().unwrap().as_secs_f64();
+                self.force_target = ffunc.value_at(elapsed);
+
+                let cycle = ffunc.cycle_at(elapsed);
+                if let Some(new_cycle) = cycle
+                    && cycle != self.cycle
+                {
+                    println!("CYCLE {}", new_cycle);
+                    self.write_marker(&test_data.data_filename, &format!("CYCLE {} STARTED", new_cycle));
+                }
+                self.cycle = cycle;
+
+                //Get the egm message
+                let msg = egm_client.recv_egm().expect("Failed to get egm message");
+
+                let time = msg.get_time().expect("Failed to get egm time");
+
+                //Log the robot information gathered by the EGM using
+                let _ = self.egm_update_state(msg);
+                self.store_state(&test_data.data_filename, cnt);
+
+                if let Some(breach) = self.safety_check() {
+                    self.safe_abort(egm_client, seqno, cnt, &test_data.data_filename, breach);
+                    return;
+                }
+
+                //Apply the controller
+                let force_speed = force_controller
+                    .calc_op(self.force_err)
+                    .expect("Failed to calculate desired axis speed")
+                    .clamp(-MAX_SPEED, MAX_SPEED);
+
+                let sensor: EgmSensor = EgmSensor::set_pose_set_speed(
+                    seqno,
+                    time,
+                    [0.0, 0.0, 0.0],
+                    self.ori.into(),
+                    [0.0, 0.0, force_speed],
+                );
+                egm_client
+                    .send_egm(sensor)
+                    .expect("Failed to send sensor info");
+                seqno += 1;
+                cnt += 1;
+            }
+
+            self.cycle = None;
+            self.write_marker(&test_data.data_filename, "CYCLIC LOADING ENDED");
+
+            let _ = self.stop_egm_stream();
+            self.go_home_pos();
+            println!("Test complete");
+            return;
+        }
+
         //For each load target
         for target in target_forces{
             //Set the setpoint and load
@@ -1497,11 +2294,8 @@ impl AbbRob<'_> {
                 let _ = self.egm_update_state(msg);
                 self.store_state(&test_data.data_filename, cnt);
 
-                if self.limit_check() {
-                    println!("Out of bounds");
-                    egm_client.egm_end();
-                    self.go_home_pos();
-                    self.write_marker(&test_data.data_filename, "TEST OUT OF SAFETY BOUNDS");
+                if let Some(breach) = self.safety_check() {
+                    self.safe_abort(egm_client, seqno, cnt, &test_data.data_filename, breach);
                     return;
                 }
 
@@ -1555,11 +2349,8 @@ impl AbbRob<'_> {
                     let _ = self.egm_update_state(msg);
                     self.store_state(&test_data.data_filename, cnt);
 
-                    if self.limit_check() {
-                        println!("Out of bounds");
-                        egm_client.egm_end();
-                        self.go_home_pos();
-                        self.write_marker(&test_data.data_filename, "TEST OUT OF  SAFETY BOUNDS");
+                    if let Some(breach) = self.safety_check() {
+                        self.safe_abort(egm_client, seqno, cnt, &test_data.data_filename, breach);
                         return;
                     }
 
@@ -1603,11 +2394,8 @@ impl AbbRob<'_> {
                 let _ = self.egm_update_state(msg);
                 self.store_state(&test_data.data_filename, cnt);
 
-                if self.limit_check() {
-                    println!("Out of bounds");
-                    egm_client.egm_end();
-                    self.go_home_pos();
-                    self.write_marker(&test_data.data_filename, "TEST OUT OF  SAFETY BOUNDS");
+                if let Some(breach) = self.safety_check() {
+                    self.safe_abort(egm_client, seqno, cnt, &test_data.data_filename, breach);
                     return;
                 }
 
@@ -1647,39 +2435,45 @@ impl AbbRob<'_> {
         fn map_terrain(&mut self){
 
             //Create the test data
-            let mut test_data = TestData::create_test_data(self.config.test_fp(), self.force_mode_flag);
+            let test_data = TestData::create_test_data(self.config.test_fp(), self.force_mode_flag, &self.frames);
             
-            //Create the set of speed instructions
+            //Create the set of speed instructions (coverage paths set their own scan speed)
             let desired_lat_speed = 10.0;
 
-            let speed_instructions = calc_lateral_timing(&mut test_data.traj, desired_lat_speed);
-            //Move to the start position
-            self.set_pos(test_data.traj[0]);
-            self.update_rob_info();
-
-
-            //Spin up the depth cam subsystem thread
-            let rust_filepath = test_data.filepath;
-
-            //Create the thread piping system
-            let(pos_tx, pos_rx)  = watch::channel([self.pos.0 as f32, self.pos.1 as f32, self.pos.2 as f32, self.ori.0 as f32, self.ori.1 as f32, self.ori.2 as f32, self.ori.3 as f32]);
+            let motion_limits = MotionLimits::load_or_default();
+            test_data.log_motion(&motion_limits);
 
-            let (hmap_tx, hmap_rx) = mpsc::channel();
+            let speed_instructions = match velocity_profile::plan_velocity_profile(&test_data.waypoints, desired_lat_speed, &motion_limits, true) {
+                Ok(profile) => {
+                    println!("Estimated mapping time: {:.0}s", profile.duration());
+                    profile.lateral_instructions(motion_limits.sample_period)
+                }
+                Err(e) => {
+                    println!("Failed to time the mapping path - {}", e);
+                    return;
+                }
+            };
 
-            let (cntrl_tx, cntrl_rx) = watch::channel(0);
+            if !self.confirm_trajectory(&test_data, desired_lat_speed, None) {
+                println!("Mapping cancelled");
+                return;
+            }
 
-            //Spawn the cam system thread
-            println!("Spinning up camera control thread....");
-            let cam_sys_thread = thread::spawn(|| {
-                if let Ok(mut cam_sys) = CamSysCntrl::default_connect(pos_rx, hmap_tx, cntrl_rx, rust_filepath){
+            //Move to the start position
+            self.set_pos(test_data.traj[0]);
+            self.update_rob_info();
 
-                    cam_sys.start_system().unwrap();
 
-                    println!("Thread closed...");
+            //Follow the lateral reference from the start point
+            let mut tracker = TrackingController::create(
+                ReferenceTrack::create_lateral((test_data.traj[0].0, test_data.traj[0].1), &speed_instructions),
+                true,
+            );
+            let track_filename = format!("{}/track_{}.txt", test_data.filepath, test_data.test_name);
+            let duration = tracker.reference().duration();
 
-            }else{
-                println!("failed");
-            }});
+            //Spin up the depth cam subsystem thread
+            let (pos_tx, hmap_rx, cntrl_tx) = self.spawn_cam_sys(test_data.filepath.clone());
 
 
         //Spin up EGM
@@ -1704,55 +2498,51 @@ impl AbbRob<'_> {
         //Wait for the first heightmap to be recieved before starting the run
         hmap_rx.recv().expect("Failed to get original heightmap");
 
-        for instruction in speed_instructions.iter() {
-            //Get the time limit
-            let time_lim = instruction.0;
+        //One clock drives the reference - the instruction in use follows from the time along it
+        let run_start = SystemTime::now();
 
-            //Start the timer
-            let local_time = SystemTime::now();
+        while run_start.elapsed().unwrap().as_secs_f64() < duration {
+            //Get the egm message
+            let msg = egm_client.recv_egm().expect("Failed to get egm message");
 
-            //While the timer is running
+            let time = msg.get_time().expect("Failed to get egm time");
 
-            while local_time.elapsed().unwrap().as_secs_f64() < time_lim {                   
+            //Log the robot information gathered by the EGM using
+            let _ = self.egm_update_state(msg);
+            self.store_state(&test_data.data_filename, cnt);
 
-                //Get the egm message
-                let msg = egm_client.recv_egm().expect("Failed to get egm message");
+            //Update the mapping tool
+            pos_tx.send_replace([self.pos.0 as f32, self.pos.1 as f32, self.pos.2 as f32, self.ori.0 as f32, self.ori.1 as f32, self.ori.2 as f32, self.ori.3 as f32]);
 
-                let time = msg.get_time().expect("Failed to get egm time");
+            if let Some(breach) = self.safety_check() {
+                self.safe_abort(egm_client, seqno, cnt, &test_data.data_filename, breach);
+                cntrl_tx.send_replace(1);
+                return;
+            }               
 
-                //Log the robot information gathered by the EGM using
-                let _ = self.egm_update_state(msg);
-                self.store_state(&test_data.data_filename, cnt);
+            //Correct the lateral instruction towards the reference
+            let track_err = tracker.update(run_start.elapsed().unwrap().as_secs_f64().min(duration), self.pos);
+            self.store_tracking(&track_filename, cnt, &track_err);
+            desired_speed = [track_err.speed[0], track_err.speed[1], 0.0];
+                        
 
-                //Update the mapping tool
-                pos_tx.send_replace([self.pos.0 as f32, self.pos.1 as f32, self.pos.2 as f32, self.ori.0 as f32, self.ori.1 as f32, self.ori.2 as f32, self.ori.3 as f32]);
-
-                if self.limit_check() {
-                    println!("Out of bounds");
-                    egm_client.egm_end();
-                    self.go_home_pos();
-                    self.write_marker(&test_data.data_filename, "TEST OUT OF  SAFETY BOUNDS");
-                    return;
-                }               
-
-                //Load the lateral instructions 
-                desired_speed = [instruction.1.0, instruction.1.1, 0.0];
-                            
-
-                let sensor: EgmSensor = EgmSensor::set_pose_set_speed(
-                    seqno,
-                    time,
-                    [0.0, 0.0, 0.0],
-                    self.ori.into(),
-                    desired_speed,
-                );
-                egm_client
-                    .send_egm(sensor)
-                    .expect("Failed to send sensor info");
-                seqno += 1;
-                cnt += 1;
-            }
+            let sensor: EgmSensor = EgmSensor::set_pose_set_speed(
+                seqno,
+                time,
+                [0.0, 0.0, 0.0],
+                self.ori.into(),
+                desired_speed,
+            );
+            egm_client
+                .send_egm(sensor)
+                .expect("Failed to send sensor info");
+            seqno += 1;
+            cnt += 1;
         }
+        self.write_marker(
+            &test_data.data_filename,
+            &format!("TRACKING END ERROR: {:.2}mm", tracker.end_error(self.pos)),
+        );
 
         //Send the off signal to the mapping thread
         cntrl_tx.send_replace(1);
@@ -1,2 +1,7 @@
 pub mod controllers;
 pub mod force_function_generator;
+pub mod load_cell_comp;
+pub mod safety_envelope;
+pub mod simulation;
+pub mod stability_detector;
+pub mod stiffness_estimator;
@@ -3,8 +3,13 @@
 ///This is to comply with the callback function used in the main test procedure
 use chrono;
 use chrono::{DateTime, Local};
+use anyhow::bail;
 use std::fmt::Display;
-use tch::{Tensor, nn ,Device, nn::OptimizerConfig, nn::Module};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use crate::control::misc_tools::misc::{read_user_line, read_with_default};
+use tch::{Tensor, nn ,Device, Kind, nn::OptimizerConfig, nn::Module};
 
 
 ///Provides a constant step up/down based only on the polarity of the error
@@ -70,6 +75,26 @@ impl PDController {
     }
 }
 
+///Common interface of the force controllers used by the test regimes
+pub trait ForceController: Display {
+    ///Calculate the controller output (axis speed) from the current force error
+    fn calc_op(&mut self, err: f64) -> Result<f64, anyhow::Error>;
+
+    ///Calculate the controller output for an error measured at a given time (e.g. simulated time)
+    fn calc_op_at(&mut self, err: f64, timestamp: DateTime<Local>) -> Result<f64, anyhow::Error>;
+
+    ///Update the gains of the controller
+    fn update_gains(&mut self, prop_gain: f64, int_gain: f64, deri_gain: f64);
+
+    ///Pass the latest soil stiffness estimate (N/mm) to the controller
+    fn update_stiffness(&mut self, _stiffness: f64) {}
+
+    ///Store any learnt controller state at the end of a test
+    fn save_state(&mut self) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
+
 ///A PID controller
 pub struct PIDController {
     ///Error history
@@ -78,6 +103,8 @@ pub struct PIDController {
     timestamps: Vec<DateTime<Local>>,
     ///The current calculated integral error
     curr_integral: f64,
+    ///The most recent derivative of the error
+    curr_deriv: f64,
     ///Proportional gain
     kp_gain: f64,
     ///Integral gain
@@ -106,6 +133,7 @@ impl PIDController {
             errs: vec![0.0],
             timestamps: vec![Local::now()],
             curr_integral: 0.0,
+            curr_deriv: 0.0,
             kp_gain: KP_gain,
             ki_gain: KI_gain,
             kd_gain: KD_gain,
@@ -114,7 +142,12 @@ impl PIDController {
 
     ///Calulcate the output of the controller
     pub fn calc_op(&mut self, err: f64) -> Result<f64, anyhow::Error> {
-        self.timestamps.push(Local::now());
+        self.calc_op_at(err, Local::now())
+    }
+
+    ///Calculate the output of the controller for an error measured at a given time
+    pub fn calc_op_at(&mut self, err: f64, timestamp: DateTime<Local>) -> Result<f64, anyhow::Error> {
+        self.timestamps.push(timestamp);
         self.errs.push(err);
 
         //Calculate the derivative in seconds
@@ -123,6 +156,8 @@ impl PIDController {
                 - self.timestamps[self.timestamps.len() - 2])
                 .as_seconds_f64());
 
+        self.curr_deriv = derr;
+
         //Calculate the integral 
         let ierr = self.calc_integral_reimann_approx();
 
@@ -164,99 +199,395 @@ impl PIDController {
 
     }
 
+    ///Get the current gains [P, I, D]
+    pub fn gains(&self) -> [f64; 3] {
+        [self.kp_gain, self.ki_gain, self.kd_gain]
+    }
+
+    ///Get the error terms used in the last output calculation [error, integral, derivative]
+    pub fn last_terms(&self) -> [f64; 3] {
+        [self.errs[self.errs.len() - 1], self.curr_integral, self.curr_deriv]
+    }
+
+    ///Get the time between the last two error measurements (s)
+    pub fn last_dt(&self) -> f64 {
+        (self.timestamps[self.timestamps.len() - 1] - self.timestamps[self.timestamps.len() - 2])
+            .as_seconds_f64()
+    }
+
+}
+
+impl ForceController for PIDController {
+    fn calc_op(&mut self, err: f64) -> Result<f64, anyhow::Error> {
+        PIDController::calc_op(self, err)
+    }
+
+    fn calc_op_at(&mut self, err: f64, timestamp: DateTime<Local>) -> Result<f64, anyhow::Error> {
+        PIDController::calc_op_at(self, err, timestamp)
+    }
+
+    fn update_gains(&mut self, prop_gain: f64, int_gain: f64, deri_gain: f64) {
+        PIDController::update_gains(self, prop_gain, int_gain, deri_gain);
+    }
+}
+
+///Limits of the gain scaling applied by the adaptive controller
+const ADAPT_SCALE_LIMS: (f64, f64) = (0.25, 4.0);
+
+///A PID controller whose gains are scaled by the estimated soil stiffness
+///Keeps the loop gain (controller gain x soil stiffness) constant between loose and compacted soil
+pub struct AdaptivePIDController {
+    ///The underlying PID controller
+    controller: PIDController,
+    ///The gains tuned for the reference stiffness
+    base_gains: [f64; 3],
+    ///The stiffness the base gains were tuned for (N/mm)
+    ref_stiffness: f64,
+    ///The current gain scaling
+    scale: f64,
+}
+
+impl Display for AdaptivePIDController {
+    ///Display the controller setup
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "ADAPTIVE PID - BASE P:{},I:{},D:{} - REF STIFFNESS:{}",
+            self.base_gains[0], self.base_gains[1], self.base_gains[2], self.ref_stiffness
+        )
+    }
+}
+
+impl AdaptivePIDController {
+    #![allow(nonstandard_style)]
+    ///Create an adaptive PID controller with gains tuned for a reference stiffness (N/mm)
+    pub fn create(KP_gain: f64, KI_gain: f64, KD_gain: f64, ref_stiffness: f64) -> Self {
+        AdaptivePIDController {
+            controller: PIDController::create_PID(KP_gain, KI_gain, KD_gain),
+            base_gains: [KP_gain, KI_gain, KD_gain],
+            ref_stiffness,
+            scale: 1.0,
+        }
+    }
+
+    ///Apply the current scaling to the base gains
+    fn apply_scale(&mut self) {
+        self.controller.update_gains(
+            self.base_gains[0] * self.scale,
+            self.base_gains[1] * self.scale,
+            self.base_gains[2] * self.scale,
+        );
+    }
+
+    ///Get the current gain scaling
+    pub fn scale(&self) -> f64 {
+        self.scale
+    }
+}
+
+impl ForceController for AdaptivePIDController {
+    fn calc_op(&mut self, err: f64) -> Result<f64, anyhow::Error> {
+        self.controller.calc_op(err)
+    }
+
+    fn calc_op_at(&mut self, err: f64, timestamp: DateTime<Local>) -> Result<f64, anyhow::Error> {
+        self.controller.calc_op_at(err, timestamp)
+    }
+
+    ///Update the base gains (the stiffness scaling is kept)
+    fn update_gains(&mut self, prop_gain: f64, int_gain: f64, deri_gain: f64) {
+        self.base_gains = [prop_gain, int_gain, deri_gain];
+        self.apply_scale();
+    }
+
+    ///Softer soil needs higher gains and vice versa
+    fn update_stiffness(&mut self, stiffness: f64) {
+        if !stiffness.is_finite() || stiffness <= 0.0 {
+            return;
+        }
+
+        self.scale = (self.ref_stiffness / stiffness).clamp(ADAPT_SCALE_LIMS.0, ADAPT_SCALE_LIMS.1);
+        self.apply_scale();
+    }
 }
 
 
 
+///Lets the user pick a force controller starting from the given gains
+///Returns None if a plain PID should be used
+pub fn pick_force_controller(gains: [f64; 3], default_tool: &str) -> Option<Box<dyn ForceController>> {
+    loop {
+        let choice = read_user_line("Select the force controller (pid/adaptive/nn):");
+
+        match choice.to_lowercase().as_str() {
+            "pid" | "" => return None,
 
+            //PID with gains scaled by the online soil stiffness estimate
+            "adaptive" => {
+                let ref_stiffness = read_with_default("Stiffness the gains were tuned for (N/mm)", DEFAULT_PLANT_SENS);
 
+                return Some(Box::new(AdaptivePIDController::create(gains[0], gains[1], gains[2], ref_stiffness)));
+            }
 
+            //PID tuned online by a neural network - weights are stored per soil/tool
+            "nn" => {
+                let soil = read_user_line("Soil name:");
+                let tool = read_with_default("Tool name", default_tool.to_string());
+                let plant_sens = read_with_default("Soil stiffness estimate (N/mm)", DEFAULT_PLANT_SENS);
+
+                match PIDWithNNTuner::create(gains[0], gains[1], gains[2], &soil, &tool, plant_sens) {
+                    Ok(tuner) => return Some(Box::new(tuner)),
+                    Err(e) => println!("Failed to create the NN tuner - {}", e),
+                }
+            }
+
+            _ => println!("Invalid controller"),
+        }
+    }
+}
 
 
-///EXPERIMENTAL - Self tuning PID controller using a neural network
+///Default plant sensitivity used by the NN tuner (N/mm)
+pub const DEFAULT_PLANT_SENS: f64 = 20.0;
+
+///Directory the NN tuner weights are stored in (one file per soil/tool combination)
+pub const NN_TUNER_FP: &str = "configs/nn_tuners";
+
+///Seed used when initialising the tuning network - keeps runs reproducible
+const NN_SEED: i64 = 2510;
+///Learning rate of the tuning network
+const NN_LEARNING_RATE: f64 = 1e-3;
+///Scale used to normalise the force error inputs (N)
+const NN_ERR_SCALE: f64 = 100.0;
+///Scale used to normalise the controller output inputs (mm/s)
+const NN_SPEED_SCALE: f64 = 10.0;
+///Weighting of the penalty that keeps the gains close to the base gains
+const NN_GAIN_REG: f64 = 1e-3;
+///Controller period used when the measured period is invalid (EGM runs at 250Hz)
+const NN_DEFAULT_DT: f64 = 0.004;
+
+///Self tuning PID controller using a neural network
+///The network scales the base gains (between 0x and 2x) based on the recent error/output history
+///Trained to minimise the predicted next force error using a linear soil stiffness plant model
 pub struct PIDWithNNTuner{
+    ///The PID controller being tuned
     controller : PIDController,
+    ///The variable storage containing the network weights
+    vs : nn::VarStore,
+    ///The tuning network
     net_tuner : nn::Sequential,
+    ///The optimiser linked to the network weights
     opt : nn::Optimizer,
-    loss : Tensor,
+    ///The gains that the network output scales
+    base_gains : [f64; 3],
+    ///The plant sensitivity - change in force per mm of axis movement (N/mm)
+    plant_sens : f64,
+    ///The file the network weights are saved in
+    weights_fp : String,
+    ///The previous force error
     prev_err : f64,
+    ///The previous controller output
     prev_u : f64,
 }
 
+impl Display for PIDWithNNTuner {
+    ///Display the controller setup
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "NN TUNED PID - BASE P:{},I:{},D:{} - SENS:{} - WEIGHTS:{}",
+            self.base_gains[0], self.base_gains[1], self.base_gains[2], self.plant_sens, self.weights_fp
+        )
+    }
+}
 
 impl PIDWithNNTuner{
+    #![allow(nonstandard_style)]
     ///Create the pid controller and tuning network with initial values
-    pub fn create(KP_gain : f64, KI_gain : f64, KD_gain : f64) -> Self{
+    ///Loads the stored weights for the soil/tool combination if they exist
+    pub fn create(KP_gain : f64, KI_gain : f64, KD_gain : f64, soil : &str, tool : &str, plant_sens : f64) -> Result<Self, anyhow::Error>{
 
         println!("Creating controller with NN tuner");
 
-        //check if cuda can be used and create the variable storage
-        let vs = nn::VarStore::new(Device::cuda_if_available());
-
+        //Fixed seed and CPU only so that runs are repeatable
+        tch::manual_seed(NN_SEED);
+        let mut vs = nn::VarStore::new(Device::Cpu);
 
         const IN : i64 = 4;
-        const HIDDEN_NODES : i64 = 128;
+        const HIDDEN_NODES : i64 = 32;
         const OUT : i64 = 3;
 
-        //Create the neural network 4 -> 128 -> 128 -> 3 (all relu)
+        //Create the neural network 4 -> 32 -> 32 -> 3 (relu hidden layers)
+        let root = vs.root();
         let net_tuner : nn::Sequential = nn::seq()
-            .add(nn::linear(&vs.root() / "layer1", IN, HIDDEN_NODES, Default::default())).add_fn(|xs| xs.relu())
-            .add(nn::linear(&vs.root(), HIDDEN_NODES, HIDDEN_NODES/2 , Default::default())).add_fn(|xs| xs.relu())
-            .add(nn::linear(&vs.root(), HIDDEN_NODES/2, HIDDEN_NODES/4 , Default::default())).add_fn(|xs| xs.relu())
-            .add(nn::linear(&vs.root(), HIDDEN_NODES/4, OUT , Default::default())).add_fn(|xs| xs.relu());
-                
+            .add(nn::linear(&root / "layer1", IN, HIDDEN_NODES, Default::default())).add_fn(|xs| xs.relu())
+            .add(nn::linear(&root / "layer2", HIDDEN_NODES, HIDDEN_NODES, Default::default())).add_fn(|xs| xs.relu())
+            .add(nn::linear(&root / "layer3", HIDDEN_NODES, OUT, Default::default()));
+
+        //Load the previously learnt weights
+        let weights_fp = format!("{}/{}_{}.ot", NN_TUNER_FP, soil.trim(), tool.trim());
+        if Path::new(&weights_fp).exists() {
+            vs.load(&weights_fp)?;
+            println!("Loaded tuner weights from {}", weights_fp);
+        } else {
+            println!("No stored weights for {} - starting from seeded weights", weights_fp);
+        }
 
         //Create the optimiser and link it to the vs
-        let opt = nn::Adam::default().build(&vs, 1e-3).unwrap();
-
-        //Add the "error" to the graph and turn on the gradient tracking
-        let mut loss = vs.root().add("err", Tensor::from_slice(&[9999.0]), false);
-        let loss = loss.requires_grad_(true);
-        
+        let opt = nn::Adam::default().build(&vs, NN_LEARNING_RATE)?;
 
         println!("Controller created");
 
-        PIDWithNNTuner { controller: PIDController::create_PID(KP_gain, KI_gain, KD_gain) , net_tuner, opt,  loss, prev_err : 0.0, prev_u : 0.0}
-
+        Ok(PIDWithNNTuner {
+            controller: PIDController::create_PID(KP_gain, KI_gain, KD_gain),
+            vs,
+            net_tuner,
+            opt,
+            base_gains: [KP_gain, KI_gain, KD_gain],
+            plant_sens,
+            weights_fp,
+            prev_err: 0.0,
+            prev_u: 0.0,
+        })
     }
 
     ///Calculate the output from the PID and tune the parameters at the same time
-    pub fn calc_op_and_tune(&mut self, err : f64 , err_hist : &[f64]) -> Result<f64, anyhow::Error> {
-
-        //Calculate the output
-        let prev_u = self.prev_u;
-        let u = self.controller.calc_op(err)?;
+    pub fn calc_op_and_tune(&mut self, err : f64) -> Result<f64, anyhow::Error> {
+        self.calc_op_and_tune_at(err, Local::now())
+    }
 
-        //Update the neural net
-        let net_out = self.net_tuner.forward(&Tensor::from_slice(&[err as f32, self.prev_err as f32, u as f32, prev_u as f32]));        
+    ///Calculate the output and tune the parameters for an error measured at a given time
+    pub fn calc_op_and_tune_at(&mut self, err : f64, timestamp : DateTime<Local>) -> Result<f64, anyhow::Error> {
 
-        //Backpropogate the network using the past 100 error measurements
-        let new_loss = Tensor::zeros(1, (tch::Kind::Double, Device::cuda_if_available()));
-        //Take the square of every error and sum them
-        let mut err_hist_sum : f64 = err_hist.iter().fold(0.0, |acc, x| acc + (x.powi(2)));
-        //err_hist_sum += self.controller.kp_gain.powi(2) + self.controller.ki_gain.powi(2) + self.controller.kd_gain.powi(2);
-        self.loss.set_data(&new_loss.fill( err_hist_sum/err_hist.len() as f64));
-        self.opt.backward_step(&self.loss);
+        //Calculate the output
+        let u = self.controller.calc_op_at(err, timestamp)?;
 
-        //Update the PID values
-        self.controller.update_gains(net_out.f_double_value(&[0]).unwrap(), net_out.f_double_value(&[1]).unwrap(), net_out.f_double_value(&[2]).unwrap());
+        let mut dt = self.controller.last_dt();
+        if !dt.is_finite() || dt <= 0.0 {
+            dt = NN_DEFAULT_DT;
+        }
 
-        println!("Controller: {} - Loss: {}", self.controller, err_hist_sum);
+        //Take a training step and apply the new gains for the next tick
+        let features = [err, self.prev_err, u, self.prev_u];
+        let (gains, _) = self.tune_step(features, self.controller.last_terms(), dt);
+        self.controller.update_gains(gains[0], gains[1], gains[2]);
 
         self.prev_err = err;
+        self.prev_u = u;
 
-        return Ok(u)
-
-
+        Ok(u)
     }
+
     ///Calculate the output without running a tuning step
     pub fn calc_op(&mut self, err : f64) -> Result<f64, anyhow::Error> {
 
         let u = self.controller.calc_op(err)?;
-        
+
+        self.prev_err = err;
         self.prev_u = u;
 
-        return Ok(u)
+        Ok(u)
+    }
+
+    ///Run a single training step of the network
+    ///features - [err, prev err, output, prev output], terms - PID [error, integral, derivative]
+    ///Returns the tuned gains and the loss
+    fn tune_step(&mut self, features : [f64; 4], terms : [f64; 3], dt : f64) -> ([f64; 3], f64) {
+        //Normalise the inputs
+        let inputs = [
+            (features[0] / NN_ERR_SCALE) as f32,
+            (features[1] / NN_ERR_SCALE) as f32,
+            (features[2] / NN_SPEED_SCALE) as f32,
+            (features[3] / NN_SPEED_SCALE) as f32,
+        ];
+
+        //Gain multipliers between 0 and 2
+        let mults = self.net_tuner.forward(&Tensor::from_slice(&inputs)).sigmoid() * 2.0;
+
+        //The controller output as a function of the tuned gains (keeps the loss connected to the network)
+        let u = (mults.get(0) * (self.base_gains[0] * terms[0]))
+            + (mults.get(1) * (self.base_gains[1] * terms[1]))
+            + (mults.get(2) * (self.base_gains[2] * terms[2]));
+
+        //Predicted error at the next tick - a positive speed moves away from the material reducing the force
+        let pred_err = (u * (-self.plant_sens * dt)) + terms[0];
+
+        let loss = (pred_err / NN_ERR_SCALE).square()
+            + ((&mults - 1.0).square().sum(Kind::Float) * NN_GAIN_REG);
+
+        self.opt.backward_step(&loss);
+
+        let gains = [
+            mults.double_value(&[0]) * self.base_gains[0],
+            mults.double_value(&[1]) * self.base_gains[1],
+            mults.double_value(&[2]) * self.base_gains[2],
+        ];
+
+        (gains, loss.double_value(&[]))
+    }
+
+    ///Pre-train the network offline from the force error histories of logged tests (data_*.txt files)
+    pub fn pretrain_from_logs(&mut self, data_fps : &[String], epochs : usize) -> Result<(), anyhow::Error> {
+
+        //Load all the histories first so a bad file is found before training starts
+        let mut histories = vec![];
+        for fp in data_fps.iter() {
+            let hist = read_force_err_history(fp)?;
+            println!("Loaded {} force errors from {}", hist.len(), fp);
+            histories.push(hist);
+        }
+
+        if histories.iter().all(|hist| hist.len() < 2) {
+            bail!("No force error history found in the given logs");
+        }
+
+        for epoch in 0..epochs {
+            let mut loss_sum = 0.0;
+            let mut step_cnt = 0;
+
+            for hist in histories.iter() {
+                if hist.len() < 2 {
+                    continue;
+                }
+
+                //Replay the history through a fresh controller so the error terms match the logged test
+                let mut pid = PIDController::create_PID(self.base_gains[0], self.base_gains[1], self.base_gains[2]);
+                pid.calc_op_at(hist[0].1, hist[0].0)?;
+                let mut prev_err = hist[0].1;
+                let mut prev_u = 0.0;
+
+                for (timestamp, err) in hist.iter().skip(1) {
+                    let u = pid.calc_op_at(*err, *timestamp)?;
+
+                    let mut dt = pid.last_dt();
+                    if !dt.is_finite() || dt <= 0.0 {
+                        dt = NN_DEFAULT_DT;
+                    }
+
+                    let (gains, loss) = self.tune_step([*err, prev_err, u, prev_u], pid.last_terms(), dt);
+                    pid.update_gains(gains[0], gains[1], gains[2]);
+
+                    loss_sum += loss;
+                    step_cnt += 1;
+                    prev_err = *err;
+                    prev_u = u;
+                }
+            }
+
+            println!("Epoch {} - Avg loss: {}", epoch + 1, loss_sum / step_cnt as f64);
+        }
+
+        self.save_weights()
+    }
+
+    ///Save the network weights for the soil/tool combination
+    pub fn save_weights(&self) -> Result<(), anyhow::Error> {
+        fs::create_dir_all(NN_TUNER_FP)?;
+        self.vs.save(&self.weights_fp)?;
+
+        println!("Tuner weights saved to {}", self.weights_fp);
+
+        Ok(())
     }
 
     ///Get the controller object
@@ -267,8 +598,92 @@ impl PIDWithNNTuner{
     ///Update the gains
     pub fn update_gains(&mut self, prop_gain : f64, int_gain : f64, deri_gain : f64){
 
+        self.base_gains = [prop_gain, int_gain, deri_gain];
         self.controller.update_gains(prop_gain, int_gain, deri_gain);
     }
+}
+
+impl ForceController for PIDWithNNTuner {
+    ///Tune the gains every tick
+    fn calc_op(&mut self, err: f64) -> Result<f64, anyhow::Error> {
+        self.calc_op_and_tune(err)
+    }
 
+    fn calc_op_at(&mut self, err: f64, timestamp: DateTime<Local>) -> Result<f64, anyhow::Error> {
+        self.calc_op_and_tune_at(err, timestamp)
+    }
+
+    fn update_gains(&mut self, prop_gain: f64, int_gain: f64, deri_gain: f64) {
+        PIDWithNNTuner::update_gains(self, prop_gain, int_gain, deri_gain);
+    }
+
+    ///Use the measured stiffness in the plant model
+    fn update_stiffness(&mut self, stiffness: f64) {
+        if stiffness.is_finite() && stiffness > 0.0 {
+            self.plant_sens = stiffness;
+        }
+    }
 
-}
\ No newline at end of file
+    ///Keep what was learnt during the test
+    fn save_state(&mut self) -> Result<(), anyhow::Error> {
+        self.save_weights()
+    }
+}
+
+///Index of the force error in a data row (after the count, time, 3 pos, 4 ori and 6 force values)
+const DATA_ERR_IDX: usize = 15;
+
+///Read the (timestamp, force error) history from a logged test data file
+///Only the force controlled phases are used if the phase markers are present
+fn read_force_err_history(filepath : &str) -> Result<Vec<(DateTime<Local>, f64)>, anyhow::Error> {
+    let file = File::open(filepath.trim())?;
+
+    let mut hist = vec![];
+    let mut all_hist = vec![];
+    let mut in_phase = false;
+
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+
+        //Markers indicate the phase of the test
+        if line.starts_with("!") {
+            if line.contains("PHASE 2 STARTED") || line.contains("PHASE 3 STARTED") {
+                in_phase = true;
+            } else if line.contains("PHASE 3 ENDED") {
+                in_phase = false;
+            }
+            continue;
+        }
+
+        //Data format: cnt,time,[pos],[ori],[force],err(,cycle)
+        let tokens: Vec<&str> = line.split(",").collect();
+        if tokens.len() <= DATA_ERR_IDX {
+            continue;
+        }
+
+        let time: f64 = tokens[1].trim().parse()?;
+        let err: f64 = tokens[DATA_ERR_IDX].trim().parse()?;
+
+        if !err.is_finite() {
+            continue;
+        }
+
+        let secs = time.floor();
+        let Some(timestamp) = DateTime::from_timestamp(secs as i64, ((time - secs) * 1e9) as u32) else {
+            bail!("Invalid timestamp in {}", filepath);
+        };
+        let timestamp = timestamp.with_timezone(&Local);
+
+        all_hist.push((timestamp, err));
+        if in_phase {
+            hist.push((timestamp, err));
+        }
+    }
+
+    //Logs without phase markers use the whole history
+    if hist.is_empty() {
+        Ok(all_hist)
+    } else {
+        Ok(hist)
+    }
+}
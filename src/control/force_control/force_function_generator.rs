@@ -1,43 +1,423 @@
 ///This file contains functions required for creation of force function generators
+///Profiles are stored as segments (holds, linear changes, sine waves and combinations of other profiles)
+///and evaluated at any time with value_at
+use crate::control::force_control::simulation::gaussian;
+use crate::control::misc_tools::misc::read_with_default;
 use anyhow::bail;
-use std::char::MAX;
+use rand::{RngExt, SeedableRng, rngs::Xoshiro256PlusPlus};
+use std::collections::HashMap;
+use std::fmt::Display;
 use std::f64::consts::PI;
-use std::fs::OpenOptions;
-use std::io::{Write, stdin};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write, stdin};
+use std::path::Path;
+
+///The directory containing the stored force profiles
+pub const FORCE_PROFILE_FP: &str = "force_profiles";
+
+///How the durations of a profile are expressed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeMode {
+    ///Percentage of the test duration - the profile stretches over the trajectory
+    Percentage,
+    ///Seconds - independent of the trajectory length
+    Absolute,
+}
+
+///Sample period used when a profile is written out as values (EGM runs at 250Hz)
+const SAMPLE_PERIOD: f64 = 0.004;
 
-///Types of signal that can be generated
-#[derive(Debug)]
-enum SignalType {
+///How the force changes over a segment
+#[derive(Debug, Clone)]
+pub enum SegmentKind {
     ///Held at one value
-    Constant,
-    ///Change between two forces
-    Step,
-    ///Ramp between two values at a constant rate
-    Ramp,
-    ///Ramp up to a peak value then ramp back down
-    Triangle,
-    ///Step up to a value then step down
-    StepUpDown,
-    ///
-    Sinusoid,
-    ///Custom function based on user input
-    Custom,
+    Hold { force: f64 },
+    ///Changes linearly between two values
+    Linear { start: f64, end: f64 },
+    ///Sine wave - frequency in Hz and phase in degrees (time measured from the segment start)
+    Sine { amplitude: f64, frequency: f64, phase: f64, offset: f64 },
+    ///Sum of several profiles - each holds its final value once it has ended
+    Sum { parts: Vec<ForceFunctionGenerator> },
+    ///Another profile limited between two forces
+    Clip { profile: Box<ForceFunctionGenerator>, min: f64, max: f64 },
+    ///Pseudo-random binary sequence - switches between offset +/- amplitude every bit time
+    ///The bits are generated from the seed so the sequence can be regenerated
+    Prbs { amplitude: f64, offset: f64, bit_time: f64, seed: u64, bits: Vec<bool> },
+    ///Sine wave sweeping from the start to the end frequency (Hz) - linearly or logarithmically
+    Chirp { amplitude: f64, offset: f64, start_freq: f64, end_freq: f64, log: bool },
+    ///Gaussian noise low pass filtered at the cutoff (Hz) - scaled to the std about the offset
+    ///The samples are generated from the seed so the noise can be regenerated
+    Noise { std: f64, offset: f64, cutoff: f64, seed: u64, period: f64, vals: Vec<f64> },
+}
+
+///A single piece of a force profile
+#[derive(Debug, Clone)]
+pub struct Segment {
+    ///The duration of the segment (percentage or seconds)
+    duration: f64,
+    ///How the force changes over the segment
+    kind: SegmentKind,
+    ///Label marked in the data file when the segment starts
+    label: Option<String>,
+    ///Whether a load cycle starts with the segment (set by cyclic profiles - counted by cycle_at)
+    cycle_start: bool,
+}
+
+impl Segment {
+    ///Create an unlabelled segment
+    fn new(duration: f64, kind: SegmentKind) -> Self {
+        Segment {
+            duration,
+            kind,
+            label: None,
+            cycle_start: false,
+        }
+    }
+
+    ///The value of the segment at a time since it started
+    fn value_at(&self, t: f64) -> f64 {
+        match &self.kind {
+            SegmentKind::Hold { force } => *force,
+            SegmentKind::Linear { start, end } => {
+                if self.duration <= 0.0 {
+                    *end
+                } else {
+                    start + ((end - start) * (t / self.duration).clamp(0.0, 1.0))
+                }
+            }
+            SegmentKind::Sine { amplitude, frequency, phase, offset } => {
+                amplitude * ((2.0 * PI * frequency * t) + phase.to_radians()).sin() + offset
+            }
+            SegmentKind::Sum { parts } => parts.iter().map(|part| part.value_at(t.min(part.total()))).sum(),
+            SegmentKind::Clip { profile, min, max } => profile.value_at(t).clamp(*min, *max),
+            SegmentKind::Prbs { amplitude, offset, bit_time, bits, .. } => {
+                let bit = ((t / bit_time).floor() as usize).min(bits.len() - 1);
+
+                if bits[bit] { offset + amplitude } else { offset - amplitude }
+            }
+            SegmentKind::Chirp { amplitude, offset, start_freq, end_freq, log } => {
+                //Phase is the integral of the instantaneous frequency
+                let phase = if self.duration <= 0.0 {
+                    0.0
+                } else if *log && start_freq != end_freq {
+                    let ratio = end_freq / start_freq;
+                    start_freq * self.duration / ratio.ln() * (ratio.powf(t / self.duration) - 1.0)
+                } else {
+                    (start_freq * t) + ((end_freq - start_freq) * t * t / (2.0 * self.duration))
+                };
+
+                amplitude * (2.0 * PI * phase).sin() + offset
+            }
+            SegmentKind::Noise { offset, period, vals, .. } => {
+                //Interpolate between the generated samples
+                let pos = (t / period).max(0.0);
+                let idx = (pos.floor() as usize).min(vals.len() - 1);
+                let next = (idx + 1).min(vals.len() - 1);
+                let frac = (pos - idx as f64).clamp(0.0, 1.0);
+
+                offset + vals[idx] + ((vals[next] - vals[idx]) * frac)
+            }
+        }
+    }
+
+    ///Stretch the segment in time (keeping its shape)
+    fn scale_time(&mut self, factor: f64) {
+        self.duration *= factor;
+
+        match &mut self.kind {
+            SegmentKind::Sine { frequency, .. } => *frequency /= factor,
+            SegmentKind::Sum { parts } => {
+                for part in parts.iter_mut() {
+                    part.scale_time(factor);
+                }
+            }
+            SegmentKind::Clip { profile, .. } => profile.scale_time(factor),
+            SegmentKind::Prbs { bit_time, .. } => *bit_time *= factor,
+            SegmentKind::Chirp { start_freq, end_freq, .. } => {
+                *start_freq /= factor;
+                *end_freq /= factor;
+            }
+            SegmentKind::Noise { cutoff, period, .. } => {
+                *cutoff /= factor;
+                *period *= factor;
+            }
+            SegmentKind::Hold { .. } | SegmentKind::Linear { .. } => {}
+        }
+    }
+
+    ///Check the segment parameters are usable
+    fn validate(&self) -> Result<(), anyhow::Error> {
+        if !self.duration.is_finite() || self.duration < 0.0 {
+            bail!("Invalid segment duration - {}", self.duration);
+        }
+
+        let vals: Vec<f64> = match &self.kind {
+            SegmentKind::Hold { force } => vec![*force],
+            SegmentKind::Linear { start, end } => vec![*start, *end],
+            SegmentKind::Sine { amplitude, frequency, phase, offset } => {
+                if *frequency < 0.0 {
+                    bail!("Invalid sine frequency - {}", frequency);
+                }
+                vec![*amplitude, *frequency, *phase, *offset]
+            }
+            SegmentKind::Sum { parts } => {
+                if parts.is_empty() {
+                    bail!("Sum segment has no parts");
+                }
+                vec![]
+            }
+            SegmentKind::Clip { min, max, .. } => {
+                if min > max {
+                    bail!("Invalid clip limits - {} to {}", min, max);
+                }
+                vec![*min, *max]
+            }
+            SegmentKind::Prbs { amplitude, offset, bit_time, bits, .. } => {
+                if *bit_time <= 0.0 || bits.is_empty() {
+                    bail!("Invalid PRBS bit time - {}", bit_time);
+                }
+                vec![*amplitude, *offset, *bit_time]
+            }
+            SegmentKind::Chirp { amplitude, offset, start_freq, end_freq, log } => {
+                if *start_freq < 0.0 || *end_freq < 0.0 || (*log && (*start_freq <= 0.0 || *end_freq <= 0.0)) {
+                    bail!("Invalid chirp frequencies - {}Hz to {}Hz", start_freq, end_freq);
+                }
+                vec![*amplitude, *offset, *start_freq, *end_freq]
+            }
+            SegmentKind::Noise { std, offset, cutoff, period, vals, .. } => {
+                if *std < 0.0 || *cutoff <= 0.0 || *period <= 0.0 || vals.is_empty() {
+                    bail!("Invalid noise - std {}, cutoff {}Hz", std, cutoff);
+                }
+                vec![*std, *offset, *cutoff]
+            }
+        };
+
+        if vals.iter().any(|val| !val.is_finite()) {
+            bail!("Segment values must be finite - {:?}", self.kind);
+        }
+
+        Ok(())
+    }
+}
+
+///How the peak load changes from cycle to cycle
+#[derive(Debug, Clone)]
+pub enum AmplitudeSchedule {
+    ///Every cycle loads to the same peak
+    Constant(f64),
+    ///The peak grows (or shrinks) linearly from the first to the last cycle
+    Linear { start: f64, end: f64 },
+    ///The peak of each cycle (the last peak is repeated for any extra cycles)
+    List(Vec<f64>),
+}
+
+impl AmplitudeSchedule {
+    ///The peak load of a cycle (0 indexed)
+    fn peak(&self, cycle: usize, cycles: usize) -> f64 {
+        match self {
+            AmplitudeSchedule::Constant(peak) => *peak,
+            AmplitudeSchedule::Linear { start, end } => {
+                if cycles <= 1 {
+                    *start
+                } else {
+                    start + ((end - start) * cycle as f64 / (cycles - 1) as f64)
+                }
+            }
+            AmplitudeSchedule::List(peaks) => peaks[cycle.min(peaks.len() - 1)],
+        }
+    }
+}
+
+impl Display for AmplitudeSchedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AmplitudeSchedule::Constant(peak) => write!(f, "CONSTANT {}N", peak),
+            AmplitudeSchedule::Linear { start, end } => write!(f, "LINEAR {}N -> {}N", start, end),
+            AmplitudeSchedule::List(peaks) => write!(f, "LIST {:?}", peaks),
+        }
+    }
+}
+
+///Parameters of a cyclic load-unload profile
+#[derive(Debug, Clone)]
+pub struct CyclicParams {
+    ///The peak load of each cycle
+    pub schedule: AmplitudeSchedule,
+    ///The load the profile unloads to between cycles (N)
+    pub min_load: f64,
+    ///The number of cycles
+    pub cycles: usize,
+    ///Loading rate (N/s)
+    pub load_rate: f64,
+    ///Unloading rate (N/s)
+    pub unload_rate: f64,
+    ///Time held at each peak (s)
+    pub peak_hold: f64,
+    ///Time held at the minimum load after each cycle (s)
+    pub min_hold: f64,
+}
+
+impl Default for CyclicParams {
+    fn default() -> Self {
+        CyclicParams {
+            schedule: AmplitudeSchedule::Constant(100.0),
+            min_load: 10.0,
+            cycles: 5,
+            load_rate: 10.0,
+            unload_rate: 10.0,
+            peak_hold: 5.0,
+            min_hold: 5.0,
+        }
+    }
+}
+
+impl Display for CyclicParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "PEAKS:{} MIN LOAD:{} CYCLES:{} LOAD RATE:{} UNLOAD RATE:{} PEAK HOLD:{} MIN HOLD:{}",
+            self.schedule, self.min_load, self.cycles, self.load_rate, self.unload_rate, self.peak_hold, self.min_hold
+        )
+    }
 }
 
+///Label prefix marking the start of each cycle of a cyclic profile
+const CYCLE_LABEL: &str = "CYCLE";
+
 ///Function specification
+#[derive(Debug, Clone)]
 pub struct ForceFunctionGenerator {
-    ///The type of signal form
-    sig_type: SignalType,
-    ///The total number of changes
-    force_changes: usize,
-    ///The values desired in the signal (in chronological order)
-    sig_vals: Vec<f64>,
-    ///The percentage time that each force is desired for (in chronological order)
-    sig_time: Vec<f64>,
+    ///Whether the segment durations are in percentages or seconds
+    time_mode: TimeMode,
+    ///The segments of the profile (in chronological order)
+    segments: Vec<Segment>,
+    ///The end time of each segment - used to find the active segment
+    ends: Vec<f64>,
+    ///The start time of each load cycle (including nested profiles, sorted) - used by cycle_at
+    cycle_starts: Vec<f64>,
+}
+
+///Builds a profile from labelled segments (all defined in seconds)
+///e.g. hold 50N for 10s, ramp to 200N over 20s, three 0.5Hz cycles, then unload
+pub struct ProfileBuilder {
+    profile: Option<ForceFunctionGenerator>,
+}
+
+impl ProfileBuilder {
+    ///Append a profile as a labelled segment
+    pub fn then(mut self, label: &str, segment: ForceFunctionGenerator) -> Result<Self, anyhow::Error> {
+        let segment = segment.labelled(label);
+
+        self.profile = Some(match self.profile {
+            Some(profile) => profile.concat(segment)?,
+            None => segment,
+        });
+
+        Ok(self)
+    }
+
+    ///Hold a force for a number of seconds
+    pub fn hold(self, label: &str, force: f64, duration: f64) -> Result<Self, anyhow::Error> {
+        self.then(label, ForceFunctionGenerator::hold(force, duration)?)
+    }
+
+    ///Ramp from the current final force to a new force over a number of seconds
+    pub fn ramp_to(self, label: &str, force: f64, duration: f64) -> Result<Self, anyhow::Error> {
+        let start = self.last_force();
+        self.then(label, ForceFunctionGenerator::ramp_force(start, force)?.with_duration(duration)?)
+    }
+
+    ///Sine cycles about the current final force
+    pub fn cycles(self, label: &str, amplitude: f64, frequency_hz: f64, cycles: usize) -> Result<Self, anyhow::Error> {
+        if frequency_hz <= 0.0 || cycles == 0 {
+            bail!("Invalid cycles - {} cycles at {}Hz", cycles, frequency_hz);
+        }
+
+        let offset = self.last_force();
+        self.then(
+            label,
+            ForceFunctionGenerator::sinusoid(amplitude, frequency_hz, 0.0, offset, cycles as f64 / frequency_hz)?,
+        )
+    }
+
+    ///Add a signal on top of the profile built so far
+    pub fn superpose(mut self, other: &ForceFunctionGenerator) -> Result<Self, anyhow::Error> {
+        let Some(profile) = self.profile else {
+            bail!("Nothing to superpose onto");
+        };
+
+        self.profile = Some(profile.superpose(other)?);
+        Ok(self)
+    }
+
+    ///Repeat the profile built so far
+    pub fn repeat(mut self, times: usize) -> Result<Self, anyhow::Error> {
+        let Some(profile) = self.profile else {
+            bail!("Nothing to repeat");
+        };
+
+        self.profile = Some(profile.repeat(times)?);
+        Ok(self)
+    }
+
+    ///Clip the profile built so far
+    pub fn clip(mut self, min_force: f64, max_force: f64) -> Result<Self, anyhow::Error> {
+        let Some(profile) = self.profile else {
+            bail!("Nothing to clip");
+        };
+
+        self.profile = Some(profile.clip(min_force, max_force)?);
+        Ok(self)
+    }
+
+    ///Finish the profile
+    pub fn build(self) -> Result<ForceFunctionGenerator, anyhow::Error> {
+        match self.profile {
+            Some(profile) => Ok(profile),
+            None => bail!("Empty profile"),
+        }
+    }
+
+    ///The final force of the profile so far (0 if empty)
+    fn last_force(&self) -> f64 {
+        match &self.profile {
+            Some(profile) => profile.end_value(),
+            None => 0.0,
+        }
+    }
 }
 
 impl ForceFunctionGenerator {
+    ///Get the user to create a force profile and (optionally) fix its duration in seconds
     pub fn user_interface() -> Result<Self, anyhow::Error> {
+        let ffunc = Self::select_profile()?;
+
+        //Profiles defined in seconds already have a duration
+        if ffunc.time_mode == TimeMode::Absolute {
+            return Ok(ffunc);
+        }
+
+        loop {
+            println!("Type the profile duration in seconds (blank to stretch over the whole test)");
+
+            let mut duration = String::new();
+            stdin()
+                .read_line(&mut duration)
+                .expect("Failed to read line");
+
+            if duration.trim().is_empty() {
+                return Ok(ffunc);
+            }
+
+            match duration.trim().parse::<f64>() {
+                Ok(duration) => return ffunc.with_duration(duration),
+                Err(_) => println!("Invalid value"),
+            }
+        }
+    }
+
+    ///Get the user to select a force profile type and its parameters
+    fn select_profile() -> Result<Self, anyhow::Error> {
         loop {
             println!("Select a force type:");
 
@@ -125,6 +505,44 @@ impl ForceFunctionGenerator {
                     }
                 }
 
+                //Load a stored profile (or the profile of a previous test)
+                "file" => {
+                    println!("Type the profile name (in {}) or filepath", FORCE_PROFILE_FP);
+
+                    let mut fp = String::new();
+                    stdin().read_line(&mut fp).expect("Failed to read line");
+
+                    match ForceFunctionGenerator::load_profile(fp.trim()) {
+                        Ok(ffunc) => return Ok(ffunc),
+                        Err(e) => {
+                            println!("Failed to load the profile - {}", e);
+                            continue;
+                        }
+                    }
+                }
+
+                //Build a profile from several labelled segments
+                "compose" => {
+                    match ForceFunctionGenerator::compose_interface() {
+                        Ok(ffunc) => return Ok(ffunc),
+                        Err(e) => {
+                            println!("Failed to compose the profile - {}", e);
+                            continue;
+                        }
+                    }
+                }
+
+                //Repeated load-unload cycles
+                "cyclic" => {
+                    match ForceFunctionGenerator::cyclic(&ForceFunctionGenerator::cyclic_interface()) {
+                        Ok(ffunc) => return Ok(ffunc),
+                        Err(e) => {
+                            println!("Invalid cyclic profile - {}", e);
+                            continue;
+                        }
+                    }
+                }
+
                 "sinusoid" =>{
                    
                      //Get user input
@@ -155,11 +573,29 @@ impl ForceFunctionGenerator {
                         .read_line(&mut offset)
                         .expect("Failed to read line");
 
+                    //Get user input
+                    println!("Set the duration (s):");
+                    let mut duration = String::new();
+                    stdin()
+                        .read_line(&mut duration)
+                        .expect("Failed to read line");
+
 
-                    return ForceFunctionGenerator::sinusoid(amplitude.trim().parse()?, freq.trim().parse()?, phase.trim().parse()?, offset.trim().parse()?)
+                    return ForceFunctionGenerator::sinusoid(amplitude.trim().parse()?, freq.trim().parse()?, phase.trim().parse()?, offset.trim().parse()?, duration.trim().parse()?)
 
                 }
 
+                //System identification excitation signals
+                "prbs" | "chirp" | "noise" => {
+                    match ForceFunctionGenerator::excitation_interface(user_inp.to_lowercase().trim()) {
+                        Ok(ffunc) => return Ok(ffunc),
+                        Err(e) => {
+                            println!("Invalid excitation signal - {}", e);
+                            continue;
+                        }
+                    }
+                }
+
                 _ => {
                     println!("Invalid choice")
                 }
@@ -167,14 +603,210 @@ impl ForceFunctionGenerator {
         }
     }
 
+    ///Get the user to set the parameters of an excitation signal (prbs/chirp/noise)
+    fn excitation_interface(sig_type: &str) -> Result<Self, anyhow::Error> {
+        let offset = read_with_default("Offset force (N)", 50.0);
+        let duration = read_with_default("Duration (s)", 60.0);
+
+        match sig_type {
+            "prbs" => {
+                let amplitude = read_with_default("Amplitude (N)", 10.0);
+                let bit_time = read_with_default("Bit time (s)", 0.5);
+                let seed = read_with_default("Seed", rand::random::<u32>() as u64);
+
+                ForceFunctionGenerator::prbs(amplitude, offset, bit_time, seed, duration)
+            }
+            "chirp" => {
+                let amplitude = read_with_default("Amplitude (N)", 10.0);
+                let start_freq = read_with_default("Start frequency (Hz)", 0.05);
+                let end_freq = read_with_default("End frequency (Hz)", 2.0);
+                let log = read_with_default("Logarithmic sweep (y/n)", "n".to_string()).to_lowercase() == "y";
+
+                ForceFunctionGenerator::chirp(amplitude, offset, start_freq, end_freq, log, duration)
+            }
+            _ => {
+                let std = read_with_default("Standard deviation (N)", 5.0);
+                let cutoff = read_with_default("Cutoff frequency (Hz)", 1.0);
+                let seed = read_with_default("Seed", rand::random::<u32>() as u64);
+
+                ForceFunctionGenerator::noise(std, offset, cutoff, seed, duration)
+            }
+        }
+    }
+
+    ///Get the user to set the parameters of a cyclic profile
+    pub fn cyclic_interface() -> CyclicParams {
+        let default = CyclicParams::default();
+
+        let schedule = loop {
+            println!("Select the peak schedule (constant/linear/list):");
+
+            let mut user_inp = String::new();
+            stdin()
+                .read_line(&mut user_inp)
+                .expect("Failed to read line");
+
+            match user_inp.to_lowercase().trim() {
+                "constant" => break AmplitudeSchedule::Constant(read_with_default("Peak load (N)", 100.0)),
+                "linear" => {
+                    break AmplitudeSchedule::Linear {
+                        start: read_with_default("First peak load (N)", 50.0),
+                        end: read_with_default("Last peak load (N)", 500.0),
+                    };
+                }
+                "list" => {
+                    let peaks = read_with_default("Peak loads (comma separated)", "10,25,50,100,200,400,500,1000".to_string());
+                    match parse_val_list(&peaks) {
+                        Ok(peaks) if !peaks.is_empty() => break AmplitudeSchedule::List(peaks),
+                        _ => println!("Invalid peak loads"),
+                    }
+                }
+                _ => println!("Invalid choice"),
+            }
+        };
+
+        let cycles = match &schedule {
+            AmplitudeSchedule::List(peaks) => read_with_default("Number of cycles", peaks.len()),
+            _ => read_with_default("Number of cycles", default.cycles),
+        };
+
+        CyclicParams {
+            schedule,
+            min_load: read_with_default("Minimum load (N)", default.min_load),
+            cycles,
+            load_rate: read_with_default("Loading rate (N/s)", default.load_rate),
+            unload_rate: read_with_default("Unloading rate (N/s)", default.unload_rate),
+            peak_hold: read_with_default("Hold at peak (s)", default.peak_hold),
+            min_hold: read_with_default("Hold at minimum load (s)", default.min_hold),
+        }
+    }
+
+    ///Get the user to compose a profile from labelled segments
+    fn compose_interface() -> Result<Self, anyhow::Error> {
+        let mut builder = ForceFunctionGenerator::builder();
+
+        loop {
+            println!("Type a label for the next segment (blank to finish)");
+            let mut label = String::new();
+            stdin().read_line(&mut label).expect("Failed to read line");
+            let label = label.trim().to_string();
+
+            if label.is_empty() {
+                break;
+            }
+
+            println!("Append the segment or add it on top of the profile so far? (then/sum)");
+            let mut mode = String::new();
+            stdin().read_line(&mut mode).expect("Failed to read line");
+
+            //Segments must be defined in seconds to be combined
+            let segment = ForceFunctionGenerator::user_interface()?;
+            if segment.time_mode != TimeMode::Absolute {
+                println!("Segments need a duration in seconds - segment discarded");
+                continue;
+            }
+
+            builder = match mode.trim().to_lowercase().as_str() {
+                "sum" => builder.superpose(&segment.labelled(&label))?,
+                _ => builder.then(&label, segment)?,
+            };
+        }
+
+        println!("Repeat the profile how many times? (blank for once)");
+        let mut repeats = String::new();
+        stdin().read_line(&mut repeats).expect("Failed to read line");
+        if !repeats.trim().is_empty() {
+            builder = builder.repeat(repeats.trim().parse()?)?;
+        }
+
+        println!("Clip the profile to min,max (blank for no clipping)");
+        let mut clip = String::new();
+        stdin().read_line(&mut clip).expect("Failed to read line");
+        if let Some((min_force, max_force)) = clip.trim().split_once(",") {
+            builder = builder.clip(min_force.trim().parse()?, max_force.trim().parse()?)?;
+        }
+
+        builder.build()
+    }
+
+    ///Start building a profile from labelled segments
+    pub fn builder() -> ProfileBuilder {
+        ProfileBuilder { profile: None }
+    }
+
+    ///Create a force held for a number of seconds
+    pub fn hold(force: f64, duration: f64) -> Result<Self, anyhow::Error> {
+        ForceFunctionGenerator::constant_force(force)?.with_duration(duration)
+    }
+
+
+    ///Create a profile from a set of segments
+    fn from_segments(time_mode: TimeMode, segments: Vec<Segment>) -> Result<Self, anyhow::Error> {
+        if segments.is_empty() {
+            bail!("Profile has no segments");
+        }
+
+        for segment in segments.iter() {
+            segment.validate()?;
+        }
+
+        let mut ffunc = ForceFunctionGenerator {
+            time_mode,
+            segments,
+            ends: vec![],
+            cycle_starts: vec![],
+        };
+        ffunc.update_ends();
+
+        if ffunc.total() <= 0.0 {
+            bail!("Invalid time constraints - profile has no duration");
+        }
+
+        Ok(ffunc)
+    }
+
+    ///Recalculate the segment end times and the cycle start times
+    fn update_ends(&mut self) {
+        let mut curr_time = 0.0;
+
+        self.ends = self
+            .segments
+            .iter()
+            .map(|segment| {
+                curr_time += segment.duration;
+                curr_time
+            })
+            .collect();
+
+        self.cycle_starts = vec![];
+        for (i, segment) in self.segments.iter().enumerate() {
+            let start = if i == 0 { 0.0 } else { self.ends[i - 1] };
+
+            if segment.cycle_start {
+                self.cycle_starts.push(start);
+            }
+
+            match &segment.kind {
+                SegmentKind::Sum { parts } => {
+                    for part in parts.iter() {
+                        self.cycle_starts.extend(part.cycle_starts.iter().map(|t| start + t));
+                    }
+                }
+                SegmentKind::Clip { profile, .. } => self.cycle_starts.extend(profile.cycle_starts.iter().map(|t| start + t)),
+                _ => {}
+            }
+        }
+
+        self.cycle_starts.sort_by(|a, b| a.total_cmp(b));
+        self.cycle_starts.dedup();
+    }
+
     ///Create a force function with a constant value
     fn constant_force(desired_force: f64) -> Result<Self, anyhow::Error> {
-        Ok(ForceFunctionGenerator {
-            sig_type: SignalType::Constant,
-            force_changes: 0,
-            sig_vals: vec![desired_force],
-            sig_time: vec![100.0],
-        })
+        ForceFunctionGenerator::from_segments(
+            TimeMode::Percentage,
+            vec![Segment::new(100.0, SegmentKind::Hold { force: desired_force })],
+        )
     }
 
     ///Create a force function with a stepped value between two values
@@ -188,32 +820,19 @@ impl ForceFunctionGenerator {
             println!("Too few steps! Generating constant force");
             return ForceFunctionGenerator::constant_force(min_force);
         }
-        if no_of_steps >= 100000 {
-            println!("Too many steps! Generating ramp");
-            return ForceFunctionGenerator::ramp_force(min_force, max_force);
-        }
-
-        let mut sig_vals: Vec<f64> = vec![];
-        let mut sig_time: Vec<f64> = vec![];
 
         let step_size = (max_force - min_force) / (no_of_steps as f64);
 
-        for i in 0..=no_of_steps {
-            let force_val = min_force + i as f64 * step_size;
-
-            sig_vals.push(force_val);
-
-            sig_time.push(100.0 / (1.0 + no_of_steps as f64));
-        }
-
-        verify_time_constraint(&sig_time)?;
+        let segments = (0..=no_of_steps)
+            .map(|i| {
+                Segment::new(
+                    100.0 / (1.0 + no_of_steps as f64),
+                    SegmentKind::Hold { force: min_force + i as f64 * step_size },
+                )
+            })
+            .collect();
 
-        Ok(ForceFunctionGenerator {
-            sig_type: SignalType::Step,
-            force_changes: no_of_steps,
-            sig_vals,
-            sig_time,
-        })
+        ForceFunctionGenerator::from_segments(TimeMode::Percentage, segments)
     }
 
      ///Create a force function with a stepped value between two values
@@ -227,250 +846,942 @@ impl ForceFunctionGenerator {
             println!("Too few steps! Generating constant force");
             return ForceFunctionGenerator::constant_force(min_force);
         }
-        if no_of_steps >= 100000 {
-            println!("Too many steps! Generating ramp");
-            return ForceFunctionGenerator::ramp_force(min_force, max_force);
-        }
-
-        let mut sig_vals: Vec<f64> = vec![];
-        let mut sig_time: Vec<f64> = vec![];
 
         let step_size = (max_force - min_force) / (no_of_steps as f64);
+        let step_time = 100.0 / (no_of_steps as f64 * 2.0);
 
-        for i in 0..no_of_steps {
-            let force_val = min_force + (i as f64 * step_size);
-
-            sig_vals.push(force_val);
+        let mut segments = vec![];
 
-            sig_time.push(100.0 / ((no_of_steps as f64 * 2.0)));
+        for i in 0..no_of_steps {
+            segments.push(Segment::new(step_time, SegmentKind::Hold { force: min_force + (i as f64 * step_size) }));
         }
 
         for i in 0..no_of_steps {
-            let force_val = max_force - (i as f64 * step_size);
-
-            sig_vals.push(force_val);
-
-            sig_time.push(100.0 / ((no_of_steps as f64 * 2.0)));
+            segments.push(Segment::new(step_time, SegmentKind::Hold { force: max_force - (i as f64 * step_size) }));
         }
 
-
-        verify_time_constraint(&sig_time)?;
-
-        Ok(ForceFunctionGenerator {
-            sig_type: SignalType::StepUpDown,
-            force_changes: no_of_steps,
-            sig_vals,
-            sig_time,
-        })
+        ForceFunctionGenerator::from_segments(TimeMode::Percentage, segments)
     }
 
 
     ///Create a force function that ramps between two values
-    ///Essentially a very fine step function
     fn ramp_force(min_force: f64, max_force: f64) -> Result<Self, anyhow::Error> {
         if min_force == max_force {
             println!("No change! Generating constant force");
             return ForceFunctionGenerator::constant_force(min_force);
         }
 
-        const RAMP_VAR: usize = 100000;
+        ForceFunctionGenerator::from_segments(
+            TimeMode::Percentage,
+            vec![Segment::new(100.0, SegmentKind::Linear { start: min_force, end: max_force })],
+        )
+    }
+
+    ///Create a force function that ramps up to a peak then ramps down
+    fn triangle_force(min_force: f64, max_force : f64) -> Result<Self, anyhow::Error>{
+        if min_force == max_force {
+            println!("No change! Generating constant force");
+            return ForceFunctionGenerator::constant_force(min_force);
+        }
 
-        let mut sig_vals: Vec<f64> = vec![];
-        let mut sig_time: Vec<f64> = vec![];
+        ForceFunctionGenerator::from_segments(
+            TimeMode::Percentage,
+            vec![
+                Segment::new(50.0, SegmentKind::Linear { start: min_force, end: max_force }),
+                Segment::new(50.0, SegmentKind::Linear { start: max_force, end: min_force }),
+            ],
+        )
+    }
+
+    ///Create a sine wave force function
+    ///Frequency in Hz, phase in degrees and duration in seconds - always defined in absolute time
+    fn sinusoid(amplitude : f64, frequency_hz : f64, phase_degs : f64, offset : f64, duration : f64) -> Result<Self, anyhow::Error>{
+        if !duration.is_finite() || duration <= 0.0 {
+            bail!("Invalid sinusoid duration - {}", duration);
+        }
 
-        let step_size = (max_force - min_force) / (RAMP_VAR as f64);
+        ForceFunctionGenerator::from_segments(
+            TimeMode::Absolute,
+            vec![Segment::new(
+                duration,
+                SegmentKind::Sine { amplitude, frequency: frequency_hz, phase: phase_degs, offset },
+            )],
+        )
+    }
 
-        for i in 0..RAMP_VAR {
-            let force_val = min_force + (i as f64 * step_size);
+    ///Create a pseudo-random binary sequence switching between offset +/- amplitude
+    ///Bit time and duration in seconds - the seed sets the sequence
+    pub fn prbs(amplitude: f64, offset: f64, bit_time: f64, seed: u64, duration: f64) -> Result<Self, anyhow::Error> {
+        ForceFunctionGenerator::from_segments(
+            TimeMode::Absolute,
+            vec![Segment::new(duration, prbs_kind(amplitude, offset, bit_time, seed, duration)?)],
+        )
+    }
+
+    ///Create a sine sweep between two frequencies (Hz) over a duration in seconds
+    ///Log sweeps spend equal time in each decade - linear sweeps change frequency at a constant rate
+    pub fn chirp(amplitude: f64, offset: f64, start_freq: f64, end_freq: f64, log: bool, duration: f64) -> Result<Self, anyhow::Error> {
+        ForceFunctionGenerator::from_segments(
+            TimeMode::Absolute,
+            vec![Segment::new(duration, SegmentKind::Chirp { amplitude, offset, start_freq, end_freq, log })],
+        )
+    }
 
-            sig_vals.push(force_val);
+    ///Create band limited noise about an offset - cutoff in Hz and duration in seconds
+    ///The seed sets the noise
+    pub fn noise(std: f64, offset: f64, cutoff: f64, seed: u64, duration: f64) -> Result<Self, anyhow::Error> {
+        ForceFunctionGenerator::from_segments(
+            TimeMode::Absolute,
+            vec![Segment::new(duration, noise_kind(std, offset, cutoff, seed, duration)?)],
+        )
+    }
 
-            sig_time.push(100.0 / RAMP_VAR as f64);
+    ///Create a cyclic load-unload profile (defined in seconds)
+    ///Each cycle loads from the minimum to its peak, holds, unloads back to the minimum then holds
+    ///The start of each cycle is labelled so the cycle number can be found with cycle_at
+    pub fn cyclic(params: &CyclicParams) -> Result<Self, anyhow::Error> {
+        if params.cycles == 0 {
+            bail!("Cyclic profile needs at least one cycle");
+        }
+        if params.load_rate <= 0.0 || params.unload_rate <= 0.0 {
+            bail!("Invalid loading rates - load {}N/s, unload {}N/s", params.load_rate, params.unload_rate);
+        }
+        if params.peak_hold < 0.0 || params.min_hold < 0.0 {
+            bail!("Invalid hold times - peak {}s, min {}s", params.peak_hold, params.min_hold);
+        }
+        if let AmplitudeSchedule::List(peaks) = &params.schedule
+            && peaks.is_empty()
+        {
+            bail!("No peak loads given");
         }
 
-        verify_time_constraint(&sig_time)?;
+        let mut segments = vec![];
 
-        Ok(ForceFunctionGenerator {
-            sig_type: SignalType::Ramp,
-            force_changes: RAMP_VAR,
-            sig_vals,
-            sig_time,
-        })
+        for cycle in 0..params.cycles {
+            let peak = params.schedule.peak(cycle, params.cycles);
+            if peak < params.min_load {
+                bail!("Cycle {} peak ({}N) below the minimum load ({}N)", cycle + 1, peak, params.min_load);
+            }
+
+            let mut cycle_segments = vec![
+                Segment::new((peak - params.min_load) / params.load_rate, SegmentKind::Linear { start: params.min_load, end: peak }),
+                Segment::new(params.peak_hold, SegmentKind::Hold { force: peak }),
+                Segment::new((peak - params.min_load) / params.unload_rate, SegmentKind::Linear { start: peak, end: params.min_load }),
+                Segment::new(params.min_hold, SegmentKind::Hold { force: params.min_load }),
+            ];
+            cycle_segments[0].label = Some(format!("{} {}", CYCLE_LABEL, cycle + 1));
+            cycle_segments[0].cycle_start = true;
+
+            segments.extend(cycle_segments);
+        }
+
+        ForceFunctionGenerator::from_segments(TimeMode::Absolute, segments)
     }
 
-    ///Create a force function that ramps up to a peak then ramps down
-    fn triangle_force(min_force: f64, max_force : f64) -> Result<Self, anyhow::Error>{ 
-        if min_force == max_force {
-            println!("No change! Generating constant force");
-            return ForceFunctionGenerator::constant_force(min_force);
+    ///The cycle (1 indexed) of a cyclic profile running at a time in seconds
+    ///Cycles are counted through the whole profile (repeats and concatenated cycles keep counting up)
+    ///None before the first cycle, after the profile ends or for profiles without cycles
+    pub fn cycle_at(&self, t: f64) -> Option<usize> {
+        if t < 0.0 || t > self.total() {
+            return None;
         }
 
-        const RAMP_VAR: usize = 100000;
+        match self.cycle_starts.partition_point(|start| *start <= t) {
+            0 => None,
+            cycle => Some(cycle),
+        }
+    }
 
-        let mut sig_vals: Vec<f64> = vec![];
-        let mut sig_time: Vec<f64> = vec![];
+    ///Create a force function that follows a custom pattern (each value held for a percentage of the test)
+    pub fn custom_force(sig_vals: Vec<f64>, sig_time: Vec<f64>) -> Result<Self, anyhow::Error> {
+        verify_time_constraint(&sig_time)?;
 
-        let step_size = (max_force - min_force) / (RAMP_VAR as f64);
+        ForceFunctionGenerator::from_segments(TimeMode::Percentage, holds(&sig_vals, &sig_time)?)
+    }
 
-        for i in 0..RAMP_VAR/2 {
-            let force_val = min_force + (i as f64 * step_size * 2.0);
+    ///Create a custom force function with each value held for a number of seconds
+    pub fn custom_force_secs(sig_vals: Vec<f64>, sig_time: Vec<f64>) -> Result<Self, anyhow::Error> {
+        verify_durations(&sig_time)?;
 
-            sig_vals.push(force_val);
+        ForceFunctionGenerator::from_segments(TimeMode::Absolute, holds(&sig_vals, &sig_time)?)
+    }
 
-            sig_time.push(100.0 / RAMP_VAR as f64);
+    ///Fix the duration of a percentage profile in seconds
+    pub fn with_duration(mut self, duration: f64) -> Result<Self, anyhow::Error> {
+        if self.time_mode == TimeMode::Absolute {
+            bail!("Profile already defined in seconds");
+        }
+        if !duration.is_finite() || duration <= 0.0 {
+            bail!("Invalid profile duration - {}", duration);
         }
 
-        for i in 0..RAMP_VAR/2 {
-            let force_val = max_force - (i as f64 * step_size * 2.0);
+        let factor = duration / self.total();
+        self.scale_time(factor);
+        self.time_mode = TimeMode::Absolute;
 
-            sig_vals.push(force_val);
+        Ok(self)
+    }
 
-            sig_time.push(100.0 / RAMP_VAR as f64);
+    ///Stretch the whole profile in time
+    fn scale_time(&mut self, factor: f64) {
+        for segment in self.segments.iter_mut() {
+            segment.scale_time(factor);
         }
 
-        verify_time_constraint(&sig_time)?;
+        self.update_ends();
+    }
 
-        Ok(ForceFunctionGenerator {
-            sig_type: SignalType::Triangle,
-            force_changes: RAMP_VAR,
-            sig_vals,
-            sig_time,
-        })
+    ///The desired force at a time (seconds - or percentage for profiles stretched over the test)
+    ///The final value is held once the profile has ended
+    pub fn value_at(&self, t: f64) -> f64 {
+        let idx = self
+            .ends
+            .partition_point(|end| *end <= t)
+            .min(self.segments.len() - 1);
+
+        let start = if idx == 0 { 0.0 } else { self.ends[idx - 1] };
+        let segment = &self.segments[idx];
+
+        segment.value_at((t - start).clamp(0.0, segment.duration))
     }
 
+    ///The final value of the profile
+    pub fn end_value(&self) -> f64 {
+        self.value_at(self.total())
+    }
 
+    ///The total length of the profile (percentage or seconds)
+    fn total(&self) -> f64 {
+        *self.ends.last().unwrap_or(&0.0)
+    }
 
-    fn sinusoid(amplitude : f64, frequency_hz : f64, phase_degs : f64, offset : f64) -> Result<Self, anyhow::Error>{
+    ///Sample the profile at a fixed period - (time, force) pairs
+    pub fn sample(&self, period: f64) -> Vec<(f64, f64)> {
+        let sample_cnt = (self.total() / period).floor() as usize;
 
-        let mut force_changes = 0;
-        let mut sig_vals = vec![];
-        let mut sig_time = vec![];
+        (0..=sample_cnt)
+            .map(|i| {
+                let t = i as f64 * period;
+                (t, self.value_at(t))
+            })
+            .collect()
+    }
 
-        //Do the sinusoid for 100 ticks (i.e. 100 % of the whole signal)
-        const MAX_TICKS : i32 = 100000;
-        for i in 0..MAX_TICKS{
-            sig_vals.push(amplitude * (2.0* PI * frequency_hz * i as f64 + 2.0*PI * phase_degs).sin() + offset);
-            sig_time.push(frequency_hz/MAX_TICKS as f64);
-            force_changes += 1;
+    ///Label the whole profile as a single segment (replaces any existing labels)
+    pub fn labelled(mut self, label: &str) -> Self {
+        for segment in self.segments.iter_mut() {
+            segment.label = None;
         }
+        self.segments[0].label = Some(label.to_string());
 
+        self
+    }
 
-        verify_time_constraint(&sig_time);
+    ///Append another profile after this one - both must be defined in seconds
+    pub fn concat(mut self, other: ForceFunctionGenerator) -> Result<Self, anyhow::Error> {
+        if self.time_mode != TimeMode::Absolute || other.time_mode != TimeMode::Absolute {
+            bail!("Only profiles defined in seconds can be concatenated");
+        }
 
+        self.segments.extend(other.segments);
+        self.update_ends();
 
-        Ok(ForceFunctionGenerator { sig_type: SignalType::Sinusoid, force_changes, sig_vals, sig_time})
+        Ok(self)
     }
 
-    ///Create a force function that follows a custom pattern
-    pub fn custom_force(sig_vals: Vec<f64>, sig_time: Vec<f64>) -> Result<Self, anyhow::Error> {
-        verify_time_constraint(&sig_time)?;
+    ///Add another profile on top of this one - both must be defined in seconds
+    ///A shorter profile holds its final value until the longer one ends
+    pub fn superpose(&self, other: &ForceFunctionGenerator) -> Result<Self, anyhow::Error> {
+        if self.time_mode != TimeMode::Absolute || other.time_mode != TimeMode::Absolute {
+            bail!("Only profiles defined in seconds can be superposed");
+        }
 
-        Ok(ForceFunctionGenerator {
-            sig_type: SignalType::Custom,
-            force_changes: sig_vals.len(),
-            sig_vals,
-            sig_time,
-        })
+        ForceFunctionGenerator::from_segments(
+            TimeMode::Absolute,
+            vec![Segment::new(
+                self.total().max(other.total()),
+                SegmentKind::Sum { parts: vec![self.clone(), other.clone()] },
+            )],
+        )
     }
 
-    ///Converts the relative percentage times to timestamps to compare against
-    ///They are additive i.e. two one second blocks will display as [1.0, 2.0]
-    pub fn as_time_f64(&self, total_time: f64) -> Vec<f64> {
-        let mut timestamp_vec: Vec<f64> = vec![];
+    ///Repeat the profile a number of times (percentage profiles are squeezed to fit)
+    pub fn repeat(mut self, times: usize) -> Result<Self, anyhow::Error> {
+        if times == 0 {
+            bail!("Profile must be repeated at least once");
+        }
 
-        let mut curr_time = 0.0;
+        if self.time_mode == TimeMode::Percentage {
+            self.scale_time(1.0 / times as f64);
+        }
+
+        let segments = self.segments.clone();
+        self.segments = vec![];
+
+        for i in 0..times {
+            for segment in segments.iter() {
+                let mut segment = segment.clone();
 
-        for perc_time in self.sig_time.iter() {
-            //Update the current time
-            curr_time += total_time * (perc_time / 100.0);
+                if times > 1
+                    && let Some(label) = &segment.label
+                {
+                    segment.label = Some(format!("{} ({}/{})", label, i + 1, times));
+                }
+
+                self.segments.push(segment);
+            }
+        }
+
+        self.update_ends();
+
+        Ok(self)
+    }
+
+    ///Limit the profile between a minimum and maximum force
+    pub fn clip(self, min_force: f64, max_force: f64) -> Result<Self, anyhow::Error> {
+        if min_force.is_nan() || max_force.is_nan() || min_force > max_force {
+            bail!("Invalid clip limits - {} to {}", min_force, max_force);
+        }
+
+        let time_mode = self.time_mode;
+        let total = self.total();
+
+        ForceFunctionGenerator::from_segments(
+            time_mode,
+            vec![Segment::new(
+                total,
+                SegmentKind::Clip { profile: Box::new(self), min: min_force, max: max_force },
+            )],
+        )
+    }
+
+    ///Get the segment labels as (start time, label) - percentages are scaled to the total time
+    pub fn labels_as_time(&self, total_time: f64) -> Vec<(f64, String)> {
+        let mut labels = vec![];
+        self.collect_labels(0.0, &mut labels);
+
+        if self.time_mode == TimeMode::Percentage {
+            for (time, _) in labels.iter_mut() {
+                *time *= total_time / 100.0;
+            }
+        }
+
+        labels.sort_by(|a, b| a.0.total_cmp(&b.0));
+        labels
+    }
+
+    ///Collect the labels of every (nested) segment offset by a start time
+    fn collect_labels(&self, offset: f64, labels: &mut Vec<(f64, String)>) {
+        for (i, segment) in self.segments.iter().enumerate() {
+            let start = offset + if i == 0 { 0.0 } else { self.ends[i - 1] };
+
+            if let Some(label) = &segment.label {
+                labels.push((start, label.clone()));
+            }
 
-            timestamp_vec.push(curr_time);
+            match &segment.kind {
+                SegmentKind::Sum { parts } => {
+                    for part in parts.iter() {
+                        part.collect_labels(start, labels);
+                    }
+                }
+                SegmentKind::Clip { profile, .. } => profile.collect_labels(start, labels),
+                _ => {}
+            }
         }
+    }
+
+    ///The duration of the profile in seconds (None if it stretches over the test)
+    pub fn duration(&self) -> Option<f64> {
+        match self.time_mode {
+            TimeMode::Percentage => None,
+            TimeMode::Absolute => Some(self.total()),
+        }
+    }
 
-        timestamp_vec
+    ///Get how the profile times are expressed
+    pub fn time_mode(&self) -> TimeMode {
+        self.time_mode
     }
 
+    ///Get the segments of the profile
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    ///Save the profile definition - can be reloaded with load_from_file to re-run the profile exactly
     pub fn save_to_file(&self, filepath: &str) -> Result<(), anyhow::Error> {
         //Open the file (or create if it doesn't exist)
         let mut file = OpenOptions::new()
             .write(true)
             .truncate(true)
             .create(true)
-            .open(filepath.trim())
-            .unwrap();
+            .open(filepath.trim())?;
 
-        let line: String = "Desired force profile".to_string();
+        writeln!(file, "#Desired force profile")?;
 
-        //Write to the file - indicating if writing failed (but don't worry about it!)
-        if let Err(e) = writeln!(file, "{}", line) {
-            bail!("Couldn't write to file: {}", e);
+        match self.time_mode {
+            TimeMode::Absolute => writeln!(file, "TIME_MODE = \"absolute\"")?,
+            TimeMode::Percentage => writeln!(file, "TIME_MODE = \"percentage\"")?,
         }
 
-        //See whether to transofmr the data by the
-        let line: String =
-            //Format the line to write
-            format!(
-                "TYPE:{:?}|VAL_CNT:{}|VALS:{:?}|PERC_TIMES:{:?}",
-                self.sig_type,
-                self.force_changes,
-                self.sig_vals,
-                self.sig_time
-            );
+        write_segments(&mut file, &self.segments)?;
 
-        //Write to the file - indicating if writing failed (but don't worry about it!)
-        if let Err(e) = writeln!(file, "{}", line) {
-            bail!("Couldn't write to file: {}", e);
+        Ok(())
+    }
+
+    ///Load a stored profile by name (from the profile directory) or by filepath
+    pub fn load_profile(name_or_fp: &str) -> Result<Self, anyhow::Error> {
+        let stored_fp = format!("{}/{}.txt", FORCE_PROFILE_FP, name_or_fp);
+
+        if Path::new(&stored_fp).exists() {
+            Self::load_from_file(&stored_fp)
+        } else {
+            Self::load_from_file(name_or_fp)
         }
+    }
 
-        Ok(())
+    ///Load a profile from a file
+    ///Accepts segment definitions (HOLD/LINEAR/SINE/SUM/CLIP lines as written by save_to_file),
+    ///parameter definitions (TYPE = "..." with parameters or POINT = [time, force] breakpoints)
+    ///and the single line TYPE:..|VALS:..|PERC_TIMES:.. format saved by older tests
+    ///TIME_MODE = "absolute" gives times in seconds, otherwise percentages of the test
+    pub fn load_from_file(filepath: &str) -> Result<Self, anyhow::Error> {
+        let file = File::open(filepath.trim())?;
+
+        //(line number, key, value)
+        let mut lines: Vec<(usize, String, String)> = vec![];
+
+        for (line_no, line) in BufReader::new(file).lines().enumerate() {
+            let curr_line = line?;
+            let curr_line = curr_line.trim();
+
+            //Skip comments/headers
+            if curr_line.is_empty() || curr_line.starts_with("#") || curr_line == "Desired force profile" {
+                continue;
+            }
+
+            //Old style profile - the values were saved directly
+            if curr_line.starts_with("TYPE:") {
+                return parse_legacy_line(curr_line).map_err(|e| anyhow::anyhow!("Line {}: {}", line_no + 1, e));
+            }
+
+            //Block markers have no value
+            let (key, val) = match curr_line.split_once("=") {
+                Some((key, val)) => (key.trim().to_uppercase(), val.trim().to_string()),
+                None => (curr_line.to_uppercase(), String::new()),
+            };
+
+            lines.push((line_no + 1, key, val));
+        }
+
+        let time_mode = match lines.iter().find(|(_, key, _)| key == "TIME_MODE") {
+            None => TimeMode::Percentage,
+            Some((line_no, _, val)) => match val.trim_matches('"').to_lowercase().as_str() {
+                "percentage" => TimeMode::Percentage,
+                "absolute" => TimeMode::Absolute,
+                other => bail!("Line {}: unknown time mode - {}", line_no, other),
+            },
+        };
+
+        //Parameter style definition
+        if lines.iter().any(|(_, key, _)| key == "TYPE") {
+            return load_param_profile(&lines, time_mode);
+        }
+
+        let lines: Vec<(usize, String, String)> = lines.into_iter().filter(|(_, key, _)| key != "TIME_MODE").collect();
+
+        let mut pos = 0;
+        let segments = parse_segments(&lines, &mut pos, time_mode)?;
+
+        if let Some((line_no, key, _)) = lines.get(pos) {
+            bail!("Line {}: unexpected {}", line_no, key);
+        }
+
+        let ffunc = ForceFunctionGenerator::from_segments(time_mode, segments)?;
+
+        if time_mode == TimeMode::Percentage {
+            verify_time_constraint(&[ffunc.total()])?;
+        }
+
+        Ok(ffunc)
     }
 
+    ///Save the profile sampled at the control rate (or every 0.1% of the test)
     pub fn save_vals(&self, filepath: &str) -> Result<(), anyhow::Error> {
         //Open the file (or create if it doesn't exist)
         let mut file = OpenOptions::new()
             .write(true)
             .truncate(true)
             .create(true)
-            .open(filepath.trim())
-            .unwrap();
+            .open(filepath.trim())?;
+
+        writeln!(file, "Desired force profile")?;
 
-        let line: String = "Desired force profile".to_string();
+        let period = match self.time_mode {
+            TimeMode::Absolute => SAMPLE_PERIOD,
+            TimeMode::Percentage => 0.1,
+        };
+        let vals: Vec<f64> = self.sample(period).into_iter().map(|(_, val)| val).collect();
 
-        //Write to the file - indicating if writing failed (but don't worry about it!)
-        if let Err(e) = writeln!(file, "{}", line) {
-            bail!("Couldn't write to file: {}", e);
+        writeln!(file, "VALS:{:?}", vals)?;
+
+        Ok(())
+    }
+}
+
+///Create held segments from values and their durations
+fn holds(sig_vals: &[f64], sig_time: &[f64]) -> Result<Vec<Segment>, anyhow::Error> {
+    if sig_vals.is_empty() || sig_vals.len() != sig_time.len() {
+        bail!("Invalid custom profile - {} values, {} times", sig_vals.len(), sig_time.len());
+    }
+
+    Ok(sig_vals
+        .iter()
+        .zip(sig_time.iter())
+        .map(|(val, time)| Segment::new(*time, SegmentKind::Hold { force: *val }))
+        .collect())
+}
+
+///Generate the bits of a PRBS segment
+fn prbs_kind(amplitude: f64, offset: f64, bit_time: f64, seed: u64, duration: f64) -> Result<SegmentKind, anyhow::Error> {
+    if !bit_time.is_finite() || bit_time <= 0.0 || !duration.is_finite() || duration <= 0.0 {
+        bail!("Invalid PRBS - bit time {}s, duration {}s", bit_time, duration);
+    }
+
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(seed);
+    let bit_cnt = (duration / bit_time).ceil().max(1.0) as usize;
+    let bits = (0..bit_cnt).map(|_| rng.random::<bool>()).collect();
+
+    Ok(SegmentKind::Prbs { amplitude, offset, bit_time, seed, bits })
+}
+
+///Generate the samples of a band limited noise segment
+///White gaussian noise through a first order low pass filter - rescaled to the desired std
+fn noise_kind(std: f64, offset: f64, cutoff: f64, seed: u64, duration: f64) -> Result<SegmentKind, anyhow::Error> {
+    if !cutoff.is_finite() || cutoff <= 0.0 || !duration.is_finite() || duration <= 0.0 {
+        bail!("Invalid noise - cutoff {}Hz, duration {}s", cutoff, duration);
+    }
+
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(seed);
+    let sample_cnt = (duration / SAMPLE_PERIOD).ceil() as usize + 1;
+
+    let time_const = 1.0 / (2.0 * PI * cutoff);
+    let alpha = SAMPLE_PERIOD / (time_const + SAMPLE_PERIOD);
+
+    let mut filtered = 0.0;
+    let mut vals: Vec<f64> = (0..sample_cnt)
+        .map(|_| {
+            filtered += alpha * (gaussian(&mut rng) - filtered);
+            filtered
+        })
+        .collect();
+
+    //Remove any drift and scale to the desired std
+    let mean = vals.iter().sum::<f64>() / vals.len() as f64;
+    let curr_std = (vals.iter().map(|val| (val - mean).powi(2)).sum::<f64>() / vals.len() as f64).sqrt();
+    for val in vals.iter_mut() {
+        *val = if curr_std > 0.0 { (*val - mean) * std / curr_std } else { 0.0 };
+    }
+
+    Ok(SegmentKind::Noise { std, offset, cutoff, seed, period: SAMPLE_PERIOD, vals })
+}
+
+///Write the segment definitions (nested profiles are written as blocks)
+fn write_segments(file: &mut File, segments: &[Segment]) -> Result<(), anyhow::Error> {
+    for segment in segments {
+        if let Some(label) = &segment.label {
+            writeln!(file, "LABEL = \"{}\"", label)?;
+        }
+        if segment.cycle_start {
+            writeln!(file, "CYCLE_START = \"true\"")?;
+        }
+
+        match &segment.kind {
+            SegmentKind::Hold { force } => {
+                writeln!(file, "HOLD = [{},{}]", segment.duration, force)?;
+            }
+            SegmentKind::Linear { start, end } => {
+                writeln!(file, "LINEAR = [{},{},{}]", segment.duration, start, end)?;
+            }
+            SegmentKind::Sine { amplitude, frequency, phase, offset } => {
+                writeln!(
+                    file,
+                    "SINE = [{},{},{},{},{}]",
+                    segment.duration, amplitude, frequency, phase, offset
+                )?;
+            }
+            SegmentKind::Sum { parts } => {
+                writeln!(file, "SUM = [{}]", segment.duration)?;
+                for part in parts {
+                    writeln!(file, "PART")?;
+                    write_segments(file, &part.segments)?;
+                }
+                writeln!(file, "END")?;
+            }
+            SegmentKind::Clip { profile, min, max } => {
+                writeln!(file, "CLIP = [{},{},{}]", segment.duration, min, max)?;
+                write_segments(file, &profile.segments)?;
+                writeln!(file, "END")?;
+            }
+            //Excitation signals are stored by their seed/parameters so they can be regenerated
+            SegmentKind::Prbs { amplitude, offset, bit_time, seed, .. } => {
+                writeln!(file, "#PRBS = [duration, amplitude, offset, bit time, seed]")?;
+                writeln!(file, "PRBS = [{},{},{},{},{}]", segment.duration, amplitude, offset, bit_time, seed)?;
+            }
+            SegmentKind::Chirp { amplitude, offset, start_freq, end_freq, log } => {
+                let key = if *log { "LOG_CHIRP" } else { "CHIRP" };
+                writeln!(file, "#{} = [duration, amplitude, offset, start freq, end freq]", key)?;
+                writeln!(file, "{} = [{},{},{},{},{}]", key, segment.duration, amplitude, offset, start_freq, end_freq)?;
+            }
+            SegmentKind::Noise { std, offset, cutoff, seed, .. } => {
+                writeln!(file, "#NOISE = [duration, std, offset, cutoff, seed]")?;
+                writeln!(file, "NOISE = [{},{},{},{},{}]", segment.duration, std, offset, cutoff, seed)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+///Parse segment lines until the end of the current block (END/PART or the end of the file)
+fn parse_segments(
+    lines: &[(usize, String, String)],
+    pos: &mut usize,
+    time_mode: TimeMode,
+) -> Result<Vec<Segment>, anyhow::Error> {
+    let mut segments = vec![];
+    let mut label: Option<String> = None;
+    let mut cycle_start = false;
+
+    while let Some((line_no, key, val)) = lines.get(*pos) {
+        //Get the values of the line - checking there are the right number
+        let vals = |cnt: usize| -> Result<Vec<f64>, anyhow::Error> {
+            let vals = parse_val_list(val).map_err(|e| anyhow::anyhow!("Line {}: {}", line_no, e))?;
+            if vals.len() != cnt {
+                bail!("Line {}: {} expects {} values - {}", line_no, key, cnt, val);
+            }
+            Ok(vals)
+        };
+
+        let segment = match key.as_str() {
+            "END" | "PART" => break,
+            "LABEL" => {
+                label = Some(val.trim_matches('"').to_string());
+                *pos += 1;
+                continue;
+            }
+            "CYCLE_START" => {
+                cycle_start = val.trim_matches('"').trim() == "true";
+                *pos += 1;
+                continue;
+            }
+            "HOLD" => {
+                let v = vals(2)?;
+                *pos += 1;
+                Segment::new(v[0], SegmentKind::Hold { force: v[1] })
+            }
+            "LINEAR" => {
+                let v = vals(3)?;
+                *pos += 1;
+                Segment::new(v[0], SegmentKind::Linear { start: v[1], end: v[2] })
+            }
+            "SINE" => {
+                let v = vals(5)?;
+                *pos += 1;
+                Segment::new(v[0], SegmentKind::Sine { amplitude: v[1], frequency: v[2], phase: v[3], offset: v[4] })
+            }
+            "PRBS" => {
+                let v = vals(5)?;
+                let seed = parse_seed(val).map_err(|e| anyhow::anyhow!("Line {}: {}", line_no, e))?;
+                *pos += 1;
+                Segment::new(v[0], prbs_kind(v[1], v[2], v[3], seed, v[0]).map_err(|e| anyhow::anyhow!("Line {}: {}", line_no, e))?)
+            }
+            "CHIRP" | "LOG_CHIRP" => {
+                let v = vals(5)?;
+                *pos += 1;
+                Segment::new(
+                    v[0],
+                    SegmentKind::Chirp { amplitude: v[1], offset: v[2], start_freq: v[3], end_freq: v[4], log: key == "LOG_CHIRP" },
+                )
+            }
+            "NOISE" => {
+                let v = vals(5)?;
+                let seed = parse_seed(val).map_err(|e| anyhow::anyhow!("Line {}: {}", line_no, e))?;
+                *pos += 1;
+                Segment::new(v[0], noise_kind(v[1], v[2], v[3], seed, v[0]).map_err(|e| anyhow::anyhow!("Line {}: {}", line_no, e))?)
+            }
+            "SUM" => {
+                let v = vals(1)?;
+                *pos += 1;
+
+                //Each part starts with PART - the block finishes with END
+                let mut parts = vec![];
+                loop {
+                    match lines.get(*pos) {
+                        Some((_, key, _)) if key == "PART" => {
+                            *pos += 1;
+                            let part = parse_segments(lines, pos, TimeMode::Absolute)?;
+                            parts.push(ForceFunctionGenerator::from_segments(TimeMode::Absolute, part)?);
+                        }
+                        Some((_, key, _)) if key == "END" => {
+                            *pos += 1;
+                            break;
+                        }
+                        _ => bail!("Line {}: SUM block not closed with END", line_no),
+                    }
+                }
+
+                Segment::new(v[0], SegmentKind::Sum { parts })
+            }
+            "CLIP" => {
+                let v = vals(3)?;
+                *pos += 1;
+
+                let inner = parse_segments(lines, pos, time_mode)?;
+                match lines.get(*pos) {
+                    Some((_, key, _)) if key == "END" => *pos += 1,
+                    _ => bail!("Line {}: CLIP block not closed with END", line_no),
+                }
+
+                let profile = ForceFunctionGenerator::from_segments(time_mode, inner)?;
+                Segment::new(v[0], SegmentKind::Clip { profile: Box::new(profile), min: v[1], max: v[2] })
+            }
+            other => bail!("Line {}: unknown segment type - {}", line_no, other),
+        };
+
+        segment.validate().map_err(|e| anyhow::anyhow!("Line {}: {}", line_no, e))?;
+
+        let mut segment = segment;
+        segment.label = label.take();
+        segment.cycle_start = std::mem::take(&mut cycle_start);
+        segments.push(segment);
+    }
+
+    Ok(segments)
+}
+
+///Load a profile defined by type and parameters (or custom breakpoints)
+fn load_param_profile(lines: &[(usize, String, String)], time_mode: TimeMode) -> Result<ForceFunctionGenerator, anyhow::Error> {
+    let mut params: HashMap<String, String> = HashMap::new();
+    let mut points: Vec<[f64; 2]> = vec![];
+    let mut labels: Vec<(f64, String)> = vec![];
+
+    for (line_no, key, val) in lines {
+        if key == "POINT" {
+            match parse_point(val) {
+                Ok(point) => points.push(point),
+                Err(e) => bail!("Line {}: {}", line_no, e),
+            }
+        } else if key == "SEGMENT" {
+            match parse_segment_label(val) {
+                Ok(label) => labels.push(label),
+                Err(e) => bail!("Line {}: {}", line_no, e),
+            }
+        } else {
+            params.insert(key.clone(), val.trim_matches('"').to_string());
+        }
+    }
+
+    let Some(sig_type) = params.get("TYPE") else {
+        bail!("Profile missing a TYPE");
+    };
+
+    //Get a required parameter
+    let param = |key: &str| -> Result<f64, anyhow::Error> {
+        match params.get(key) {
+            Some(val) => match val.parse::<f64>() {
+                Ok(val) if val.is_finite() => Ok(val),
+                _ => bail!("Invalid {} - {}", key, val),
+            },
+            None => bail!("Profile missing {}", key),
         }
+    };
+    let steps = || -> Result<usize, anyhow::Error> {
+        match params.get("STEPS") {
+            Some(val) => Ok(val.parse::<usize>()?),
+            None => bail!("Profile missing STEPS"),
+        }
+    };
+
+    let duration = match time_mode {
+        TimeMode::Absolute => Some(param("DURATION")?),
+        TimeMode::Percentage => None,
+    };
+
+    let ffunc = match sig_type.to_lowercase().as_str() {
+        "constant" => ForceFunctionGenerator::constant_force(param("FORCE")?),
+        "step" => ForceFunctionGenerator::step_force(param("START")?, param("END")?, steps()?),
+        "stepupdown" => ForceFunctionGenerator::step_up_down_force(param("START")?, param("END")?, steps()?),
+        "ramp" => ForceFunctionGenerator::ramp_force(param("START")?, param("END")?),
+        "triangle" => ForceFunctionGenerator::triangle_force(param("START")?, param("END")?),
+        //Sinusoids are always in seconds
+        "sinusoid" => {
+            return ForceFunctionGenerator::sinusoid(param("AMPLITUDE")?, param("FREQUENCY")?, param("PHASE")?, param("OFFSET")?, param("DURATION")?);
+        }
+        //Cycles are always in seconds - PEAKS = [a,b,c] gives a list schedule, otherwise PEAK or FIRST_PEAK/LAST_PEAK
+        "cyclic" => {
+            let schedule = if let Some(peaks) = params.get("PEAKS") {
+                AmplitudeSchedule::List(parse_val_list(peaks)?)
+            } else if params.contains_key("FIRST_PEAK") {
+                AmplitudeSchedule::Linear { start: param("FIRST_PEAK")?, end: param("LAST_PEAK")? }
+            } else {
+                AmplitudeSchedule::Constant(param("PEAK")?)
+            };
+
+            let cycles = match params.get("CYCLES") {
+                Some(val) => val.parse::<usize>()?,
+                None => bail!("Profile missing CYCLES"),
+            };
+
+            return ForceFunctionGenerator::cyclic(&CyclicParams {
+                schedule,
+                min_load: param("MIN_LOAD")?,
+                cycles,
+                load_rate: param("LOAD_RATE")?,
+                unload_rate: param("UNLOAD_RATE")?,
+                peak_hold: param("PEAK_HOLD")?,
+                min_hold: param("MIN_HOLD")?,
+            });
+        }
+        "custom" => {
+            if points.is_empty() {
+                bail!("Custom profile has no points");
+            }
+            if points[0][0] != 0.0 {
+                bail!("Custom profile must start at time 0");
+            }
+
+            //Each point holds until the next one (the last holds until the end)
+            let mut sig_vals = vec![];
+            let mut sig_time = vec![];
+            for (i, point) in points.iter().enumerate() {
+                let end_time = match points.get(i + 1) {
+                    Some(next) => next[0],
+                    None => duration.unwrap_or(100.0),
+                };
+
+                if end_time < point[0] {
+                    bail!("Custom profile times must be increasing - {} then {}", point[0], end_time);
+                }
 
-        //See whether to transofmr the data by the
-        let line: String =
-            //Format the line to write
-            format!(
-                "VALS:{:?}",
-                self.sig_vals,
-            );
+                sig_vals.push(point[1]);
+                sig_time.push(end_time - point[0]);
+            }
+
+            //Custom breakpoints are already in the right units
+            let mut ffunc = match duration {
+                Some(_) => ForceFunctionGenerator::custom_force_secs(sig_vals, sig_time)?,
+                None => ForceFunctionGenerator::custom_force(sig_vals, sig_time)?,
+            };
+
+            //Apply the labels to the segments starting at their times
+            for (time, label) in labels {
+                let idx = points.iter().position(|point| (point[0] - time).abs() < 1e-9);
+                match idx {
+                    Some(idx) => ffunc.segments[idx].label = Some(label),
+                    None => bail!("Segment label {} does not start at a breakpoint ({})", label, time),
+                }
+            }
 
-        //Write to the file - indicating if writing failed (but don't worry about it!)
-        if let Err(e) = writeln!(file, "{}", line) {
-            bail!("Couldn't write to file: {}", e);
+            return Ok(ffunc);
         }
+        other => bail!("Unknown profile type - {}", other),
+    }?;
 
-        Ok(())
+    match duration {
+        Some(duration) => ffunc.with_duration(duration),
+        None => Ok(ffunc),
+    }
+}
+
+
+///Parse a [time, force] breakpoint
+fn parse_point(val: &str) -> Result<[f64; 2], anyhow::Error> {
+    let vals: Vec<&str> = val.trim().trim_start_matches("[").trim_end_matches("]").split(",").collect();
+
+    if vals.len() != 2 {
+        bail!("Expected [time, force] - {}", val);
+    }
+
+    let point = [vals[0].trim().parse::<f64>()?, vals[1].trim().parse::<f64>()?];
+
+    if !point[0].is_finite() || !point[1].is_finite() {
+        bail!("Breakpoint values must be finite - {}", val);
+    }
+
+    Ok(point)
+}
+
+///Parse a [time, "label"] segment label
+fn parse_segment_label(val: &str) -> Result<(f64, String), anyhow::Error> {
+    let inner = val.trim().trim_start_matches("[").trim_end_matches("]");
+
+    let Some((time, label)) = inner.split_once(",") else {
+        bail!("Expected [time, \"label\"] - {}", val);
+    };
+
+    let time = time.trim().parse::<f64>()?;
+    if !time.is_finite() || time < 0.0 {
+        bail!("Invalid segment time - {}", time);
+    }
+
+    Ok((time, label.trim().trim_matches('"').to_string()))
+}
+
+///Parse a list of values saved with {:?} (e.g. [1.0, 2.0])
+fn parse_val_list(val: &str) -> Result<Vec<f64>, anyhow::Error> {
+    let inner = val.trim().trim_start_matches("[").trim_end_matches("]");
+
+    let mut vals = vec![];
+    for token in inner.split(",").map(|t| t.trim()).filter(|t| !t.is_empty()) {
+        vals.push(token.parse::<f64>()?);
+    }
+
+    Ok(vals)
+}
+
+///Parse the seed from the end of a value list - kept as an integer so large seeds aren't rounded
+fn parse_seed(val: &str) -> Result<u64, anyhow::Error> {
+    let inner = val.trim().trim_start_matches("[").trim_end_matches("]");
+
+    match inner.rsplit(",").next().map(|seed| seed.trim().parse::<u64>()) {
+        Some(Ok(seed)) => Ok(seed),
+        _ => bail!("Invalid seed - {}", val),
     }
+}
 
+///Parse the old single line profile format - the saved values are used directly
+fn parse_legacy_line(line: &str) -> Result<ForceFunctionGenerator, anyhow::Error> {
+    let mut vals = None;
+    let mut times = None;
 
-    pub fn force_changes(&self) -> usize {
-        self.force_changes
+    for field in line.split("|") {
+        if let Some(list) = field.strip_prefix("VALS:") {
+            vals = Some(parse_val_list(list)?);
+        } else if let Some(list) = field.strip_prefix("PERC_TIMES:") {
+            times = Some(parse_val_list(list)?);
+        }
     }
 
-    ///Get the signal values
-    pub fn sig_vals(&self) -> Vec<f64> {
-        self.sig_vals.clone()
+    match (vals, times) {
+        (Some(vals), Some(times)) => ForceFunctionGenerator::custom_force(vals, times),
+        _ => bail!("Old profile format missing VALS or PERC_TIMES"),
     }
+}
 
-    ///Get the signal times
-    pub fn sig_time(&self) -> Vec<f64> {
-        self.sig_time.clone()
+///Verify that durations in seconds are valid
+fn verify_durations(sig_time: &[f64]) -> Result<(), anyhow::Error> {
+    if sig_time.iter().any(|time| *time < 0.0) {
+        bail!("Invalid time constraints - negative duration");
     }
+
+    let total: f64 = sig_time.iter().sum();
+    if total <= 0.0 {
+        bail!("Invalid time constraints - profile has no duration");
+    }
+
+    Ok(())
 }
 
 ///Verify that the signal time adds up to 100%
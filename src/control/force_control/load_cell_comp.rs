@@ -0,0 +1,273 @@
+///Tool gravity and load cell bias compensation
+///The tool mass, centre of gravity and sensor offsets are fitted from force/torque readings taken at several orientations
+///Assumes the load cell frame is aligned with the tool frame reported by the robot
+use crate::config::pos_ori_parser;
+use anyhow::bail;
+use nalgebra::{DMatrix, DVector, Matrix3, Quaternion, UnitQuaternion, Vector3};
+use std::fmt::Display;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+
+///Gravitational acceleration (m/s^2)
+const GRAVITY: f64 = 9.81;
+
+///Angle (deg) between two gravity directions for them to count as different orientations
+const MIN_ORI_SEP: f64 = 5.0;
+
+///Smallest eigenvalue of the mean outer product of the distinct gravity directions (1/3 when evenly spread)
+///Coplanar directions give 0 and can't separate the mass, centre of gravity and bias
+const MIN_ORI_SPREAD: f64 = 0.01;
+
+///The directory containing the compensation model of each tool
+pub const TOOL_COMP_FP: &str = "configs/tools";
+
+///A single load cell measurement taken at a known orientation
+#[derive(Debug, Clone, Copy)]
+pub struct LoadCellSample {
+    ///The wxyz orientation of the TCP when the measurement was taken
+    pub ori: [f64; 4],
+    ///The measured force (N) and torque (Nm)
+    pub force: [f64; 6],
+}
+
+///The fitted gravity and bias model of a tool
+#[derive(Debug, Clone)]
+pub struct LoadCellComp {
+    ///The name of the tool the model belongs to
+    tool_name: String,
+    ///The mass of the tool (kg)
+    mass: f64,
+    ///The centre of gravity of the tool in the sensor frame (m)
+    cog: [f64; 3],
+    ///The force offset of the sensor (N)
+    force_bias: [f64; 3],
+    ///The torque offset of the sensor (Nm)
+    torque_bias: [f64; 3],
+}
+
+impl Display for LoadCellComp {
+    ///Display the compensation model
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "TOOL:{} MASS:{} COG:{:?} F_BIAS:{:?} T_BIAS:{:?}",
+            self.tool_name, self.mass, self.cog, self.force_bias, self.torque_bias
+        )
+    }
+}
+
+impl LoadCellComp {
+    ///Fit the tool mass, centre of gravity and sensor bias from a set of samples
+    ///Force: F = m * g_s + F_bias, Torque: T = cog x (m * g_s) + T_bias - where g_s is gravity in the sensor frame
+    pub fn fit(tool_name: &str, samples: &[LoadCellSample]) -> Result<Self, anyhow::Error> {
+        //Need at least three distinct, non-coplanar gravity directions to observe the centre of gravity
+        let dirs = distinct_gravity_dirs(samples);
+        if dirs.len() < 3 {
+            bail!("Too few distinct orientations to fit the tool model - {} (need 3)", dirs.len());
+        }
+
+        let spread = dirs.iter().map(|dir| dir * dir.transpose()).sum::<Matrix3<f64>>() / dirs.len() as f64;
+        let min_spread = spread.symmetric_eigenvalues().min();
+        if min_spread < MIN_ORI_SPREAD {
+            bail!("The sample orientations are (nearly) coplanar - spread {:.4} below {}", min_spread, MIN_ORI_SPREAD);
+        }
+
+        //Build the force problem - unknowns [m, bx, by, bz]
+        let mut force_a = DMatrix::<f64>::zeros(samples.len() * 3, 4);
+        let mut force_b = DVector::<f64>::zeros(samples.len() * 3);
+
+        for (i, sample) in samples.iter().enumerate() {
+            let g_s = gravity_in_sensor(sample.ori);
+
+            for axis in 0..3 {
+                let row = (i * 3) + axis;
+                force_a[(row, 0)] = g_s[axis];
+                force_a[(row, axis + 1)] = 1.0;
+                force_b[row] = sample.force[axis];
+            }
+        }
+
+        let force_sol = match force_a.svd(true, true).solve(&force_b, 1e-12) {
+            Ok(sol) => sol,
+            Err(e) => bail!("Failed to fit the tool mass - {}", e),
+        };
+
+        let mass = force_sol[0];
+        let force_bias = [force_sol[1], force_sol[2], force_sol[3]];
+
+        if mass <= 0.0 {
+            println!("WARNING: Fitted tool mass is not positive ({mass}kg) - check the load cell");
+        }
+
+        //Build the torque problem - unknowns [cx, cy, cz, bx, by, bz]
+        let mut torque_a = DMatrix::<f64>::zeros(samples.len() * 3, 6);
+        let mut torque_b = DVector::<f64>::zeros(samples.len() * 3);
+
+        for (i, sample) in samples.iter().enumerate() {
+            //cog x w = -[w]x cog
+            let weight = gravity_in_sensor(sample.ori) * mass;
+            let skew = -weight.cross_matrix();
+
+            for axis in 0..3 {
+                let row = (i * 3) + axis;
+                for col in 0..3 {
+                    torque_a[(row, col)] = skew[(axis, col)];
+                }
+                torque_a[(row, axis + 3)] = 1.0;
+                torque_b[row] = sample.force[axis + 3];
+            }
+        }
+
+        let torque_sol = match torque_a.svd(true, true).solve(&torque_b, 1e-12) {
+            Ok(sol) => sol,
+            Err(e) => bail!("Failed to fit the tool centre of gravity - {}", e),
+        };
+
+        Ok(LoadCellComp {
+            tool_name: tool_name.to_string(),
+            mass,
+            cog: [torque_sol[0], torque_sol[1], torque_sol[2]],
+            force_bias,
+            torque_bias: [torque_sol[3], torque_sol[4], torque_sol[5]],
+        })
+    }
+
+    ///Remove the tool weight and the sensor offsets from a force/torque measurement
+    pub fn compensate(&self, force: [f64; 6], ori: [f64; 4]) -> [f64; 6] {
+        let weight = gravity_in_sensor(ori) * self.mass;
+        let moment = Vector3::from(self.cog).cross(&weight);
+
+        [
+            force[0] - weight.x - self.force_bias[0],
+            force[1] - weight.y - self.force_bias[1],
+            force[2] - weight.z - self.force_bias[2],
+            force[3] - moment.x - self.torque_bias[0],
+            force[4] - moment.y - self.torque_bias[1],
+            force[5] - moment.z - self.torque_bias[2],
+        ]
+    }
+
+    ///Save the model in the tool directory (or a given filepath)
+    pub fn save_to_file(&self, filepath: &str) -> Result<(), anyhow::Error> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .create(true)
+            .open(filepath.trim())?;
+
+        writeln!(file, "TOOL_NAME = \"{}\"", self.tool_name)?;
+        writeln!(file, "MASS = \"{}\"", self.mass)?;
+        writeln!(
+            file,
+            "COG = [{},{},{}]",
+            self.cog[0], self.cog[1], self.cog[2]
+        )?;
+        writeln!(
+            file,
+            "FORCE_BIAS = [{},{},{}]",
+            self.force_bias[0], self.force_bias[1], self.force_bias[2]
+        )?;
+        writeln!(
+            file,
+            "TORQUE_BIAS = [{},{},{}]",
+            self.torque_bias[0], self.torque_bias[1], self.torque_bias[2]
+        )?;
+
+        Ok(())
+    }
+
+    ///Save the model as the stored model for its tool
+    pub fn save_for_tool(&self) -> Result<(), anyhow::Error> {
+        fs::create_dir_all(TOOL_COMP_FP)?;
+        self.save_to_file(&tool_filepath(&self.tool_name))
+    }
+
+    ///Load a model from a given file
+    pub fn load_from_file(filepath: &str) -> Result<Self, anyhow::Error> {
+        let mut tool_name = String::new();
+        let mut mass = f64::NAN;
+        let mut cog = [0.0, 0.0, 0.0];
+        let mut force_bias = [0.0, 0.0, 0.0];
+        let mut torque_bias = [0.0, 0.0, 0.0];
+
+        let file = File::open(filepath.trim())?;
+
+        for line in BufReader::new(file).lines() {
+            let curr_line = line?;
+
+            if curr_line.starts_with("TOOL_NAME") {
+                let split: Vec<&str> = curr_line.split("\"").collect();
+                tool_name = split[1].to_string();
+            } else if curr_line.starts_with("MASS") {
+                let split: Vec<&str> = curr_line.split("\"").collect();
+                mass = split[1].parse()?;
+            } else if curr_line.starts_with("COG") {
+                cog = pos_ori_parser(curr_line)?;
+            } else if curr_line.starts_with("FORCE_BIAS") {
+                force_bias = pos_ori_parser(curr_line)?;
+            } else if curr_line.starts_with("TORQUE_BIAS") {
+                torque_bias = pos_ori_parser(curr_line)?;
+            } else if !curr_line.trim().is_empty() {
+                bail!("Invalid line in tool compensation file!")
+            }
+        }
+
+        if mass.is_nan() {
+            bail!("Tool compensation file missing the tool mass");
+        }
+
+        Ok(LoadCellComp {
+            tool_name,
+            mass,
+            cog,
+            force_bias,
+            torque_bias,
+        })
+    }
+
+    ///Load the stored model of a given tool
+    pub fn load_for_tool(tool_name: &str) -> Result<Self, anyhow::Error> {
+        Self::load_from_file(&tool_filepath(tool_name))
+    }
+
+    ///Get the tool name
+    pub fn tool_name(&self) -> String {
+        self.tool_name.clone()
+    }
+
+    ///Get the fitted tool mass
+    pub fn mass(&self) -> f64 {
+        self.mass
+    }
+}
+
+///The filepath of a given tools compensation model
+fn tool_filepath(tool_name: &str) -> String {
+    format!("{}/{}.txt", TOOL_COMP_FP, tool_name.trim())
+}
+
+///Calculate the unit gravity vector (scaled by g) in the sensor frame for a given wxyz orientation
+///The distinct gravity directions (unit vectors, sensor frame) of a set of samples
+fn distinct_gravity_dirs(samples: &[LoadCellSample]) -> Vec<Vector3<f64>> {
+    let min_cos = MIN_ORI_SEP.to_radians().cos();
+    let mut dirs: Vec<Vector3<f64>> = vec![];
+
+    for sample in samples {
+        let dir = gravity_in_sensor(sample.ori).normalize();
+
+        if !dir.iter().all(|val| val.is_finite()) || dirs.iter().any(|seen| seen.dot(&dir) > min_cos) {
+            continue;
+        }
+        dirs.push(dir);
+    }
+
+    dirs
+}
+
+fn gravity_in_sensor(ori: [f64; 4]) -> Vector3<f64> {
+    let rot = UnitQuaternion::from_quaternion(Quaternion::new(ori[0], ori[1], ori[2], ori[3]));
+    let rot_mat: Matrix3<f64> = rot.to_rotation_matrix().into_inner();
+
+    //Gravity acts along the negative base z axis
+    rot_mat.transpose() * Vector3::new(0.0, 0.0, -GRAVITY)
+}
@@ -0,0 +1,219 @@
+///Force/torque safety envelope for the EGM control loops
+///Checks the measured load against per-axis magnitude and rate of change limits
+use crate::config::{load_config_or_default, pos_ori_parser, read_config_values};
+use anyhow::bail;
+use std::fmt::Display;
+
+///The file containing the safety limits
+pub const SAFETY_CONFIG_FP: &str = "configs/safety.txt";
+
+///Minimum time (s) between the samples used to calculate the rate of change - stops noise tripping the rate limits
+const RATE_DT: f64 = 0.02;
+
+///The configurable safety limits
+#[derive(Debug, Clone, Copy)]
+pub struct SafetyLimits {
+    ///Maximum force magnitude per axis (N)
+    pub max_force: [f64; 3],
+    ///Maximum torque magnitude per axis (Nm)
+    pub max_torque: [f64; 3],
+    ///Maximum force rate of change per axis (N/s)
+    pub max_force_rate: [f64; 3],
+    ///Maximum torque rate of change per axis (Nm/s)
+    pub max_torque_rate: [f64; 3],
+    ///Force (N) below which the tool is considered unloaded
+    pub unload_force: f64,
+    ///Distance (mm) to retract vertically after unloading
+    pub retract_dist: f64,
+    ///Lowest height (mm) trajectories may send the TCP to
+    pub min_tcp_z: f64,
+}
+
+impl Default for SafetyLimits {
+    fn default() -> Self {
+        SafetyLimits {
+            max_force: [600.0, 600.0, 1500.0],
+            max_torque: [100.0, 100.0, 100.0],
+            max_force_rate: [5000.0, 5000.0, 10000.0],
+            max_torque_rate: [500.0, 500.0, 500.0],
+            unload_force: 5.0,
+            retract_dist: 50.0,
+            min_tcp_z: -20.0,
+        }
+    }
+}
+
+impl Display for SafetyLimits {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "F:{:?} T:{:?} dF:{:?} dT:{:?} UNLOAD:{} RETRACT:{} MIN Z:{}",
+            self.max_force,
+            self.max_torque,
+            self.max_force_rate,
+            self.max_torque_rate,
+            self.unload_force,
+            self.retract_dist,
+            self.min_tcp_z
+        )
+    }
+}
+
+impl SafetyLimits {
+    ///Load the safety limits - the load and rate limits must all be positive
+    pub fn load_from_file(filepath: &str) -> Result<Self, anyhow::Error> {
+        let mut limits = SafetyLimits::default();
+
+        for (key, val) in read_config_values(filepath)? {
+            match key.as_str() {
+                "FORCE_LIMITS" => limits.max_force = pos_ori_parser(val)?,
+                "TORQUE_LIMITS" => limits.max_torque = pos_ori_parser(val)?,
+                "FORCE_RATE_LIMITS" => limits.max_force_rate = pos_ori_parser(val)?,
+                "TORQUE_RATE_LIMITS" => limits.max_torque_rate = pos_ori_parser(val)?,
+                "UNLOAD_FORCE" => limits.unload_force = val.parse()?,
+                "RETRACT_DIST" => limits.retract_dist = val.parse()?,
+                "MIN_TCP_Z" => limits.min_tcp_z = val.parse()?,
+                other => bail!("Unknown setting in safety config - {}", other),
+            }
+        }
+
+        let all_lims = limits
+            .max_force
+            .iter()
+            .chain(limits.max_torque.iter())
+            .chain(limits.max_force_rate.iter())
+            .chain(limits.max_torque_rate.iter());
+
+        for lim in all_lims {
+            if *lim <= 0.0 {
+                bail!("Safety limits must be positive");
+            }
+        }
+
+        Ok(limits)
+    }
+
+    ///The configured safety limits - the defaults are used without a valid config
+    pub fn load_or_default() -> Self {
+        load_config_or_default(SAFETY_CONFIG_FP, "safety limits", Self::load_from_file, SafetyLimits::default)
+    }
+}
+
+///The reason a safety stop was triggered
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SafetyBreach {
+    ///The TCP left the safe workspace
+    OutOfBounds { pos: [f64; 3] },
+    ///A force/torque exceeded its limit (axis 0-5)
+    Load { axis: usize, val: f64, lim: f64 },
+    ///A force/torque changed too quickly (axis 0-5)
+    LoadRate { axis: usize, rate: f64, lim: f64 },
+}
+
+impl Display for SafetyBreach {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const AXES: [&str; 6] = ["FX", "FY", "FZ", "TX", "TY", "TZ"];
+
+        match self {
+            SafetyBreach::OutOfBounds { pos } => {
+                write!(f, "OUT OF SAFETY BOUNDS AT {:?}", pos)
+            }
+            SafetyBreach::Load { axis, val, lim } => {
+                write!(f, "{} LOAD {} EXCEEDED LIMIT {}", AXES[*axis], val, lim)
+            }
+            SafetyBreach::LoadRate { axis, rate, lim } => {
+                write!(f, "{} RATE {}/s EXCEEDED LIMIT {}/s", AXES[*axis], rate, lim)
+            }
+        }
+    }
+}
+
+///Streams the measured load through the safety limits
+pub struct SafetyEnvelope {
+    limits: SafetyLimits,
+    ///The reference sample (load, time) used for the rate of change
+    rate_ref: Option<([f64; 6], f64)>,
+}
+
+impl SafetyEnvelope {
+    ///Create an envelope from a set of limits
+    pub fn create(limits: SafetyLimits) -> Self {
+        SafetyEnvelope {
+            limits,
+            rate_ref: None,
+        }
+    }
+
+    ///Check a force/torque measurement taken at a given time (s)
+    pub fn check(&mut self, load: [f64; 6], time: f64) -> Option<SafetyBreach> {
+        //Ignore measurements that haven't been read yet
+        if load.iter().any(|val| !val.is_finite()) {
+            return None;
+        }
+
+        let lims = self.load_lims();
+
+        for (axis, val) in load.iter().enumerate() {
+            if val.abs() > lims[axis] {
+                return Some(SafetyBreach::Load {
+                    axis,
+                    val: *val,
+                    lim: lims[axis],
+                });
+            }
+        }
+
+        //Rate of change against the reference sample
+        match self.rate_ref {
+            Some((ref_load, ref_time)) => {
+                let dt = time - ref_time;
+
+                if dt >= RATE_DT {
+                    let rate_lims = self.rate_lims();
+                    self.rate_ref = Some((load, time));
+
+                    //Large gaps mean the loop wasn't running - start again
+                    if dt > RATE_DT * 10.0 {
+                        return None;
+                    }
+
+                    for axis in 0..6 {
+                        let rate = (load[axis] - ref_load[axis]) / dt;
+                        if rate.abs() > rate_lims[axis] {
+                            return Some(SafetyBreach::LoadRate {
+                                axis,
+                                rate,
+                                lim: rate_lims[axis],
+                            });
+                        }
+                    }
+                }
+            }
+            None => self.rate_ref = Some((load, time)),
+        }
+
+        None
+    }
+
+    ///Forget the previous measurements (e.g. at the start of a new loop)
+    pub fn reset(&mut self) {
+        self.rate_ref = None;
+    }
+
+    ///Get the limits in use
+    pub fn limits(&self) -> SafetyLimits {
+        self.limits
+    }
+
+    fn load_lims(&self) -> [f64; 6] {
+        let f = self.limits.max_force;
+        let t = self.limits.max_torque;
+        [f[0], f[1], f[2], t[0], t[1], t[2]]
+    }
+
+    fn rate_lims(&self) -> [f64; 6] {
+        let f = self.limits.max_force_rate;
+        let t = self.limits.max_torque_rate;
+        [f[0], f[1], f[2], t[0], t[1], t[2]]
+    }
+}
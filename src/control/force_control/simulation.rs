@@ -0,0 +1,327 @@
+///Offline force control simulation
+///Runs a force controller against a simple soil plant so gain sets can be compared without the robot
+///Plant - linear soil stiffness below the surface, EGM latency on the speed command and noise on the load cell
+use crate::control::force_control::controllers::ForceController;
+use crate::control::force_control::force_function_generator::ForceFunctionGenerator;
+use crate::control::force_control::stiffness_estimator::StiffnessEstimator;
+use anyhow::bail;
+use chrono::{Local, TimeDelta};
+use rand::{RngExt, SeedableRng, rngs::Xoshiro256PlusPlus};
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+///Band (fraction of the step) the force must stay within to be considered settled
+const SETTLE_BAND: f64 = 0.02;
+
+///The simulated plant
+#[derive(Debug, Clone, Copy)]
+pub struct PlantParams {
+    ///Soil stiffness (N/mm)
+    pub stiffness: f64,
+    ///Delay between sending a speed command and the robot acting on it (s)
+    pub latency: f64,
+    ///Standard deviation of the load cell noise (N)
+    pub noise_std: f64,
+    ///Control period (s) - EGM runs at 250Hz
+    pub dt: f64,
+    ///Maximum commanded speed (mm/s)
+    pub max_speed: f64,
+    ///Seed of the load cell noise
+    pub seed: u64,
+}
+
+impl Default for PlantParams {
+    fn default() -> Self {
+        PlantParams {
+            stiffness: 20.0,
+            latency: 0.02,
+            noise_std: 0.5,
+            dt: 0.004,
+            max_speed: 10.0,
+            seed: 2510,
+        }
+    }
+}
+
+impl Display for PlantParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "STIFFNESS:{} LATENCY:{} NOISE:{} DT:{} MAX SPEED:{} SEED:{}",
+            self.stiffness, self.latency, self.noise_std, self.dt, self.max_speed, self.seed
+        )
+    }
+}
+
+///A single tick of the simulation
+#[derive(Debug, Clone, Copy)]
+pub struct SimSample {
+    pub time: f64,
+    pub target: f64,
+    ///The true force (N)
+    pub force: f64,
+    ///The force seen by the controller (N)
+    pub measured: f64,
+    ///The tool position (mm - positive is above the soil surface)
+    pub pos: f64,
+    ///The commanded speed (mm/s)
+    pub speed: f64,
+}
+
+///The step response and tracking metrics of a simulation run
+#[derive(Debug, Clone, Copy)]
+pub struct SimMetrics {
+    ///The step the response metrics are calculated for (largest change in target)
+    pub step: (f64, f64),
+    ///Time to go from 10% to 90% of the step (s)
+    pub rise_time: Option<f64>,
+    ///Overshoot as a percentage of the step
+    pub overshoot: f64,
+    ///Time after the step until the force stays within the settling band (s)
+    pub settling_time: Option<f64>,
+    ///Mean error over the last 10% of the step (N)
+    pub ss_err: f64,
+    ///RMS tracking error over the whole run (N)
+    pub rms_err: f64,
+}
+
+impl Display for SimMetrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "STEP {}N -> {}N | RISE TIME: {} | OVERSHOOT: {:.2}% | SETTLING TIME: {} | SS ERR: {:.3}N | RMS ERR: {:.3}N",
+            self.step.0,
+            self.step.1,
+            opt_secs(self.rise_time),
+            self.overshoot,
+            opt_secs(self.settling_time),
+            self.ss_err,
+            self.rms_err
+        )
+    }
+}
+
+///Format an optional time for display
+fn opt_secs(time: Option<f64>) -> String {
+    match time {
+        Some(t) => format!("{:.3}s", t),
+        None => "N/A".to_string(),
+    }
+}
+
+///Run a controller against the plant for a force profile (defined in seconds)
+///The tool starts touching the soil surface with no load
+pub fn simulate(
+    controller: &mut dyn ForceController,
+    plant: &PlantParams,
+    ffunc: &ForceFunctionGenerator,
+    duration: f64,
+) -> Result<Vec<SimSample>, anyhow::Error> {
+    if ffunc.duration().is_none() {
+        bail!("Force profile must be defined in seconds to be simulated");
+    }
+    if plant.dt <= 0.0 || plant.stiffness <= 0.0 || plant.latency < 0.0 || plant.noise_std < 0.0 {
+        bail!("Invalid plant parameters - {}", plant);
+    }
+
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(plant.seed);
+    let mut stiff_est = StiffnessEstimator::create();
+
+    //Commands waiting to be acted on by the robot
+    let delay_ticks = (plant.latency / plant.dt).round() as usize;
+    let mut cmd_queue: VecDeque<f64> = VecDeque::from(vec![0.0; delay_ticks]);
+
+    let sim_start = Local::now();
+    let mut pos: f64 = 0.0;
+    let mut samples = vec![];
+
+    let tick_cnt = (duration / plant.dt).ceil() as usize;
+
+    for tick in 0..tick_cnt {
+        let time = tick as f64 * plant.dt;
+
+        let target = ffunc.value_at(time);
+
+        //Soil reaction and load cell reading
+        let force = plant.stiffness * (-pos).max(0.0);
+        let measured = force + (gaussian(&mut rng) * plant.noise_std);
+
+        if let Some(stiffness) = stiff_est.update(pos, measured) {
+            controller.update_stiffness(stiffness);
+        }
+
+        //Same error convention as the robot (measured - target)
+        let timestamp = sim_start + TimeDelta::microseconds((time * 1e6) as i64);
+        let speed = controller
+            .calc_op_at(measured - target, timestamp)?
+            .clamp(-plant.max_speed, plant.max_speed);
+
+        if !speed.is_finite() {
+            bail!("Controller output invalid at {}s", time);
+        }
+
+        samples.push(SimSample {
+            time,
+            target,
+            force,
+            measured,
+            pos,
+            speed,
+        });
+
+        //Move the tool with the delayed command
+        cmd_queue.push_back(speed);
+        let applied = cmd_queue.pop_front().unwrap_or(0.0);
+        pos += applied * plant.dt;
+    }
+
+    Ok(samples)
+}
+
+///Standard normal sample (Box-Muller)
+pub fn gaussian(rng: &mut Xoshiro256PlusPlus) -> f64 {
+    let u1: f64 = rng.random::<f64>().max(f64::MIN_POSITIVE);
+    let u2: f64 = rng.random::<f64>();
+
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+}
+
+///Calculate the step response and tracking metrics of a run
+pub fn calc_metrics(samples: &[SimSample]) -> SimMetrics {
+    let rms_err = if samples.is_empty() {
+        f64::NAN
+    } else {
+        (samples.iter().map(|s| (s.force - s.target).powi(2)).sum::<f64>() / samples.len() as f64).sqrt()
+    };
+
+    //Find the largest step in the target (starting from no load)
+    let mut step_idx = 0;
+    let mut step = (0.0, samples.first().map_or(0.0, |s| s.target));
+    for i in 1..samples.len() {
+        let change = samples[i].target - samples[i - 1].target;
+        if change.abs() > (step.1 - step.0).abs() {
+            step_idx = i;
+            step = (samples[i - 1].target, samples[i].target);
+        }
+    }
+
+    //The samples the target holds the step value for
+    let step_end = samples[step_idx..]
+        .iter()
+        .position(|s| s.target != step.1)
+        .map_or(samples.len(), |i| step_idx + i);
+    let step_samples = &samples[step_idx..step_end];
+
+    let size = step.1 - step.0;
+    if step_samples.is_empty() || size == 0.0 {
+        return SimMetrics {
+            step,
+            rise_time: None,
+            overshoot: 0.0,
+            settling_time: None,
+            ss_err: f64::NAN,
+            rms_err,
+        };
+    }
+
+    let start_time = step_samples[0].time;
+
+    //Progress through the step (0 - start, 1 - target)
+    let progress = |s: &SimSample| (s.force - step.0) / size;
+
+    let t_10 = step_samples.iter().find(|s| progress(s) >= 0.1).map(|s| s.time);
+    let t_90 = step_samples.iter().find(|s| progress(s) >= 0.9).map(|s| s.time);
+    let rise_time = match (t_10, t_90) {
+        (Some(t_10), Some(t_90)) => Some(t_90 - t_10),
+        _ => None,
+    };
+
+    let peak = step_samples.iter().map(progress).fold(f64::NEG_INFINITY, f64::max);
+    let overshoot = ((peak - 1.0) * 100.0).max(0.0);
+
+    //Settled once the force never leaves the band again
+    let settling_time = match step_samples.iter().rposition(|s| (progress(s) - 1.0).abs() > SETTLE_BAND) {
+        Some(i) if i + 1 < step_samples.len() => Some(step_samples[i + 1].time - start_time),
+        Some(_) => None,
+        None => Some(0.0),
+    };
+
+    let tail = &step_samples[(step_samples.len() * 9) / 10..];
+    let ss_err = tail.iter().map(|s| s.force - s.target).sum::<f64>() / tail.len() as f64;
+
+    SimMetrics {
+        step,
+        rise_time,
+        overshoot,
+        settling_time,
+        ss_err,
+        rms_err,
+    }
+}
+
+///Write every tick of a run to a CSV file
+pub fn save_trace_csv(samples: &[SimSample], filepath: &str) -> Result<(), anyhow::Error> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .create(true)
+        .open(filepath.trim())?;
+
+    writeln!(file, "time,target,force,measured,pos,speed")?;
+
+    for s in samples {
+        writeln!(
+            file,
+            "{},{},{},{},{},{}",
+            s.time, s.target, s.force, s.measured, s.pos, s.speed
+        )?;
+    }
+
+    Ok(())
+}
+
+///Append the results of a run to a CSV file (one row per run)
+pub fn append_results_csv(
+    filepath: &str,
+    run_name: &str,
+    controller: &str,
+    plant: &PlantParams,
+    metrics: &SimMetrics,
+) -> Result<(), anyhow::Error> {
+    let new_file = !Path::new(filepath.trim()).exists();
+
+    let mut file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(filepath.trim())?;
+
+    if new_file {
+        writeln!(
+            file,
+            "run,controller,stiffness,latency,noise_std,seed,step_from,step_to,rise_time,overshoot,settling_time,ss_err,rms_err"
+        )?;
+    }
+
+    writeln!(
+        file,
+        "{},\"{}\",{},{},{},{},{},{},{},{},{},{},{}",
+        run_name,
+        controller,
+        plant.stiffness,
+        plant.latency,
+        plant.noise_std,
+        plant.seed,
+        metrics.step.0,
+        metrics.step.1,
+        metrics.rise_time.map_or(String::new(), |t| t.to_string()),
+        metrics.overshoot,
+        metrics.settling_time.map_or(String::new(), |t| t.to_string()),
+        metrics.ss_err,
+        metrics.rms_err
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,288 @@
+///Detection of a stable force (geo-test phase 2)
+///Streams the force error through a rolling window and checks the mean and peak error against configurable bounds
+use crate::config::{load_config_or_default, read_config_values};
+use anyhow::bail;
+use std::collections::VecDeque;
+use std::fmt::Display;
+
+///The file containing the stability criteria
+pub const STABILITY_CONFIG_FP: &str = "configs/stability.txt";
+
+///The criteria a force error signal must meet to be declared stable
+///A bound is the larger of the relative (fraction of the target) and absolute (N) tolerance
+#[derive(Debug, Clone, Copy)]
+pub struct StabilityCriteria {
+    ///Number of samples in the rolling window used for the mean error
+    pub window_len: usize,
+    ///Number of the most recent samples that must be within the peak bound
+    pub peak_len: usize,
+    ///Relative bound on the mean error
+    pub rel_mean_tol: f64,
+    ///Absolute bound on the mean error (N)
+    pub abs_mean_tol: f64,
+    ///Relative bound on the peak error
+    pub rel_peak_tol: f64,
+    ///Absolute bound on the peak error (N)
+    pub abs_peak_tol: f64,
+    ///Maximum time (s) to wait for the force to settle (None - wait forever)
+    pub max_settle_time: Option<f64>,
+}
+
+impl Default for StabilityCriteria {
+    ///Based on the original phase 2 criteria - 2500 samples, the last 2000 within 5% of the target
+    ///The original widened this to 20% for the 10N and 25N targets - the 2N floor replaces that
+    ///(the same bound at 10N, tighter than the original 5N at 25N) and settling gives up after 120s instead of waiting forever
+    fn default() -> Self {
+        StabilityCriteria {
+            window_len: 2500,
+            peak_len: 2000,
+            rel_mean_tol: 0.05,
+            abs_mean_tol: 2.0,
+            rel_peak_tol: 0.05,
+            abs_peak_tol: 2.0,
+            max_settle_time: Some(120.0),
+        }
+    }
+}
+
+impl Display for StabilityCriteria {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "WINDOW:{} PEAK_WINDOW:{} MEAN_TOL:[{},{}N] PEAK_TOL:[{},{}N] MAX_SETTLE:{}",
+            self.window_len,
+            self.peak_len,
+            self.rel_mean_tol,
+            self.abs_mean_tol,
+            self.rel_peak_tol,
+            self.abs_peak_tol,
+            match self.max_settle_time {
+                Some(t) => format!("{t}s"),
+                None => "NONE".to_string(),
+            }
+        )
+    }
+}
+
+impl StabilityCriteria {
+    ///Load and validate stability criteria - settings not given keep their defaults
+    pub fn load_from_file(filepath: &str) -> Result<Self, anyhow::Error> {
+        let mut criteria = StabilityCriteria::default();
+
+        for (key, val) in read_config_values(filepath)? {
+            match key.as_str() {
+                "WINDOW_LEN" => criteria.window_len = val.parse()?,
+                "PEAK_LEN" => criteria.peak_len = val.parse()?,
+                "REL_MEAN_TOL" => criteria.rel_mean_tol = val.parse()?,
+                "ABS_MEAN_TOL" => criteria.abs_mean_tol = val.parse()?,
+                "REL_PEAK_TOL" => criteria.rel_peak_tol = val.parse()?,
+                "ABS_PEAK_TOL" => criteria.abs_peak_tol = val.parse()?,
+                "MAX_SETTLE_TIME" => {
+                    criteria.max_settle_time = match val.to_uppercase().as_str() {
+                        "NONE" => None,
+                        _ => Some(val.parse()?),
+                    }
+                }
+                other => bail!("Unknown setting in stability config - {}", other),
+            }
+        }
+
+        criteria.validate()?;
+
+        Ok(criteria)
+    }
+
+    ///The configured stability criteria (the defaults if there is no valid config)
+    pub fn load_or_default() -> Self {
+        load_config_or_default(STABILITY_CONFIG_FP, "stability criteria", Self::load_from_file, StabilityCriteria::default)
+    }
+
+    ///Check the criteria are usable
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.window_len == 0 {
+            bail!("Stability window must contain at least one sample");
+        }
+        if self.peak_len > self.window_len {
+            bail!("Peak window ({}) larger than the stability window ({})", self.peak_len, self.window_len);
+        }
+        if self.rel_mean_tol < 0.0 || self.abs_mean_tol < 0.0 || self.rel_peak_tol < 0.0 || self.abs_peak_tol < 0.0 {
+            bail!("Stability tolerances must not be negative");
+        }
+        if let Some(t) = self.max_settle_time
+            && t <= 0.0
+        {
+            bail!("Maximum settle time must be positive");
+        }
+
+        Ok(())
+    }
+
+    ///The allowed mean error for a given target
+    fn mean_bound(&self, target: f64) -> f64 {
+        (self.rel_mean_tol * target.abs()).max(self.abs_mean_tol)
+    }
+
+    ///The allowed peak error for a given target
+    fn peak_bound(&self, target: f64) -> f64 {
+        (self.rel_peak_tol * target.abs()).max(self.abs_peak_tol)
+    }
+}
+
+///The outcome of a stability check
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StabilityStatus {
+    ///Not enough samples have been gathered yet
+    Filling { samples: usize, needed: usize },
+    ///The error has settled
+    Stable { mean: f64, peak: f64 },
+    ///The mean error is too large
+    MeanOutOfBounds { mean: f64, bound: f64 },
+    ///An error in the peak window is too large
+    PeakOutOfBounds { peak: f64, bound: f64 },
+    ///The error failed to settle in time
+    TimedOut { elapsed: f64 },
+}
+
+impl Display for StabilityStatus {
+    ///The reason stability was (or was not) declared
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StabilityStatus::Filling { samples, needed } => {
+                write!(f, "FILLING WINDOW ({samples}/{needed})")
+            }
+            StabilityStatus::Stable { mean, peak } => {
+                write!(f, "STABLE - MEAN ERR:{mean} PEAK ERR:{peak}")
+            }
+            StabilityStatus::MeanOutOfBounds { mean, bound } => {
+                write!(f, "MEAN ERR {mean} OUTSIDE BOUND {bound}")
+            }
+            StabilityStatus::PeakOutOfBounds { peak, bound } => {
+                write!(f, "PEAK ERR {peak} OUTSIDE BOUND {bound}")
+            }
+            StabilityStatus::TimedOut { elapsed } => {
+                write!(f, "FAILED TO SETTLE WITHIN {elapsed}s")
+            }
+        }
+    }
+}
+
+impl StabilityStatus {
+    ///Whether the force has been declared stable
+    pub fn is_stable(&self) -> bool {
+        matches!(self, StabilityStatus::Stable { .. })
+    }
+
+    ///Whether the check has finished (stable or timed out)
+    pub fn is_finished(&self) -> bool {
+        matches!(self, StabilityStatus::Stable { .. } | StabilityStatus::TimedOut { .. })
+    }
+}
+
+///Streaming stability detector - every update is O(1) (amortised for the peak)
+pub struct StabilityDetector {
+    criteria: StabilityCriteria,
+    ///The errors in the mean window
+    window: VecDeque<f64>,
+    ///Running sum of the window
+    sum: f64,
+    ///Monotonic queue of (sample index, |error|) - the front is the peak of the peak window
+    peak_queue: VecDeque<(usize, f64)>,
+    ///The total number of samples seen
+    sample_cnt: usize,
+}
+
+impl StabilityDetector {
+    ///Create a detector with the given criteria
+    pub fn create(criteria: StabilityCriteria) -> Self {
+        StabilityDetector {
+            criteria,
+            window: VecDeque::with_capacity(criteria.window_len),
+            sum: 0.0,
+            peak_queue: VecDeque::new(),
+            sample_cnt: 0,
+        }
+    }
+
+    ///Add a force error sample and check for stability
+    ///target - the force target (N), elapsed - the time since the detector started (s)
+    pub fn update(&mut self, err: f64, target: f64, elapsed: f64) -> StabilityStatus {
+        //Mean window
+        self.window.push_back(err);
+        self.sum += err;
+        if self.window.len() > self.criteria.window_len
+            && let Some(old) = self.window.pop_front()
+        {
+            self.sum -= old;
+        }
+
+        //Peak window - drop anything that can never be the peak again
+        let abs_err = err.abs();
+        while let Some(&(_, back)) = self.peak_queue.back() {
+            if back <= abs_err {
+                self.peak_queue.pop_back();
+            } else {
+                break;
+            }
+        }
+        self.peak_queue.push_back((self.sample_cnt, abs_err));
+        self.sample_cnt += 1;
+
+        while let Some(&(idx, _)) = self.peak_queue.front() {
+            if idx + self.criteria.peak_len < self.sample_cnt {
+                self.peak_queue.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        let status = self.status(target);
+
+        //Only time out if not already stable
+        if !status.is_stable()
+            && let Some(max_time) = self.criteria.max_settle_time
+            && elapsed > max_time
+        {
+            return StabilityStatus::TimedOut { elapsed };
+        }
+
+        status
+    }
+
+    ///Check the current window against the criteria
+    fn status(&self, target: f64) -> StabilityStatus {
+        if self.window.len() < self.criteria.window_len {
+            return StabilityStatus::Filling {
+                samples: self.window.len(),
+                needed: self.criteria.window_len,
+            };
+        }
+
+        let peak = match self.peak_queue.front() {
+            Some(&(_, peak)) if self.criteria.peak_len > 0 => peak,
+            _ => 0.0,
+        };
+        let peak_bound = self.criteria.peak_bound(target);
+        if peak > peak_bound {
+            return StabilityStatus::PeakOutOfBounds {
+                peak,
+                bound: peak_bound,
+            };
+        }
+
+        let mean = self.sum / self.window.len() as f64;
+        let mean_bound = self.criteria.mean_bound(target);
+        if mean.abs() > mean_bound {
+            return StabilityStatus::MeanOutOfBounds {
+                mean,
+                bound: mean_bound,
+            };
+        }
+
+        StabilityStatus::Stable { mean, peak }
+    }
+
+    ///Get the criteria in use
+    pub fn criteria(&self) -> StabilityCriteria {
+        self.criteria
+    }
+}
@@ -0,0 +1,107 @@
+///Online estimation of the local soil stiffness
+///Recursive least squares fit of the measured force against the penetration along the force controlled axis
+///Penetration is measured from the position at first contact (re-taken whenever contact is lost) so the surface height isn't fitted
+use nalgebra::{Matrix2, Vector2};
+
+///Forgetting factor - lets the estimate track changes in the soil
+const FORGETTING_FACTOR: f64 = 0.995;
+///Initial covariance of the estimate (large as nothing is known about the soil)
+const INITIAL_COVARIANCE: f64 = 1e4;
+///Maximum covariance trace - stops windup when the tool isn't moving
+const MAX_COVARIANCE_TRACE: f64 = 1e6;
+///Minimum movement (mm) between updates - no information is gained without movement
+const MIN_STEP: f64 = 0.01;
+///Minimum force (N) for the tool to be considered in contact with the soil
+const MIN_CONTACT_FORCE: f64 = 2.0;
+///Number of updates before the estimate is considered valid
+const MIN_UPDATES: usize = 50;
+
+///Tracks the local soil stiffness using RLS on force vs penetration (F = k * d + c)
+pub struct StiffnessEstimator {
+    ///The estimated parameters [k (N/mm), c (N)]
+    theta: Vector2<f64>,
+    ///The covariance of the estimate
+    cov: Matrix2<f64>,
+    ///The position contact was made at (NaN when not in contact)
+    contact_pos: f64,
+    ///The position the last update was made at
+    last_pos: f64,
+    ///The number of updates made
+    update_cnt: usize,
+}
+
+impl Default for StiffnessEstimator {
+    fn default() -> Self {
+        Self::create()
+    }
+}
+
+impl StiffnessEstimator {
+    ///Create a stiffness estimator with no knowledge of the soil
+    pub fn create() -> Self {
+        StiffnessEstimator {
+            theta: Vector2::zeros(),
+            cov: Matrix2::identity() * INITIAL_COVARIANCE,
+            contact_pos: f64::NAN,
+            last_pos: f64::NAN,
+            update_cnt: 0,
+        }
+    }
+
+    ///Update the estimate with the current position (mm) and force (N) along the controlled axis
+    ///Returns the current stiffness estimate if it is valid
+    pub fn update(&mut self, pos: f64, force: f64) -> Option<f64> {
+        if !pos.is_finite() || !force.is_finite() {
+            return self.stiffness();
+        }
+
+        //Penetration is measured from where contact was made
+        if force.abs() < MIN_CONTACT_FORCE {
+            self.contact_pos = f64::NAN;
+            return self.stiffness();
+        }
+        if !self.contact_pos.is_finite() {
+            self.contact_pos = pos;
+        }
+
+        //Only update when the tool has moved far enough to gain information
+        if self.last_pos.is_finite() && (pos - self.last_pos).abs() < MIN_STEP {
+            return self.stiffness();
+        }
+
+        self.last_pos = pos;
+
+        //Standard RLS update with forgetting
+        let phi = Vector2::new(pos - self.contact_pos, 1.0);
+        let cov_phi = self.cov * phi;
+        let gain = cov_phi / (FORGETTING_FACTOR + phi.dot(&cov_phi));
+
+        let pred_err = force - phi.dot(&self.theta);
+        self.theta += gain * pred_err;
+
+        self.cov = (self.cov - gain * cov_phi.transpose()) / FORGETTING_FACTOR;
+
+        //Reset the covariance if it winds up
+        if self.cov.trace() > MAX_COVARIANCE_TRACE {
+            self.cov = Matrix2::identity() * INITIAL_COVARIANCE;
+        }
+
+        self.update_cnt += 1;
+
+        self.stiffness()
+    }
+
+    ///Get the stiffness magnitude (N/mm) if enough updates have been made
+    pub fn stiffness(&self) -> Option<f64> {
+        if self.update_cnt < MIN_UPDATES {
+            None
+        } else {
+            Some(self.theta[0].abs())
+        }
+    }
+
+    ///Get the number of updates made
+    pub fn update_cnt(&self) -> usize {
+        self.update_cnt
+    }
+}
@@ -0,0 +1,263 @@
+//!Coordinate frames of the test setup
+//!base - the robot base, the frame the robot reports and is commanded in
+//!bed - the work object on the soil bed, placed in the base frame (by default at the min corner of the workspace, level with the base)
+//!sensor - the load cell relative to the TCP
+//!Orientations are given as [roll, pitch, yaw] (deg) - roll about x, then pitch about y, then yaw about z (all about the parent axes)
+//!This is nalgebra's from_euler_angles convention - angle_tools::euler_to_quart differs in the signs of its x and z terms
+//!Generated trajectories (shapes, test plans, coverage and imports) and library files are in the bed frame - they are converted to the base
+//!frame once, when the trajectory is picked. The heightmap placement and the workspace limits are in the base frame
+//!Trajectory files name their frame with a "# FRAME: base" (or bed) comment - TRAJ_FRAME is the frame of files that don't
+use crate::config::{load_config_or_default, pos_ori_parser, read_config_values};
+use crate::control::abb_rob::WORKSPACE_MIN;
+use crate::control::misc_tools::angle_tools;
+use crate::control::trajectory_planner::Waypoint;
+use crate::control::trajectory_planner::tracking::TrackingError;
+use anyhow::bail;
+use nalgebra::{Isometry3, Point3, Quaternion, Translation3, UnitQuaternion, Vector3};
+use std::fmt::Display;
+
+///The file containing the frame definitions
+pub const FRAMES_CONFIG_FP: &str = "configs/frames.txt";
+
+///The bed origin used if BED_POS isn't set (base frame) - the min corner of the workspace at the height of the base
+pub const DEFAULT_BED_POS: [f64; 3] = [WORKSPACE_MIN[0], WORKSPACE_MIN[1], 0.0];
+
+///A frame given by a position and orientation in its parent frame
+#[derive(Debug, Clone, Copy)]
+pub struct FrameDef {
+    ///Position of the origin (mm)
+    pub pos: [f64; 3],
+    ///[roll, pitch, yaw] (deg)
+    pub ori: [f64; 3],
+}
+
+impl Display for FrameDef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "POS:[{},{},{}] ORI:[{},{},{}]",
+            self.pos[0], self.pos[1], self.pos[2], self.ori[0], self.ori[1], self.ori[2]
+        )
+    }
+}
+
+impl FrameDef {
+    ///The frame at its parent's origin
+    pub fn identity() -> FrameDef {
+        FrameDef {
+            pos: [0.0; 3],
+            ori: [0.0; 3],
+        }
+    }
+
+    ///The transform from this frame to its parent
+    pub fn isometry(&self) -> Isometry3<f64> {
+        let [roll, pitch, yaw] = self.ori.map(|ang| ang.to_radians());
+
+        Isometry3::from_parts(
+            Translation3::new(self.pos[0], self.pos[1], self.pos[2]),
+            UnitQuaternion::from_euler_angles(roll, pitch, yaw),
+        )
+    }
+}
+
+///The frames of the test setup
+#[derive(Debug, Clone, Copy)]
+pub struct Frames {
+    ///The bed in the base frame
+    pub bed: FrameDef,
+    ///The load cell in the TCP frame
+    pub sensor: FrameDef,
+    ///Whether trajectory files that don't name a frame are in the bed frame (otherwise the base frame)
+    pub traj_in_bed: bool,
+    ///Whether logged positions, orientations and forces are transformed into the bed frame
+    ///(otherwise they are logged as reported - positions in the base frame and forces in the sensor frame)
+    pub log_in_bed: bool,
+}
+
+impl Display for Frames {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "BED:{} SENSOR:{} TRAJ FRAME:{} LOG FRAME:{}",
+            self.bed,
+            self.sensor,
+            frame_name(self.traj_in_bed),
+            frame_name(self.log_in_bed)
+        )
+    }
+}
+
+impl Frames {
+    ///The default frames - the bed sits level at the default origin and files and logs stay in the base frame
+    pub fn create() -> Frames {
+        Frames {
+            bed: FrameDef {
+                pos: DEFAULT_BED_POS,
+                ori: [0.0; 3],
+            },
+            sensor: FrameDef::identity(),
+            traj_in_bed: false,
+            log_in_bed: false,
+        }
+    }
+
+    ///Load the frames config - frames not given stay at their defaults
+    pub fn load_from_file(filepath: &str) -> Result<Self, anyhow::Error> {
+        let mut frames = Frames::create();
+
+        for (key, val) in read_config_values(filepath)? {
+            match key.as_str() {
+                "BED_POS" => frames.bed.pos = pos_ori_parser(val)?,
+                "BED_ORI" => frames.bed.ori = pos_ori_parser(val)?,
+                "SENSOR_POS" => frames.sensor.pos = pos_ori_parser(val)?,
+                "SENSOR_ORI" => frames.sensor.ori = pos_ori_parser(val)?,
+                "TRAJ_FRAME" => frames.traj_in_bed = parse_frame_name(&val)?,
+                "LOG_FRAME" => frames.log_in_bed = parse_frame_name(&val)?,
+                other => bail!("Unknown setting in frames config - {}", other),
+            }
+        }
+
+        Ok(frames)
+    }
+
+    ///The configured frames, or the defaults if the config is missing or invalid
+    pub fn load_or_default() -> Self {
+        load_config_or_default(FRAMES_CONFIG_FP, "the frames", Self::load_from_file, Frames::create)
+    }
+
+    ///Convert the waypoints of a trajectory file to the bed frame
+    ///frame - the frame named in the file (None uses the trajectory frame)
+    pub fn file_to_bed(&self, waypoints: &[Waypoint], frame: Option<&str>) -> Result<Vec<Waypoint>, anyhow::Error> {
+        let in_bed = match frame {
+            Some(name) => parse_frame_name(name)?,
+            None => self.traj_in_bed,
+        };
+
+        if in_bed {
+            return Ok(waypoints.to_vec());
+        }
+
+        Ok(transform_waypoints(&self.bed.isometry().inverse(), waypoints))
+    }
+
+    ///Convert bed frame waypoints to the base frame (the frame the robot is commanded in)
+    pub fn bed_to_base(&self, waypoints: &[Waypoint]) -> Vec<Waypoint> {
+        transform_waypoints(&self.bed.isometry(), waypoints)
+    }
+
+    ///Convert a base frame point to the bed frame
+    pub fn base_to_bed(&self, pnt: [f64; 3]) -> [f64; 3] {
+        let pnt = self.bed.isometry().inverse() * Point3::new(pnt[0], pnt[1], pnt[2]);
+
+        [pnt.x, pnt.y, pnt.z]
+    }
+
+    ///The name of the frame positions are logged in
+    pub fn log_frame(&self) -> &'static str {
+        frame_name(self.log_in_bed)
+    }
+
+    ///Convert base frame waypoints to the log frame
+    pub fn log_waypoints(&self, waypoints: &[Waypoint]) -> Vec<Waypoint> {
+        if !self.log_in_bed {
+            return waypoints.to_vec();
+        }
+
+        transform_waypoints(&self.bed.isometry().inverse(), waypoints)
+    }
+
+    ///Convert a tracking error to the log frame
+    ///The cross track side is judged looking down the log frame's z axis (it flips if the bed is upside down)
+    pub fn log_tracking(&self, err: TrackingError) -> TrackingError {
+        if !self.log_in_bed {
+            return err;
+        }
+
+        let base_to_bed = self.bed.isometry().inverse();
+        let pos_ref = base_to_bed * Point3::new(err.pos_ref[0], err.pos_ref[1], err.pos_ref[2]);
+        let up = base_to_bed.rotation * Vector3::z();
+
+        TrackingError {
+            pos_ref: [pos_ref.x, pos_ref.y, pos_ref.z],
+            cross: if up.z < 0.0 { -err.cross } else { err.cross },
+            ..err
+        }
+    }
+
+    ///Convert a measured TCP pose (base frame, wxyz orientation) and load cell reading (sensor frame) to the log frame
+    ///Torques are rotated about the load cell origin
+    pub fn log_state(&self, pos: [f64; 3], ori: [f64; 4], force: [f64; 6]) -> ([f64; 3], [f64; 4], [f64; 6]) {
+        if !self.log_in_bed {
+            return (pos, ori, force);
+        }
+
+        let base_to_bed = self.bed.isometry().inverse();
+        let tcp = UnitQuaternion::from_quaternion(Quaternion::new(ori[0], ori[1], ori[2], ori[3]));
+
+        let pos = base_to_bed * Point3::new(pos[0], pos[1], pos[2]);
+        let ori = base_to_bed.rotation * tcp;
+
+        //Sensor -> TCP -> base -> bed
+        let rot = base_to_bed.rotation * tcp * self.sensor.isometry().rotation;
+        let lin = rot * Vector3::new(force[0], force[1], force[2]);
+        let ang = rot * Vector3::new(force[3], force[4], force[5]);
+
+        (
+            [pos.x, pos.y, pos.z],
+            [ori.w, ori.i, ori.j, ori.k],
+            [lin.x, lin.y, lin.z, ang.x, ang.y, ang.z],
+        )
+    }
+
+    ///Convert a base frame orientation to the log frame
+    pub fn log_ori(&self, ori: angle_tools::Quaternion) -> angle_tools::Quaternion {
+        if !self.log_in_bed {
+            return ori;
+        }
+
+        from_unit(self.bed.isometry().inverse().rotation * to_unit(ori))
+    }
+}
+
+fn frame_name(in_bed: bool) -> &'static str {
+    if in_bed { "bed" } else { "base" }
+}
+
+///Parse a frame name - true for the bed frame
+fn parse_frame_name(name: &str) -> Result<bool, anyhow::Error> {
+    match name.to_lowercase().as_str() {
+        "base" => Ok(false),
+        "bed" => Ok(true),
+        other => bail!("Unknown frame - {} (base/bed)", other),
+    }
+}
+
+///Move waypoints (and their orientations) by a transform
+fn transform_waypoints(iso: &Isometry3<f64>, waypoints: &[Waypoint]) -> Vec<Waypoint> {
+    waypoints
+        .iter()
+        .map(|waypoint| {
+            let pos = iso * Point3::new(waypoint.pos.0, waypoint.pos.1, waypoint.pos.2);
+
+            Waypoint {
+                pos: (pos.x, pos.y, pos.z),
+                ori: waypoint.ori.map(|ori| from_unit(iso.rotation * to_unit(ori))),
+                ..*waypoint
+            }
+        })
+        .collect()
+}
+
+fn to_unit(q: angle_tools::Quaternion) -> UnitQuaternion<f64> {
+    UnitQuaternion::from_quaternion(Quaternion::new(q.w, q.x, q.y, q.z))
+}
+
+fn from_unit(q: UnitQuaternion<f64>) -> angle_tools::Quaternion {
+    angle_tools::Quaternion {
+        w: q.w,
+        x: q.i,
+        y: q.j,
+        z: q.k,
+    }
+}
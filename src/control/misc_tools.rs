@@ -1,3 +1,4 @@
 pub mod angle_tools;
 pub mod misc;
+pub mod preview;
 pub mod string_tools;
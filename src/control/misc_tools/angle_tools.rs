@@ -2,7 +2,7 @@
 //!Implementations/Calculations taken from: https://danceswithcode.net/engineeringnotes/quaternions/quaternions.html
 
 ///The quaternion structure
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy)]
 pub struct Quaternion {
     ///Real value
     pub w: f64,
@@ -104,3 +104,43 @@ pub fn quart_rotate(q1: Quaternion, q2: Quaternion) -> Quaternion {
 
     Quaternion { w, x, y, z }
 }
+
+///The angle (radians) of the rotation between two unit quaternions
+pub fn quart_angle(q1: Quaternion, q2: Quaternion) -> f64 {
+    let dot = (q1.w * q2.w) + (q1.x * q2.x) + (q1.y * q2.y) + (q1.z * q2.z);
+
+    //q and -q are the same rotation
+    2.0 * dot.abs().min(1.0).acos()
+}
+
+///Spherical linear interpolation between two unit quaternions (t from 0 to 1) - takes the shortest rotation
+pub fn quart_slerp(q1: Quaternion, q2: Quaternion, t: f64) -> Quaternion {
+    let mut dot = (q1.w * q2.w) + (q1.x * q2.x) + (q1.y * q2.y) + (q1.z * q2.z);
+
+    //Flip the end quaternion to go the short way round
+    let q2 = if dot < 0.0 {
+        dot = -dot;
+        Quaternion { w: -q2.w, x: -q2.x, y: -q2.y, z: -q2.z }
+    } else {
+        q2
+    };
+
+    //Nearly identical - linear interpolation avoids dividing by sin(0)
+    let (s1, s2) = if dot > 0.9995 {
+        (1.0 - t, t)
+    } else {
+        let theta = dot.acos();
+        (((1.0 - t) * theta).sin() / theta.sin(), (t * theta).sin() / theta.sin())
+    };
+
+    let q = Quaternion {
+        w: (s1 * q1.w) + (s2 * q2.w),
+        x: (s1 * q1.x) + (s2 * q2.x),
+        y: (s1 * q1.y) + (s2 * q2.y),
+        z: (s1 * q1.z) + (s2 * q2.z),
+    };
+
+    let norm = (q.w.powi(2) + q.x.powi(2) + q.y.powi(2) + q.z.powi(2)).sqrt();
+
+    Quaternion { w: q.w / norm, x: q.x / norm, y: q.y / norm, z: q.z / norm }
+}
@@ -12,3 +12,32 @@ pub fn wait_for_enter() {
         .read_line(&mut user_inp)
         .expect("Failed to read line");
 }
+
+///Prints a prompt and returns the trimmed line the user enters
+pub fn read_user_line(prompt: &str) -> String {
+    println!("{prompt}");
+
+    let mut user_inp = String::new();
+    stdin()
+        .read_line(&mut user_inp)
+        .expect("Failed to read line");
+
+    user_inp.trim().to_string()
+}
+
+///Prompts the user for a value - an empty (or invalid) entry returns the default
+pub fn read_with_default<T: std::str::FromStr + std::fmt::Display>(prompt: &str, default: T) -> T {
+    let user_inp = read_user_line(&format!("{} [{}]:", prompt, default));
+
+    if user_inp.is_empty() {
+        return default;
+    }
+
+    match user_inp.parse::<T>() {
+        Ok(val) => val,
+        Err(_) => {
+            println!("Invalid value - using {}", default);
+            default
+        }
+    }
+}
@@ -0,0 +1,254 @@
+//!Renders previews of force profiles and trajectories so a test can be checked before it runs
+use crate::control::force_control::force_function_generator::{ForceFunctionGenerator, TimeMode};
+use anyhow::bail;
+use image::{Rgb, RgbImage};
+
+///Size of a single plot panel (pixels)
+const PANEL_W: u32 = 800;
+const PANEL_H: u32 = 500;
+///Gap between the panel edge and the plot area (pixels)
+const MARGIN: u32 = 40;
+
+const BACKGROUND: Rgb<u8> = Rgb([255, 255, 255]);
+const AXIS: Rgb<u8> = Rgb([0, 0, 0]);
+const GRID: Rgb<u8> = Rgb([220, 220, 220]);
+const TRACE: Rgb<u8> = Rgb([20, 80, 200]);
+const START: Rgb<u8> = Rgb([20, 160, 40]);
+const LIMIT: Rgb<u8> = Rgb([220, 30, 30]);
+
+///Characters used for the terminal sparkline (lowest to highest)
+const SPARK_CHARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+///Maps data coordinates onto a panel of an image
+struct Panel {
+    ///Pixel offset of the panel in the image
+    origin: (u32, u32),
+    ///Data range shown - (min x, max x, min y, max y)
+    bounds: (f64, f64, f64, f64),
+}
+
+impl Panel {
+    ///Create a panel showing the given data range (padded so the data doesn't touch the edges)
+    fn create(origin: (u32, u32), bounds: (f64, f64, f64, f64)) -> Panel {
+        let (mut min_x, mut max_x, mut min_y, mut max_y) = bounds;
+
+        //Avoid zero width ranges (e.g. constant profiles)
+        if max_x - min_x < 1e-9 {
+            min_x -= 1.0;
+            max_x += 1.0;
+        }
+        if max_y - min_y < 1e-9 {
+            min_y -= 1.0;
+            max_y += 1.0;
+        }
+
+        let pad_x = (max_x - min_x) * 0.05;
+        let pad_y = (max_y - min_y) * 0.05;
+
+        Panel {
+            origin,
+            bounds: (min_x - pad_x, max_x + pad_x, min_y - pad_y, max_y + pad_y),
+        }
+    }
+
+    ///Convert a data point to a pixel (may lie outside the panel)
+    fn to_pixel(&self, x: f64, y: f64) -> (f64, f64) {
+        let (min_x, max_x, min_y, max_y) = self.bounds;
+        let plot_w = (PANEL_W - 2 * MARGIN) as f64;
+        let plot_h = (PANEL_H - 2 * MARGIN) as f64;
+
+        (
+            self.origin.0 as f64 + MARGIN as f64 + ((x - min_x) / (max_x - min_x)) * plot_w,
+            //Image y runs downwards
+            self.origin.1 as f64 + (PANEL_H - MARGIN) as f64 - ((y - min_y) / (max_y - min_y)) * plot_h,
+        )
+    }
+
+    ///Draw a line between two data points - clipped to the plot area
+    fn line(&self, img: &mut RgbImage, a: (f64, f64), b: (f64, f64), colour: Rgb<u8>) {
+        let a = self.to_pixel(a.0, a.1);
+        let b = self.to_pixel(b.0, b.1);
+
+        let steps = (b.0 - a.0).abs().max((b.1 - a.1).abs()).ceil().max(1.0) as usize;
+
+        for i in 0..=steps {
+            let frac = i as f64 / steps as f64;
+            self.put(img, a.0 + (b.0 - a.0) * frac, a.1 + (b.1 - a.1) * frac, colour);
+        }
+    }
+
+    ///Colour a pixel if it lies within the plot area
+    fn put(&self, img: &mut RgbImage, x: f64, y: f64, colour: Rgb<u8>) {
+        let min_x = (self.origin.0 + MARGIN) as f64;
+        let max_x = (self.origin.0 + PANEL_W - MARGIN) as f64;
+        let min_y = (self.origin.1 + MARGIN) as f64;
+        let max_y = (self.origin.1 + PANEL_H - MARGIN) as f64;
+
+        if x.is_finite() && y.is_finite() && x >= min_x && x <= max_x && y >= min_y && y <= max_y {
+            img.put_pixel(x.round() as u32, y.round() as u32, colour);
+        }
+    }
+
+    ///Draw a filled square marker at a data point
+    fn marker(&self, img: &mut RgbImage, pnt: (f64, f64), colour: Rgb<u8>) {
+        let (x, y) = self.to_pixel(pnt.0, pnt.1);
+
+        for dx in -3..=3 {
+            for dy in -3..=3 {
+                self.put(img, x + dx as f64, y + dy as f64, colour);
+            }
+        }
+    }
+
+    ///Draw the grid (10 divisions) and the border of the plot area
+    fn axes(&self, img: &mut RgbImage) {
+        let (min_x, max_x, min_y, max_y) = self.bounds;
+
+        for i in 1..10 {
+            let x = min_x + (max_x - min_x) * i as f64 / 10.0;
+            let y = min_y + (max_y - min_y) * i as f64 / 10.0;
+            self.line(img, (x, min_y), (x, max_y), GRID);
+            self.line(img, (min_x, y), (max_x, y), GRID);
+        }
+
+        self.rect(img, (min_x, min_y), (max_x, max_y), AXIS);
+    }
+
+    ///Draw a rectangle between two corners
+    fn rect(&self, img: &mut RgbImage, min: (f64, f64), max: (f64, f64), colour: Rgb<u8>) {
+        self.line(img, (min.0, min.1), (max.0, min.1), colour);
+        self.line(img, (max.0, min.1), (max.0, max.1), colour);
+        self.line(img, (max.0, max.1), (min.0, max.1), colour);
+        self.line(img, (min.0, max.1), (min.0, min.1), colour);
+    }
+
+    ///Draw a polyline through the points
+    fn trace(&self, img: &mut RgbImage, pnts: &[(f64, f64)], colour: Rgb<u8>) {
+        for pair in pnts.windows(2) {
+            self.line(img, pair[0], pair[1], colour);
+        }
+    }
+}
+
+///The (min, max) of a set of values
+fn range(vals: impl Iterator<Item = f64>) -> (f64, f64) {
+    vals.fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), val| (min.min(val), max.max(val)))
+}
+
+///Sample a profile for plotting - (time, force) with time in seconds (or % for profiles stretched over the test)
+fn profile_samples(ffunc: &ForceFunctionGenerator) -> Vec<(f64, f64)> {
+    let total = match ffunc.duration() {
+        Some(duration) => duration,
+        None => 100.0,
+    };
+
+    //Enough samples to resolve each pixel of the plot
+    ffunc.sample(total / (2 * PANEL_W) as f64)
+}
+
+///Save a plot of a force profile against time
+///Times are in seconds - or percentage of the test for profiles stretched over the trajectory
+pub fn save_profile_preview(ffunc: &ForceFunctionGenerator, filepath: &str) -> Result<(), anyhow::Error> {
+    let samples = profile_samples(ffunc);
+    if samples.is_empty() {
+        bail!("Profile has no samples to preview");
+    }
+
+    let (min_t, max_t) = range(samples.iter().map(|s| s.0));
+    //Always show the zero force line
+    let (min_f, max_f) = range(samples.iter().map(|s| s.1).chain([0.0]));
+
+    let mut img = RgbImage::from_pixel(PANEL_W, PANEL_H, BACKGROUND);
+    let panel = Panel::create((0, 0), (min_t, max_t, min_f, max_f));
+
+    panel.axes(&mut img);
+    panel.line(&mut img, (min_t, 0.0), (max_t, 0.0), AXIS);
+    panel.trace(&mut img, &samples, TRACE);
+
+    img.save(filepath.trim())?;
+
+    Ok(())
+}
+
+///Save plots of a trajectory in XY (left) and XZ (right) with the workspace limits overlaid
+///limits - (min xyz, max xyz) of the allowed workspace
+pub fn save_traj_preview(
+    traj: &[(f64, f64, f64)],
+    limits: ([f64; 3], [f64; 3]),
+    filepath: &str,
+) -> Result<(), anyhow::Error> {
+    if traj.is_empty() {
+        bail!("Trajectory has no points to preview");
+    }
+
+    let (min, max) = limits;
+    let mut img = RgbImage::from_pixel(PANEL_W * 2, PANEL_H, BACKGROUND);
+
+    //XY - the whole workspace is shown
+    let (min_x, max_x) = range(traj.iter().map(|p| p.0).chain([min[0], max[0]]));
+    let (min_y, max_y) = range(traj.iter().map(|p| p.1).chain([min[1], max[1]]));
+    let xy = Panel::create((0, 0), (min_x, max_x, min_y, max_y));
+
+    xy.axes(&mut img);
+    xy.rect(&mut img, (min[0], min[1]), (max[0], max[1]), LIMIT);
+    xy.trace(&mut img, &traj.iter().map(|p| (p.0, p.1)).collect::<Vec<_>>(), TRACE);
+    xy.marker(&mut img, (traj[0].0, traj[0].1), START);
+
+    //XZ - the height limits are much larger than the trajectory so only the floor is always shown
+    let (min_z, max_z) = range(traj.iter().map(|p| p.2).chain([min[2]]));
+    let xz = Panel::create((PANEL_W, 0), (min_x, max_x, min_z, max_z + (max_z - min_z) * 0.25));
+
+    xz.axes(&mut img);
+    xz.rect(&mut img, (min[0], min[2]), (max[0], max[2]), LIMIT);
+    xz.trace(&mut img, &traj.iter().map(|p| (p.0, p.2)).collect::<Vec<_>>(), TRACE);
+    xz.marker(&mut img, (traj[0].0, traj[0].2), START);
+
+    img.save(filepath.trim())?;
+
+    Ok(())
+}
+
+///Create a single line sparkline of a set of values
+pub fn sparkline(vals: &[f64], width: usize) -> String {
+    if vals.is_empty() || width == 0 {
+        return String::new();
+    }
+
+    let (min, max) = range(vals.iter().copied());
+
+    (0..width)
+        .map(|i| {
+            //Take the value at the middle of each character's span
+            let val = vals[((i * vals.len()) + (vals.len() / 2)) / width];
+
+            let level = if max - min < 1e-9 {
+                0
+            } else {
+                (((val - min) / (max - min)) * (SPARK_CHARS.len() - 1) as f64).round() as usize
+            };
+
+            SPARK_CHARS[level.min(SPARK_CHARS.len() - 1)]
+        })
+        .collect()
+}
+
+///Create a sparkline of a force profile with its range (for confirming the profile in the terminal)
+pub fn profile_sparkline(ffunc: &ForceFunctionGenerator) -> String {
+    let samples = profile_samples(ffunc);
+    let vals: Vec<f64> = samples.iter().map(|s| s.1).collect();
+    let (min, max) = range(vals.iter().copied());
+
+    let units = match ffunc.time_mode() {
+        TimeMode::Absolute => "s",
+        TimeMode::Percentage => "%",
+    };
+
+    format!(
+        "{} [{:.1}N to {:.1}N over {:.1}{}]",
+        sparkline(&vals, 60),
+        min,
+        max,
+        samples.last().map_or(0.0, |s| s.0),
+        units
+    )
+}
@@ -1,231 +1,133 @@
 ///Generates xyz trajectories for tests
-use anyhow::bail;
-use std::f64::consts::PI;
-use std::fs::File;
-use std::io::{BufRead, stdin};
-use std::{fs, io};
+pub mod coverage;
+pub mod generators;
+pub mod height_grid;
+pub mod importers;
+pub mod interpolation;
+pub mod library;
+pub mod pose;
+pub mod terrain;
+pub mod tracking;
+pub mod traj_file;
+pub mod transforms;
+pub mod validation;
+pub mod velocity_profile;
+
+use crate::control::frames::Frames;
+use crate::control::misc_tools::angle_tools::Quaternion;
+use crate::control::trajectory_planner::generators::TrajParams;
+use std::io::stdin;
 
 ///The trajectories that have been implemented
-const IMPL_TRAJS: [&str; 7] = [
+const IMPL_TRAJS: [&str; 15] = [
     "line",
+    "bline",
+    "dline",
     "circle",
+    "spiral",
     "slidedown",
-    "dline",
-    "wiggle",
     "depthcomp",
-    "custom",
+    "wiggle",
+    "pushdown",
+    "map",
+    "coverage",
+    "library",
+    "gcode",
+    "svg",
+    "plan",
 ];
 
-//Default height at which the tool sits just on top of the terrain
+//Default height (bed frame) at which the tool sits just on top of the terrain
 const DEFAULT_Z : f64 = 66.85;
 
-///Generates a trajectory bsaed on string input from user
-pub fn traj_gen(traj: &str) -> Result<Vec<(f64, f64, f64)>, anyhow::Error> {
-    //Define the trajcetory - it will be of unknown size so have to store on the heap
-    let mut trajectory: Vec<(f64, f64, f64)> = Vec::new();
-
-    match traj.to_lowercase().as_str() {
-        //Line trajectory
-        "line" => {
-
-            let offset = 0.0;
-
-            //Define all the starting points etc
-            let line_x = 600.0;
-            let line_z = DEFAULT_Z;
-            let start_y = 1900.0;
-            let end_y = 2000.0;
-            let start_pos = (line_x, start_y + offset, line_z);
-            let end_pos = (line_x, end_y + offset, line_z);
-
-            trajectory = vec![start_pos, end_pos];
-        }
-
-        //Backwards line
-        "bline" =>{
-            //Define all the starting points etc
-            let line_x = 600.0;
-            let line_z = DEFAULT_Z;
-            let start_y = 1800.0;
-            let end_y = 1900.0;
-            let start_pos = (line_x, start_y, line_z);
-            let end_pos = (line_x, end_y, line_z);
-
-            trajectory = vec![end_pos, start_pos];
-        }
-
-        //Straight line trajectory - discretised into multiple points
-        "dline" => {
-            //Define all the starting points etc
-            let line_x = 500.0;
-            let line_z = DEFAULT_Z;
-            let start_y = 1800.0;
-            let end_y = 2200.0;
-            let start_pos = (line_x, start_y, line_z);
-            let end_pos = (line_x, end_y, line_z);
-
-            let num_of_points = 1000;
-
-            let dist_per_pnt = (end_y - start_y) / num_of_points as f64;
-
-            trajectory = vec![start_pos];
-
-            //Add all the middle points
-            for i in 1..num_of_points {
-                trajectory.push((line_x, start_y + (i as f64 * dist_per_pnt), DEFAULT_Z));
-            }
+///A point of a trajectory with the optional per-waypoint settings
+#[derive(Debug, Clone, Copy)]
+pub struct Waypoint {
+    ///The xyz position of the TCP
+    pub pos: (f64, f64, f64),
+    ///The orientation of the TCP (the current orientation is kept if not set)
+    pub ori: Option<Quaternion>,
+    ///The speed to travel to the waypoint at (mm/s)
+    pub speed: Option<f64>,
+    ///The force target at the waypoint (N)
+    pub force: Option<f64>,
+    ///Time to hold at the waypoint once reached (s)
+    pub dwell: Option<f64>,
+}
 
-            trajectory.push(end_pos);
+impl From<(f64, f64, f64)> for Waypoint {
+    ///Create a waypoint with only a position
+    fn from(pos: (f64, f64, f64)) -> Self {
+        Waypoint {
+            pos,
+            ori: None,
+            speed: None,
+            force: None,
+            dwell: None,
         }
+    }
+}
 
-        //A circular trajectory
-        "circle" => {
-            //Define all characteristics of the circle
-            let centre = (400.0, 2160.0, DEFAULT_Z);
-            //Number of times the circle goes round
-            let loops = 1;
-            //"size" of circle
-            let radius = 350.0;
-
-            //Create the circle trajectory
-            for i in 1..(360 * loops) {
-                trajectory.push((
-                    centre.0 + ((i as f64 * (PI / 180.0)).sin() * radius),
-                    centre.1 + ((i as f64 * (PI / 180.0)).cos() * radius),
-                    centre.2,
-                ));
-            }
-        }
+///Get the positions of a set of waypoints
+pub fn positions(waypoints: &[Waypoint]) -> Vec<(f64, f64, f64)> {
+    waypoints.iter().map(|waypoint| waypoint.pos).collect()
+}
 
-        //A circular trajectory
-        "spiral" => {
-            //Define all characteristics of the circle
-            let centre = (400.0, 2000.0, DEFAULT_Z);
-            //Number of times the circle goes round
-            let loops = 1;
-
-            //"size" of circle
-            let start_r = 100.0;
-            let stop_r = 300.0;
-
-            let mut curr_r = start_r;
-
-            //Create the circle trajectory
-            for i in 1..(360 * loops) {
-                trajectory.push((
-                    centre.0 + ((i as f64 * (PI / 180.0)).sin() * curr_r),
-                    centre.1 + ((i as f64 * (PI / 180.0)).cos() * curr_r),
-                    centre.2,
-                ));
-
-                if start_r <= stop_r {
-                    curr_r = start_r + ((stop_r - start_r).abs() * (i / (360 * loops)) as f64);
-                } else {
-                    curr_r = start_r - ((stop_r - start_r).abs() * (i / (360 * loops)) as f64);
-                }
-            }
-        }
+///The force target a lateral distance along the waypoints - interpolated between the waypoints that set one
+///and held past the first/last of them (None if no waypoint sets a force)
+pub fn force_at_dist(waypoints: &[Waypoint], dist: f64) -> Option<f64> {
+    let mut travelled = 0.0;
+    let mut forces: Vec<(f64, f64)> = vec![];
 
-        //A straight line that descends in height (i.e. slides down)
-        "slidedown" => {
-            let line_x = 262.0;
-            let start_z = DEFAULT_Z;
-            let end_z = start_z - 50.0;
-            let start_y = 1650.0;
-            let end_y = 2100.0;
-            let start_pos = (line_x, start_y, start_z);
-            let end_pos = (line_x, end_y, end_z);
-
-            //Ensure the trajectory starts in the right position
-            trajectory = vec![start_pos, end_pos];
+    for (i, waypoint) in waypoints.iter().enumerate() {
+        if i > 0 {
+            let last = waypoints[i - 1].pos;
+            travelled += (waypoint.pos.0 - last.0).hypot(waypoint.pos.1 - last.1);
         }
 
-        //Three straight lines with different depths
-        "depthcomp" => {
-            let start_x = 150.0;
-            let start_z = DEFAULT_Z - 5.00;
-
-            let start_y = 1780.0;
-            let end_y = 2310.0;
-            let pnts_per_line = 5;
-            let step = (end_y - start_y) / pnts_per_line as f64;
-
-            let no_of_lines = 3;
-            let line_sep = 200.0;
-            let depth_sep = 25.0;
-
-            for i in 0..no_of_lines {
-                for j in 0..=pnts_per_line {
-                    trajectory.push((
-                        start_x + (i as f64 * line_sep),
-                        (start_y + (step * j as f64)),
-                        start_z - (i as f64 * depth_sep),
-                    ));
-                }
-
-                //Go home to not disturb the other lines
-                trajectory.push((start_x + (i as f64 * line_sep), start_y, DEFAULT_Z + 50.0))
-            }
+        if let Some(force) = waypoint.force {
+            forces.push((travelled, force));
         }
+    }
 
-        //Small vibrational movements
-        "wiggle" => {
-            const WIGGLE_MOVE: f64 = 0.25;
-
-            let start_x = 200.0;
-            let start_y = 2160.0;
-
-            //NORMALLY 300
-            for i in 1..300 {
-                if i % 2 == 0 {
-                    trajectory.push((start_x + WIGGLE_MOVE, start_y, DEFAULT_Z));
-                }
-                if i % 3 == 0 {
-                    trajectory.push((start_x, start_y, DEFAULT_Z));
-                }
-                if i % 4 == 0 {
-                    trajectory.push((start_x - WIGGLE_MOVE, start_y, DEFAULT_Z));
-                }
-                if i % 5 == 0 {
-                    trajectory.push((start_x, start_y + WIGGLE_MOVE, DEFAULT_Z));
-                }
-                if i % 6 == 0 {
-                    trajectory.push((start_x, start_y - WIGGLE_MOVE, DEFAULT_Z));
-                }
-            }
-        }
+    let idx = forces.partition_point(|(at, _)| *at <= dist);
 
-        "pushdown" =>{
-            //Define all the starting points etc
-            let line_x = 500.0;
-            let line_y = 1900.0;
+    if idx == 0 {
+        return forces.first().map(|(_, force)| *force);
+    }
+    if idx >= forces.len() {
+        return forces.last().map(|(_, force)| *force);
+    }
 
-            let start_z = DEFAULT_Z;
-            let end_z = start_z - 80.0;
+    let ((d0, f0), (d1, f1)) = (forces[idx - 1], forces[idx]);
+    Some(f0 + (f1 - f0) * ((dist - d0) / (d1 - d0)))
+}
 
-            let start_pos = (line_x, line_y, start_z);
-            let end_pos = (line_x, line_y, end_z);
+///Generates a trajectory bsaed on string input from user
+///Returns the waypoints (bed frame) and a description of how they were generated (logged in the test config)
+pub fn traj_gen(traj: &str, frames: &Frames) -> Result<(Vec<Waypoint>, String), anyhow::Error> {
+    let params = match traj.to_lowercase().as_str() {
+        //Draw a trajectory from the library (keeping the per-waypoint settings)
+        "library" | "custom" => return library::pick(frames),
 
-            trajectory = vec![start_pos, end_pos];
-        }
+        //Paths drawn in CAD (G-code) or a vector editor (SVG) - placed with the import config
+        kind @ ("gcode" | "svg") => return importers::prompt(kind),
 
-        //Trajectory that hovers above the entire terrain to map out the whole thing
-        "map" =>{
+        //Boustrophedon mapping path over an area
+        "coverage" => return coverage::prompt(frames),
 
-            trajectory = vec![(25.37, 1786.00, DEFAULT_Z), (25.33, 2554.89, DEFAULT_Z), (387.91, 2554.93, DEFAULT_Z), (387.93, 1814.03, DEFAULT_Z), (785.68, 1819.25, DEFAULT_Z), (785.64, 2504.83, DEFAULT_Z)]
+        //Shape and parameters from a test plan file
+        "plan" => {
+            println!("Type the test plan name (in {}) or filepath", generators::TEST_PLAN_FP);
 
+            let mut fp = String::new();
+            stdin().read_line(&mut fp).expect("Failed to read line");
 
+            TrajParams::load_plan(fp.trim())?
         }
 
-
-        //Draw a trajectory from a custom file
-        "custom" => {
-            if let Some(traj) = cust_traj_handler() {
-                trajectory = traj;
-            } else {
-                bail!("Trajectory not selected!")
-            }
-        }
+        //Shapes with parameters set by the user
+        shape if generators::is_shape(shape) => TrajParams::prompt(shape)?,
 
         _ => {
             //Dont provide a trajectory
@@ -236,96 +138,13 @@ pub fn traj_gen(traj: &str) -> Result<Vec<(f64, f64, f64)>, anyhow::Error> {
             }
             anyhow::bail!("Invalid Trajectory");
         }
-    }
-    Ok(trajectory)
-}
-
-///Reads a custom trajectory file and creates the xyz vector
-fn cust_traj_handler() -> Option<Vec<(f64, f64, f64)>> {
-    //Stored within the local directory for the project! (updated manually with custom trajectories)
-    const CUST_TRAJ_LOC: &str = "./cust_trajs/";
-
-    let mut dir_list = Vec::new();
-
-    //Generate a list of the trajectories available in the pre-determined location -
-    //shouldn't crash unless file doesn't exist - at which point custom trajectories wont exist anyways
-    for file in fs::read_dir(CUST_TRAJ_LOC).unwrap() {
-        //Really really gross unwrapping and type changing...
-        dir_list.push(
-            file.unwrap()
-                .path()
-                .file_stem()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_lowercase(),
-        );
-    }
-
-    println!("Available custom trajectories:");
-    //Display all the trajectories (inefficient - but doing in one loop is tricky)
-    for filestem in dir_list.iter() {
-        //Print the file and add it to the vector
-        println!("\t {:?}", filestem);
-    }
-
-    //Print the list of possible trajectories
-
-    println!("Enter the chosen trajectory:");
-
-    //Get user input
-    let mut user_inp = String::new();
-    stdin()
-        .read_line(&mut user_inp)
-        .expect("Failed to read line");
-
-    //Attempt to match to a trajectory in the directory
-    if let Ok(file) = File::open(format!("{}{}.traj", CUST_TRAJ_LOC, user_inp.trim())) {
-        //Create an empty vector
-        let mut traj: Vec<(f64, f64, f64)> = vec![];
+    };
 
-        //Extract the line containing the trajectory info
-        let mut traj_str = String::new();
+    let trajectory = params.generate()?;
 
-        for line in io::BufReader::new(file).lines() {
-            traj_str = line.unwrap();
-        }
-
-        //Parse each trajectory coordinate via comma
-        let split_traj = traj_str.split(",");
-
-        //Place each trajectory coordinate into the trajectory vector (with a default height)
-        for coord in split_traj {
-            if coord.is_empty() {
-                println!("Empty line");
-            } else {
-                //trim the parantheses (using a pattern)
-                let coord = coord.replace(&['(', ')'][..], "");
-
-                println!("{coord}");
-
-                //split via space
-                let coord_split: Vec<_> = coord.split(" ").collect();
-
-                //Place in a tuple and add to the trajectory
-                let default_height: f64 = 125.0;
-                traj.push((
-                    coord_split[0].parse().unwrap(),
-                    coord_split[1].parse().unwrap(),
-                    default_height,
-                ))
-            }
-        }
-
-        Option::from(traj)
-    } else {
-        println!("Custom trajectory not found!");
-        None
-    }
+    Ok((trajectory.into_iter().map(Waypoint::from).collect(), params.to_string()))
 }
 
-
-
 ///Calculates the required xy speeds to achieve a desired trajectory based on a desired speed
 ///Return format (time of speed (s), (X speed (mm/s), Y speed (mm/s))
 pub fn calc_lateral_timing(traj: &mut [(f64, f64, f64)], des_lat_speed: f64) -> Vec<(f64, (f64, f64))> {
@@ -345,6 +164,12 @@ pub fn calc_lateral_timing(traj: &mut [(f64, f64, f64)], des_lat_speed: f64) ->
         //Calculate distance between points
         let lat_distance = (xy_distances.0.powi(2) + xy_distances.1.powi(2)).sqrt();
 
+        //No lateral movement - nothing to time
+        if lat_distance == 0.0 {
+            last_pnt = *pnt;
+            continue;
+        }
+
         let mut req_x_speed;
         let mut req_y_speed;
 
@@ -397,6 +222,12 @@ pub fn calc_xyz_timing(traj: &mut [(f64, f64, f64)], desired_speed : f64) -> Vec
 
         let total_distance = (del_x.powi(2) + del_y.powi(2) + del_z.powi(2)).sqrt();
 
+        //Repeated points would divide by zero
+        if total_distance == 0.0 {
+            last_pnt = *pnt;
+            continue;
+        }
+
         let time = total_distance / desired_speed;
 
         let speeds = (del_x/time, del_y/time, del_z/time);
@@ -410,4 +241,34 @@ pub fn calc_xyz_timing(traj: &mut [(f64, f64, f64)], desired_speed : f64) -> Vec
     }
     timing_instructions
 
-}
\ No newline at end of file
+}
+
+///Calculate the orthogonal speeds to follow a set of waypoints
+///Each waypoint's speed is used to travel to it (the default speed if not set) and its dwell adds a stationary instruction
+pub fn calc_waypoint_timing(waypoints: &[Waypoint], default_speed: f64) -> Vec<(f64, (f64, f64, f64))> {
+    let mut timing_instructions: Vec<(f64, (f64, f64, f64))> = vec![];
+
+    for (i, waypoint) in waypoints.iter().enumerate() {
+        if i > 0 {
+            let last_pnt = waypoints[i - 1].pos;
+            let pnt = waypoint.pos;
+
+            let (del_x, del_y, del_z) = (pnt.0 - last_pnt.0, pnt.1 - last_pnt.1, pnt.2 - last_pnt.2);
+            let total_distance = (del_x.powi(2) + del_y.powi(2) + del_z.powi(2)).sqrt();
+
+            //Repeated points have nothing to travel
+            if total_distance > 0.0 {
+                let time = total_distance / waypoint.speed.unwrap_or(default_speed);
+                timing_instructions.push((time, (del_x / time, del_y / time, del_z / time)));
+            }
+        }
+
+        if let Some(dwell) = waypoint.dwell
+            && dwell > 0.0
+        {
+            timing_instructions.push((dwell, (0.0, 0.0, 0.0)));
+        }
+    }
+
+    timing_instructions
+}
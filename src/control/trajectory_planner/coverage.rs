@@ -0,0 +1,334 @@
+//!Boustrophedon (back and forth) coverage of an area of the bed for terrain mapping
+//!Passes run along y and are spaced in x so neighbouring camera footprints overlap by the requested fraction,
+//!neighbouring passes are joined by semicircular turn-arounds outside the area
+//!The area and the path are in the bed frame
+use crate::control::frames::Frames;
+use crate::control::misc_tools::misc::{read_user_line, read_with_default};
+use crate::control::trajectory_planner::Waypoint;
+use crate::control::trajectory_planner::height_grid::{GridPlacement, HeightGrid};
+use crate::control::trajectory_planner::velocity_profile::{MotionLimits, plan_velocity_profile};
+use anyhow::bail;
+use std::fmt::Display;
+
+///Points used for each turn-around
+const TURN_PNTS: usize = 8;
+
+///The area to cover and how to cover it
+#[derive(Debug, Clone)]
+pub struct CoverageParams {
+    ///Corners of the area (mm) - a rectangle or any simple polygon
+    pub area: Vec<(f64, f64)>,
+    ///Camera footprint on the surface - (across the passes, along the passes) (mm)
+    pub footprint: (f64, f64),
+    ///Fraction of the footprint width shared by neighbouring passes (0 to <1)
+    pub overlap: f64,
+    ///Height of the TCP above the surface (mm)
+    pub standoff: f64,
+    ///Scan speed (mm/s)
+    pub speed: f64,
+}
+
+impl Display for CoverageParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let area: Vec<String> = self.area.iter().map(|pnt| format!("({} {})", pnt.0, pnt.1)).collect();
+
+        write!(
+            f,
+            "COVERAGE AREA:{} FOOTPRINT:{}x{} OVERLAP:{} STANDOFF:{} SPEED:{}",
+            area.join(","),
+            self.footprint.0,
+            self.footprint.1,
+            self.overlap,
+            self.standoff,
+            self.speed
+        )
+    }
+}
+
+///A planned coverage path
+#[derive(Debug, Clone)]
+pub struct CoveragePlan {
+    pub waypoints: Vec<Waypoint>,
+    ///Number of scan passes
+    pub passes: usize,
+    ///Path length including the turn-arounds (mm)
+    pub length: f64,
+    ///Time to run the path with the configured motion limits (s)
+    pub est_time: f64,
+}
+
+///Plan a coverage path over the area
+///surface_z - height of the bed surface (mm), rescan - only cover these empty heightmap cells (xy)
+pub fn plan_coverage(params: &CoverageParams, surface_z: f64, rescan: Option<&[(f64, f64)]>) -> Result<CoveragePlan, anyhow::Error> {
+    if params.area.len() < 3 {
+        bail!("The area needs at least 3 corners");
+    }
+    if !(params.footprint.0 > 0.0 && params.footprint.1 > 0.0) {
+        bail!("Camera footprint must be positive");
+    }
+    if !(0.0..1.0).contains(&params.overlap) {
+        bail!("Overlap must be from 0 up to 1 - {}", params.overlap);
+    }
+    if !(params.speed > 0.0) {
+        bail!("Scan speed must be positive");
+    }
+
+    let (min_x, max_x) = params.area.iter().fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), pnt| (min.min(pnt.0), max.max(pnt.0)));
+    let width = max_x - min_x;
+    let spacing = params.footprint.0 * (1.0 - params.overlap);
+
+    //Pass x positions - centred so the outer footprints reach the edges
+    let pass_cnt = if width <= params.footprint.0 {
+        1
+    } else {
+        ((width - params.footprint.0) / spacing).ceil() as usize + 1
+    };
+    let first_x = if pass_cnt == 1 {
+        min_x + width / 2.0
+    } else {
+        min_x + (width - spacing * (pass_cnt - 1) as f64) / 2.0
+    };
+
+    let empty_cells: Option<Vec<(f64, f64)>> =
+        rescan.map(|cells| cells.iter().copied().filter(|cell| in_polygon(&params.area, *cell)).collect());
+
+    //(x, y start, y end) of each pass
+    let mut passes: Vec<(f64, f64, f64)> = vec![];
+
+    for i in 0..pass_cnt {
+        let x = first_x + spacing * i as f64;
+
+        let Some((mut low, mut high)) = y_span(&params.area, x) else {
+            continue;
+        };
+
+        //Keep the footprint inside the area at the ends of the pass
+        let half_len = params.footprint.1 / 2.0;
+        if high - low > params.footprint.1 {
+            low += half_len;
+            high -= half_len;
+        } else {
+            low = (low + high) / 2.0;
+            high = low;
+        }
+
+        //Only the part of the pass that sees empty cells
+        if let Some(cells) = &empty_cells {
+            let seen: Vec<f64> = cells.iter().filter(|cell| (cell.0 - x).abs() <= params.footprint.0 / 2.0).map(|cell| cell.1).collect();
+
+            if seen.is_empty() {
+                continue;
+            }
+
+            let (seen_low, seen_high) = seen.iter().fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), y| (min.min(*y), max.max(*y)));
+            low = seen_low.clamp(low, high);
+            high = seen_high.clamp(low, high);
+        }
+
+        passes.push((x, low, high));
+    }
+
+    if passes.is_empty() {
+        bail!("Nothing to scan in the area");
+    }
+
+    let z = surface_z + params.standoff;
+    let mut pnts: Vec<(f64, f64)> = vec![];
+
+    for (k, pass) in passes.iter().enumerate() {
+        //Alternate directions
+        let (start, end) = if k % 2 == 0 { (pass.1, pass.2) } else { (pass.2, pass.1) };
+
+        if let Some(last) = pnts.last().copied() {
+            add_turn(&mut pnts, last, (pass.0, start), k % 2 == 1, spacing);
+        }
+
+        pnts.push((pass.0, start));
+        pnts.push((pass.0, end));
+    }
+    pnts.dedup_by(|a, b| (a.0 - b.0).abs() < 1e-9 && (a.1 - b.1).abs() < 1e-9);
+
+    let waypoints: Vec<Waypoint> = pnts
+        .iter()
+        .map(|pnt| Waypoint {
+            speed: Some(params.speed),
+            ..Waypoint::from((pnt.0, pnt.1, z))
+        })
+        .collect();
+
+    let length: f64 = pnts.windows(2).map(|pair| (pair[1].0 - pair[0].0).hypot(pair[1].1 - pair[0].1)).sum();
+
+    let est_time = if waypoints.len() > 1 {
+        plan_velocity_profile(&waypoints, params.speed, &MotionLimits::load_or_default(), true)?.duration()
+    } else {
+        0.0
+    };
+
+    Ok(CoveragePlan {
+        waypoints,
+        passes: passes.len(),
+        length,
+        est_time,
+    })
+}
+
+///Get the user to describe the coverage and plan it
+///The default area is the heightmap area (placed in the base frame) moved onto the bed
+pub fn prompt(frames: &Frames) -> Result<(Vec<Waypoint>, String), anyhow::Error> {
+    let placement = GridPlacement::load_or_default();
+
+    //Bounds of the heightmap area's corners in the bed frame
+    let corners = [
+        (placement.min.0, placement.min.1),
+        (placement.max.0, placement.min.1),
+        (placement.max.0, placement.max.1),
+        (placement.min.0, placement.max.1),
+    ]
+    .map(|(x, y)| frames.base_to_bed([x, y, 0.0]));
+    let hmap_min = corners.iter().fold((f64::INFINITY, f64::INFINITY), |min, pnt| (min.0.min(pnt[0]), min.1.min(pnt[1])));
+    let hmap_max = corners.iter().fold((f64::NEG_INFINITY, f64::NEG_INFINITY), |max, pnt| (max.0.max(pnt[0]), max.1.max(pnt[1])));
+
+    let area_type: String = read_with_default("Area (rect/polygon)", "rect".to_string());
+    let area = match area_type.trim().to_lowercase().as_str() {
+        "rect" => {
+            let min_x = read_with_default("Min x (mm)", hmap_min.0);
+            let max_x = read_with_default("Max x (mm)", hmap_max.0);
+            let min_y = read_with_default("Min y (mm)", hmap_min.1);
+            let max_y = read_with_default("Max y (mm)", hmap_max.1);
+
+            vec![(min_x, min_y), (max_x, min_y), (max_x, max_y), (min_x, max_y)]
+        }
+        "polygon" => parse_polygon(&read_user_line("Corners as \"x y, x y, ...\" (mm)"))?,
+        other => bail!("Unknown area type - {}", other),
+    };
+
+    let params = CoverageParams {
+        area,
+        footprint: (
+            read_with_default("Footprint width across passes (mm)", 400.0),
+            read_with_default("Footprint length along passes (mm)", 300.0),
+        ),
+        overlap: read_with_default("Overlap fraction", 0.2),
+        standoff: read_with_default("Standoff of the TCP above the surface (mm)", 0.0),
+        speed: read_with_default("Scan speed (mm/s)", 10.0),
+    };
+
+    //Only go back over the parts a previous map missed (the cells are moved onto the bed)
+    let rescan = read_with_default("Only rescan empty heightmap cells (y/n)", "n".to_string());
+    let empty_cells = if rescan.trim().eq_ignore_ascii_case("y") {
+        let fp = read_user_line("Heightmap file");
+        let grid = HeightGrid::load_from_file(&fp, placement)?;
+
+        Some(
+            grid.empty_cells()
+                .into_iter()
+                .map(|(x, y)| {
+                    let pnt = frames.base_to_bed([x, y, 0.0]);
+                    (pnt[0], pnt[1])
+                })
+                .collect::<Vec<(f64, f64)>>(),
+        )
+    } else {
+        None
+    };
+
+    let plan = plan_coverage(&params, super::DEFAULT_Z, empty_cells.as_deref())?;
+
+    println!(
+        "Coverage: {} passes, {:.0}mm, estimated mapping time {:.0}s ({:.1} min)",
+        plan.passes,
+        plan.length,
+        plan.est_time,
+        plan.est_time / 60.0
+    );
+
+    let mut desc = params.to_string();
+    if empty_cells.is_some() {
+        desc = format!("{} RESCAN EMPTY", desc);
+    }
+
+    Ok((plan.waypoints, desc))
+}
+
+///Parse polygon corners - "x y, x y, ..."
+fn parse_polygon(line: &str) -> Result<Vec<(f64, f64)>, anyhow::Error> {
+    let mut corners = vec![];
+
+    for corner in line.split(",").map(|corner| corner.trim()).filter(|corner| !corner.is_empty()) {
+        let vals: Vec<&str> = corner.split_whitespace().collect();
+        if vals.len() != 2 {
+            bail!("Invalid corner \"{}\" - expected \"x y\"", corner);
+        }
+
+        corners.push((vals[0].parse()?, vals[1].parse()?));
+    }
+
+    Ok(corners)
+}
+
+///The y range of the polygon along a vertical line (None if the line misses it)
+fn y_span(polygon: &[(f64, f64)], x: f64) -> Option<(f64, f64)> {
+    let mut span: Option<(f64, f64)> = None;
+
+    for (i, a) in polygon.iter().enumerate() {
+        let b = polygon[(i + 1) % polygon.len()];
+
+        if (a.0 <= x && x <= b.0) || (b.0 <= x && x <= a.0) {
+            let ys = if (b.0 - a.0).abs() < 1e-9 {
+                //Edge along the line
+                [a.1, b.1]
+            } else {
+                let y = a.1 + (x - a.0) * (b.1 - a.1) / (b.0 - a.0);
+                [y, y]
+            };
+
+            for y in ys {
+                span = Some(match span {
+                    Some((low, high)) => (low.min(y), high.max(y)),
+                    None => (y, y),
+                });
+            }
+        }
+    }
+
+    span
+}
+
+///Whether a point is inside a polygon (ray casting)
+fn in_polygon(polygon: &[(f64, f64)], pnt: (f64, f64)) -> bool {
+    let mut inside = false;
+
+    for (i, a) in polygon.iter().enumerate() {
+        let b = polygon[(i + 1) % polygon.len()];
+
+        if (a.1 > pnt.1) != (b.1 > pnt.1) && pnt.0 < a.0 + (pnt.1 - a.1) * (b.0 - a.0) / (b.1 - a.1) {
+            inside = !inside;
+        }
+    }
+
+    inside
+}
+
+///Join the end of one pass to the start of the next
+///Neighbouring passes are joined with a semicircle beyond the further pass end - wider gaps (skipped passes) go straight across
+fn add_turn(pnts: &mut Vec<(f64, f64)>, from: (f64, f64), to: (f64, f64), at_top: bool, spacing: f64) {
+    let gap = to.0 - from.0;
+    if gap.abs() > spacing * 1.5 {
+        return;
+    }
+
+    let radius = gap.abs() / 2.0;
+    let centre_x = (from.0 + to.0) / 2.0;
+    //Turn beyond whichever pass end is further out
+    let turn_y = if at_top { from.1.max(to.1) } else { from.1.min(to.1) };
+    let dir = if at_top { 1.0 } else { -1.0 };
+
+    pnts.push((from.0, turn_y));
+    for i in 1..TURN_PNTS {
+        //Sweep from the current pass to the next
+        let ang = std::f64::consts::PI * i as f64 / TURN_PNTS as f64;
+        let x = centre_x - gap.signum() * radius * ang.cos();
+        pnts.push((x, turn_y + dir * radius * ang.sin()));
+    }
+    pnts.push((to.0, turn_y));
+}
@@ -0,0 +1,460 @@
+//!Parametric trajectory generators
+//!Each shape's parameters come from prompts (with defaults) or from a test plan file:
+//!TRAJECTORY = "circle" followed by KEY = value lines - any missing parameters take their defaults
+//!Positions (and the defaults) are in the bed frame
+use super::DEFAULT_Z;
+use crate::control::misc_tools::misc::read_with_default;
+use anyhow::bail;
+use std::f64::consts::PI;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+///The directory containing the stored test plans
+pub const TEST_PLAN_FP: &str = "test_plans";
+
+///A parameter of a trajectory shape
+struct ParamDef {
+    ///Key used in test plan files and the test config
+    key: &'static str,
+    ///Prompt shown to the user
+    prompt: &'static str,
+    ///Value used if the parameter isn't given
+    default: f64,
+}
+
+///Shorthand for defining parameters
+const fn def(key: &'static str, prompt: &'static str, default: f64) -> ParamDef {
+    ParamDef { key, prompt, default }
+}
+
+const LINE_PARAMS: [ParamDef; 6] = [
+    def("START_X", "Start x (mm)", 578.0),
+    def("START_Y", "Start y (mm)", 550.0),
+    def("END_X", "End x (mm)", 578.0),
+    def("END_Y", "End y (mm)", 650.0),
+    def("Z", "Height (mm)", DEFAULT_Z),
+    def("POINTS", "Number of points", 2.0),
+];
+
+const BLINE_PARAMS: [ParamDef; 6] = [
+    def("START_X", "Start x (mm)", 578.0),
+    def("START_Y", "Start y (mm)", 550.0),
+    def("END_X", "End x (mm)", 578.0),
+    def("END_Y", "End y (mm)", 450.0),
+    def("Z", "Height (mm)", DEFAULT_Z),
+    def("POINTS", "Number of points", 2.0),
+];
+
+const DLINE_PARAMS: [ParamDef; 6] = [
+    def("START_X", "Start x (mm)", 478.0),
+    def("START_Y", "Start y (mm)", 450.0),
+    def("END_X", "End x (mm)", 478.0),
+    def("END_Y", "End y (mm)", 850.0),
+    def("Z", "Height (mm)", DEFAULT_Z),
+    def("POINTS", "Number of points", 1001.0),
+];
+
+const CIRCLE_PARAMS: [ParamDef; 5] = [
+    def("CENTRE_X", "Centre x (mm)", 378.0),
+    def("CENTRE_Y", "Centre y (mm)", 810.0),
+    def("Z", "Height (mm)", DEFAULT_Z),
+    def("RADIUS", "Radius (mm)", 350.0),
+    def("LOOPS", "Number of loops", 1.0),
+];
+
+const SPIRAL_PARAMS: [ParamDef; 6] = [
+    def("CENTRE_X", "Centre x (mm)", 378.0),
+    def("CENTRE_Y", "Centre y (mm)", 650.0),
+    def("Z", "Height (mm)", DEFAULT_Z),
+    def("START_R", "Start radius (mm)", 100.0),
+    def("PITCH", "Radius change per loop (mm)", 200.0),
+    def("LOOPS", "Number of loops", 1.0),
+];
+
+const SLIDEDOWN_PARAMS: [ParamDef; 5] = [
+    def("X", "Line x (mm)", 240.0),
+    def("START_Y", "Start y (mm)", 300.0),
+    def("END_Y", "End y (mm)", 750.0),
+    def("START_Z", "Start height (mm)", DEFAULT_Z),
+    def("DROP", "Height drop over the line (mm)", 50.0),
+];
+
+const DEPTHCOMP_PARAMS: [ParamDef; 9] = [
+    def("START_X", "First line x (mm)", 128.0),
+    def("START_Y", "Start y (mm)", 430.0),
+    def("END_Y", "End y (mm)", 960.0),
+    def("START_Z", "First line height (mm)", DEFAULT_Z - 5.0),
+    def("LINES", "Number of lines", 3.0),
+    def("LINE_SPACING", "Spacing between lines (mm)", 200.0),
+    def("DEPTH_STEP", "Depth increase per line (mm)", 25.0),
+    def("POINTS_PER_LINE", "Segments per line", 5.0),
+    def("RETRACT_Z", "Height to retract to between lines (mm)", DEFAULT_Z + 50.0),
+];
+
+const WIGGLE_PARAMS: [ParamDef; 5] = [
+    def("X", "Centre x (mm)", 178.0),
+    def("Y", "Centre y (mm)", 810.0),
+    def("Z", "Height (mm)", DEFAULT_Z),
+    def("AMPLITUDE", "Wiggle size (mm)", 0.25),
+    def("CYCLES", "Number of wiggle cycles", 300.0),
+];
+
+const PUSHDOWN_PARAMS: [ParamDef; 4] = [
+    def("X", "x (mm)", 478.0),
+    def("Y", "y (mm)", 550.0),
+    def("START_Z", "Start height (mm)", DEFAULT_Z),
+    def("DEPTH", "Push depth (mm)", 80.0),
+];
+
+//MEASURED = 1 runs the measured mapping path (only Z is used) - 0 runs a raster over the given area
+const MAP_PARAMS: [ParamDef; 7] = [
+    def("MEASURED", "Use the measured mapping path (1) or a raster (0)", 1.0),
+    def("MIN_X", "Min x (mm)", 3.37),
+    def("MAX_X", "Max x (mm)", 763.68),
+    def("MIN_Y", "Min y (mm)", 436.0),
+    def("MAX_Y", "Max y (mm)", 1204.9),
+    def("Z", "Height (mm)", DEFAULT_Z),
+    def("LINES", "Number of passes", 3.0),
+];
+
+///Get the parameters of a shape (None if the shape isn't generated)
+fn param_defs(shape: &str) -> Option<&'static [ParamDef]> {
+    match shape {
+        "line" => Some(&LINE_PARAMS),
+        "bline" => Some(&BLINE_PARAMS),
+        "dline" => Some(&DLINE_PARAMS),
+        "circle" => Some(&CIRCLE_PARAMS),
+        "spiral" => Some(&SPIRAL_PARAMS),
+        "slidedown" => Some(&SLIDEDOWN_PARAMS),
+        "depthcomp" => Some(&DEPTHCOMP_PARAMS),
+        "wiggle" => Some(&WIGGLE_PARAMS),
+        "pushdown" => Some(&PUSHDOWN_PARAMS),
+        "map" => Some(&MAP_PARAMS),
+        _ => None,
+    }
+}
+
+///Check whether a shape can be generated
+pub fn is_shape(shape: &str) -> bool {
+    param_defs(shape).is_some()
+}
+
+///The shape and parameters of a generated trajectory
+#[derive(Debug, Clone)]
+pub struct TrajParams {
+    ///The shape generated
+    shape: String,
+    ///The value of each of the shape's parameters
+    vals: Vec<(&'static str, f64)>,
+}
+
+impl Display for TrajParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.shape.to_uppercase())?;
+
+        for (key, val) in self.vals.iter() {
+            write!(f, " {}:{}", key, val)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl TrajParams {
+    ///Get the user to set the parameters of a shape (blank keeps the default)
+    pub fn prompt(shape: &str) -> Result<Self, anyhow::Error> {
+        let Some(defs) = param_defs(shape) else {
+            bail!("Unknown trajectory shape - {}", shape);
+        };
+
+        let vals = defs
+            .iter()
+            .map(|def| (def.key, read_with_default(def.prompt, def.default)))
+            .collect();
+
+        Ok(TrajParams {
+            shape: shape.to_string(),
+            vals,
+        })
+    }
+
+    ///Load the shape and parameters from a test plan (by name from the plan directory or by filepath)
+    pub fn load_plan(name_or_fp: &str) -> Result<Self, anyhow::Error> {
+        let stored_fp = format!("{}/{}.txt", TEST_PLAN_FP, name_or_fp);
+        let filepath = if Path::new(&stored_fp).exists() {
+            stored_fp
+        } else {
+            name_or_fp.to_string()
+        };
+
+        let file = File::open(filepath.trim())?;
+
+        let mut shape = None;
+        let mut given: Vec<(usize, String, f64)> = vec![];
+
+        for (line_no, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with("#") {
+                continue;
+            }
+
+            let Some((key, val)) = line.split_once("=") else {
+                bail!("Line {}: expected KEY = value - {}", line_no + 1, line);
+            };
+            let key = key.trim().to_uppercase();
+            let val = val.trim().trim_matches('"');
+
+            if key == "TRAJECTORY" {
+                shape = Some(val.to_lowercase());
+            } else {
+                match val.parse::<f64>() {
+                    Ok(val) if val.is_finite() => given.push((line_no + 1, key, val)),
+                    _ => bail!("Line {}: invalid {} - {}", line_no + 1, key, val),
+                }
+            }
+        }
+
+        let Some(shape) = shape else {
+            bail!("Test plan missing a TRAJECTORY");
+        };
+        let Some(defs) = param_defs(&shape) else {
+            bail!("Unknown trajectory shape - {}", shape);
+        };
+
+        //Catch typos rather than silently using the default
+        for (line_no, key, _) in given.iter() {
+            if !defs.iter().any(|def| def.key == key) {
+                bail!("Line {}: {} is not a parameter of {}", line_no, key, shape);
+            }
+        }
+
+        let vals = defs
+            .iter()
+            .map(|def| {
+                let val = given.iter().find(|(_, key, _)| key == def.key).map_or(def.default, |(_, _, val)| *val);
+                (def.key, val)
+            })
+            .collect();
+
+        Ok(TrajParams { shape, vals })
+    }
+
+    ///Get the value of a parameter
+    fn get(&self, key: &str) -> f64 {
+        self.vals
+            .iter()
+            .find(|(param, _)| *param == key)
+            .map_or(f64::NAN, |(_, val)| *val)
+    }
+
+    ///Get a parameter that counts something (must be a positive whole number)
+    fn get_count(&self, key: &str) -> Result<usize, anyhow::Error> {
+        let val = self.get(key);
+
+        if val < 1.0 || val.fract() != 0.0 {
+            bail!("{} must be a positive whole number - {}", key, val);
+        }
+
+        Ok(val as usize)
+    }
+
+    ///Generate the trajectory
+    pub fn generate(&self) -> Result<Vec<(f64, f64, f64)>, anyhow::Error> {
+        let traj = match self.shape.as_str() {
+            "line" | "bline" | "dline" => line(
+                (self.get("START_X"), self.get("START_Y"), self.get("Z")),
+                (self.get("END_X"), self.get("END_Y"), self.get("Z")),
+                self.get_count("POINTS")?,
+            )?,
+            "circle" => spiral(
+                (self.get("CENTRE_X"), self.get("CENTRE_Y"), self.get("Z")),
+                self.get("RADIUS"),
+                0.0,
+                self.get("LOOPS"),
+            )?,
+            "spiral" => spiral(
+                (self.get("CENTRE_X"), self.get("CENTRE_Y"), self.get("Z")),
+                self.get("START_R"),
+                self.get("PITCH"),
+                self.get("LOOPS"),
+            )?,
+            "slidedown" => line(
+                (self.get("X"), self.get("START_Y"), self.get("START_Z")),
+                (self.get("X"), self.get("END_Y"), self.get("START_Z") - self.get("DROP")),
+                2,
+            )?,
+            "depthcomp" => depth_lines(
+                (self.get("START_X"), self.get("START_Y"), self.get("START_Z")),
+                self.get("END_Y"),
+                self.get_count("LINES")?,
+                self.get("LINE_SPACING"),
+                self.get("DEPTH_STEP"),
+                self.get_count("POINTS_PER_LINE")?,
+                self.get("RETRACT_Z"),
+            ),
+            "wiggle" => wiggle(
+                (self.get("X"), self.get("Y"), self.get("Z")),
+                self.get("AMPLITUDE"),
+                self.get_count("CYCLES")?,
+            ),
+            "pushdown" => line(
+                (self.get("X"), self.get("Y"), self.get("START_Z")),
+                (self.get("X"), self.get("Y"), self.get("START_Z") - self.get("DEPTH")),
+                2,
+            )?,
+            "map" if self.get("MEASURED") != 0.0 => measured_map(self.get("Z")),
+            "map" => raster(
+                (self.get("MIN_X"), self.get("MIN_Y")),
+                (self.get("MAX_X"), self.get("MAX_Y")),
+                self.get("Z"),
+                self.get_count("LINES")?,
+            )?,
+            other => bail!("Unknown trajectory shape - {}", other),
+        };
+
+        if traj.iter().any(|pnt| !pnt.0.is_finite() || !pnt.1.is_finite() || !pnt.2.is_finite()) {
+            bail!("Invalid trajectory parameters - {}", self);
+        }
+
+        //e.g. too few wiggle cycles or less than a degree of a circle
+        if traj.len() < 2 {
+            bail!("Trajectory needs at least 2 points - {}", self);
+        }
+
+        Ok(traj)
+    }
+}
+
+///A straight line split into evenly spaced points (including both ends)
+pub fn line(start: (f64, f64, f64), end: (f64, f64, f64), points: usize) -> Result<Vec<(f64, f64, f64)>, anyhow::Error> {
+    if points < 2 {
+        bail!("A line needs at least 2 points");
+    }
+
+    Ok((0..points)
+        .map(|i| {
+            let frac = i as f64 / (points - 1) as f64;
+            (
+                start.0 + (end.0 - start.0) * frac,
+                start.1 + (end.1 - start.1) * frac,
+                start.2 + (end.2 - start.2) * frac,
+            )
+        })
+        .collect())
+}
+
+///A spiral about a centre (1 point per degree) - the radius changes by the pitch every loop
+///A pitch of 0 gives a circle
+pub fn spiral(centre: (f64, f64, f64), start_r: f64, pitch: f64, loops: f64) -> Result<Vec<(f64, f64, f64)>, anyhow::Error> {
+    if loops <= 0.0 {
+        bail!("Number of loops must be positive - {}", loops);
+    }
+
+    let end_r = start_r + (pitch * loops);
+    if start_r <= 0.0 || end_r <= 0.0 {
+        bail!("Spiral radius must stay positive - {}mm to {}mm", start_r, end_r);
+    }
+
+    let degs = (360.0 * loops).round() as usize;
+
+    Ok((1..degs)
+        .map(|i| {
+            let ang = i as f64 * (PI / 180.0);
+            let radius = start_r + (pitch * i as f64 / 360.0);
+            (centre.0 + (ang.sin() * radius), centre.1 + (ang.cos() * radius), centre.2)
+        })
+        .collect())
+}
+
+///Parallel lines along y, each deeper than the last - retracting to the start of each line when it's done
+pub fn depth_lines(
+    start: (f64, f64, f64),
+    end_y: f64,
+    lines: usize,
+    line_spacing: f64,
+    depth_step: f64,
+    points_per_line: usize,
+    retract_z: f64,
+) -> Vec<(f64, f64, f64)> {
+    let mut trajectory = vec![];
+    let step = (end_y - start.1) / points_per_line as f64;
+
+    for i in 0..lines {
+        let x = start.0 + (i as f64 * line_spacing);
+
+        for j in 0..=points_per_line {
+            trajectory.push((x, start.1 + (step * j as f64), start.2 - (i as f64 * depth_step)));
+        }
+
+        //Go home to not disturb the other lines
+        trajectory.push((x, start.1, retract_z));
+    }
+
+    trajectory
+}
+
+///Small vibrational movements about a point
+pub fn wiggle(centre: (f64, f64, f64), amplitude: f64, cycles: usize) -> Vec<(f64, f64, f64)> {
+    let mut trajectory = vec![];
+    let (x, y, z) = centre;
+
+    for i in 1..cycles {
+        if i % 2 == 0 {
+            trajectory.push((x + amplitude, y, z));
+        }
+        if i % 3 == 0 {
+            trajectory.push((x, y, z));
+        }
+        if i % 4 == 0 {
+            trajectory.push((x - amplitude, y, z));
+        }
+        if i % 5 == 0 {
+            trajectory.push((x, y + amplitude, z));
+        }
+        if i % 6 == 0 {
+            trajectory.push((x, y - amplitude, z));
+        }
+    }
+
+    trajectory
+}
+
+///The measured path used to map the soil bed
+pub fn measured_map(z: f64) -> Vec<(f64, f64, f64)> {
+    vec![
+        (3.37, 436.0, z),
+        (3.33, 1204.89, z),
+        (365.91, 1204.93, z),
+        (365.93, 464.03, z),
+        (763.68, 469.25, z),
+        (763.64, 1154.83, z),
+    ]
+}
+
+///Back and forth passes along y covering a rectangle (passes evenly spaced in x)
+pub fn raster(min: (f64, f64), max: (f64, f64), z: f64, lines: usize) -> Result<Vec<(f64, f64, f64)>, anyhow::Error> {
+    if lines < 2 {
+        bail!("A raster needs at least 2 passes");
+    }
+
+    let spacing = (max.0 - min.0) / (lines - 1) as f64;
+    let mut trajectory = vec![];
+
+    for i in 0..lines {
+        let x = min.0 + (spacing * i as f64);
+
+        if i % 2 == 0 {
+            trajectory.push((x, min.1, z));
+            trajectory.push((x, max.1, z));
+        } else {
+            trajectory.push((x, max.1, z));
+            trajectory.push((x, min.1, z));
+        }
+    }
+
+    Ok(trajectory)
+}
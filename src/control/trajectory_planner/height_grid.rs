@@ -0,0 +1,218 @@
+//!Heightmaps placed on the bed
+//!The camera subsystem's heightmap is a grid of cells with no position information - the placement config
+//!gives the bed area (mm, robot frame) the grid covers and how cell values convert to surface heights
+use crate::config::{load_config_or_default, read_config_values};
+use anyhow::bail;
+use rustgeomapping::data_types::heightmap::Heightmap;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+///The file containing the heightmap placement
+pub const HMAP_CONFIG_FP: &str = "configs/heightmap.txt";
+
+///Where the heightmap grid sits on the bed
+#[derive(Debug, Clone, Copy)]
+pub struct GridPlacement {
+    ///Min xy covered by the grid (mm)
+    pub min: (f64, f64),
+    ///Max xy covered by the grid (mm)
+    pub max: (f64, f64),
+    ///Surface height (mm) = offset + scale * cell value
+    pub height_scale: f64,
+    pub height_offset: f64,
+}
+
+impl Default for GridPlacement {
+    fn default() -> Self {
+        GridPlacement {
+            min: (25.37, 1786.0),
+            max: (785.68, 2554.9),
+            height_scale: 1000.0,
+            height_offset: 0.0,
+        }
+    }
+}
+
+impl Display for GridPlacement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "X:{} to {} Y:{} to {} HEIGHT:{} + {} * cell",
+            self.min.0, self.max.0, self.min.1, self.max.1, self.height_offset, self.height_scale
+        )
+    }
+}
+
+impl GridPlacement {
+    ///Load the heightmap placement - the area must have max above min
+    pub fn load_from_file(filepath: &str) -> Result<Self, anyhow::Error> {
+        let mut placement = GridPlacement::default();
+
+        for (key, val) in read_config_values(filepath)? {
+            let val: f64 = val.parse()?;
+
+            match key.as_str() {
+                "MIN_X" => placement.min.0 = val,
+                "MAX_X" => placement.max.0 = val,
+                "MIN_Y" => placement.min.1 = val,
+                "MAX_Y" => placement.max.1 = val,
+                "HEIGHT_SCALE" => placement.height_scale = val,
+                "HEIGHT_OFFSET" => placement.height_offset = val,
+                other => bail!("Unknown setting in heightmap config - {}", other),
+            }
+        }
+
+        if placement.max.0 <= placement.min.0 || placement.max.1 <= placement.min.1 {
+            bail!("Heightmap area must have max above min");
+        }
+
+        Ok(placement)
+    }
+
+    ///The configured heightmap placement, or the default bed area
+    pub fn load_or_default() -> Self {
+        load_config_or_default(HMAP_CONFIG_FP, "heightmap placement", Self::load_from_file, GridPlacement::default)
+    }
+
+    ///Convert a cell value to a surface height - None for empty cells (0.0 or NaN)
+    fn surface(&self, val: f64) -> Option<f64> {
+        if !val.is_finite() || val == 0.0 {
+            None
+        } else {
+            Some(self.height_offset + self.height_scale * val)
+        }
+    }
+}
+
+///A heightmap with its cells placed on the bed
+#[derive(Debug, Clone)]
+pub struct HeightGrid {
+    ///Cells along x
+    width: usize,
+    ///Cells along y
+    height: usize,
+    ///Surface height of each cell (mm) - None where nothing has been mapped
+    cells: Vec<Option<f64>>,
+    placement: GridPlacement,
+}
+
+impl HeightGrid {
+    ///Load a heightmap saved by the camera subsystem
+    ///The first line holds the bounds, then each line is a row of cells (one per x index) - empty cells are 0.0
+    pub fn load_from_file(filepath: &str, placement: GridPlacement) -> Result<HeightGrid, anyhow::Error> {
+        let file = File::open(filepath.trim())?;
+
+        let mut rows: Vec<Vec<Option<f64>>> = vec![];
+
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+
+            if line.trim().is_empty() || line.contains("[") {
+                continue;
+            }
+
+            let mut row = vec![];
+            for val in line.trim().split(",").map(|val| val.trim()).filter(|val| !val.is_empty()) {
+                let val: f64 = val.parse()?;
+                row.push(placement.surface(val));
+            }
+            rows.push(row);
+        }
+
+        let width = rows.len();
+        let height = rows.first().map_or(0, |row| row.len());
+
+        if width == 0 || height == 0 {
+            bail!("Heightmap {} has no cells", filepath);
+        }
+        if rows.iter().any(|row| row.len() != height) {
+            bail!("Heightmap {} rows have different lengths", filepath);
+        }
+
+        Ok(HeightGrid {
+            width,
+            height,
+            cells: rows.concat(),
+            placement,
+        })
+    }
+
+    ///Place a heightmap received from the camera subsystem
+    pub fn from_heightmap(hmap: &Heightmap, placement: GridPlacement) -> HeightGrid {
+        let (width, height) = (hmap.width(), hmap.height());
+        let mut cells = Vec::with_capacity(width * height);
+
+        for i in 0..width {
+            for j in 0..height {
+                let val = hmap.get_cell_height(i, j).map_or(f64::NAN, |val| val as f64);
+                cells.push(placement.surface(val));
+            }
+        }
+
+        HeightGrid {
+            width,
+            height,
+            cells,
+            placement,
+        }
+    }
+
+    ///The mean surface height of the mapped cells within a radius of a point (mm)
+    ///Always includes the cell under the point - None if nothing nearby has been mapped
+    pub fn surface_near(&self, pnt: (f64, f64), radius: f64) -> Option<f64> {
+        let (min, max) = (self.placement.min, self.placement.max);
+        let cell_size = ((max.0 - min.0) / self.width as f64, (max.1 - min.1) / self.height as f64);
+
+        //Cell index range covering the circle
+        let idx_range = |val: f64, min: f64, size: f64, cnt: usize| {
+            let low = ((val - radius - min) / size).floor().max(0.0) as usize;
+            let high = (((val + radius - min) / size).floor().max(0.0) as usize).min(cnt - 1);
+            (low, high)
+        };
+        let (i_low, i_high) = idx_range(pnt.0, min.0, cell_size.0, self.width);
+        let (j_low, j_high) = idx_range(pnt.1, min.1, cell_size.1, self.height);
+
+        let (mut total, mut cnt) = (0.0, 0);
+        for i in i_low..=i_high {
+            for j in j_low..=j_high {
+                let centre = self.cell_centre(i, j);
+                let in_cell = (centre.0 - pnt.0).abs() <= cell_size.0 / 2.0 && (centre.1 - pnt.1).abs() <= cell_size.1 / 2.0;
+
+                if (in_cell || (centre.0 - pnt.0).hypot(centre.1 - pnt.1) <= radius)
+                    && let Some(surface) = self.cells[i * self.height + j]
+                {
+                    total += surface;
+                    cnt += 1;
+                }
+            }
+        }
+
+        if cnt == 0 { None } else { Some(total / cnt as f64) }
+    }
+
+    ///The xy centre of a cell (mm)
+    fn cell_centre(&self, i: usize, j: usize) -> (f64, f64) {
+        let (min, max) = (self.placement.min, self.placement.max);
+
+        (
+            min.0 + (i as f64 + 0.5) * (max.0 - min.0) / self.width as f64,
+            min.1 + (j as f64 + 0.5) * (max.1 - min.1) / self.height as f64,
+        )
+    }
+
+    ///The centres of the cells that haven't been mapped
+    pub fn empty_cells(&self) -> Vec<(f64, f64)> {
+        let mut empty = vec![];
+
+        for i in 0..self.width {
+            for j in 0..self.height {
+                if self.cells[i * self.height + j].is_none() {
+                    empty.push(self.cell_centre(i, j));
+                }
+            }
+        }
+
+        empty
+    }
+}
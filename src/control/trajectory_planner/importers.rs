@@ -0,0 +1,838 @@
+//!Importers for trajectories drawn outside the program - G-code and SVG path data
+//!G-code: G0/G1 lines, G2/G3 arcs in the XY plane (I J centre offsets or R), F feed rates (per minute),
+//!G4 P dwells (s), G20/G21 units and G90/G91 positioning - other words are ignored
+//!SVG: the d attribute of every <path> element - lines, elliptical arcs and quadratic/cubic Béziers (transforms aren't applied)
+//!Curves are split into lines within the arc tolerance, then the drawing is placed on the bed with the import config
+use crate::config::{load_config_or_default, pos_ori_parser, read_config_values};
+use crate::control::misc_tools::misc::read_user_line;
+use crate::control::trajectory_planner::Waypoint;
+use anyhow::bail;
+use std::f64::consts::PI;
+use std::fmt::Display;
+use std::fs;
+
+///The file containing the import placement
+pub const IMPORT_CONFIG_FP: &str = "configs/import.txt";
+
+///Units of the imported drawing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Units {
+    Mm,
+    Inch,
+    ///CSS pixels (96 per inch) - the SVG default
+    Px,
+}
+
+impl Display for Units {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Units::Mm => write!(f, "mm"),
+            Units::Inch => write!(f, "in"),
+            Units::Px => write!(f, "px"),
+        }
+    }
+}
+
+impl Units {
+    pub fn parse(units: &str) -> Result<Units, anyhow::Error> {
+        match units.trim().to_lowercase().as_str() {
+            "mm" => Ok(Units::Mm),
+            "in" | "inch" => Ok(Units::Inch),
+            "px" => Ok(Units::Px),
+            other => bail!("Unknown units - {}", other),
+        }
+    }
+
+    ///Millimetres per unit
+    pub fn mm(&self) -> f64 {
+        match self {
+            Units::Mm => 1.0,
+            Units::Inch => 25.4,
+            Units::Px => 25.4 / 96.0,
+        }
+    }
+}
+
+///The point of the drawing placed at the bed position
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImportOrigin {
+    ///The drawing's own zero
+    Zero,
+    ///The min xy corner of the drawing's bounds
+    Min,
+    ///The centre of the drawing's bounds
+    Centre,
+}
+
+impl Display for ImportOrigin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImportOrigin::Zero => write!(f, "zero"),
+            ImportOrigin::Min => write!(f, "min"),
+            ImportOrigin::Centre => write!(f, "centre"),
+        }
+    }
+}
+
+///How imported drawings are placed on the bed
+#[derive(Debug, Clone, Copy)]
+pub struct ImportPlacement {
+    ///Units of the drawing (G20/G21 in a G-code file take priority)
+    pub units: Units,
+    ///Scale applied to the drawing
+    pub scale: f64,
+    pub origin: ImportOrigin,
+    ///Where the origin is placed (mm, bed frame) - drawing heights are added to the z
+    pub bed_pos: [f64; 3],
+    ///Rotation of the drawing about the origin (deg, anticlockwise from above)
+    pub rotation: f64,
+    ///Largest distance between a curve and the lines it is split into (mm)
+    pub arc_tol: f64,
+    ///Speed of G0 moves and moves between SVG paths (mm/s)
+    pub travel_speed: f64,
+    ///Height the tool is lifted by to move between SVG paths (mm)
+    pub travel_lift: f64,
+}
+
+impl Default for ImportPlacement {
+    fn default() -> Self {
+        ImportPlacement {
+            units: Units::Mm,
+            scale: 1.0,
+            origin: ImportOrigin::Min,
+            bed_pos: [278.0, 650.0, super::DEFAULT_Z],
+            rotation: 0.0,
+            arc_tol: 0.1,
+            travel_speed: 5.0,
+            travel_lift: 10.0,
+        }
+    }
+}
+
+impl Display for ImportPlacement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "UNITS:{} SCALE:{} ORIGIN:{} BED POS:[{},{},{}] ROTATION:{} ARC TOL:{}",
+            self.units, self.scale, self.origin, self.bed_pos[0], self.bed_pos[1], self.bed_pos[2], self.rotation, self.arc_tol
+        )
+    }
+}
+
+impl ImportPlacement {
+    ///Load and validate the import placement
+    pub fn load_from_file(filepath: &str) -> Result<Self, anyhow::Error> {
+        let mut placement = ImportPlacement::default();
+
+        for (key, val) in read_config_values(filepath)? {
+            match key.as_str() {
+                "UNITS" => placement.units = Units::parse(&val)?,
+                "SCALE" => placement.scale = val.parse()?,
+                "ORIGIN" => {
+                    placement.origin = match val.to_lowercase().as_str() {
+                        "zero" => ImportOrigin::Zero,
+                        "min" => ImportOrigin::Min,
+                        "centre" | "center" => ImportOrigin::Centre,
+                        other => bail!("Unknown import origin - {}", other),
+                    }
+                }
+                "BED_POS" => placement.bed_pos = pos_ori_parser(val)?,
+                "ROTATION" => placement.rotation = val.parse()?,
+                "ARC_TOL" => placement.arc_tol = val.parse()?,
+                "TRAVEL_SPEED" => placement.travel_speed = val.parse()?,
+                "TRAVEL_LIFT" => placement.travel_lift = val.parse()?,
+                other => bail!("Unknown setting in import config - {}", other),
+            }
+        }
+
+        placement.validate()?;
+
+        Ok(placement)
+    }
+
+    ///The configured import placement, or the defaults
+    pub fn load_or_default() -> Self {
+        load_config_or_default(IMPORT_CONFIG_FP, "the import placement", Self::load_from_file, ImportPlacement::default)
+    }
+
+    ///Check the placement makes sense
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if !(self.scale > 0.0) {
+            bail!("Import scale must be positive");
+        }
+        if !(self.arc_tol > 0.0) {
+            bail!("Arc tolerance must be positive");
+        }
+        if !(self.travel_speed > 0.0) {
+            bail!("Travel speed must be positive");
+        }
+        if !(self.travel_lift >= 0.0) {
+            bail!("Travel lift can't be negative");
+        }
+
+        Ok(())
+    }
+
+    ///Place a drawing on the bed
+    ///unit_mm - millimetres per drawing unit (speeds are already in mm/s)
+    pub fn place(&self, drawing: &[Waypoint], unit_mm: f64) -> Vec<Waypoint> {
+        let (min, max) = drawing.iter().fold(([f64::INFINITY; 2], [f64::NEG_INFINITY; 2]), |(min, max), w| {
+            ([min[0].min(w.pos.0), min[1].min(w.pos.1)], [max[0].max(w.pos.0), max[1].max(w.pos.1)])
+        });
+
+        let origin = match self.origin {
+            ImportOrigin::Zero => [0.0, 0.0],
+            ImportOrigin::Min => min,
+            ImportOrigin::Centre => [(min[0] + max[0]) / 2.0, (min[1] + max[1]) / 2.0],
+        };
+
+        let scale = unit_mm * self.scale;
+        let (sin, cos) = self.rotation.to_radians().sin_cos();
+
+        drawing
+            .iter()
+            .map(|w| {
+                let (dx, dy) = ((w.pos.0 - origin[0]) * scale, (w.pos.1 - origin[1]) * scale);
+
+                Waypoint {
+                    pos: (
+                        self.bed_pos[0] + dx * cos - dy * sin,
+                        self.bed_pos[1] + dx * sin + dy * cos,
+                        self.bed_pos[2] + w.pos.2 * scale,
+                    ),
+                    ..*w
+                }
+            })
+            .collect()
+    }
+}
+
+///Get the user to pick a G-code ("gcode") or SVG ("svg") file and import it
+pub fn prompt(kind: &str) -> Result<(Vec<Waypoint>, String), anyhow::Error> {
+    let placement = ImportPlacement::load_or_default();
+    println!("Import placement ({}) - {}", IMPORT_CONFIG_FP, placement);
+
+    let (waypoints, desc) = match kind {
+        "gcode" => {
+            let fp = read_user_line("G-code file:");
+            (import_gcode(&fp, &placement)?, format!("GCODE FILE:{}", fp))
+        }
+        "svg" => {
+            let fp = read_user_line("SVG file:");
+            (import_svg(&fp, &placement)?, format!("SVG FILE:{}", fp))
+        }
+        other => bail!("Unknown import type - {}", other),
+    };
+
+    println!("Imported {} waypoints", waypoints.len());
+
+    Ok((waypoints, format!("{} {}", desc, placement)))
+}
+
+///Import a G-code file and place it on the bed
+pub fn import_gcode(filepath: &str, placement: &ImportPlacement) -> Result<Vec<Waypoint>, anyhow::Error> {
+    let text = fs::read_to_string(filepath.trim())?;
+
+    let drawing = parse_gcode(&text, placement)?;
+
+    //Coordinates are converted to mm while parsing (the units can change part way through)
+    Ok(placement.place(&drawing, 1.0))
+}
+
+///Parse G-code into waypoints (mm, unplaced)
+pub fn parse_gcode(text: &str, placement: &ImportPlacement) -> Result<Vec<Waypoint>, anyhow::Error> {
+    let mut waypoints: Vec<Waypoint> = vec![];
+
+    //Modal state
+    let mut pos: Option<[f64; 3]> = None;
+    let mut motion = 0;
+    let mut absolute = true;
+    let mut unit_mm = placement.units.mm();
+    let mut feed: Option<f64> = None;
+
+    //Curves are split in mm before scaling
+    let tol = placement.arc_tol / placement.scale;
+
+    for (line_no, line) in text.lines().enumerate() {
+        let words = gcode_words(line).map_err(|e| anyhow::anyhow!("Line {}: {}", line_no + 1, e))?;
+        let word = |letter: char| words.iter().find(|(l, _)| *l == letter).map(|(_, val)| *val);
+
+        let mut dwell = false;
+        for (_, code) in words.iter().filter(|(l, _)| *l == 'G') {
+            match *code as i32 {
+                code @ 0..=3 => motion = code,
+                4 => dwell = true,
+                17 => {}
+                18 | 19 => bail!("Line {}: only arcs in the XY plane (G17) are supported", line_no + 1),
+                20 => unit_mm = Units::Inch.mm(),
+                21 => unit_mm = Units::Mm.mm(),
+                90 => absolute = true,
+                91 => absolute = false,
+                _ => {}
+            }
+        }
+
+        //Feed rates are per minute
+        if let Some(f) = word('F') {
+            feed = Some(f * unit_mm / 60.0);
+        }
+
+        if dwell {
+            if let (Some(last), Some(time)) = (waypoints.last_mut(), word('P')) {
+                last.dwell = Some(last.dwell.unwrap_or(0.0) + time);
+            }
+            continue;
+        }
+
+        if ['X', 'Y', 'Z'].iter().all(|axis| word(*axis).is_none()) {
+            continue;
+        }
+
+        //Unset axes keep the current position (zero before the first move)
+        let current = pos.unwrap_or([0.0; 3]);
+        let mut target = current;
+        for (axis, letter) in ['X', 'Y', 'Z'].iter().enumerate() {
+            if let Some(val) = word(*letter) {
+                target[axis] = if absolute { val * unit_mm } else { current[axis] + val * unit_mm };
+            }
+        }
+
+        let Some(start) = pos else {
+            //The first move only sets where the trajectory starts
+            waypoints.push(Waypoint::from((target[0], target[1], target[2])));
+            pos = Some(target);
+            continue;
+        };
+
+        let speed = if motion == 0 { Some(placement.travel_speed) } else { feed };
+
+        let pnts = match motion {
+            2 | 3 => {
+                let clockwise = motion == 2;
+                let centre = match (word('I'), word('J'), word('R')) {
+                    (None, None, Some(r)) => radius_centre(start, target, r * unit_mm, clockwise)?,
+                    (None, None, None) => bail!("Line {}: arc without a centre (I J or R)", line_no + 1),
+                    (i, j, _) => [start[0] + i.unwrap_or(0.0) * unit_mm, start[1] + j.unwrap_or(0.0) * unit_mm],
+                };
+
+                let a0 = (start[1] - centre[1]).atan2(start[0] - centre[0]);
+                let a1 = (target[1] - centre[1]).atan2(target[0] - centre[0]);
+                let mut sweep = a1 - a0;
+                //Going the arc's way round - the same start and end is a full circle
+                if clockwise {
+                    while sweep >= -1e-9 {
+                        sweep -= 2.0 * PI;
+                    }
+                } else {
+                    while sweep <= 1e-9 {
+                        sweep += 2.0 * PI;
+                    }
+                }
+
+                flatten_arc(start, target, centre, sweep, tol)
+            }
+            _ => vec![target],
+        };
+
+        for pnt in pnts {
+            waypoints.push(Waypoint {
+                speed,
+                ..Waypoint::from((pnt[0], pnt[1], pnt[2]))
+            });
+        }
+        pos = Some(target);
+    }
+
+    if waypoints.len() < 2 {
+        bail!("G-code has no moves");
+    }
+
+    Ok(waypoints)
+}
+
+///Split a G-code line into its (letter, value) words - comments are removed
+fn gcode_words(line: &str) -> Result<Vec<(char, f64)>, anyhow::Error> {
+    //Remove the comments - ";" to the end of the line and anything in brackets
+    let line = line.split(";").next().unwrap_or("");
+    let mut code = String::new();
+    let mut in_comment = false;
+    for c in line.chars() {
+        match c {
+            '(' => in_comment = true,
+            //Program start/end markers
+            '%' => {}
+            ')' => in_comment = false,
+            c if !in_comment => code.push(c.to_ascii_uppercase()),
+            _ => {}
+        }
+    }
+
+    let mut words = vec![];
+    let mut chars = code.chars().filter(|c| !c.is_whitespace()).peekable();
+
+    while let Some(letter) = chars.next() {
+        if !letter.is_ascii_alphabetic() {
+            bail!("Unexpected \"{}\"", letter);
+        }
+
+        let mut val = String::new();
+        while let Some(c) = chars.peek()
+            && (c.is_ascii_digit() || *c == '.' || *c == '-' || *c == '+')
+        {
+            val.push(*c);
+            chars.next();
+        }
+
+        match val.parse() {
+            Ok(val) => words.push((letter, val)),
+            Err(_) => bail!("Invalid value for {} - \"{}\"", letter, val),
+        }
+    }
+
+    Ok(words)
+}
+
+///The centre of an R format arc - a negative radius takes the longer way round
+fn radius_centre(start: [f64; 3], end: [f64; 3], radius: f64, clockwise: bool) -> Result<[f64; 2], anyhow::Error> {
+    let (dx, dy) = (end[0] - start[0], end[1] - start[1]);
+    let chord = dx.hypot(dy);
+
+    if chord < 1e-9 {
+        bail!("R format arcs can't be full circles");
+    }
+    if radius.abs() < chord / 2.0 - 1e-6 {
+        bail!("Arc radius {} is too small to reach the end point", radius);
+    }
+
+    let offset = (radius.powi(2) - (chord / 2.0).powi(2)).max(0.0).sqrt();
+    //Left of the direction of travel for anticlockwise short arcs
+    let side = if clockwise == (radius > 0.0) { -1.0 } else { 1.0 };
+
+    Ok([
+        (start[0] + end[0]) / 2.0 - side * offset * dy / chord,
+        (start[1] + end[1]) / 2.0 + side * offset * dx / chord,
+    ])
+}
+
+///Split a (helical) arc into points within the tolerance - the start isn't included
+fn flatten_arc(start: [f64; 3], end: [f64; 3], centre: [f64; 2], sweep: f64, tol: f64) -> Vec<[f64; 3]> {
+    let r0 = (start[0] - centre[0]).hypot(start[1] - centre[1]);
+    let r1 = (end[0] - centre[0]).hypot(end[1] - centre[1]);
+    let a0 = (start[1] - centre[1]).atan2(start[0] - centre[0]);
+
+    let cnt = segment_cnt(r0.max(r1), sweep, tol);
+
+    (1..=cnt)
+        .map(|k| {
+            if k == cnt {
+                return end;
+            }
+
+            let frac = k as f64 / cnt as f64;
+            //The radius is blended in case the end is slightly off the circle
+            let r = r0 + (r1 - r0) * frac;
+            let ang = a0 + sweep * frac;
+
+            [centre[0] + r * ang.cos(), centre[1] + r * ang.sin(), start[2] + (end[2] - start[2]) * frac]
+        })
+        .collect()
+}
+
+///The number of lines to split an arc into so the sagitta is within the tolerance
+fn segment_cnt(radius: f64, sweep: f64, tol: f64) -> usize {
+    let step = if tol >= radius { PI / 2.0 } else { 2.0 * (1.0 - tol / radius).acos() };
+
+    ((sweep.abs() / step).ceil() as usize).max(1)
+}
+
+///Import the paths of an SVG file and place them on the bed
+///The tool lifts by the travel lift to move between paths
+pub fn import_svg(filepath: &str, placement: &ImportPlacement) -> Result<Vec<Waypoint>, anyhow::Error> {
+    let text = fs::read_to_string(filepath.trim())?;
+
+    let unit_mm = placement.units.mm();
+    let tol = placement.arc_tol / (unit_mm * placement.scale);
+
+    let mut polylines = vec![];
+    for data in svg_path_data(&text) {
+        polylines.extend(parse_path_data(&data, tol)?);
+    }
+
+    if polylines.is_empty() {
+        bail!("No paths found in {}", filepath);
+    }
+
+    //SVG y points down the page
+    let lift = placement.travel_lift / (unit_mm * placement.scale);
+    let travel = Some(placement.travel_speed);
+    let mut drawing: Vec<Waypoint> = vec![];
+
+    for polyline in polylines {
+        if let Some(last) = drawing.last().map(|w| w.pos) {
+            let first = polyline[0];
+
+            drawing.push(Waypoint {
+                speed: travel,
+                ..Waypoint::from((last.0, last.1, lift))
+            });
+            drawing.push(Waypoint {
+                speed: travel,
+                ..Waypoint::from((first[0], -first[1], lift))
+            });
+            drawing.push(Waypoint {
+                speed: travel,
+                ..Waypoint::from((first[0], -first[1], 0.0))
+            });
+        } else {
+            drawing.push(Waypoint::from((polyline[0][0], -polyline[0][1], 0.0)));
+        }
+
+        for pnt in polyline.iter().skip(1) {
+            drawing.push(Waypoint::from((pnt[0], -pnt[1], 0.0)));
+        }
+    }
+
+    Ok(placement.place(&drawing, unit_mm))
+}
+
+///Get the d attribute of every path element
+fn svg_path_data(text: &str) -> Vec<String> {
+    let mut data = vec![];
+
+    for element in text.split("<path").skip(1) {
+        let element = element.split(">").next().unwrap_or("");
+        let bytes = element.as_bytes();
+
+        //Find " d=" (allowing spaces around the =) - not the end of another attribute name
+        for (i, _) in element.match_indices("d") {
+            if i == 0 || !bytes[i - 1].is_ascii_whitespace() {
+                continue;
+            }
+
+            let rest = element[i + 1..].trim_start();
+            let Some(rest) = rest.strip_prefix("=") else {
+                continue;
+            };
+            let rest = rest.trim_start();
+
+            let Some(quote) = rest.chars().next().filter(|c| *c == '"' || *c == '\'') else {
+                continue;
+            };
+            if let Some(end) = rest[1..].find(quote) {
+                data.push(rest[1..end + 1].to_string());
+            }
+            break;
+        }
+    }
+
+    data
+}
+
+///Reads the parts of SVG path data
+struct PathCursor {
+    chars: Vec<char>,
+    idx: usize,
+}
+
+impl PathCursor {
+    fn skip_sep(&mut self) {
+        while self.idx < self.chars.len() && (self.chars[self.idx].is_whitespace() || self.chars[self.idx] == ',') {
+            self.idx += 1;
+        }
+    }
+
+    fn done(&mut self) -> bool {
+        self.skip_sep();
+        self.idx >= self.chars.len()
+    }
+
+    ///The next command letter (if the next part is one)
+    fn command(&mut self) -> Option<char> {
+        self.skip_sep();
+        let c = *self.chars.get(self.idx)?;
+
+        if c.is_ascii_alphabetic() {
+            self.idx += 1;
+            Some(c)
+        } else {
+            None
+        }
+    }
+
+    fn number(&mut self) -> Result<f64, anyhow::Error> {
+        self.skip_sep();
+        let start = self.idx;
+        let peek = |idx: usize| self.chars.get(idx).copied();
+
+        if matches!(peek(self.idx), Some('+' | '-')) {
+            self.idx += 1;
+        }
+        while peek(self.idx).is_some_and(|c| c.is_ascii_digit()) {
+            self.idx += 1;
+        }
+        //A second "." starts the next number
+        if peek(self.idx) == Some('.') {
+            self.idx += 1;
+            while peek(self.idx).is_some_and(|c| c.is_ascii_digit()) {
+                self.idx += 1;
+            }
+        }
+        if matches!(peek(self.idx), Some('e' | 'E')) {
+            self.idx += 1;
+            if matches!(peek(self.idx), Some('+' | '-')) {
+                self.idx += 1;
+            }
+            while peek(self.idx).is_some_and(|c| c.is_ascii_digit()) {
+                self.idx += 1;
+            }
+        }
+
+        let num: String = self.chars[start..self.idx].iter().collect();
+        match num.parse() {
+            Ok(num) => Ok(num),
+            Err(_) => bail!("Invalid number in path data at {}", start),
+        }
+    }
+
+    ///Arc flags can be written without separators ("a5 5 0 01 10 10")
+    fn flag(&mut self) -> Result<bool, anyhow::Error> {
+        self.skip_sep();
+
+        match self.chars.get(self.idx) {
+            Some('0') => {
+                self.idx += 1;
+                Ok(false)
+            }
+            Some('1') => {
+                self.idx += 1;
+                Ok(true)
+            }
+            _ => bail!("Invalid arc flag in path data at {}", self.idx),
+        }
+    }
+
+    fn point(&mut self) -> Result<[f64; 2], anyhow::Error> {
+        Ok([self.number()?, self.number()?])
+    }
+}
+
+///Parse SVG path data into polylines (one per subpath) - curves are split within the tolerance
+fn parse_path_data(data: &str, tol: f64) -> Result<Vec<Vec<[f64; 2]>>, anyhow::Error> {
+    let mut cursor = PathCursor {
+        chars: data.chars().collect(),
+        idx: 0,
+    };
+
+    let mut polylines = vec![];
+    let mut polyline: Vec<[f64; 2]> = vec![];
+    let mut cur = [0.0, 0.0];
+    let mut sub_start = [0.0, 0.0];
+    //The last control point - for the smooth curve commands
+    let mut last_cubic: Option<[f64; 2]> = None;
+    let mut last_quad: Option<[f64; 2]> = None;
+
+    let mut cmd: Option<char> = None;
+
+    while !cursor.done() {
+        if let Some(c) = cursor.command() {
+            cmd = Some(c);
+        }
+        let Some(c) = cmd else {
+            bail!("Path data must start with a command");
+        };
+
+        //Relative commands are lower case
+        let rel = c.is_ascii_lowercase();
+        let abs = move |pnt: [f64; 2]| if rel { [cur[0] + pnt[0], cur[1] + pnt[1]] } else { pnt };
+
+        let (mut cubic, mut quad) = (None, None);
+
+        match c.to_ascii_uppercase() {
+            'M' => {
+                let pnt = abs(cursor.point()?);
+                if polyline.len() > 1 {
+                    polylines.push(polyline);
+                }
+                polyline = vec![pnt];
+                sub_start = pnt;
+                cur = pnt;
+
+                //Further points are lines
+                cmd = Some(if rel { 'l' } else { 'L' });
+            }
+            'L' => {
+                cur = abs(cursor.point()?);
+                polyline.push(cur);
+            }
+            'H' => {
+                let x = cursor.number()?;
+                cur = [if rel { cur[0] + x } else { x }, cur[1]];
+                polyline.push(cur);
+            }
+            'V' => {
+                let y = cursor.number()?;
+                cur = [cur[0], if rel { cur[1] + y } else { y }];
+                polyline.push(cur);
+            }
+            'C' | 'S' => {
+                let p1 = if c.eq_ignore_ascii_case(&'C') {
+                    abs(cursor.point()?)
+                } else {
+                    //Reflection of the last control point
+                    last_cubic.map_or(cur, |ctrl| [2.0 * cur[0] - ctrl[0], 2.0 * cur[1] - ctrl[1]])
+                };
+                let p2 = abs(cursor.point()?);
+                let end = abs(cursor.point()?);
+
+                polyline.extend(flatten_cubic([cur, p1, p2, end], tol));
+                cubic = Some(p2);
+                cur = end;
+            }
+            'Q' | 'T' => {
+                let p1 = if c.eq_ignore_ascii_case(&'Q') {
+                    abs(cursor.point()?)
+                } else {
+                    last_quad.map_or(cur, |ctrl| [2.0 * cur[0] - ctrl[0], 2.0 * cur[1] - ctrl[1]])
+                };
+                let end = abs(cursor.point()?);
+
+                polyline.extend(flatten_quad([cur, p1, end], tol));
+                quad = Some(p1);
+                cur = end;
+            }
+            'A' => {
+                let radii = [cursor.number()?, cursor.number()?];
+                let phi = cursor.number()?;
+                let large = cursor.flag()?;
+                let sweep = cursor.flag()?;
+                let end = abs(cursor.point()?);
+
+                polyline.extend(flatten_svg_arc(cur, end, radii, phi, large, sweep, tol));
+                cur = end;
+            }
+            'Z' => {
+                if (cur[0] - sub_start[0]).hypot(cur[1] - sub_start[1]) > 1e-9 {
+                    polyline.push(sub_start);
+                }
+                cur = sub_start;
+
+                //Anything drawn after closing starts a new subpath from the same point
+                if polyline.len() > 1 {
+                    polylines.push(polyline);
+                }
+                polyline = vec![cur];
+                cmd = None;
+            }
+            other => bail!("Unsupported path command - {}", other),
+        }
+
+        last_cubic = cubic;
+        last_quad = quad;
+    }
+
+    if polyline.len() > 1 {
+        polylines.push(polyline);
+    }
+
+    Ok(polylines)
+}
+
+///Split a cubic Bézier into points within the tolerance - the start isn't included
+fn flatten_cubic(p: [[f64; 2]; 4], tol: f64) -> Vec<[f64; 2]> {
+    //The deviation from the chords is at most 3/4 of the largest second difference over the count squared
+    let second_diff = |a: [f64; 2], b: [f64; 2], c: [f64; 2]| (a[0] - 2.0 * b[0] + c[0]).hypot(a[1] - 2.0 * b[1] + c[1]);
+    let dd = second_diff(p[0], p[1], p[2]).max(second_diff(p[1], p[2], p[3]));
+    let cnt = ((0.75 * dd / tol).sqrt().ceil() as usize).max(1);
+
+    (1..=cnt)
+        .map(|k| {
+            let t = k as f64 / cnt as f64;
+            let u = 1.0 - t;
+            let w = [u * u * u, 3.0 * u * u * t, 3.0 * u * t * t, t * t * t];
+
+            [
+                w[0] * p[0][0] + w[1] * p[1][0] + w[2] * p[2][0] + w[3] * p[3][0],
+                w[0] * p[0][1] + w[1] * p[1][1] + w[2] * p[2][1] + w[3] * p[3][1],
+            ]
+        })
+        .collect()
+}
+
+///Split a quadratic Bézier into points within the tolerance - the start isn't included
+fn flatten_quad(p: [[f64; 2]; 3], tol: f64) -> Vec<[f64; 2]> {
+    let dd = (p[0][0] - 2.0 * p[1][0] + p[2][0]).hypot(p[0][1] - 2.0 * p[1][1] + p[2][1]);
+    let cnt = ((0.25 * dd / tol).sqrt().ceil() as usize).max(1);
+
+    (1..=cnt)
+        .map(|k| {
+            let t = k as f64 / cnt as f64;
+            let u = 1.0 - t;
+            let w = [u * u, 2.0 * u * t, t * t];
+
+            [
+                w[0] * p[0][0] + w[1] * p[1][0] + w[2] * p[2][0],
+                w[0] * p[0][1] + w[1] * p[1][1] + w[2] * p[2][1],
+            ]
+        })
+        .collect()
+}
+
+///Split an SVG elliptical arc into points within the tolerance - the start isn't included
+///Converted from the endpoint to the centre form as in the SVG spec (appendix B.2.4)
+fn flatten_svg_arc(start: [f64; 2], end: [f64; 2], radii: [f64; 2], phi: f64, large: bool, sweep: bool, tol: f64) -> Vec<[f64; 2]> {
+    let (mut rx, mut ry) = (radii[0].abs(), radii[1].abs());
+
+    //Zero radii (or no movement) are straight lines
+    if rx < 1e-12 || ry < 1e-12 || (start[0] - end[0]).hypot(start[1] - end[1]) < 1e-12 {
+        return vec![end];
+    }
+
+    let (sin, cos) = phi.to_radians().sin_cos();
+    let (dx, dy) = ((start[0] - end[0]) / 2.0, (start[1] - end[1]) / 2.0);
+    let x1 = cos * dx + sin * dy;
+    let y1 = -sin * dx + cos * dy;
+
+    //Radii too small to reach the end are scaled up
+    let lambda = (x1 / rx).powi(2) + (y1 / ry).powi(2);
+    if lambda > 1.0 {
+        rx *= lambda.sqrt();
+        ry *= lambda.sqrt();
+    }
+
+    let num = (rx * ry).powi(2) - (rx * y1).powi(2) - (ry * x1).powi(2);
+    let den = (rx * y1).powi(2) + (ry * x1).powi(2);
+    let coef = if large == sweep { -1.0 } else { 1.0 } * (num / den).max(0.0).sqrt();
+    let (cx1, cy1) = (coef * rx * y1 / ry, -coef * ry * x1 / rx);
+
+    let centre = [
+        cos * cx1 - sin * cy1 + (start[0] + end[0]) / 2.0,
+        sin * cx1 + cos * cy1 + (start[1] + end[1]) / 2.0,
+    ];
+
+    let angle = |u: [f64; 2], v: [f64; 2]| (u[0] * v[1] - u[1] * v[0]).atan2(u[0] * v[0] + u[1] * v[1]);
+    let u = [(x1 - cx1) / rx, (y1 - cy1) / ry];
+    let v = [(-x1 - cx1) / rx, (-y1 - cy1) / ry];
+
+    let theta = angle([1.0, 0.0], u);
+    let mut delta = angle(u, v);
+    if !sweep && delta > 0.0 {
+        delta -= 2.0 * PI;
+    } else if sweep && delta < 0.0 {
+        delta += 2.0 * PI;
+    }
+
+    let cnt = segment_cnt(rx.max(ry), delta, tol);
+
+    (1..=cnt)
+        .map(|k| {
+            if k == cnt {
+                return end;
+            }
+
+            let ang = theta + delta * k as f64 / cnt as f64;
+            let (x, y) = (rx * ang.cos(), ry * ang.sin());
+
+            [centre[0] + cos * x - sin * y, centre[1] + sin * x + cos * y]
+        })
+        .collect()
+}
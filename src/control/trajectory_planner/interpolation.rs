@@ -0,0 +1,318 @@
+//!Smooth paths through waypoints
+//!Every interpolation is converted to a chain of cubic Bézier segments (one per pair of path waypoints)
+//!and parameterised by arc length so the path can be resampled at an even spacing
+//!The spacing only sets how finely the curve is followed - the speed along it comes from the velocity profile and waypoint speeds
+use crate::control::misc_tools::angle_tools::quart_slerp;
+use crate::control::misc_tools::misc::read_with_default;
+use crate::control::trajectory_planner::Waypoint;
+use anyhow::bail;
+use std::fmt::Display;
+
+///Arc length table entries per segment
+const ARC_SAMPLES: usize = 64;
+
+///How the path between waypoints is drawn
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interpolation {
+    ///Straight lines (the waypoints are used as they are)
+    Linear,
+    ///Natural cubic spline through the waypoints (chord length parameterised)
+    CubicSpline,
+    ///Catmull-Rom spline through the waypoints
+    CatmullRom,
+    ///Cubic Bézier segments - every third waypoint is on the path, the two between are control points
+    Bezier,
+}
+
+impl Display for Interpolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Interpolation::Linear => "LINEAR",
+            Interpolation::CubicSpline => "SPLINE",
+            Interpolation::CatmullRom => "CATMULLROM",
+            Interpolation::Bezier => "BEZIER",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+impl Interpolation {
+    ///Parse an interpolation name
+    pub fn parse(name: &str) -> Result<Self, anyhow::Error> {
+        match name.trim().to_lowercase().as_str() {
+            "linear" | "line" => Ok(Interpolation::Linear),
+            "spline" | "cubic" => Ok(Interpolation::CubicSpline),
+            "catmullrom" | "catmull" => Ok(Interpolation::CatmullRom),
+            "bezier" => Ok(Interpolation::Bezier),
+            other => bail!("Unknown interpolation - {} (linear/spline/catmullrom/bezier)", other),
+        }
+    }
+}
+
+///A smooth path - cubic Bézier segments with arc length tables
+#[derive(Debug, Clone)]
+pub struct SmoothPath {
+    ///Control points of each segment
+    segments: Vec<[[f64; 3]; 4]>,
+    ///The waypoints at the start and end of each path segment (indexes into the original waypoints)
+    anchors: Vec<(usize, usize)>,
+    ///(curve parameter, distance along the segment) for each segment
+    arc_tables: Vec<Vec<(f64, f64)>>,
+}
+
+impl SmoothPath {
+    ///Create a path through the waypoints
+    pub fn create(waypoints: &[Waypoint], interp: Interpolation) -> Result<SmoothPath, anyhow::Error> {
+        //Repeated points would give zero length segments
+        let mut idxs: Vec<usize> = vec![];
+        for (i, waypoint) in waypoints.iter().enumerate() {
+            if let Some(last) = idxs.last()
+                && dist(to_arr(waypoints[*last].pos), to_arr(waypoint.pos)) < 1e-9
+                && interp != Interpolation::Bezier
+            {
+                continue;
+            }
+            idxs.push(i);
+        }
+
+        let pnts: Vec<[f64; 3]> = idxs.iter().map(|i| to_arr(waypoints[*i].pos)).collect();
+        if pnts.len() < 2 {
+            bail!("A path needs at least two different points");
+        }
+
+        let (segments, anchors) = match interp {
+            Interpolation::Linear => (linear_segments(&pnts), consecutive(&idxs)),
+            Interpolation::CatmullRom => (catmull_rom_segments(&pnts), consecutive(&idxs)),
+            Interpolation::CubicSpline => (spline_segments(&pnts), consecutive(&idxs)),
+            Interpolation::Bezier => {
+                if (pnts.len() - 1) % 3 != 0 {
+                    bail!("Bézier paths need 3n+1 points (path point, 2 control points, path point...) - got {}", pnts.len());
+                }
+
+                let segments = pnts.chunks(3).zip(pnts.iter().skip(3).step_by(3)).map(|(c, end)| [c[0], c[1], c[2], *end]).collect();
+                let anchors = (0..(pnts.len() - 1) / 3).map(|i| (idxs[i * 3], idxs[(i + 1) * 3])).collect();
+
+                (segments, anchors)
+            }
+        };
+
+        let arc_tables = segments.iter().map(arc_table).collect();
+
+        Ok(SmoothPath { segments, anchors, arc_tables })
+    }
+
+    ///Total length of the path (mm)
+    pub fn length(&self) -> f64 {
+        self.arc_tables.iter().map(|table| table.last().map_or(0.0, |entry| entry.1)).sum()
+    }
+
+    ///The point a distance along a segment
+    fn point_at(&self, seg: usize, s: f64) -> [f64; 3] {
+        let table = &self.arc_tables[seg];
+
+        //Find the table entries either side of the distance and interpolate the curve parameter
+        let idx = table.partition_point(|entry| entry.1 < s).clamp(1, table.len() - 1);
+        let (u0, s0) = table[idx - 1];
+        let (u1, s1) = table[idx];
+        let u = if s1 - s0 > 1e-12 { u0 + (u1 - u0) * ((s - s0) / (s1 - s0)) } else { u1 };
+
+        bezier(&self.segments[seg], u.clamp(0.0, 1.0))
+    }
+
+    ///Resample the path at an even spacing (mm) - the spacing is adjusted per segment so the path waypoints are kept
+    ///Points take the speed of the waypoint they travel towards, a force and orientation interpolated between the waypoints,
+    ///and the path waypoints keep their dwell
+    pub fn resample(&self, waypoints: &[Waypoint], spacing: f64) -> Result<Vec<Waypoint>, anyhow::Error> {
+        if !(spacing > 0.0) {
+            bail!("Sample spacing must be positive - {}", spacing);
+        }
+
+        let mut resampled = vec![waypoints[self.anchors[0].0]];
+
+        for (seg, (start, end)) in self.anchors.iter().enumerate() {
+            let length = self.arc_tables[seg].last().map_or(0.0, |entry| entry.1);
+            let cnt = ((length / spacing).ceil() as usize).max(1);
+            let (start, end) = (waypoints[*start], waypoints[*end]);
+
+            for i in 1..cnt {
+                let frac = i as f64 / cnt as f64;
+                let pnt = self.point_at(seg, length * frac);
+
+                resampled.push(Waypoint {
+                    pos: (pnt[0], pnt[1], pnt[2]),
+                    ori: match (start.ori, end.ori) {
+                        (Some(a), Some(b)) => Some(quart_slerp(a, b, frac)),
+                        _ => None,
+                    },
+                    speed: end.speed,
+                    force: match (start.force, end.force) {
+                        (Some(a), Some(b)) => Some(a + (b - a) * frac),
+                        _ => end.force,
+                    },
+                    dwell: None,
+                });
+            }
+
+            //End exactly on the path waypoint
+            resampled.push(end);
+        }
+
+        Ok(resampled)
+    }
+}
+
+///Ask the user how to join the waypoints - the waypoints are returned unchanged for straight lines
+pub fn prompt_interpolation(waypoints: Vec<Waypoint>, desc: String) -> Result<(Vec<Waypoint>, String), anyhow::Error> {
+    let interp: String = read_with_default("Path interpolation (linear/spline/catmullrom/bezier)", "linear".to_string());
+    let interp = Interpolation::parse(&interp)?;
+
+    if interp == Interpolation::Linear {
+        return Ok((waypoints, desc));
+    }
+
+    //Resolution of the path - the speed is set when the trajectory is timed
+    let spacing = read_with_default("Sample spacing (mm)", 1.0);
+
+    let path = SmoothPath::create(&waypoints, interp)?;
+    let resampled = path.resample(&waypoints, spacing)?;
+
+    println!(
+        "{} path: {} waypoints -> {} points ({:.1}mm)",
+        interp,
+        waypoints.len(),
+        resampled.len(),
+        path.length()
+    );
+
+    Ok((resampled, format!("{} - INTERP:{} SPACING:{}", desc, interp, spacing)))
+}
+
+fn to_arr(pos: (f64, f64, f64)) -> [f64; 3] {
+    [pos.0, pos.1, pos.2]
+}
+
+fn dist(a: [f64; 3], b: [f64; 3]) -> f64 {
+    ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt()
+}
+
+///a + b * scale
+fn add_scaled(a: [f64; 3], b: [f64; 3], scale: f64) -> [f64; 3] {
+    [a[0] + b[0] * scale, a[1] + b[1] * scale, a[2] + b[2] * scale]
+}
+
+///Anchor pairs for paths that pass through every point
+fn consecutive(idxs: &[usize]) -> Vec<(usize, usize)> {
+    idxs.windows(2).map(|pair| (pair[0], pair[1])).collect()
+}
+
+///Evaluate a cubic Bézier segment
+fn bezier(ctrl: &[[f64; 3]; 4], u: f64) -> [f64; 3] {
+    let inv = 1.0 - u;
+    let weights = [inv.powi(3), 3.0 * inv * inv * u, 3.0 * inv * u * u, u.powi(3)];
+
+    let mut pnt = [0.0; 3];
+    for (weight, ctrl_pnt) in weights.iter().zip(ctrl.iter()) {
+        pnt = add_scaled(pnt, *ctrl_pnt, *weight);
+    }
+
+    pnt
+}
+
+///Build the arc length table of a segment from a dense polyline approximation
+fn arc_table(ctrl: &[[f64; 3]; 4]) -> Vec<(f64, f64)> {
+    let mut table = vec![(0.0, 0.0)];
+    let mut last = ctrl[0];
+    let mut length = 0.0;
+
+    for i in 1..=ARC_SAMPLES {
+        let u = i as f64 / ARC_SAMPLES as f64;
+        let pnt = bezier(ctrl, u);
+
+        length += dist(last, pnt);
+        table.push((u, length));
+        last = pnt;
+    }
+
+    table
+}
+
+///Segment from a start point, end point and the tangents (per unit of the segment parameter) at each end
+fn hermite(p0: [f64; 3], p1: [f64; 3], m0: [f64; 3], m1: [f64; 3]) -> [[f64; 3]; 4] {
+    [p0, add_scaled(p0, m0, 1.0 / 3.0), add_scaled(p1, m1, -1.0 / 3.0), p1]
+}
+
+fn linear_segments(pnts: &[[f64; 3]]) -> Vec<[[f64; 3]; 4]> {
+    pnts.windows(2)
+        .map(|pair| {
+            let del = add_scaled(pair[1], pair[0], -1.0);
+            hermite(pair[0], pair[1], del, del)
+        })
+        .collect()
+}
+
+///Tangent at each point is half the vector between its neighbours (one sided at the ends)
+fn catmull_rom_segments(pnts: &[[f64; 3]]) -> Vec<[[f64; 3]; 4]> {
+    let last = pnts.len() - 1;
+    let tangents: Vec<[f64; 3]> = (0..pnts.len())
+        .map(|i| {
+            if i == 0 {
+                add_scaled(pnts[1], pnts[0], -1.0)
+            } else if i == last {
+                add_scaled(pnts[last], pnts[last - 1], -1.0)
+            } else {
+                add_scaled(pnts[i + 1], pnts[i - 1], -1.0).map(|val| val / 2.0)
+            }
+        })
+        .collect();
+
+    (0..last).map(|i| hermite(pnts[i], pnts[i + 1], tangents[i], tangents[i + 1])).collect()
+}
+
+///Natural cubic spline (zero curvature at the ends) with the parameter spacing set by the chord lengths
+fn spline_segments(pnts: &[[f64; 3]]) -> Vec<[[f64; 3]; 4]> {
+    let n = pnts.len() - 1;
+    let h: Vec<f64> = pnts.windows(2).map(|pair| dist(pair[0], pair[1])).collect();
+
+    //Second derivatives at each point - solved per axis with the Thomas algorithm
+    let mut second = vec![[0.0; 3]; n + 1];
+    if n > 1 {
+        for axis in 0..3 {
+            let mut diag = vec![0.0; n + 1];
+            let mut rhs = vec![0.0; n + 1];
+
+            for i in 1..n {
+                diag[i] = 2.0 * (h[i - 1] + h[i]);
+                rhs[i] = 6.0 * (((pnts[i + 1][axis] - pnts[i][axis]) / h[i]) - ((pnts[i][axis] - pnts[i - 1][axis]) / h[i - 1]));
+            }
+
+            //Forward elimination (sub and super diagonals are h[i-1] and h[i])
+            for i in 2..n {
+                let factor = h[i - 1] / diag[i - 1];
+                diag[i] -= factor * h[i - 1];
+                rhs[i] -= factor * rhs[i - 1];
+            }
+
+            //Back substitution
+            for i in (1..n).rev() {
+                let next = if i + 1 < n { second[i + 1][axis] } else { 0.0 };
+                second[i][axis] = (rhs[i] - h[i] * next) / diag[i];
+            }
+        }
+    }
+
+    (0..n)
+        .map(|i| {
+            //Derivatives at each end of the segment - scaled by the chord length so the segment parameter runs 0 to 1
+            let mut m0 = [0.0; 3];
+            let mut m1 = [0.0; 3];
+            for axis in 0..3 {
+                let slope = (pnts[i + 1][axis] - pnts[i][axis]) / h[i];
+                m0[axis] = h[i] * (slope - h[i] * (2.0 * second[i][axis] + second[i + 1][axis]) / 6.0);
+                m1[axis] = h[i] * (slope + h[i] * (second[i][axis] + 2.0 * second[i + 1][axis]) / 6.0);
+            }
+
+            hermite(pnts[i], pnts[i + 1], m0, m1)
+        })
+        .collect()
+}
@@ -0,0 +1,190 @@
+//!The trajectory library - named trajectory files kept in one directory
+//!Trajectories are stored in the trajectory file format (.csv, or the older .traj) and referred to by their file stem
+//!New trajectories are saved in the bed frame - files that don't name a frame are read in the trajectory frame
+use crate::control::frames::Frames;
+use crate::control::abb_rob::{WORKSPACE_MAX, WORKSPACE_MIN};
+use crate::control::misc_tools::misc::{read_user_line, read_with_default};
+use crate::control::misc_tools::preview;
+use crate::control::trajectory_planner::transforms::{self, bounds};
+use crate::control::trajectory_planner::{self as planner, Waypoint, traj_file};
+use anyhow::bail;
+use std::fs;
+use std::path::Path;
+
+///The directory holding the library (relative to where the program is run)
+pub const TRAJ_LIB_FP: &str = "traj_library";
+
+///The file extensions the library reads
+const LIB_EXTS: [&str; 2] = ["csv", "traj"];
+
+///The names of the trajectories in the library (sorted)
+pub fn list() -> Result<Vec<String>, anyhow::Error> {
+    let mut names = vec![];
+
+    for entry in fs::read_dir(TRAJ_LIB_FP)?.flatten() {
+        let path = entry.path();
+
+        let is_traj = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| LIB_EXTS.contains(&ext.to_lowercase().as_str()));
+
+        if is_traj && let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) {
+            names.push(stem.to_string());
+        }
+    }
+
+    names.sort();
+    names.dedup();
+
+    Ok(names)
+}
+
+///The file of a named trajectory - None if it isn't in the library
+pub fn filepath(name: &str) -> Option<String> {
+    LIB_EXTS
+        .iter()
+        .map(|ext| format!("{}/{}.{}", TRAJ_LIB_FP, name.trim(), ext))
+        .find(|fp| Path::new(fp).exists())
+}
+
+///Load a named trajectory in the bed frame
+pub fn load(name: &str, frames: &Frames) -> Result<Vec<Waypoint>, anyhow::Error> {
+    let Some(fp) = filepath(name) else {
+        bail!("No trajectory called {} in {}", name.trim(), TRAJ_LIB_FP);
+    };
+
+    frames.file_to_bed(&traj_file::load_traj_file(&fp)?, traj_file::load_traj_frame(&fp)?.as_deref())
+}
+
+///Save a bed frame trajectory to the library under a name - returns the filepath
+pub fn save(name: &str, waypoints: &[Waypoint]) -> Result<String, anyhow::Error> {
+    let name = name.trim();
+    if name.is_empty() || name.contains(['/', '\\', '.']) {
+        bail!("Invalid trajectory name - {}", name);
+    }
+
+    fs::create_dir_all(TRAJ_LIB_FP)?;
+
+    let fp = format!("{}/{}.csv", TRAJ_LIB_FP, name);
+    traj_file::save_traj_file(waypoints, &fp, Some("bed"))?;
+
+    Ok(fp)
+}
+
+///Save a preview image of a named trajectory (in the previews subdirectory) - returns the filepath
+///The preview is drawn in the base frame so it can be checked against the workspace
+pub fn save_preview(name: &str, frames: &Frames) -> Result<String, anyhow::Error> {
+    let waypoints = frames.bed_to_base(&load(name, frames)?);
+
+    let preview_dir = format!("{}/previews", TRAJ_LIB_FP);
+    fs::create_dir_all(&preview_dir)?;
+
+    let fp = format!("{}/{}.png", preview_dir, name.trim());
+    preview::save_traj_preview(&planner::positions(&waypoints), (WORKSPACE_MIN, WORKSPACE_MAX), &fp)?;
+
+    Ok(fp)
+}
+
+///A one line summary of a trajectory
+fn summary(waypoints: &[Waypoint]) -> String {
+    let (min, max) = bounds(waypoints);
+    let length: f64 = waypoints
+        .windows(2)
+        .map(|pair| {
+            let (a, b) = (pair[0].pos, pair[1].pos);
+            ((b.0 - a.0).powi(2) + (b.1 - a.1).powi(2) + (b.2 - a.2).powi(2)).sqrt()
+        })
+        .sum();
+
+    format!(
+        "{} waypoints, {:.0}mm, X:{:.0} to {:.0} Y:{:.0} to {:.0} Z:{:.0} to {:.0}",
+        waypoints.len(),
+        length,
+        min[0],
+        max[0],
+        min[1],
+        max[1],
+        min[2],
+        max[2]
+    )
+}
+
+///Print the trajectories in the library
+pub fn print_list(frames: &Frames) -> Result<(), anyhow::Error> {
+    let names = list()?;
+
+    if names.is_empty() {
+        println!("No trajectories in {}", TRAJ_LIB_FP);
+        return Ok(());
+    }
+
+    println!("Available library trajectories:");
+    for name in names {
+        match load(&name, frames) {
+            Ok(waypoints) => println!("\t {} - {}", name, summary(&waypoints)),
+            Err(e) => println!("\t {} - failed to load ({})", name, e),
+        }
+    }
+
+    Ok(())
+}
+
+///Get the user to pick a trajectory from the library
+pub fn pick(frames: &Frames) -> Result<(Vec<Waypoint>, String), anyhow::Error> {
+    print_list(frames)?;
+
+    let name = read_user_line("Enter the chosen trajectory:");
+    let waypoints = load(&name, frames)?;
+
+    Ok((waypoints, format!("LIBRARY:{}", name.trim())))
+}
+
+///Manage the library - list, preview and add trajectories (robot not required)
+pub fn user_interface(frames: &Frames) {
+    loop {
+        let cmd: String = read_with_default("Trajectory library (list/preview/add/done)", "done".to_string());
+
+        let res = match cmd.trim().to_lowercase().as_str() {
+            "done" => break,
+
+            "list" => print_list(frames),
+
+            "preview" => save_preview(&read_user_line("Trajectory name:"), frames).map(|fp| println!("Preview saved to {}", fp)),
+
+            //Generate (and optionally transform) a trajectory then store it
+            "add" => add_trajectory(frames),
+
+            other => {
+                println!("Unknown library command - {}", other);
+                Ok(())
+            }
+        };
+
+        if let Err(e) = res {
+            println!("Library command failed - {}", e);
+        }
+    }
+}
+
+///Generate a trajectory, transform it and save it to the library
+fn add_trajectory(frames: &Frames) -> Result<(), anyhow::Error> {
+    let traj = read_user_line("Trajectory to generate:");
+
+    let (waypoints, desc) = planner::traj_gen(&traj, frames)?;
+    let (waypoints, desc) = transforms::prompt_transforms(waypoints, desc)?;
+
+    println!("{} - {}", desc, summary(&waypoints));
+
+    let name = read_user_line("Save as (name):");
+    if filepath(&name).is_some() {
+        let overwrite: String = read_with_default("Trajectory exists - overwrite (y/n)", "n".to_string());
+        if !overwrite.trim().eq_ignore_ascii_case("y") {
+            return Ok(());
+        }
+    }
+
+    println!("Saved to {}", save(&name, &waypoints)?);
+
+    Ok(())
+}
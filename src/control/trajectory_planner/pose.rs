@@ -0,0 +1,104 @@
+//!Orientation along a trajectory
+//!Waypoint orientations are slerped by distance travelled along the path - waypoints without an orientation
+//!keep the last one set (the first set orientation is used before it)
+//!Speeds are capped so no segment rotates faster than the angular speed limit
+use crate::control::misc_tools::angle_tools::{Quaternion, quart_angle, quart_slerp};
+use crate::control::trajectory_planner::Waypoint;
+
+///The planned orientation at each waypoint
+#[derive(Debug, Clone)]
+pub struct PosePlan {
+    ///Distance along the path to each waypoint (mm)
+    dists: Vec<f64>,
+    ///Orientation at each waypoint
+    oris: Vec<Quaternion>,
+}
+
+impl PosePlan {
+    ///Plan the orientation through the waypoints - None if no waypoint sets an orientation
+    ///lateral - distances are measured in xy only (force controlled tests)
+    pub fn create(waypoints: &[Waypoint], lateral: bool) -> Option<PosePlan> {
+        let oris = filled_oris(waypoints)?;
+
+        let mut dists = vec![0.0];
+        for pair in waypoints.windows(2) {
+            dists.push(dists.last().unwrap() + seg_length(&pair[0], &pair[1], lateral));
+        }
+
+        Some(PosePlan { dists, oris })
+    }
+
+    ///The orientation at the start of the trajectory
+    pub fn start_ori(&self) -> Quaternion {
+        self.oris[0]
+    }
+
+    ///The orientation a distance along the path
+    pub fn ori_at_dist(&self, dist: f64) -> Quaternion {
+        //First waypoint beyond the distance
+        let idx = self.dists.partition_point(|d| *d <= dist);
+
+        if idx == 0 {
+            return self.oris[0];
+        }
+        if idx >= self.dists.len() {
+            return *self.oris.last().unwrap();
+        }
+
+        let (start, end) = (self.dists[idx - 1], self.dists[idx]);
+
+        quart_slerp(self.oris[idx - 1], self.oris[idx], (dist - start) / (end - start))
+    }
+}
+
+///Cap the waypoint speeds so the orientation never changes faster than the limit
+///max_ang_speed - deg/s, default_speed - used for waypoints without their own speed (mm/s)
+pub fn limit_angular_speed(waypoints: &[Waypoint], default_speed: f64, max_ang_speed: f64, lateral: bool) -> Vec<Waypoint> {
+    let mut limited = waypoints.to_vec();
+
+    let Some(oris) = filled_oris(waypoints) else {
+        return limited;
+    };
+
+    for i in 1..waypoints.len() {
+        let angle = quart_angle(oris[i - 1], oris[i]).to_degrees();
+        let length = seg_length(&waypoints[i - 1], &waypoints[i], lateral);
+
+        //Rotations on the spot happen instantly - flagged by validation
+        if angle < 1e-6 || length < 1e-9 {
+            continue;
+        }
+
+        let max_speed = max_ang_speed * length / angle;
+        let speed = waypoints[i].speed.unwrap_or(default_speed);
+
+        if speed > max_speed {
+            limited[i].speed = Some(max_speed);
+        }
+    }
+
+    limited
+}
+
+///The orientation of every waypoint - None if no waypoint sets an orientation
+pub fn filled_oris(waypoints: &[Waypoint]) -> Option<Vec<Quaternion>> {
+    let mut last = waypoints.iter().find_map(|waypoint| waypoint.ori)?;
+
+    Some(
+        waypoints
+            .iter()
+            .map(|waypoint| {
+                if let Some(ori) = waypoint.ori {
+                    last = ori;
+                }
+                last
+            })
+            .collect(),
+    )
+}
+
+fn seg_length(a: &Waypoint, b: &Waypoint, lateral: bool) -> f64 {
+    let dz = if lateral { 0.0 } else { b.pos.2 - a.pos.2 };
+
+    ((b.pos.0 - a.pos.0).powi(2) + (b.pos.1 - a.pos.1).powi(2) + dz.powi(2)).sqrt()
+}
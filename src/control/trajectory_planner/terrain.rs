@@ -0,0 +1,188 @@
+//!Terrain following - sets the trajectory height from the latest heightmap
+//!The path is sampled at a fixed spacing, each sample takes the mean surface height around it plus the offset,
+//!then points are lifted (never lowered) until no slope is steeper than the limit - so the tool never goes deeper than asked
+use crate::control::misc_tools::misc::read_with_default;
+use crate::control::trajectory_planner::Waypoint;
+use crate::control::trajectory_planner::height_grid::HeightGrid;
+use anyhow::bail;
+use std::fmt::Display;
+
+///Terrain following settings
+#[derive(Debug, Clone, Copy)]
+pub struct TerrainFollow {
+    ///Height of the TCP relative to the surface (mm) - negative for a depth below it
+    pub offset: f64,
+    ///Radius the surface is averaged over (mm)
+    pub smoothing: f64,
+    ///Steepest slope the TCP may follow (deg)
+    pub max_slope: f64,
+    ///Spacing of the height samples along the path (mm)
+    pub spacing: f64,
+}
+
+impl Display for TerrainFollow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "OFFSET:{} SMOOTHING:{} MAX SLOPE:{} SPACING:{}",
+            self.offset, self.smoothing, self.max_slope, self.spacing
+        )
+    }
+}
+
+impl TerrainFollow {
+    ///Ask the user whether to follow the terrain - None to keep the trajectory heights
+    pub fn prompt() -> Result<Option<TerrainFollow>, anyhow::Error> {
+        let mode: String = read_with_default("Terrain following (none/depth/clearance)", "none".to_string());
+
+        let offset = match mode.trim().to_lowercase().as_str() {
+            "none" => return Ok(None),
+            "depth" => -read_with_default("Depth below the surface (mm)", 50.0),
+            "clearance" => read_with_default("Clearance above the surface (mm)", 10.0),
+            other => bail!("Unknown terrain following mode - {}", other),
+        };
+
+        let follow = TerrainFollow {
+            offset,
+            smoothing: read_with_default("Surface smoothing radius (mm)", 20.0),
+            max_slope: read_with_default("Max slope (deg)", 20.0),
+            spacing: read_with_default("Height sample spacing (mm)", 5.0),
+        };
+
+        if !(follow.smoothing >= 0.0 && follow.spacing > 0.0 && follow.max_slope > 0.0 && follow.max_slope < 90.0) {
+            bail!("Invalid terrain following settings - {}", follow);
+        }
+
+        Ok(Some(follow))
+    }
+
+    ///Plan the TCP height along the (lateral) path - None if the heightmap has nothing under the path
+    pub fn plan(&self, waypoints: &[Waypoint], grid: &HeightGrid) -> Option<TerrainProfile> {
+        //Sample the path
+        let mut dists = vec![];
+        let mut pnts = vec![];
+        let mut travelled = 0.0;
+
+        for (i, waypoint) in waypoints.iter().enumerate() {
+            let pos = (waypoint.pos.0, waypoint.pos.1);
+
+            if i > 0 {
+                let last = waypoints[i - 1].pos;
+                let length = (pos.0 - last.0).hypot(pos.1 - last.1);
+                let cnt = (length / self.spacing).ceil() as usize;
+
+                for k in 1..cnt {
+                    let frac = k as f64 / cnt as f64;
+                    dists.push(travelled + length * frac);
+                    pnts.push((last.0 + (pos.0 - last.0) * frac, last.1 + (pos.1 - last.1) * frac));
+                }
+                travelled += length;
+            }
+
+            dists.push(travelled);
+            pnts.push(pos);
+        }
+
+        let heights: Vec<Option<f64>> = pnts.iter().map(|pnt| grid.surface_near(*pnt, self.smoothing)).collect();
+        let unmapped = heights.iter().filter(|height| height.is_none()).count();
+
+        let mut zs = fill_gaps(&dists, &heights)?;
+        for z in zs.iter_mut() {
+            *z += self.offset;
+        }
+
+        //Lift points so neither neighbour is more than the slope limit above or below
+        let slope = self.max_slope.to_radians().tan();
+        for i in 1..zs.len() {
+            zs[i] = zs[i].max(zs[i - 1] - slope * (dists[i] - dists[i - 1]));
+        }
+        for i in (0..zs.len() - 1).rev() {
+            zs[i] = zs[i].max(zs[i + 1] - slope * (dists[i + 1] - dists[i]));
+        }
+
+        Some(TerrainProfile { dists, zs, unmapped })
+    }
+}
+
+///The planned TCP height along the path
+#[derive(Debug, Clone)]
+pub struct TerrainProfile {
+    ///Lateral distance along the path of each sample (mm)
+    dists: Vec<f64>,
+    ///TCP height at each sample (mm)
+    zs: Vec<f64>,
+    ///Samples with no mapped surface nearby (interpolated from their neighbours)
+    pub unmapped: usize,
+}
+
+impl TerrainProfile {
+    ///The TCP height a lateral distance along the path
+    pub fn z_at(&self, dist: f64) -> f64 {
+        interp(&self.dists, &self.zs, dist)
+    }
+
+    ///Rise of the planned height per mm along the path (flat past the ends)
+    pub fn slope_at(&self, dist: f64) -> f64 {
+        let idx = self.dists.partition_point(|val| *val <= dist);
+
+        if idx == 0 || idx >= self.dists.len() {
+            return 0.0;
+        }
+
+        (self.zs[idx] - self.zs[idx - 1]) / (self.dists[idx] - self.dists[idx - 1])
+    }
+
+    ///Apply the heights to the waypoints
+    pub fn apply(&self, waypoints: &[Waypoint]) -> Vec<Waypoint> {
+        let mut travelled = 0.0;
+
+        waypoints
+            .iter()
+            .enumerate()
+            .map(|(i, waypoint)| {
+                if i > 0 {
+                    let last = waypoints[i - 1].pos;
+                    travelled += (waypoint.pos.0 - last.0).hypot(waypoint.pos.1 - last.1);
+                }
+
+                Waypoint {
+                    pos: (waypoint.pos.0, waypoint.pos.1, self.z_at(travelled)),
+                    ..*waypoint
+                }
+            })
+            .collect()
+    }
+}
+
+///Fill unmapped samples by interpolating between the mapped ones (held flat past the ends)
+fn fill_gaps(dists: &[f64], heights: &[Option<f64>]) -> Option<Vec<f64>> {
+    let (known_dists, known_heights): (Vec<f64>, Vec<f64>) =
+        dists.iter().zip(heights.iter()).filter_map(|(dist, height)| height.map(|height| (*dist, height))).unzip();
+
+    if known_dists.is_empty() {
+        return None;
+    }
+
+    Some(
+        dists
+            .iter()
+            .zip(heights.iter())
+            .map(|(dist, height)| height.unwrap_or_else(|| interp(&known_dists, &known_heights, *dist)))
+            .collect(),
+    )
+}
+
+///Linear interpolation of ys at x (held flat past the ends)
+fn interp(xs: &[f64], ys: &[f64], x: f64) -> f64 {
+    let idx = xs.partition_point(|val| *val <= x);
+
+    if idx == 0 {
+        return ys[0];
+    }
+    if idx >= xs.len() {
+        return ys[ys.len() - 1];
+    }
+
+    let (x0, x1) = (xs[idx - 1], xs[idx]);
+    ys[idx - 1] + (ys[idx] - ys[idx - 1]) * ((x - x0) / (x1 - x0))
+}
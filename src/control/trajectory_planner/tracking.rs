@@ -0,0 +1,223 @@
+//!Closed loop tracking of the time-parameterised reference made by the speed instructions
+//!The reference position is the integral of the instruction speeds from the start of the trajectory -
+//!the speed command is the reference speed plus a proportional correction of the position error
+use std::fmt::Display;
+
+///Correction speed per mm of position error (1/s)
+pub const TRACK_GAIN: f64 = 1.0;
+///Largest correction added to the reference speed (mm/s)
+pub const MAX_CORRECTION: f64 = 2.0;
+
+///The reference trajectory - piecewise constant speed from a start point
+#[derive(Debug, Clone)]
+pub struct ReferenceTrack {
+    ///Start time of each instruction (s)
+    starts: Vec<f64>,
+    ///Reference position at the start of each instruction
+    pnts: Vec<[f64; 3]>,
+    ///Speed of each instruction
+    vels: Vec<[f64; 3]>,
+    ///Path length travelled at the start of each instruction (mm)
+    dists: Vec<f64>,
+    ///Lateral (xy) path length travelled at the start of each instruction (mm)
+    lateral_dists: Vec<f64>,
+    ///Total duration (s)
+    duration: f64,
+}
+
+impl ReferenceTrack {
+    ///Create the reference from xyz speed instructions
+    pub fn create(start: (f64, f64, f64), instructions: &[(f64, (f64, f64, f64))]) -> ReferenceTrack {
+        let mut track = ReferenceTrack {
+            starts: vec![],
+            pnts: vec![],
+            vels: vec![],
+            dists: vec![],
+            lateral_dists: vec![],
+            duration: 0.0,
+        };
+        let mut pnt = [start.0, start.1, start.2];
+        let mut dist = 0.0;
+        let mut lateral_dist = 0.0;
+
+        for (time, vel) in instructions {
+            let vel = [vel.0, vel.1, vel.2];
+
+            track.starts.push(track.duration);
+            track.pnts.push(pnt);
+            track.vels.push(vel);
+            track.dists.push(dist);
+            track.lateral_dists.push(lateral_dist);
+
+            for axis in 0..3 {
+                pnt[axis] += vel[axis] * time;
+            }
+            dist += vel.iter().map(|v| v * v).sum::<f64>().sqrt() * time;
+            lateral_dist += vel[0].hypot(vel[1]) * time;
+            track.duration += time;
+        }
+
+        //Hold the end point
+        track.starts.push(track.duration);
+        track.pnts.push(pnt);
+        track.vels.push([0.0; 3]);
+        track.dists.push(dist);
+        track.lateral_dists.push(lateral_dist);
+
+        track
+    }
+
+    ///Create the reference from lateral speed instructions (the height is not tracked)
+    pub fn create_lateral(start: (f64, f64), instructions: &[(f64, (f64, f64))]) -> ReferenceTrack {
+        let instructions: Vec<(f64, (f64, f64, f64))> = instructions.iter().map(|(time, vel)| (*time, (vel.0, vel.1, 0.0))).collect();
+
+        ReferenceTrack::create((start.0, start.1, 0.0), &instructions)
+    }
+
+    ///The reference (position, speed) a time into the trajectory
+    pub fn at(&self, t: f64) -> ([f64; 3], [f64; 3]) {
+        let idx = self.starts.partition_point(|start| *start <= t).max(1) - 1;
+        let dt = t.clamp(0.0, self.duration) - self.starts[idx];
+
+        let mut pos = self.pnts[idx];
+        for axis in 0..3 {
+            pos[axis] += self.vels[idx][axis] * dt;
+        }
+
+        (pos, self.vels[idx])
+    }
+
+    ///Path length along the reference a time into the trajectory (mm)
+    pub fn dist_at(&self, t: f64) -> f64 {
+        let idx = self.starts.partition_point(|start| *start <= t).max(1) - 1;
+        let dt = t.clamp(0.0, self.duration) - self.starts[idx];
+        let speed = self.vels[idx].iter().map(|v| v * v).sum::<f64>().sqrt();
+
+        self.dists[idx] + speed * dt
+    }
+
+    ///Lateral (xy) path length along the reference a time into the trajectory (mm)
+    pub fn lateral_dist_at(&self, t: f64) -> f64 {
+        let idx = self.starts.partition_point(|start| *start <= t).max(1) - 1;
+        let dt = t.clamp(0.0, self.duration) - self.starts[idx];
+
+        self.lateral_dists[idx] + self.vels[idx][0].hypot(self.vels[idx][1]) * dt
+    }
+
+    ///Total duration of the reference (s)
+    pub fn duration(&self) -> f64 {
+        self.duration
+    }
+
+    ///The end point of the reference
+    pub fn end(&self) -> [f64; 3] {
+        *self.pnts.last().unwrap()
+    }
+}
+
+///The tracking error at an instant
+#[derive(Debug, Clone, Copy)]
+pub struct TrackingError {
+    ///Reference position (the measured position on untracked axes)
+    pub pos_ref: [f64; 3],
+    ///Error along the direction of travel (mm) - positive when behind the reference
+    pub along: f64,
+    ///Error across the direction of travel (mm) - positive when right of the path (looking along it, xy)
+    pub cross: f64,
+    ///Corrected speed command
+    pub speed: [f64; 3],
+}
+
+impl Display for TrackingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "[{},{},{}],{},{}",
+            self.pos_ref[0], self.pos_ref[1], self.pos_ref[2], self.along, self.cross
+        )
+    }
+}
+
+///Corrects the speed commands so the TCP follows the reference
+#[derive(Debug, Clone)]
+pub struct TrackingController {
+    reference: ReferenceTrack,
+    ///The axes that are tracked - the others are left to other controllers
+    tracked: [bool; 3],
+    ///The last direction of travel (for measuring errors while the reference is stationary)
+    last_dir: [f64; 3],
+}
+
+impl TrackingController {
+    ///lateral - only track x and y
+    pub fn create(reference: ReferenceTrack, lateral: bool) -> TrackingController {
+        TrackingController {
+            reference,
+            tracked: [true, true, !lateral],
+            last_dir: [1.0, 0.0, 0.0],
+        }
+    }
+
+    ///The reference being followed
+    pub fn reference(&self) -> &ReferenceTrack {
+        &self.reference
+    }
+
+    ///Stop tracking an axis (e.g. the force controlled axis) - it's left out of the errors and the corrections
+    pub fn free_axis(mut self, axis: usize) -> TrackingController {
+        self.tracked[axis] = false;
+        self
+    }
+
+    ///Compare the measured position with the reference at a time into the trajectory and get the corrected speed
+    pub fn update(&mut self, t: f64, pos: (f64, f64, f64)) -> TrackingError {
+        let (mut pos_ref, mut vel_ref) = self.reference.at(t);
+        let pos = [pos.0, pos.1, pos.2];
+
+        for axis in 0..3 {
+            if !self.tracked[axis] {
+                pos_ref[axis] = pos[axis];
+                vel_ref[axis] = 0.0;
+            }
+        }
+        let err = [pos_ref[0] - pos[0], pos_ref[1] - pos[1], pos_ref[2] - pos[2]];
+
+        //Direction of travel
+        let speed = vel_ref.iter().map(|v| v * v).sum::<f64>().sqrt();
+        if speed > 1e-9 {
+            for axis in 0..3 {
+                self.last_dir[axis] = vel_ref[axis] / speed;
+            }
+        }
+        let dir = self.last_dir;
+
+        let along = err[0] * dir[0] + err[1] * dir[1] + err[2] * dir[2];
+        //Sign from the xy cross product - the remaining error is the magnitude
+        let side = if (dir[0] * err[1] - dir[1] * err[0]) >= 0.0 { 1.0 } else { -1.0 };
+        let cross = side * (0..3).map(|axis| (err[axis] - along * dir[axis]).powi(2)).sum::<f64>().sqrt();
+
+        let mut speed_cmd = vel_ref;
+        for axis in (0..3).filter(|axis| self.tracked[*axis]) {
+            speed_cmd[axis] += (TRACK_GAIN * err[axis]).clamp(-MAX_CORRECTION, MAX_CORRECTION);
+        }
+
+        TrackingError {
+            pos_ref,
+            along,
+            cross,
+            speed: speed_cmd,
+        }
+    }
+
+    ///Distance between the measured position and the end of the reference (mm)
+    pub fn end_error(&self, pos: (f64, f64, f64)) -> f64 {
+        let end = self.reference.end();
+        let pos = [pos.0, pos.1, pos.2];
+
+        (0..3)
+            .filter(|axis| self.tracked[*axis])
+            .map(|axis| (end[axis] - pos[axis]).powi(2))
+            .sum::<f64>()
+            .sqrt()
+    }
+}
@@ -0,0 +1,265 @@
+//!Reads and writes trajectory files
+//!CSV with a header naming the columns - x,y,z are required, the rest are optional:
+//!qw,qx,qy,qz - TCP orientation (all four or none), speed - mm/s to reach the waypoint,
+//!force - force target at the waypoint (N), dwell - time held at the waypoint (s)
+//!Empty cells leave that value unset for the waypoint
+//!A "# FRAME: base" (or bed) comment names the frame of the positions - see frames.rs
+//!Older files ("(x, y, z)" per line or the single line "(x y),(x y)" custom format) can still be read
+use crate::control::misc_tools::angle_tools::Quaternion;
+use crate::control::trajectory_planner::Waypoint;
+use anyhow::bail;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+
+///Height given to points in the old custom format (which only stored x and y)
+const LEGACY_CUST_HEIGHT: f64 = 125.0;
+
+///The comment naming the frame of a trajectory file
+const FRAME_TAG: &str = "# FRAME:";
+
+///The columns that can appear in a trajectory file
+const COLUMNS: [&str; 10] = ["x", "y", "z", "qw", "qx", "qy", "qz", "speed", "force", "dwell"];
+
+///Load a trajectory file
+pub fn load_traj_file(filepath: &str) -> Result<Vec<Waypoint>, anyhow::Error> {
+    let file = File::open(filepath.trim())?;
+
+    //(line number, line) - comments and blank lines are skipped
+    let mut lines = vec![];
+    for (line_no, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with("#") {
+            continue;
+        }
+
+        lines.push((line_no + 1, line.to_string()));
+    }
+
+    let Some((header_no, header)) = lines.first() else {
+        bail!("Trajectory file {} is empty", filepath);
+    };
+
+    //Older formats start with a bracketed point
+    let waypoints = if header.starts_with("(") {
+        parse_legacy(&lines)?
+    } else {
+        let columns = parse_header(header).map_err(|e| anyhow::anyhow!("Line {}: {}", header_no, e))?;
+
+        let mut waypoints = vec![];
+        for (line_no, line) in lines.iter().skip(1) {
+            waypoints.push(parse_row(&columns, line).map_err(|e| anyhow::anyhow!("Line {}: {}", line_no, e))?);
+        }
+        waypoints
+    };
+
+    if waypoints.is_empty() {
+        bail!("Trajectory file {} has no waypoints", filepath);
+    }
+
+    Ok(waypoints)
+}
+
+///The frame named in a trajectory file - None if it doesn't name one
+pub fn load_traj_frame(filepath: &str) -> Result<Option<String>, anyhow::Error> {
+    let file = File::open(filepath.trim())?;
+
+    for line in BufReader::new(file).lines() {
+        if let Some(frame) = line?.trim().strip_prefix(FRAME_TAG) {
+            return Ok(Some(frame.trim().to_string()));
+        }
+    }
+
+    Ok(None)
+}
+
+///Save a trajectory file - only the optional columns used by at least one waypoint are written
+///frame - the frame of the positions (written as a comment if given)
+pub fn save_traj_file(waypoints: &[Waypoint], filepath: &str, frame: Option<&str>) -> Result<(), anyhow::Error> {
+    let has_ori = waypoints.iter().any(|w| w.ori.is_some());
+    let has_speed = waypoints.iter().any(|w| w.speed.is_some());
+    let has_force = waypoints.iter().any(|w| w.force.is_some());
+    let has_dwell = waypoints.iter().any(|w| w.dwell.is_some());
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .create(true)
+        .open(filepath.trim())?;
+
+    if let Some(frame) = frame {
+        writeln!(file, "{} {}", FRAME_TAG, frame)?;
+    }
+
+    let mut header = vec!["x", "y", "z"];
+    if has_ori {
+        header.extend(["qw", "qx", "qy", "qz"]);
+    }
+    if has_speed {
+        header.push("speed");
+    }
+    if has_force {
+        header.push("force");
+    }
+    if has_dwell {
+        header.push("dwell");
+    }
+    writeln!(file, "{}", header.join(","))?;
+
+    //Unset values are left empty
+    let opt = |val: Option<f64>| val.map_or(String::new(), |val| val.to_string());
+
+    for waypoint in waypoints {
+        let mut row = vec![
+            waypoint.pos.0.to_string(),
+            waypoint.pos.1.to_string(),
+            waypoint.pos.2.to_string(),
+        ];
+
+        if has_ori {
+            match waypoint.ori {
+                Some(q) => row.extend([q.w, q.x, q.y, q.z].map(|val| val.to_string())),
+                None => row.extend(["", "", "", ""].map(String::from)),
+            }
+        }
+        if has_speed {
+            row.push(opt(waypoint.speed));
+        }
+        if has_force {
+            row.push(opt(waypoint.force));
+        }
+        if has_dwell {
+            row.push(opt(waypoint.dwell));
+        }
+
+        writeln!(file, "{}", row.join(","))?;
+    }
+
+    Ok(())
+}
+
+///Parse the header - returns the column index of each name in COLUMNS
+fn parse_header(header: &str) -> Result<[Option<usize>; 10], anyhow::Error> {
+    let mut columns = [None; 10];
+
+    for (i, name) in header.split(",").map(|name| name.trim().to_lowercase()).enumerate() {
+        let Some(col) = COLUMNS.iter().position(|col| *col == name) else {
+            bail!("Unknown column \"{}\" - expected {}", name, COLUMNS.join(","));
+        };
+
+        if columns[col].is_some() {
+            bail!("Column \"{}\" given twice", name);
+        }
+        columns[col] = Some(i);
+    }
+
+    for required in 0..3 {
+        if columns[required].is_none() {
+            bail!("Missing required column \"{}\"", COLUMNS[required]);
+        }
+    }
+
+    //Orientation needs all four components
+    let ori_cnt = columns[3..7].iter().filter(|col| col.is_some()).count();
+    if ori_cnt != 0 && ori_cnt != 4 {
+        bail!("Orientation needs all of qw,qx,qy,qz");
+    }
+
+    Ok(columns)
+}
+
+///Parse a single waypoint row
+fn parse_row(columns: &[Option<usize>; 10], line: &str) -> Result<Waypoint, anyhow::Error> {
+    let cells: Vec<&str> = line.split(",").map(|cell| cell.trim()).collect();
+
+    let col_cnt = columns.iter().flatten().count();
+    if cells.len() != col_cnt {
+        bail!("Expected {} values, found {}", col_cnt, cells.len());
+    }
+
+    //Parse a column (None if the column isn't in the file or the cell is empty)
+    let get = |col: usize| -> Result<Option<f64>, anyhow::Error> {
+        let Some(idx) = columns[col] else {
+            return Ok(None);
+        };
+
+        if cells[idx].is_empty() {
+            return Ok(None);
+        }
+
+        match cells[idx].parse::<f64>() {
+            Ok(val) if val.is_finite() => Ok(Some(val)),
+            _ => bail!("Invalid {} - \"{}\"", COLUMNS[col], cells[idx]),
+        }
+    };
+
+    let required = |col: usize| -> Result<f64, anyhow::Error> {
+        match get(col)? {
+            Some(val) => Ok(val),
+            None => bail!("Missing {}", COLUMNS[col]),
+        }
+    };
+
+    let ori = match (get(3)?, get(4)?, get(5)?, get(6)?) {
+        (Some(w), Some(x), Some(y), Some(z)) => {
+            let norm = (w * w + x * x + y * y + z * z).sqrt();
+            if (norm - 1.0).abs() > 0.01 {
+                bail!("Orientation is not a unit quaternion (norm {})", norm);
+            }
+            Some(Quaternion::from([w / norm, x / norm, y / norm, z / norm]))
+        }
+        (None, None, None, None) => None,
+        _ => bail!("Orientation needs all of qw,qx,qy,qz"),
+    };
+
+    let speed = get(7)?;
+    if let Some(speed) = speed
+        && speed <= 0.0
+    {
+        bail!("Speed must be positive - {}", speed);
+    }
+
+    let dwell = get(9)?;
+    if let Some(dwell) = dwell
+        && dwell < 0.0
+    {
+        bail!("Dwell must not be negative - {}", dwell);
+    }
+
+    Ok(Waypoint {
+        pos: (required(0)?, required(1)?, required(2)?),
+        ori,
+        speed,
+        force: get(8)?,
+        dwell,
+    })
+}
+
+///Parse the older formats - "(x, y, z)" per line (desired trajectory files) or "(x y),(x y)" (custom trajectories)
+fn parse_legacy(lines: &[(usize, String)]) -> Result<Vec<Waypoint>, anyhow::Error> {
+    let mut waypoints = vec![];
+
+    for (line_no, line) in lines {
+        //Each point is bracketed - split on the closing bracket so "(x, y, z)" commas stay with their point
+        for coord in line.split(")").map(|c| c.trim().trim_start_matches(",").trim()).filter(|c| !c.is_empty()) {
+            let coord = coord.trim_start_matches("(");
+
+            let vals: Result<Vec<f64>, _> = if coord.contains(",") {
+                coord.split(",").map(|v| v.trim().parse::<f64>()).collect()
+            } else {
+                coord.split_whitespace().map(|v| v.parse::<f64>()).collect()
+            };
+
+            let pos = match vals.as_deref() {
+                Ok([x, y, z]) => (*x, *y, *z),
+                Ok([x, y]) => (*x, *y, LEGACY_CUST_HEIGHT),
+                _ => bail!("Line {}: invalid point \"({})\"", line_no, coord),
+            };
+
+            waypoints.push(Waypoint::from(pos));
+        }
+    }
+
+    Ok(waypoints)
+}
@@ -0,0 +1,259 @@
+//!Geometric transforms of trajectories - applied in order as a chain
+//!Rotations, scales and mirrors act in the xy plane (the bed) so the trajectory heights are kept
+use crate::control::misc_tools::angle_tools::{Quaternion, quart_rotate};
+use crate::control::misc_tools::misc::read_with_default;
+use crate::control::trajectory_planner::Waypoint;
+use anyhow::bail;
+use std::fmt::Display;
+
+///A transform of a trajectory
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transform {
+    ///Move by (dx, dy, dz) (mm)
+    Translate([f64; 3]),
+    ///Rotate about a vertical axis through centre (mm) by angle (deg, anticlockwise from above)
+    Rotate { centre: (f64, f64), angle: f64 },
+    ///Scale the xy positions about centre (mm)
+    Scale { centre: (f64, f64), factor: f64 },
+    ///Mirror across the line x = about (mirror_x) or y = about
+    Mirror { mirror_x: bool, about: f64 },
+    ///Run the trajectory backwards
+    Reverse,
+    ///Run the trajectory copies times, each copy moved by offset (mm) from the last
+    Repeat { copies: usize, offset: [f64; 3] },
+}
+
+impl Display for Transform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Transform::Translate(d) => write!(f, "TRANSLATE [{},{},{}]", d[0], d[1], d[2]),
+            Transform::Rotate { centre, angle } => write!(f, "ROTATE {}deg ABOUT ({} {})", angle, centre.0, centre.1),
+            Transform::Scale { centre, factor } => write!(f, "SCALE {} ABOUT ({} {})", factor, centre.0, centre.1),
+            Transform::Mirror { mirror_x, about } => write!(f, "MIRROR {}={}", if *mirror_x { "X" } else { "Y" }, about),
+            Transform::Reverse => write!(f, "REVERSE"),
+            Transform::Repeat { copies, offset } => {
+                write!(f, "REPEAT {} OFFSET [{},{},{}]", copies, offset[0], offset[1], offset[2])
+            }
+        }
+    }
+}
+
+impl Transform {
+    ///Apply the transform to a set of waypoints
+    pub fn apply(&self, waypoints: &[Waypoint]) -> Vec<Waypoint> {
+        match *self {
+            Transform::Translate(d) => waypoints.iter().map(|w| moved(w, d)).collect(),
+
+            Transform::Rotate { centre, angle } => {
+                let (sin, cos) = angle.to_radians().sin_cos();
+                let half = (angle.to_radians() / 2.0).sin_cos();
+                //Rotation about the base z axis
+                let rot = Quaternion {
+                    w: half.1,
+                    x: 0.0,
+                    y: 0.0,
+                    z: half.0,
+                };
+
+                waypoints
+                    .iter()
+                    .map(|w| {
+                        let (dx, dy) = (w.pos.0 - centre.0, w.pos.1 - centre.1);
+
+                        Waypoint {
+                            pos: (centre.0 + dx * cos - dy * sin, centre.1 + dx * sin + dy * cos, w.pos.2),
+                            ori: w.ori.map(|ori| quart_rotate(rot, ori)),
+                            ..*w
+                        }
+                    })
+                    .collect()
+            }
+
+            Transform::Scale { centre, factor } => waypoints
+                .iter()
+                .map(|w| Waypoint {
+                    pos: (
+                        centre.0 + (w.pos.0 - centre.0) * factor,
+                        centre.1 + (w.pos.1 - centre.1) * factor,
+                        w.pos.2,
+                    ),
+                    ..*w
+                })
+                .collect(),
+
+            //The orientations are kept - a mirrored orientation isn't a rotation
+            Transform::Mirror { mirror_x, about } => waypoints
+                .iter()
+                .map(|w| {
+                    let pos = if mirror_x {
+                        (2.0 * about - w.pos.0, w.pos.1, w.pos.2)
+                    } else {
+                        (w.pos.0, 2.0 * about - w.pos.1, w.pos.2)
+                    };
+
+                    Waypoint { pos, ..*w }
+                })
+                .collect(),
+
+            //A waypoint's speed is for the move to it - so each reversed move takes the speed of the original move
+            Transform::Reverse => {
+                let cnt = waypoints.len();
+
+                (0..cnt)
+                    .map(|k| {
+                        let waypoint = waypoints[cnt - 1 - k];
+                        let speed = if k == 0 { waypoint.speed } else { waypoints[cnt - k].speed };
+
+                        Waypoint { speed, ..waypoint }
+                    })
+                    .collect()
+            }
+
+            Transform::Repeat { copies, offset } => (0..copies)
+                .flat_map(|k| {
+                    let d = offset.map(|val| val * k as f64);
+                    waypoints.iter().map(move |w| moved(w, d))
+                })
+                .collect(),
+        }
+    }
+
+    ///Get the user to describe a transform - centres default to the middle of the trajectory
+    ///None if the user is done
+    pub fn prompt(waypoints: &[Waypoint]) -> Result<Option<Transform>, anyhow::Error> {
+        let kind: String =
+            read_with_default("Add a transform (translate/rotate/scale/mirror/reverse/repeat/done)", "done".to_string());
+
+        let (min, max) = bounds(waypoints);
+        let mid = ((min[0] + max[0]) / 2.0, (min[1] + max[1]) / 2.0);
+
+        let transform = match kind.trim().to_lowercase().as_str() {
+            "done" => return Ok(None),
+            "translate" => Transform::Translate([
+                read_with_default("dx (mm)", 0.0),
+                read_with_default("dy (mm)", 0.0),
+                read_with_default("dz (mm)", 0.0),
+            ]),
+            "rotate" => Transform::Rotate {
+                centre: (read_with_default("Centre x (mm)", mid.0), read_with_default("Centre y (mm)", mid.1)),
+                angle: read_with_default("Angle (deg, anticlockwise)", 90.0),
+            },
+            "scale" => {
+                let centre = (read_with_default("Centre x (mm)", mid.0), read_with_default("Centre y (mm)", mid.1));
+                let factor = read_with_default("Scale factor", 1.0);
+                if !(factor > 0.0) {
+                    bail!("Scale factor must be positive - {}", factor);
+                }
+
+                Transform::Scale { centre, factor }
+            }
+            "mirror" => {
+                let axis: String = read_with_default("Mirror the x or y positions (x/y)", "x".to_string());
+                let mirror_x = match axis.trim().to_lowercase().as_str() {
+                    "x" => true,
+                    "y" => false,
+                    other => bail!("Unknown mirror axis - {}", other),
+                };
+                let about = read_with_default("Mirror about (mm)", if mirror_x { mid.0 } else { mid.1 });
+
+                Transform::Mirror { mirror_x, about }
+            }
+            "reverse" => Transform::Reverse,
+            "repeat" => {
+                let copies = read_with_default("Total number of copies", 2_usize);
+                if copies == 0 {
+                    bail!("Need at least one copy");
+                }
+
+                Transform::Repeat {
+                    copies,
+                    offset: [
+                        read_with_default("Offset x between copies (mm)", max[0] - min[0]),
+                        read_with_default("Offset y between copies (mm)", 0.0),
+                        read_with_default("Offset z between copies (mm)", 0.0),
+                    ],
+                }
+            }
+            other => bail!("Unknown transform - {}", other),
+        };
+
+        Ok(Some(transform))
+    }
+}
+
+///An ordered list of transforms
+#[derive(Debug, Clone, Default)]
+pub struct TransformChain {
+    pub transforms: Vec<Transform>,
+}
+
+impl Display for TransformChain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let transforms: Vec<String> = self.transforms.iter().map(|t| t.to_string()).collect();
+        write!(f, "{}", transforms.join(" | "))
+    }
+}
+
+impl TransformChain {
+    ///Apply each transform in turn
+    pub fn apply(&self, waypoints: &[Waypoint]) -> Vec<Waypoint> {
+        self.transforms.iter().fold(waypoints.to_vec(), |waypoints, transform| transform.apply(&waypoints))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transforms.is_empty()
+    }
+}
+
+///Optionally transform a trajectory - the chain is added to the description (logged in the test config)
+pub fn prompt_transforms(waypoints: Vec<Waypoint>, desc: String) -> Result<(Vec<Waypoint>, String), anyhow::Error> {
+    let mut chain = TransformChain::default();
+    let mut transformed = waypoints;
+
+    while let Some(transform) = Transform::prompt(&transformed)? {
+        transformed = transform.apply(&transformed);
+        chain.transforms.push(transform);
+
+        let (min, max) = bounds(&transformed);
+        println!(
+            "{} - {} waypoints, X:{:.1} to {:.1} Y:{:.1} to {:.1} Z:{:.1} to {:.1}",
+            transform,
+            transformed.len(),
+            min[0],
+            max[0],
+            min[1],
+            max[1],
+            min[2],
+            max[2]
+        );
+    }
+
+    if chain.is_empty() {
+        return Ok((transformed, desc));
+    }
+
+    Ok((transformed, format!("{} - TRANSFORMS:{}", desc, chain)))
+}
+
+///The (min xyz, max xyz) of a set of waypoints
+pub fn bounds(waypoints: &[Waypoint]) -> ([f64; 3], [f64; 3]) {
+    let mut min = [f64::INFINITY; 3];
+    let mut max = [f64::NEG_INFINITY; 3];
+
+    for waypoint in waypoints {
+        let pos = [waypoint.pos.0, waypoint.pos.1, waypoint.pos.2];
+        for axis in 0..3 {
+            min[axis] = min[axis].min(pos[axis]);
+            max[axis] = max[axis].max(pos[axis]);
+        }
+    }
+
+    (min, max)
+}
+
+fn moved(waypoint: &Waypoint, d: [f64; 3]) -> Waypoint {
+    Waypoint {
+        pos: (waypoint.pos.0 + d[0], waypoint.pos.1 + d[1], waypoint.pos.2 + d[2]),
+        ..*waypoint
+    }
+}
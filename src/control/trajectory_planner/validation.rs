@@ -0,0 +1,247 @@
+//!Checks a whole trajectory before it is run
+//!Every waypoint and the straight segments between them are checked against the workspace and minimum height,
+//!the waypoint speeds against the speed and angular speed limits, and repeated points (zero length segments) are flagged
+use crate::control::misc_tools::angle_tools::quart_angle;
+use crate::control::trajectory_planner::{Waypoint, pose};
+use std::fmt::Display;
+
+///The limits a trajectory is checked against
+#[derive(Debug, Clone, Copy)]
+pub struct ValidationLimits {
+    ///Minimum xyz of the allowed workspace (mm)
+    pub workspace_min: [f64; 3],
+    ///Maximum xyz of the allowed workspace (mm)
+    pub workspace_max: [f64; 3],
+    ///Lowest height the tool may be sent to (mm)
+    pub min_z: f64,
+    ///The axis moved by force control (if any) - its planned positions aren't checked
+    pub force_axis: Option<usize>,
+    ///Maximum travel speed (mm/s)
+    pub max_speed: f64,
+    ///Maximum rate of change of the TCP orientation (deg/s)
+    pub max_ang_speed: f64,
+}
+
+///How serious a problem is
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    ///The trajectory can run but may not do what was intended
+    Warning,
+    ///The trajectory would be stopped by the safety checks (or is unsafe)
+    Error,
+}
+
+///A problem found in a trajectory
+#[derive(Debug, Clone)]
+pub struct Issue {
+    pub severity: Severity,
+    ///The waypoint the problem is at (or the end of the segment)
+    pub waypoint: usize,
+    pub msg: String,
+}
+
+impl Display for Issue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "WARNING",
+            Severity::Error => "ERROR",
+        };
+
+        write!(f, "{} - waypoint {}: {}", severity, self.waypoint, self.msg)
+    }
+}
+
+///The result of validating a trajectory
+#[derive(Debug, Clone)]
+pub struct ValidationReport {
+    ///The problems found
+    pub issues: Vec<Issue>,
+    ///Number of waypoints
+    pub waypoint_cnt: usize,
+    ///Total path length (mm)
+    pub length: f64,
+    ///Min/max xyz reached
+    pub bounds: ([f64; 3], [f64; 3]),
+    ///Time to travel the path at the waypoint (or default) speeds, including dwells (s)
+    pub est_time: f64,
+}
+
+impl ValidationReport {
+    ///Whether any problem would stop the trajectory running safely
+    pub fn has_errors(&self) -> bool {
+        self.issues.iter().any(|issue| issue.severity == Severity::Error)
+    }
+}
+
+impl Display for ValidationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "TRAJECTORY CHECK")?;
+        writeln!(f, "\tWaypoints: {}", self.waypoint_cnt)?;
+        writeln!(f, "\tLength: {:.1}mm", self.length)?;
+        writeln!(
+            f,
+            "\tX: {:.1} to {:.1} | Y: {:.1} to {:.1} | Z: {:.1} to {:.1}",
+            self.bounds.0[0], self.bounds.1[0], self.bounds.0[1], self.bounds.1[1], self.bounds.0[2], self.bounds.1[2]
+        )?;
+        writeln!(f, "\tEstimated time: {:.1}s", self.est_time)?;
+
+        if self.issues.is_empty() {
+            write!(f, "\tNo problems found")?;
+        } else {
+            let errors = self.issues.iter().filter(|issue| issue.severity == Severity::Error).count();
+            write!(f, "\t{} errors, {} warnings", errors, self.issues.len() - errors)?;
+
+            for issue in self.issues.iter() {
+                write!(f, "\n\t{}", issue)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+///Check a trajectory against the limits
+///default_speed - the speed used for waypoints that don't set their own (mm/s)
+pub fn validate_trajectory(waypoints: &[Waypoint], default_speed: f64, limits: &ValidationLimits) -> ValidationReport {
+    let mut issues = vec![];
+    let mut length = 0.0;
+    let mut est_time = 0.0;
+    let mut bounds = ([f64::INFINITY; 3], [f64::NEG_INFINITY; 3]);
+    let oris = pose::filled_oris(waypoints);
+
+    if waypoints.is_empty() {
+        issues.push(Issue {
+            severity: Severity::Error,
+            waypoint: 0,
+            msg: "Trajectory has no waypoints".to_string(),
+        });
+    }
+
+    for (i, waypoint) in waypoints.iter().enumerate() {
+        let pnt = [waypoint.pos.0, waypoint.pos.1, waypoint.pos.2];
+
+        if pnt.iter().any(|val| !val.is_finite()) {
+            issues.push(Issue {
+                severity: Severity::Error,
+                waypoint: i,
+                msg: format!("Invalid position {:?}", waypoint.pos),
+            });
+            continue;
+        }
+
+        for axis in 0..3 {
+            bounds.0[axis] = bounds.0[axis].min(pnt[axis]);
+            bounds.1[axis] = bounds.1[axis].max(pnt[axis]);
+        }
+
+        //The workspace is a box so the segments between waypoints inside it are also inside it
+        for (axis, name) in ["x", "y", "z"].iter().enumerate() {
+            if limits.force_axis == Some(axis) {
+                continue;
+            }
+
+            if pnt[axis] < limits.workspace_min[axis] || pnt[axis] > limits.workspace_max[axis] {
+                issues.push(Issue {
+                    severity: Severity::Error,
+                    waypoint: i,
+                    msg: format!(
+                        "{} = {:.2} outside the workspace ({} to {})",
+                        name, pnt[axis], limits.workspace_min[axis], limits.workspace_max[axis]
+                    ),
+                });
+            }
+        }
+
+        //Segments are straight so their lowest point is at one of the waypoints
+        if limits.force_axis != Some(2) && pnt[2] < limits.min_z {
+            issues.push(Issue {
+                severity: Severity::Error,
+                waypoint: i,
+                msg: format!("z = {:.2} below the minimum height ({})", pnt[2], limits.min_z),
+            });
+        }
+
+        if let Some(ori) = waypoint.ori {
+            let norm = (ori.w.powi(2) + ori.x.powi(2) + ori.y.powi(2) + ori.z.powi(2)).sqrt();
+            if (norm - 1.0).abs() > 0.01 {
+                issues.push(Issue {
+                    severity: Severity::Error,
+                    waypoint: i,
+                    msg: format!("Orientation is not a unit quaternion (norm {:.3})", norm),
+                });
+            }
+        }
+
+        let speed = waypoint.speed.unwrap_or(default_speed);
+        if !speed.is_finite() || speed <= 0.0 {
+            issues.push(Issue {
+                severity: Severity::Error,
+                waypoint: i,
+                msg: format!("Invalid speed {}mm/s", speed),
+            });
+        } else if speed > limits.max_speed {
+            issues.push(Issue {
+                severity: Severity::Error,
+                waypoint: i,
+                msg: format!("Speed {}mm/s above the limit ({}mm/s)", speed, limits.max_speed),
+            });
+        }
+
+        if let Some(dwell) = waypoint.dwell {
+            est_time += dwell.max(0.0);
+        }
+
+        if i == 0 {
+            continue;
+        }
+
+        let last = waypoints[i - 1].pos;
+        let dist = ((pnt[0] - last.0).powi(2) + (pnt[1] - last.1).powi(2) + (pnt[2] - last.2).powi(2)).sqrt();
+
+        //Angle turned through reaching the waypoint
+        let angle = oris.as_ref().map_or(0.0, |oris| quart_angle(oris[i - 1], oris[i]).to_degrees());
+
+        //Repeated points would give zero time instructions - they are skipped when timing the trajectory
+        if dist < 1e-6 {
+            issues.push(Issue {
+                severity: Severity::Warning,
+                waypoint: i,
+                msg: format!("Zero length segment from waypoint {} (will be skipped)", i - 1),
+            });
+
+            if angle > 1e-3 {
+                issues.push(Issue {
+                    severity: Severity::Warning,
+                    waypoint: i,
+                    msg: format!("Rotates {:.1}deg without moving (orientation steps)", angle),
+                });
+            }
+            continue;
+        }
+
+        if speed > 0.0 && angle * speed / dist > limits.max_ang_speed {
+            issues.push(Issue {
+                severity: Severity::Error,
+                waypoint: i,
+                msg: format!(
+                    "Rotates at {:.1}deg/s - above the limit ({}deg/s)",
+                    angle * speed / dist,
+                    limits.max_ang_speed
+                ),
+            });
+        }
+
+        length += dist;
+        if speed > 0.0 {
+            est_time += dist / speed;
+        }
+    }
+
+    ValidationReport {
+        issues,
+        waypoint_cnt: waypoints.len(),
+        length,
+        bounds,
+        est_time,
+    }
+}
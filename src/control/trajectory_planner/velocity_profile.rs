@@ -0,0 +1,447 @@
+//!Acceleration limited velocity profiles for speed controlled trajectories
+//!Each segment ramps up to its cruise speed and back down (trapezoidal or jerk limited S-curve ramps)
+//!Corners are passed at the speed that keeps the tool within the corner tolerance of the waypoint (junction deviation)
+//!so only sharp corners and dwells bring the tool to a stop
+use crate::control::trajectory_planner::Waypoint;
+use crate::config::{load_config_or_default, read_config_values};
+use anyhow::bail;
+use std::fmt::Display;
+
+///The file containing the motion limits
+pub const MOTION_CONFIG_FP: &str = "configs/motion.txt";
+
+///Iterations used when searching for the speeds that fit a segment
+const SEARCH_ITERS: usize = 60;
+
+///The shape of the speed changes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProfileShape {
+    ///Instant speed changes at each waypoint (constant speed segments)
+    Step,
+    ///Constant acceleration ramps
+    Trapezoidal,
+    ///Jerk limited ramps
+    SCurve,
+}
+
+///The configurable motion limits
+#[derive(Debug, Clone, Copy)]
+pub struct MotionLimits {
+    ///The shape of the speed changes
+    pub shape: ProfileShape,
+    ///Maximum acceleration (mm/s^2)
+    pub max_accel: f64,
+    ///Maximum jerk (mm/s^3) - S-curve only
+    pub max_jerk: f64,
+    ///How far the tool may cut a corner (mm) - sets the corner speed
+    pub corner_tol: f64,
+    ///The period the speed commands are sampled at (s)
+    pub sample_period: f64,
+}
+
+impl Default for MotionLimits {
+    fn default() -> Self {
+        MotionLimits {
+            shape: ProfileShape::Trapezoidal,
+            max_accel: 20.0,
+            max_jerk: 100.0,
+            corner_tol: 0.5,
+            sample_period: 0.02,
+        }
+    }
+}
+
+impl Display for MotionLimits {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let shape = match self.shape {
+            ProfileShape::Step => "STEP",
+            ProfileShape::Trapezoidal => "TRAPEZOIDAL",
+            ProfileShape::SCurve => "SCURVE",
+        };
+
+        write!(
+            f,
+            "{} ACCEL:{} JERK:{} CORNER TOL:{} PERIOD:{}",
+            shape, self.max_accel, self.max_jerk, self.corner_tol, self.sample_period
+        )
+    }
+}
+
+impl MotionLimits {
+    ///Load and validate the motion limits
+    pub fn load_from_file(filepath: &str) -> Result<Self, anyhow::Error> {
+        let mut limits = MotionLimits::default();
+
+        for (key, val) in read_config_values(filepath)? {
+            match key.as_str() {
+                "PROFILE" => {
+                    limits.shape = match val.to_lowercase().as_str() {
+                        "step" => ProfileShape::Step,
+                        "trapezoidal" => ProfileShape::Trapezoidal,
+                        "scurve" => ProfileShape::SCurve,
+                        other => bail!("Unknown velocity profile - {}", other),
+                    }
+                }
+                "MAX_ACCEL" => limits.max_accel = val.parse()?,
+                "MAX_JERK" => limits.max_jerk = val.parse()?,
+                "CORNER_TOL" => limits.corner_tol = val.parse()?,
+                "SAMPLE_PERIOD" => limits.sample_period = val.parse()?,
+                other => bail!("Unknown setting in motion config - {}", other),
+            }
+        }
+
+        limits.validate()?;
+
+        Ok(limits)
+    }
+
+    ///The configured motion limits, or the defaults
+    pub fn load_or_default() -> Self {
+        load_config_or_default(MOTION_CONFIG_FP, "motion limits", Self::load_from_file, MotionLimits::default)
+    }
+
+    ///Check the limits are usable
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if !(self.max_accel > 0.0 && self.max_jerk > 0.0) {
+            bail!("Acceleration and jerk limits must be positive");
+        }
+        if !(self.corner_tol >= 0.0) {
+            bail!("Corner tolerance must not be negative");
+        }
+        if !(self.sample_period > 0.0) {
+            bail!("Sample period must be positive");
+        }
+
+        Ok(())
+    }
+}
+
+///A change in speed from v0 to v1
+#[derive(Debug, Clone, Copy)]
+struct Ramp {
+    v0: f64,
+    v1: f64,
+    duration: f64,
+    ///Time spent changing the acceleration at each end (0 for trapezoidal ramps)
+    jerk_time: f64,
+    ///The peak acceleration magnitude
+    accel: f64,
+}
+
+impl Ramp {
+    ///Plan the fastest ramp between two speeds within the limits
+    fn create(v0: f64, v1: f64, limits: &MotionLimits) -> Ramp {
+        let dv = (v1 - v0).abs();
+        let a = limits.max_accel;
+
+        match limits.shape {
+            ProfileShape::SCurve => {
+                let j = limits.max_jerk;
+
+                if dv >= a * a / j {
+                    //Reaches the max acceleration
+                    let jerk_time = a / j;
+                    Ramp { v0, v1, duration: (dv / a) + jerk_time, jerk_time, accel: a }
+                } else {
+                    //Only ramps the acceleration up and back down
+                    let jerk_time = (dv / j).sqrt();
+                    Ramp { v0, v1, duration: 2.0 * jerk_time, jerk_time, accel: j * jerk_time }
+                }
+            }
+            _ => Ramp { v0, v1, duration: dv / a, jerk_time: 0.0, accel: a },
+        }
+    }
+
+    ///Distance travelled during the ramp - both ramp shapes are symmetric
+    fn dist(&self) -> f64 {
+        (self.v0 + self.v1) / 2.0 * self.duration
+    }
+
+    ///The speed a time into the ramp
+    fn speed(&self, t: f64) -> f64 {
+        let t = t.clamp(0.0, self.duration);
+        let dv = (self.v1 - self.v0).abs();
+        let sign = if self.v1 >= self.v0 { 1.0 } else { -1.0 };
+
+        let change = if self.jerk_time <= 0.0 {
+            self.accel * t
+        } else {
+            let jerk = self.accel / self.jerk_time;
+            let const_end = self.duration - self.jerk_time;
+
+            if t < self.jerk_time {
+                jerk * t * t / 2.0
+            } else if t < const_end {
+                (jerk * self.jerk_time * self.jerk_time / 2.0) + (self.accel * (t - self.jerk_time))
+            } else {
+                let remaining = self.duration - t;
+                dv - (jerk * remaining * remaining / 2.0)
+            }
+        };
+
+        self.v0 + (sign * change.min(dv))
+    }
+}
+
+///A part of the profile travelled in one direction
+#[derive(Debug, Clone, Copy)]
+enum Phase {
+    ///Changing speed
+    Ramp { dir: [f64; 3], ramp: Ramp },
+    ///Constant speed
+    Cruise { dir: [f64; 3], speed: f64, duration: f64 },
+    ///Stationary
+    Dwell { duration: f64 },
+}
+
+impl Phase {
+    fn duration(&self) -> f64 {
+        match self {
+            Phase::Ramp { ramp, .. } => ramp.duration,
+            Phase::Cruise { duration, .. } | Phase::Dwell { duration } => *duration,
+        }
+    }
+
+    ///The velocity a time into the phase
+    fn velocity(&self, t: f64) -> [f64; 3] {
+        let (dir, speed) = match self {
+            Phase::Ramp { dir, ramp } => (*dir, ramp.speed(t)),
+            Phase::Cruise { dir, speed, .. } => (*dir, *speed),
+            Phase::Dwell { .. } => ([0.0; 3], 0.0),
+        };
+
+        dir.map(|d| d * speed)
+    }
+}
+
+///A velocity profile through a set of waypoints
+#[derive(Debug, Clone)]
+pub struct VelocityProfile {
+    phases: Vec<Phase>,
+    ///The end time of each phase
+    ends: Vec<f64>,
+}
+
+impl VelocityProfile {
+    ///The time taken to run the profile (s)
+    pub fn duration(&self) -> f64 {
+        *self.ends.last().unwrap_or(&0.0)
+    }
+
+    ///The commanded xyz speed a time into the profile (stationary once finished)
+    pub fn speed_at(&self, t: f64) -> [f64; 3] {
+        if t < 0.0 || t >= self.duration() {
+            return [0.0; 3];
+        }
+
+        let idx = self.ends.partition_point(|end| *end <= t).min(self.phases.len() - 1);
+        let start = if idx == 0 { 0.0 } else { self.ends[idx - 1] };
+
+        self.phases[idx].velocity(t - start)
+    }
+
+    ///Sample the profile into (time, (x speed, y speed, z speed)) instructions - as used by the EGM loops
+    ///Each instruction uses the speed at the middle of its period
+    pub fn instructions(&self, period: f64) -> Vec<(f64, (f64, f64, f64))> {
+        let mut instructions = vec![];
+        let mut t = 0.0;
+
+        while t < self.duration() {
+            let time = period.min(self.duration() - t);
+            let speed = self.speed_at(t + (time / 2.0));
+
+            instructions.push((time, (speed[0], speed[1], speed[2])));
+            t += period;
+        }
+
+        instructions
+    }
+
+    ///Sample the profile into lateral (time, (x speed, y speed)) instructions
+    pub fn lateral_instructions(&self, period: f64) -> Vec<(f64, (f64, f64))> {
+        self.instructions(period)
+            .into_iter()
+            .map(|(time, speed)| (time, (speed.0, speed.1)))
+            .collect()
+    }
+}
+
+///A straight move between waypoints
+struct Move {
+    dir: [f64; 3],
+    length: f64,
+    ///Cruise speed limit
+    speed: f64,
+    ///Dwell at the end of the move
+    dwell: f64,
+}
+
+///Plan a velocity profile through the waypoints
+///default_speed - used for waypoints without their own speed (mm/s)
+///lateral - only the xy movement is profiled (the z axis is force controlled)
+pub fn plan_velocity_profile(
+    waypoints: &[Waypoint],
+    default_speed: f64,
+    limits: &MotionLimits,
+    lateral: bool,
+) -> Result<VelocityProfile, anyhow::Error> {
+    if !(default_speed > 0.0) {
+        bail!("Invalid trajectory speed - {}", default_speed);
+    }
+    limits.validate()?;
+
+    //Build the moves - repeated points are merged (keeping their dwell)
+    let mut moves: Vec<Move> = vec![];
+    let mut start_dwell = waypoints.first().and_then(|w| w.dwell).unwrap_or(0.0);
+
+    for pair in waypoints.windows(2) {
+        let (a, b) = (pair[0].pos, pair[1].pos);
+        let mut del = [b.0 - a.0, b.1 - a.1, b.2 - a.2];
+        if lateral {
+            del[2] = 0.0;
+        }
+
+        let length = (del[0].powi(2) + del[1].powi(2) + del[2].powi(2)).sqrt();
+        let dwell = pair[1].dwell.unwrap_or(0.0).max(0.0);
+
+        if length < 1e-9 {
+            match moves.last_mut() {
+                Some(last) => last.dwell += dwell,
+                None => start_dwell += dwell,
+            }
+            continue;
+        }
+
+        moves.push(Move {
+            dir: del.map(|d| d / length),
+            length,
+            speed: pair[1].speed.unwrap_or(default_speed),
+            dwell,
+        });
+    }
+
+    let mut phases = vec![];
+    if start_dwell > 0.0 {
+        phases.push(Phase::Dwell { duration: start_dwell });
+    }
+
+    //Speed at each junction (start, between each move, end)
+    let mut junctions = vec![0.0; moves.len() + 1];
+    for i in 1..moves.len() {
+        junctions[i] = if moves[i - 1].dwell > 0.0 {
+            0.0
+        } else {
+            corner_speed(&moves[i - 1].dir, &moves[i].dir, limits)
+                .min(moves[i - 1].speed)
+                .min(moves[i].speed)
+        };
+    }
+
+    if limits.shape != ProfileShape::Step {
+        //Backwards - make sure each move can slow down in time
+        for i in (0..moves.len()).rev() {
+            junctions[i] = junctions[i].min(max_start_speed(junctions[i + 1], moves[i].length, junctions[i], limits));
+        }
+        //Forwards - make sure each move can speed up in time
+        for i in 0..moves.len() {
+            junctions[i + 1] = junctions[i + 1].min(max_start_speed(junctions[i], moves[i].length, junctions[i + 1], limits));
+        }
+    }
+
+    for (i, mv) in moves.iter().enumerate() {
+        if limits.shape == ProfileShape::Step {
+            phases.push(Phase::Cruise { dir: mv.dir, speed: mv.speed, duration: mv.length / mv.speed });
+        } else {
+            let (v_in, v_out) = (junctions[i], junctions[i + 1]);
+            let cruise = cruise_speed(v_in, v_out, mv.length, mv.speed, limits);
+
+            let up = Ramp::create(v_in, cruise, limits);
+            let down = Ramp::create(cruise, v_out, limits);
+            let cruise_dist = (mv.length - up.dist() - down.dist()).max(0.0);
+
+            if up.duration > 0.0 {
+                phases.push(Phase::Ramp { dir: mv.dir, ramp: up });
+            }
+            if cruise_dist > 0.0 && cruise > 0.0 {
+                phases.push(Phase::Cruise { dir: mv.dir, speed: cruise, duration: cruise_dist / cruise });
+            }
+            if down.duration > 0.0 {
+                phases.push(Phase::Ramp { dir: mv.dir, ramp: down });
+            }
+        }
+
+        if mv.dwell > 0.0 {
+            phases.push(Phase::Dwell { duration: mv.dwell });
+        }
+    }
+
+    let mut curr_time = 0.0;
+    let ends = phases
+        .iter()
+        .map(|phase| {
+            curr_time += phase.duration();
+            curr_time
+        })
+        .collect();
+
+    Ok(VelocityProfile { phases, ends })
+}
+
+///The fastest speed a corner can be taken at while staying within the corner tolerance
+fn corner_speed(dir_in: &[f64; 3], dir_out: &[f64; 3], limits: &MotionLimits) -> f64 {
+    let cos_theta = -(dir_in[0] * dir_out[0] + dir_in[1] * dir_out[1] + dir_in[2] * dir_out[2]);
+
+    //Straight on - no limit
+    if cos_theta < -0.999999 {
+        return f64::INFINITY;
+    }
+    //Reversing - must stop
+    if cos_theta > 0.999999 {
+        return 0.0;
+    }
+
+    let sin_half = (0.5 * (1.0 - cos_theta)).sqrt();
+
+    (limits.max_accel * limits.corner_tol * sin_half / (1.0 - sin_half)).sqrt()
+}
+
+///The fastest a move can start (up to the cap) and still reach the end speed within its length
+///Used both ways - ramps are symmetric so the same distance is needed to speed up or slow down
+fn max_start_speed(end_speed: f64, length: f64, cap: f64, limits: &MotionLimits) -> f64 {
+    if cap <= end_speed || Ramp::create(cap, end_speed, limits).dist() <= length {
+        return cap;
+    }
+
+    let (mut low, mut high) = (end_speed, cap);
+    for _ in 0..SEARCH_ITERS {
+        let mid = (low + high) / 2.0;
+        if Ramp::create(mid, end_speed, limits).dist() <= length {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+
+    low
+}
+
+///The fastest cruise speed (up to the limit) that leaves room to ramp in and out of a move
+fn cruise_speed(v_in: f64, v_out: f64, length: f64, max_speed: f64, limits: &MotionLimits) -> f64 {
+    let fits = |v: f64| Ramp::create(v_in, v, limits).dist() + Ramp::create(v, v_out, limits).dist() <= length;
+
+    if fits(max_speed) {
+        return max_speed;
+    }
+
+    let (mut low, mut high) = (v_in.max(v_out), max_speed);
+    for _ in 0..SEARCH_ITERS {
+        let mid = (low + high) / 2.0;
+        if fits(mid) {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+
+    low
+}
@@ -9,7 +9,12 @@ mod control;
 mod networking;
 mod cam_sys_cntrl;
 use crate::config::Config;
+use crate::control::force_control::controllers::{
+    DEFAULT_PLANT_SENS, ForceController, PIDController, PIDWithNNTuner, pick_force_controller,
+};
 use crate::control::force_control::force_function_generator::ForceFunctionGenerator;
+use crate::control::force_control::simulation::{self, PlantParams};
+use crate::control::misc_tools::misc::{read_user_line, read_with_default};
 use cam_sys_cntrl::cam_sys_cntrl::CamSysCntrl;
 use std::sync::mpsc;
 use rustgeomapping::data_types::heightmap::Heightmap;
@@ -20,6 +25,8 @@ use std::process::{Command, Stdio};
 
 
 use control::abb_rob;
+use control::frames::Frames;
+use control::trajectory_planner;
 
 const VER_NUM: &str = "V0.9";
 //Program title
@@ -57,7 +64,7 @@ fn main() -> Result<(), anyhow::Error> {
 ///Handles commands given by the user - robot not required!
 fn core_cmd_handler(config: &mut Config) {
     //Array of implemented commands
-    const VALID_CMDS: [&str; 7] = [
+    const VALID_CMDS: [&str; 10] = [
         "info - get title and version number",
         "quit - close the program",
         "cmds - list the currently implemented commands",
@@ -65,6 +72,9 @@ fn core_cmd_handler(config: &mut Config) {
         "connect - connect to a robot on a given ip and port (if successful unlocks robot specific commands",
         "analyse - analyse a previous tests data",
         "snsdpth - Take N heightmap measurements",
+        "nntrain - pre-train the NN PID tuner from logged test data",
+        "simctl - simulate a force controller against a soil model",
+        "trajlib - list, preview and add trajectories in the trajectory library",
     ];
 
     println!("{TITLE} - {VER_NUM}");
@@ -95,6 +105,12 @@ fn core_cmd_handler(config: &mut Config) {
 
             "connect" => rob_connect(config),
 
+            "nntrain" => nn_pretrain(),
+
+            "simctl" => sim_control(config),
+
+            "trajlib" => trajectory_planner::library::user_interface(&Frames::load_or_default()),
+
             //Currently testing how to create sinusoid force signals
             "test" => {
 
@@ -169,3 +185,137 @@ fn rob_connect(config: &mut Config) {
         println!("{TITLE} - {VER_NUM}");
     }
 }
+
+///Pre-train the NN PID tuner for a soil/tool combination from previously logged tests
+fn nn_pretrain() {
+    let soil = read_user_line("Soil name:");
+    let tool = read_user_line("Tool name:");
+    let plant_sens = read_with_default("Soil stiffness estimate (N/mm)", DEFAULT_PLANT_SENS);
+
+    //Collect the data files - either given directly or found in a test directory
+    let paths = read_user_line("Enter the test directories/data files (comma separated):");
+    let mut data_fps: Vec<String> = vec![];
+
+    for path in paths.split(",").map(|p| p.trim()).filter(|p| !p.is_empty()) {
+        if let Ok(dir) = std::fs::read_dir(path) {
+            for entry in dir.flatten() {
+                let name = entry.file_name().to_string_lossy().to_string();
+                if name.starts_with("data_") && name.ends_with(".txt") {
+                    data_fps.push(entry.path().to_string_lossy().to_string());
+                }
+            }
+        } else {
+            data_fps.push(path.to_string());
+        }
+    }
+
+    if data_fps.is_empty() {
+        println!("No data files found");
+        return;
+    }
+
+    let epochs = read_with_default("Number of epochs", 5_usize);
+
+    let gains = abb_rob::PHASE3_GAINS;
+    match PIDWithNNTuner::create(gains[0], gains[1], gains[2], &soil, &tool, plant_sens) {
+        Ok(mut tuner) => {
+            if let Err(e) = tuner.pretrain_from_logs(&data_fps, epochs) {
+                println!("Pre-training failed - {}", e);
+            }
+        }
+        Err(e) => println!("Failed to create the NN tuner - {}", e),
+    }
+}
+
+///Simulate a force controller against a soil plant for a force profile and report the response
+fn sim_control(config: &Config) {
+    let ffunc = match ForceFunctionGenerator::user_interface() {
+        Ok(ffunc) => ffunc,
+        Err(e) => {
+            println!("Invalid force profile - {}", e);
+            return;
+        }
+    };
+
+    let duration = read_with_default("Simulation duration (s)", ffunc.duration().unwrap_or(60.0));
+
+    //Plant setup
+    let default_plant = PlantParams::default();
+    let plant = PlantParams {
+        stiffness: read_with_default("Soil stiffness (N/mm)", default_plant.stiffness),
+        latency: read_with_default("EGM latency (s)", default_plant.latency),
+        noise_std: read_with_default("Load cell noise std (N)", default_plant.noise_std),
+        seed: read_with_default("Noise seed", default_plant.seed),
+        ..default_plant
+    };
+
+    //Controller setup
+    let default_gains = abb_rob::PHASE3_GAINS;
+    let gains = [
+        read_with_default("P gain", default_gains[0]),
+        read_with_default("I gain", default_gains[1]),
+        read_with_default("D gain", default_gains[2]),
+    ];
+
+    let mut controller: Box<dyn ForceController> = match pick_force_controller(gains, "default") {
+        Some(cntrl) => cntrl,
+        None => Box::new(PIDController::create_PID(gains[0], gains[1], gains[2])),
+    };
+    let controller_desc = controller.to_string();
+
+    //Percentage profiles are stretched over the simulation
+    let ffunc = match ffunc.duration() {
+        Some(_) => ffunc,
+        None => match ffunc.with_duration(duration) {
+            Ok(ffunc) => ffunc,
+            Err(e) => {
+                println!("Invalid simulation duration - {}", e);
+                return;
+            }
+        },
+    };
+
+    println!("Simulating {} - {}", controller_desc, plant);
+    let samples = match simulation::simulate(controller.as_mut(), &plant, &ffunc, duration) {
+        Ok(samples) => samples,
+        Err(e) => {
+            println!("Simulation failed - {}", e);
+            return;
+        }
+    };
+
+    let metrics = simulation::calc_metrics(&samples);
+    println!("{}", metrics);
+
+    //Store the results alongside the test data
+    let run_name = read_user_line("Name the simulation run (blank to not save):");
+    if run_name.is_empty() {
+        return;
+    }
+
+    let sim_fp = format!("{}/simctl", config.test_fp());
+    if let Err(e) = std::fs::create_dir_all(&sim_fp) {
+        println!("Failed to create the simulation directory - {}", e);
+        return;
+    }
+
+    if let Err(e) = simulation::save_trace_csv(&samples, &format!("{}/sim_{}.csv", sim_fp, run_name)) {
+        println!("Failed to save the simulation trace - {}", e);
+    }
+
+    if let Err(e) = simulation::append_results_csv(
+        &format!("{}/simctl_results.csv", sim_fp),
+        &run_name,
+        &controller_desc,
+        &plant,
+        &metrics,
+    ) {
+        println!("Failed to save the simulation results - {}", e);
+    }
+
+    if let Err(e) = ffunc.save_to_file(&format!("{}/forcefunc_{}.txt", sim_fp, run_name)) {
+        println!("Failed to save the force profile - {}", e);
+    }
+
+    println!("Saved to {}", sim_fp);
+}